@@ -0,0 +1,15 @@
+-- Create Deliveries Table
+CREATE TABLE deliveries(
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id),
+    subscriber_email TEXT NOT NULL,
+    status TEXT NOT NULL
+        CHECK (status IN ('queued', 'sent', 'failed', 'bounced')),
+    attempts SMALLINT NOT NULL DEFAULT 0,
+    last_error TEXT NULL,
+    provider_message_id TEXT NULL,
+    updated_at timestamptz NOT NULL,
+    PRIMARY KEY (newsletter_issue_id, subscriber_id)
+);
@@ -0,0 +1,17 @@
+-- Add open and click tracking to newsletter issues
+ALTER TABLE newsletter_issues ADD COLUMN track_opens BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE newsletter_issues ADD COLUMN track_clicks BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE tracking_events(
+    tracking_event_id uuid NOT NULL,
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id),
+    kind TEXT NOT NULL
+        CHECK (kind IN ('open', 'click')),
+    url TEXT NULL,
+    occurred_at timestamptz NOT NULL,
+    PRIMARY KEY (tracking_event_id)
+);
+CREATE INDEX tracking_events_newsletter_issue_id_idx ON tracking_events (newsletter_issue_id, kind);
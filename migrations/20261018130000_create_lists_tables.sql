@@ -0,0 +1,44 @@
+-- Create Lists and List Subscriptions Tables
+CREATE TABLE lists(
+    list_id uuid NOT NULL,
+    slug TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    created_at timestamptz NOT NULL,
+    PRIMARY KEY (list_id)
+);
+
+-- Every existing subscriber and issue belonged to the single implicit list
+INSERT INTO lists (list_id, slug, name, created_at)
+VALUES ('00000000-0000-0000-0000-000000000001', 'default', 'Newsletter', now());
+
+CREATE TABLE list_subscriptions(
+    list_id uuid NOT NULL
+        REFERENCES lists (list_id),
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id),
+    status TEXT NOT NULL
+        CHECK (status IN ('pending_confirmation', 'confirmed')),
+    subscribed_at timestamptz NOT NULL,
+    PRIMARY KEY (list_id, subscriber_id)
+);
+
+INSERT INTO list_subscriptions (list_id, subscriber_id, status, subscribed_at)
+SELECT '00000000-0000-0000-0000-000000000001', id, status, subscribed_at
+FROM subscriptions;
+
+ALTER TABLE subscription_tokens ADD COLUMN list_id uuid NULL
+    REFERENCES lists (list_id);
+UPDATE subscription_tokens SET list_id = '00000000-0000-0000-0000-000000000001';
+ALTER TABLE subscription_tokens ALTER COLUMN list_id SET NOT NULL;
+
+CREATE TABLE newsletter_issue_lists(
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    list_id uuid NOT NULL
+        REFERENCES lists (list_id),
+    PRIMARY KEY (newsletter_issue_id, list_id)
+);
+
+INSERT INTO newsletter_issue_lists (newsletter_issue_id, list_id)
+SELECT newsletter_issue_id, '00000000-0000-0000-0000-000000000001'
+FROM newsletter_issues;
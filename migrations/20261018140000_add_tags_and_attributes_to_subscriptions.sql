@@ -0,0 +1,13 @@
+-- Add tags and custom attributes to subscribers
+ALTER TABLE subscriptions ADD COLUMN attributes JSONB NOT NULL DEFAULT '{}';
+
+CREATE TABLE subscriber_tags(
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id),
+    tag TEXT NOT NULL,
+    PRIMARY KEY (subscriber_id, tag)
+);
+CREATE INDEX subscriber_tags_tag_idx ON subscriber_tags (tag);
+
+-- Keep track of the segment an issue was sent to
+ALTER TABLE newsletter_issues ADD COLUMN segment TEXT NULL;
@@ -0,0 +1,24 @@
+-- Every subscriber gets a stable token to manage their preferences
+ALTER TABLE subscriptions ADD COLUMN preferences_token TEXT NULL;
+UPDATE subscriptions SET preferences_token = replace(gen_random_uuid()::text, '-', '');
+ALTER TABLE subscriptions ALTER COLUMN preferences_token SET NOT NULL;
+ALTER TABLE subscriptions
+    ADD CONSTRAINT subscriptions_preferences_token_key UNIQUE (preferences_token);
+
+ALTER TABLE subscriptions ADD COLUMN digest_frequency TEXT NOT NULL DEFAULT 'immediate'
+    CHECK (digest_frequency IN ('immediate', 'daily', 'weekly'));
+ALTER TABLE subscriptions ADD COLUMN paused_until TIMESTAMPTZ NULL;
+
+-- Deliveries to digest subscribers are sent by the background worker once due;
+-- the others are sent as soon as the issue is published.
+ALTER TABLE deliveries ADD COLUMN scheduled_for TIMESTAMPTZ NULL;
+CREATE INDEX deliveries_due_idx ON deliveries (scheduled_for) WHERE status = 'queued';
+
+CREATE TABLE email_change_tokens(
+    email_change_token TEXT NOT NULL,
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id),
+    new_email TEXT NOT NULL,
+    requested_at timestamptz NOT NULL,
+    PRIMARY KEY (email_change_token)
+);
@@ -0,0 +1,15 @@
+-- Subscription tokens also confirm email address changes
+ALTER TABLE subscription_tokens ADD COLUMN purpose TEXT NOT NULL DEFAULT 'subscribe'
+    CHECK (purpose IN ('subscribe', 'email_change'));
+ALTER TABLE subscription_tokens ADD COLUMN new_email TEXT NULL;
+ALTER TABLE subscription_tokens ADD COLUMN created_at timestamptz NOT NULL DEFAULT now();
+ALTER TABLE subscription_tokens ALTER COLUMN list_id DROP NOT NULL;
+ALTER TABLE subscription_tokens ADD CONSTRAINT subscription_tokens_target_check CHECK (
+    (purpose = 'subscribe' AND list_id IS NOT NULL AND new_email IS NULL)
+    OR (purpose = 'email_change' AND list_id IS NULL AND new_email IS NOT NULL)
+);
+
+INSERT INTO subscription_tokens (subscription_token, subscriber_id, purpose, new_email, created_at)
+SELECT email_change_token, subscriber_id, 'email_change', new_email, requested_at
+FROM email_change_tokens;
+DROP TABLE email_change_tokens;
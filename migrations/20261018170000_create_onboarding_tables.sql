@@ -0,0 +1,32 @@
+-- The onboarding sequence sent after a subscriber confirms their email address
+CREATE TABLE onboarding_emails(
+    step SMALLINT NOT NULL CHECK (step > 0),
+    delay_days INTEGER NOT NULL CHECK (delay_days >= 0),
+    subject TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    PRIMARY KEY (step)
+);
+
+INSERT INTO onboarding_emails (step, delay_days, subject, html_content, text_content)
+VALUES (
+    1,
+    0,
+    'Welcome aboard!',
+    '<p>Hi {{name}},</p><p>Thanks for confirming your subscription, the next issue will land in your inbox soon.</p>',
+    E'Hi {{name}},\n\nThanks for confirming your subscription, the next issue will land in your inbox soon.'
+);
+
+CREATE TABLE onboarding_deliveries(
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id),
+    step SMALLINT NOT NULL
+        REFERENCES onboarding_emails (step) ON DELETE CASCADE,
+    scheduled_for timestamptz NOT NULL,
+    status TEXT NOT NULL
+        CHECK (status IN ('scheduled', 'sent', 'failed', 'cancelled')),
+    updated_at timestamptz NOT NULL,
+    PRIMARY KEY (subscriber_id, step)
+);
+CREATE INDEX onboarding_deliveries_due_idx ON onboarding_deliveries (scheduled_for)
+    WHERE status = 'scheduled';
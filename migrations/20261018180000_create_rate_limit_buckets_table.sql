@@ -0,0 +1,6 @@
+-- Token buckets shared by every instance when rate limits are stored in Postgres
+CREATE TABLE rate_limit_buckets(
+    bucket_key TEXT NOT NULL PRIMARY KEY,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at timestamptz NOT NULL
+);
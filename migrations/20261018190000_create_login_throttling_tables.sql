@@ -0,0 +1,17 @@
+-- Recent failed logins per username and per client address
+CREATE TABLE login_throttles(
+    throttle_key TEXT NOT NULL PRIMARY KEY,
+    failures INT NOT NULL,
+    last_failure_at timestamptz NOT NULL,
+    locked_until timestamptz
+);
+
+-- Audit trail of every rejected login attempt
+CREATE TABLE failed_logins(
+    failed_login_id uuid NOT NULL PRIMARY KEY,
+    username TEXT NOT NULL,
+    client_ip TEXT,
+    reason TEXT NOT NULL CHECK (reason IN ('invalid_credentials', 'locked_out')),
+    attempted_at timestamptz NOT NULL
+);
+CREATE INDEX failed_logins_username_idx ON failed_logins (username, attempted_at);
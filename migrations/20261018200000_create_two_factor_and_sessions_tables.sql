@@ -0,0 +1,29 @@
+-- TOTP secrets, pending until a first code has been verified
+CREATE TABLE user_totp(
+    user_id uuid NOT NULL PRIMARY KEY REFERENCES users (user_id) ON DELETE CASCADE,
+    secret BYTEA NOT NULL,
+    confirmed_at timestamptz,
+    -- The step of the last accepted code, which cannot be used again
+    last_used_step BIGINT,
+    created_at timestamptz NOT NULL
+);
+
+CREATE TABLE recovery_codes(
+    recovery_code_id uuid NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    code_hash TEXT NOT NULL,
+    used_at timestamptz
+);
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
+
+-- Sessions are identified by the hash of their token. A session waiting for the second
+-- factor of its login cannot be used to authenticate requests.
+CREATE TABLE user_sessions(
+    session_id uuid NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL UNIQUE,
+    status TEXT NOT NULL CHECK (status IN ('pending_second_factor', 'active')),
+    created_at timestamptz NOT NULL,
+    expires_at timestamptz NOT NULL
+);
+CREATE INDEX user_sessions_user_id_idx ON user_sessions (user_id);
@@ -0,0 +1,14 @@
+-- Keys are identified by the public prefix embedded in them and stored hashed
+CREATE TABLE api_keys(
+    api_key_id uuid NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    lookup_prefix TEXT NOT NULL UNIQUE,
+    key_hash TEXT NOT NULL,
+    scopes TEXT[] NOT NULL,
+    created_at timestamptz NOT NULL,
+    expires_at timestamptz,
+    last_used_at timestamptz,
+    revoked_at timestamptz
+);
+CREATE INDEX api_keys_user_id_idx ON api_keys (user_id);
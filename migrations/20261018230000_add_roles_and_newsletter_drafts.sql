@@ -0,0 +1,22 @@
+-- Roles, existing users keep the power they had while new ones start as viewers
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'admin'
+    CHECK (role IN ('admin', 'publisher', 'editor', 'viewer'));
+ALTER TABLE users ALTER COLUMN role SET DEFAULT 'viewer';
+
+-- Issues prepared by editors, until a publisher sends them
+CREATE TABLE newsletter_drafts(
+    newsletter_draft_id uuid NOT NULL,
+    title TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    lists TEXT[] NOT NULL,
+    segment TEXT NULL,
+    track_opens BOOLEAN NOT NULL,
+    track_clicks BOOLEAN NOT NULL,
+    created_by uuid NOT NULL REFERENCES users (user_id),
+    created_at timestamptz NOT NULL,
+    updated_at timestamptz NOT NULL,
+    newsletter_issue_id uuid NULL REFERENCES newsletter_issues (newsletter_issue_id),
+    PRIMARY KEY (newsletter_draft_id)
+);
+CREATE INDEX newsletter_drafts_updated_at_idx ON newsletter_drafts (updated_at DESC);
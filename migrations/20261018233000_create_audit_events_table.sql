@@ -0,0 +1,31 @@
+-- Append-only record of administrative actions
+CREATE TABLE audit_events(
+    audit_event_id BIGINT GENERATED ALWAYS AS IDENTITY,
+    occurred_at timestamptz NOT NULL,
+    -- Not a foreign key, so that events outlive the users who caused them
+    actor_user_id uuid NOT NULL,
+    actor_api_key_id uuid NULL,
+    action TEXT NOT NULL,
+    target_type TEXT NOT NULL,
+    target_id TEXT NOT NULL,
+    request_id TEXT NULL,
+    client_ip TEXT NULL,
+    diff JSONB NOT NULL,
+    PRIMARY KEY (audit_event_id)
+);
+CREATE INDEX audit_events_actor_user_id_idx ON audit_events (actor_user_id, audit_event_id);
+CREATE INDEX audit_events_target_idx ON audit_events (target_type, target_id, audit_event_id);
+CREATE INDEX audit_events_action_idx ON audit_events (action, audit_event_id);
+
+CREATE FUNCTION reject_audit_event_changes() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_append_only
+    BEFORE UPDATE OR DELETE ON audit_events
+    FOR EACH ROW EXECUTE FUNCTION reject_audit_event_changes();
+CREATE TRIGGER audit_events_no_truncate
+    BEFORE TRUNCATE ON audit_events
+    FOR EACH STATEMENT EXECUTE FUNCTION reject_audit_event_changes();
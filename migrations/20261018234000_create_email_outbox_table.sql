@@ -0,0 +1,19 @@
+-- Emails written in the same transaction as the state they refer to, sent after commit
+CREATE TABLE email_outbox(
+    outbox_message_id uuid NOT NULL,
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    status TEXT NOT NULL
+        CHECK (status IN ('pending', 'sent', 'failed')),
+    attempts INT NOT NULL DEFAULT 0,
+    -- While an attempt is in flight, the time after which it is presumed lost
+    next_attempt_at timestamptz NOT NULL,
+    last_error TEXT NULL,
+    created_at timestamptz NOT NULL,
+    sent_at timestamptz NULL,
+    PRIMARY KEY (outbox_message_id)
+);
+CREATE INDEX email_outbox_pending_idx ON email_outbox (next_attempt_at)
+    WHERE status = 'pending';
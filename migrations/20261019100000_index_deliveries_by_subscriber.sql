@@ -0,0 +1,2 @@
+-- The latest delivery to each subscriber tells whether their address bounced
+CREATE INDEX deliveries_subscriber_id_idx ON deliveries (subscriber_id, updated_at DESC);
@@ -0,0 +1,191 @@
+use crate::session::{generate_token, hash_token};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Every API key starts with this, so that they are easy to tell apart from session
+/// tokens and to spot in leaked files.
+const KEY_PREFIX: &str = "nlk_";
+const LOOKUP_PREFIX_LENGTH: usize = 8;
+
+/// What a request is allowed to do. Passwords and sessions grant the scopes of the user's
+/// [`Role`](crate::role::Role), API keys only those they were created with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    NewslettersDraft,
+    NewslettersPublish,
+    IssuesRead,
+    ListsRead,
+    ListsWrite,
+    OnboardingRead,
+    OnboardingWrite,
+    SubscribersRead,
+    SubscribersWrite,
+    AuditRead,
+    /// Managing the account itself, including its API keys. Never granted to API keys.
+    Account,
+    /// Managing other users and their roles. Never granted to API keys.
+    Users,
+}
+
+impl Scope {
+    pub const GRANTABLE: [Self; 10] = [
+        Self::NewslettersDraft,
+        Self::NewslettersPublish,
+        Self::IssuesRead,
+        Self::ListsRead,
+        Self::ListsWrite,
+        Self::OnboardingRead,
+        Self::OnboardingWrite,
+        Self::SubscribersRead,
+        Self::SubscribersWrite,
+        Self::AuditRead,
+    ];
+
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NewslettersDraft => "newsletters:draft",
+            Self::NewslettersPublish => "newsletters:publish",
+            Self::IssuesRead => "issues:read",
+            Self::ListsRead => "lists:read",
+            Self::ListsWrite => "lists:write",
+            Self::OnboardingRead => "onboarding:read",
+            Self::OnboardingWrite => "onboarding:write",
+            Self::SubscribersRead => "subscribers:read",
+            Self::SubscribersWrite => "subscribers:write",
+            Self::AuditRead => "audit:read",
+            Self::Account => "account",
+            Self::Users => "users",
+        }
+    }
+
+    /// Only the scopes that can be granted to an API key are parsed.
+    #[must_use]
+    pub fn parse_grantable(s: &str) -> Option<Self> {
+        Self::GRANTABLE
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[must_use]
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(KEY_PREFIX)
+}
+
+/// A new key, along with the public part used to look it up.
+pub struct GeneratedKey {
+    pub key: String,
+    pub lookup_prefix: String,
+    pub key_hash: String,
+}
+
+#[must_use]
+pub fn generate_api_key() -> GeneratedKey {
+    let mut rng = thread_rng();
+    let lookup_prefix: String = std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(|c| char::from(c).to_ascii_lowercase())
+        .take(LOOKUP_PREFIX_LENGTH)
+        .collect();
+    let key = format!("{KEY_PREFIX}{lookup_prefix}_{}", generate_token());
+    GeneratedKey {
+        key_hash: hash_token(&key),
+        lookup_prefix,
+        key,
+    }
+}
+
+fn lookup_prefix(key: &str) -> Option<&str> {
+    let rest = key.strip_prefix(KEY_PREFIX)?;
+    let (prefix, _) = rest.split_once('_')?;
+    (prefix.len() == LOOKUP_PREFIX_LENGTH).then_some(prefix)
+}
+
+/// Stored in the request extensions when a request is authenticated with an API key.
+#[derive(Clone, Copy, Debug)]
+pub struct AuthenticatedApiKey(pub Uuid);
+
+pub struct ApiKey {
+    pub api_key_id: Uuid,
+    pub user_id: Uuid,
+    pub scopes: Vec<Scope>,
+}
+
+/// The usable key matching `key`, if any, recording that it was used.
+/// # Errors
+/// Returns an error if a query fails.
+#[tracing::instrument(name = "Find an API key", skip(pool, key))]
+pub async fn find_api_key(pool: &PgPool, key: &str) -> Result<Option<ApiKey>, sqlx::Error> {
+    let Some(lookup_prefix) = lookup_prefix(key) else {
+        return Ok(None);
+    };
+    let Some(row) = sqlx::query!(
+        r#"
+        SELECT api_key_id, user_id, key_hash, scopes
+        FROM api_keys
+        WHERE lookup_prefix = $1
+            AND revoked_at IS NULL
+            AND (expires_at IS NULL OR expires_at > now())
+        "#,
+        lookup_prefix,
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    if row.key_hash != hash_token(key) {
+        return Ok(None);
+    }
+    sqlx::query!(
+        r#"UPDATE api_keys SET last_used_at = now() WHERE api_key_id = $1"#,
+        row.api_key_id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(Some(ApiKey {
+        api_key_id: row.api_key_id,
+        user_id: row.user_id,
+        scopes: row
+            .scopes
+            .iter()
+            .filter_map(|scope| Scope::parse_grantable(scope))
+            .collect(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate_api_key, is_api_key, lookup_prefix, Scope};
+
+    #[test]
+    fn generated_keys_carry_their_lookup_prefix() {
+        let generated = generate_api_key();
+        assert!(is_api_key(&generated.key));
+        assert_eq!(
+            lookup_prefix(&generated.key),
+            Some(generated.lookup_prefix.as_str())
+        );
+        assert_eq!(lookup_prefix("nlk_short_secret"), None);
+        assert_eq!(lookup_prefix("a-session-token"), None);
+    }
+
+    #[test]
+    fn the_account_scope_cannot_be_granted() {
+        assert_eq!(
+            Scope::parse_grantable("newsletters:publish"),
+            Some(Scope::NewslettersPublish)
+        );
+        assert_eq!(Scope::parse_grantable("account"), None);
+        assert_eq!(Scope::parse_grantable("users"), None);
+        assert_eq!(Scope::parse_grantable("everything"), None);
+    }
+}
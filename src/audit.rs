@@ -0,0 +1,96 @@
+use crate::api_key::AuthenticatedApiKey;
+use crate::login_throttle::LoginThrottle;
+use actix_web::web::Data;
+use actix_web::{HttpMessage, HttpRequest};
+use chrono::Utc;
+use sqlx::{Executor, Postgres};
+use tracing_actix_web::RequestId;
+use uuid::Uuid;
+
+/// A state-changing administrative action, along with the state of its target before and
+/// after it, as far as the action changed it.
+pub struct AuditEvent {
+    action: &'static str,
+    target_type: &'static str,
+    target_id: String,
+    before: serde_json::Value,
+    after: serde_json::Value,
+}
+
+impl AuditEvent {
+    #[must_use]
+    pub fn new(action: &'static str, target_type: &'static str, target_id: impl ToString) -> Self {
+        Self {
+            action,
+            target_type,
+            target_id: target_id.to_string(),
+            before: serde_json::Value::Null,
+            after: serde_json::Value::Null,
+        }
+    }
+
+    #[must_use]
+    pub fn before(mut self, before: serde_json::Value) -> Self {
+        self.before = before;
+        self
+    }
+
+    #[must_use]
+    pub fn after(mut self, after: serde_json::Value) -> Self {
+        self.after = after;
+        self
+    }
+}
+
+/// Append `event`, caused by `actor_user_id` through `request`, to the audit log. Record it
+/// in the same transaction as the change whenever there is one, so that neither is kept
+/// without the other.
+/// # Errors
+/// Returns an error if the query fails.
+#[tracing::instrument(
+    name = "Record an audit event",
+    skip(executor, request, event),
+    fields(action = event.action, target_type = event.target_type, target_id = %event.target_id)
+)]
+pub async fn record_audit_event<'c, E>(
+    executor: E,
+    request: &HttpRequest,
+    actor_user_id: Uuid,
+    event: AuditEvent,
+) -> Result<(), sqlx::Error>
+where
+    E: Executor<'c, Database = Postgres>,
+{
+    let actor_api_key_id = request
+        .extensions()
+        .get::<AuthenticatedApiKey>()
+        .map(|key| key.0);
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(ToString::to_string);
+    // The login throttle knows which proxies to trust with the client address.
+    let client_ip = request
+        .app_data::<Data<LoginThrottle>>()
+        .and_then(|throttle| throttle.client_ip(request))
+        .map(|ip| ip.to_string());
+    sqlx::query!(
+        r#"
+        INSERT INTO audit_events (occurred_at, actor_user_id, actor_api_key_id, action,
+            target_type, target_id, request_id, client_ip, diff)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        "#,
+        Utc::now(),
+        actor_user_id,
+        actor_api_key_id,
+        event.action,
+        event.target_type,
+        event.target_id,
+        request_id,
+        client_ip,
+        serde_json::json!({ "before": event.before, "after": event.after }),
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
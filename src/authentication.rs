@@ -0,0 +1,421 @@
+use crate::api_key::{find_api_key, is_api_key, AuthenticatedApiKey, Scope};
+use crate::configuration::PasswordHashingSettings;
+use crate::login_throttle::LoginThrottle;
+use crate::role::{user_role, Role};
+use crate::session::{find_session, SessionStatus};
+use crate::telemetry::spawn_blocking_with_tracing;
+use actix_web::http::header::HeaderMap;
+use actix_web::web::Data;
+use actix_web::{HttpMessage, HttpRequest};
+use anyhow::Context;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use base64::Engine;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use tokio::sync::OwnedSemaphorePermit;
+
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    #[error("Invalid credentials.")]
+    InvalidCredentials(#[source] anyhow::Error),
+    #[error("Too many failed logins, try again in {retry_after} seconds.")]
+    LockedOut { retry_after: u64 },
+    #[error("The API key does not grant the {0} scope.")]
+    MissingScope(Scope),
+    #[error("The {role} role does not grant the {scope} scope.")]
+    NotPermitted { role: Role, scope: Scope },
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// How new passwords are hashed, and which stored hashes are due for an upgrade.
+#[derive(Clone)]
+pub struct PasswordPolicy {
+    params: Params,
+    /// Verified against when the username is unknown, so that it takes as long as for a
+    /// known user whose hash follows the policy.
+    dummy_hash: Secret<String>,
+}
+
+impl PasswordPolicy {
+    /// # Errors
+    /// Returns an error if the parameters are out of the range Argon2 accepts.
+    pub fn new(settings: &PasswordHashingSettings) -> Result<Self, argon2::password_hash::Error> {
+        let params = Params::new(
+            settings.memory_kib,
+            settings.iterations,
+            settings.parallelism,
+            None,
+        )?;
+        let salt = SaltString::generate(&mut OsRng);
+        let dummy_hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+            .hash_password(salt.as_str().as_bytes(), &salt)?
+            .to_string();
+        Ok(Self {
+            params,
+            dummy_hash: Secret::new(dummy_hash),
+        })
+    }
+
+    fn hasher(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    /// Hash `password` with a fresh salt, in PHC string format.
+    /// # Errors
+    /// Returns an error if Argon2 fails to hash the password.
+    pub fn hash(&self, password: &Secret<String>) -> Result<Secret<String>, anyhow::Error> {
+        let salt = SaltString::generate(&mut OsRng);
+        let password_hash = self
+            .hasher()
+            .hash_password(password.expose_secret().as_bytes(), &salt)
+            .map_err(|e| anyhow::anyhow!(e))
+            .context("Failed to hash a password.")?
+            .to_string();
+        Ok(Secret::new(password_hash))
+    }
+
+    /// Whether `password_hash` was computed with another algorithm or with less memory,
+    /// fewer iterations or a different parallelism than the policy asks for.
+    #[must_use]
+    pub fn is_outdated(&self, password_hash: &PasswordHash) -> bool {
+        if password_hash.algorithm != Algorithm::Argon2id.ident()
+            || password_hash.version != Some(Version::V0x13.into())
+        {
+            return true;
+        }
+        Params::try_from(password_hash).map_or(true, |params| {
+            params.m_cost() < self.params.m_cost()
+                || params.t_cost() < self.params.t_cost()
+                || params.p_cost() != self.params.p_cost()
+        })
+    }
+}
+
+/// Authenticate a request carrying either a `Bearer` API key or session token, or `Basic`
+/// credentials, recording the outcome on the current span's `username` and `user_id`
+/// fields. Users with two-factor authentication must log in to get a session. The user's
+/// role, and the API key if one is used, must grant `scope`.
+/// # Errors
+/// See [`check_password`]. Unknown or expired sessions and API keys are reported as
+/// `AuthError::InvalidCredentials`, API keys without `scope` as `AuthError::MissingScope`
+/// and roles without it as `AuthError::NotPermitted`.
+pub async fn authenticate(
+    request: &HttpRequest,
+    pool: &PgPool,
+    scope: Scope,
+) -> Result<uuid::Uuid, AuthError> {
+    let user_id = identify(request, pool, scope).await?;
+    let role = user_role(pool, user_id)
+        .await
+        .context("Failed to retrieve the role of a user.")?;
+    if !role.grants(scope) {
+        return Err(AuthError::NotPermitted { role, scope });
+    }
+    Ok(user_id)
+}
+
+async fn identify(
+    request: &HttpRequest,
+    pool: &PgPool,
+    scope: Scope,
+) -> Result<uuid::Uuid, AuthError> {
+    if let Some(key) = bearer_token(request.headers()).filter(|token| is_api_key(token)) {
+        let api_key = find_api_key(pool, key)
+            .await
+            .context("Failed to retrieve an API key.")?
+            .ok_or_else(|| anyhow::anyhow!("Unknown, expired or revoked API key."))
+            .map_err(AuthError::InvalidCredentials)?;
+        tracing::Span::current().record("user_id", tracing::field::display(&api_key.user_id));
+        tracing::info!(api_key_id = %api_key.api_key_id, "Authenticated with an API key");
+        request
+            .extensions_mut()
+            .insert(AuthenticatedApiKey(api_key.api_key_id));
+        if !api_key.scopes.contains(&scope) {
+            return Err(AuthError::MissingScope(scope));
+        }
+        return Ok(api_key.user_id);
+    }
+    if let Some(token) = bearer_token(request.headers()) {
+        let session = find_session(pool, token, SessionStatus::Active)
+            .await
+            .context("Failed to retrieve a session.")?
+            .ok_or_else(|| anyhow::anyhow!("Unknown or expired session."))
+            .map_err(AuthError::InvalidCredentials)?;
+        tracing::Span::current().record("user_id", tracing::field::display(&session.user_id));
+        return Ok(session.user_id);
+    }
+    let credentials =
+        basic_authentication(request.headers()).map_err(AuthError::InvalidCredentials)?;
+    let user_id = check_password(request, pool, credentials).await?;
+    if two_factor_enabled(pool, user_id).await? {
+        return Err(AuthError::InvalidCredentials(anyhow::anyhow!(
+            "The user has two-factor authentication enabled and must log in."
+        )));
+    }
+    Ok(user_id)
+}
+
+/// Check a password, going through the [`LoginThrottle`] registered as application data.
+/// # Errors
+/// See [`validate_credentials`]. Attempts during a lockout are reported as
+/// `AuthError::LockedOut`.
+pub async fn check_password(
+    request: &HttpRequest,
+    pool: &PgPool,
+    credentials: Credentials,
+) -> Result<uuid::Uuid, AuthError> {
+    tracing::Span::current().record("username", tracing::field::display(&credentials.username));
+    let throttle = request
+        .app_data::<Data<LoginThrottle>>()
+        .context("The login throttle is not registered.")?;
+    let ip = throttle.client_ip(request);
+    throttle.admit(pool, &credentials.username, ip).await?;
+
+    let policy = request
+        .app_data::<Data<PasswordPolicy>>()
+        .context("The password policy is not registered.")?;
+
+    let username = credentials.username.clone();
+    let permit = throttle.verification_permit().await;
+    let outcome = validate_credentials(credentials, pool, policy, permit).await;
+    match &outcome {
+        Ok(_) => throttle.record_success(pool, &username).await?,
+        Err(AuthError::InvalidCredentials(_)) => {
+            throttle.record_failure(pool, &username, ip).await?;
+        }
+        Err(_) => {}
+    }
+    let user_id = outcome?;
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+    Ok(user_id)
+}
+
+/// Whether `user_id` has confirmed a two-factor enrolment.
+/// # Errors
+/// Returns an error if the query fails.
+#[tracing::instrument(name = "Check for two-factor authentication", skip(pool))]
+pub async fn two_factor_enabled(pool: &PgPool, user_id: uuid::Uuid) -> Result<bool, anyhow::Error> {
+    let enabled = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM user_totp WHERE user_id = $1 AND confirmed_at IS NOT NULL
+        ) AS "enabled!"
+        "#,
+        user_id,
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to check whether two-factor authentication is enabled.")?;
+    Ok(enabled)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("Authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// # Errors
+/// Returns an error if the `Authorization` header is missing or is not a well-formed
+/// `Basic` credential pair.
+pub fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, anyhow::Error> {
+    let header_value = headers
+        .get("Authorization")
+        .context("The 'Authorization' header was missing")?
+        .to_str()
+        .context("The 'Authorization' header was not a valid UTF8 string.")?;
+    let base64encoded_segment = header_value
+        .strip_prefix("Basic ")
+        .context("The authorization scheme was not 'Basic'.")?;
+    let decoded_bytes = base64::engine::general_purpose::STANDARD
+        .decode(base64encoded_segment)
+        .context("Failed to base64-decode 'Basic' credentials.")?;
+    let decoded_credentials = String::from_utf8(decoded_bytes)
+        .context("The decoded credential string is not valid UTF8.")?;
+    let mut credentials = decoded_credentials.splitn(2, ':');
+    let username = credentials
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("A username must be provided in 'Basic' auth."))?
+        .to_string();
+    let password = credentials
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("A password must be provided in 'Basic' auth."))?
+        .to_string();
+    Ok(Credentials {
+        username,
+        password: Secret::new(password),
+    })
+}
+
+/// Once the password is verified, a hash that is outdated according to `policy` is
+/// replaced in the background. `permit`, a turn to hash passwords, is held until then.
+/// # Errors
+/// Returns `AuthError::InvalidCredentials` if the username is unknown or the password
+/// does not match, `AuthError::UnexpectedError` otherwise.
+#[tracing::instrument(name = "Validate credentials", skip(credentials, pool, policy, permit))]
+pub async fn validate_credentials(
+    credentials: Credentials,
+    pool: &PgPool,
+    policy: &PasswordPolicy,
+    permit: OwnedSemaphorePermit,
+) -> Result<uuid::Uuid, AuthError> {
+    let mut user_id = None;
+    let mut expected_password_hash = policy.dummy_hash.clone();
+    if let Some((stored_user_id, stored_password_hash)) =
+        get_stored_credentials(&credentials.username, pool).await?
+    {
+        user_id = Some(stored_user_id);
+        expected_password_hash = stored_password_hash;
+    }
+
+    let stored_password_hash = expected_password_hash.clone();
+    let password = credentials.password.clone();
+    spawn_blocking_with_tracing(move || {
+        verify_password_hash(expected_password_hash, credentials.password)
+    })
+    .await
+    .context("Failed to spawn blocking task.")??;
+    let user_id = user_id
+        .ok_or_else(|| anyhow::anyhow!("Unknown username."))
+        .map_err(AuthError::InvalidCredentials)?;
+
+    let outdated = PasswordHash::new(stored_password_hash.expose_secret())
+        .map_or(true, |password_hash| policy.is_outdated(&password_hash));
+    if outdated {
+        tokio::spawn(upgrade_password_hash(
+            user_id,
+            password,
+            stored_password_hash,
+            policy.clone(),
+            pool.clone(),
+            permit,
+        ));
+    }
+    Ok(user_id)
+}
+
+/// Re-hash the password with the current policy. The hash is only replaced if it has not
+/// changed in the meantime, and a failure leaves the old hash in place for the next login.
+#[tracing::instrument(
+    name = "Upgrade a password hash",
+    skip(password, stored_password_hash, policy, pool, _permit)
+)]
+async fn upgrade_password_hash(
+    user_id: uuid::Uuid,
+    password: Secret<String>,
+    stored_password_hash: Secret<String>,
+    policy: PasswordPolicy,
+    pool: PgPool,
+    _permit: OwnedSemaphorePermit,
+) {
+    let upgrade = async {
+        let password_hash = spawn_blocking_with_tracing(move || policy.hash(&password))
+            .await
+            .context("Failed to spawn blocking task.")??;
+        sqlx::query!(
+            r#"UPDATE users SET password_hash = $1 WHERE user_id = $2 AND password_hash = $3"#,
+            password_hash.expose_secret(),
+            user_id,
+            stored_password_hash.expose_secret(),
+        )
+        .execute(&pool)
+        .await
+        .context("Failed to store the upgraded password hash.")?;
+        Ok::<_, anyhow::Error>(())
+    };
+    if let Err(error) = upgrade.await {
+        tracing::error!(error.cause_chain = ?error, "Failed to upgrade a password hash");
+    }
+}
+
+#[tracing::instrument(
+    name = "Verify password hash",
+    skip(expected_password_hash, password_candidate)
+)]
+fn verify_password_hash(
+    expected_password_hash: Secret<String>,
+    password_candidate: Secret<String>,
+) -> Result<(), AuthError> {
+    let expected_password_hash = PasswordHash::new(expected_password_hash.expose_secret())
+        .context("Failed to parse hash in PHC string format.")?;
+    // Verification uses the algorithm and parameters recorded in the hash itself.
+    Argon2::default()
+        .verify_password(
+            password_candidate.expose_secret().as_bytes(),
+            &expected_password_hash,
+        )
+        .context("Invalid password.")
+        .map_err(AuthError::InvalidCredentials)
+}
+
+#[tracing::instrument(name = "Get stored credentials", skip(username, pool))]
+async fn get_stored_credentials(
+    username: &str,
+    pool: &PgPool,
+) -> Result<Option<(uuid::Uuid, Secret<String>)>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"SELECT user_id, password_hash FROM users WHERE username = $1"#,
+        username,
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to perform a query to retrieve stored credentials.")?
+    .map(|row| (row.user_id, Secret::new(row.password_hash)));
+    Ok(row)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PasswordPolicy;
+    use crate::configuration::PasswordHashingSettings;
+    use argon2::PasswordHash;
+    use secrecy::{ExposeSecret, Secret};
+
+    fn policy(memory_kib: u32, iterations: u32) -> PasswordPolicy {
+        PasswordPolicy::new(&PasswordHashingSettings {
+            memory_kib,
+            iterations,
+            parallelism: 1,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn hashes_are_outdated_when_the_policy_asks_for_more() {
+        let password_hash = policy(8192, 2)
+            .hash(&Secret::new("password".into()))
+            .unwrap();
+        let password_hash = PasswordHash::new(password_hash.expose_secret()).unwrap();
+
+        assert!(!policy(8192, 2).is_outdated(&password_hash));
+        assert!(!policy(4096, 1).is_outdated(&password_hash));
+        assert!(policy(16384, 2).is_outdated(&password_hash));
+        assert!(policy(8192, 3).is_outdated(&password_hash));
+    }
+
+    #[test]
+    fn the_dummy_hash_costs_as_much_as_the_policy_asks_for() {
+        let policy = policy(8192, 3);
+        let dummy_hash = PasswordHash::new(policy.dummy_hash.expose_secret()).unwrap();
+
+        assert!(!policy.is_outdated(&dummy_hash));
+    }
+
+    #[test]
+    fn hashes_of_other_argon2_variants_are_outdated() {
+        let password_hash =
+            PasswordHash::new("$argon2i$v=19$m=16,t=2,p=1$c29tZXNhbHQ$ySD0UFqYRm9gjmfnqJFgAg")
+                .unwrap();
+        assert!(policy(8, 1).is_outdated(&password_hash));
+    }
+}
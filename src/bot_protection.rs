@@ -0,0 +1,370 @@
+use crate::configuration::{BotProtectionSettings, CaptchaProvider, CaptchaSettings};
+use crate::utils::hmac_sha256;
+use anyhow::Context;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use hmac::Mac;
+use reqwest::Client;
+use secrecy::{ExposeSecret, Secret};
+use std::future::Future;
+use std::pin::Pin;
+
+pub type VerifyFuture<'a> = Pin<Box<dyn Future<Output = Result<bool, anyhow::Error>> + Send + 'a>>;
+
+/// Checks the response of a captcha challenge with its provider.
+pub trait CaptchaVerifier: Send + Sync {
+    /// Returns whether the provider accepted `response`.
+    fn verify<'a>(&'a self, response: &'a str) -> VerifyFuture<'a>;
+}
+
+/// hCaptcha and Turnstile share the same `siteverify` protocol, at different endpoints.
+pub struct SiteVerify {
+    http_client: Client,
+    verify_url: String,
+    secret: Secret<String>,
+}
+
+impl SiteVerify {
+    /// # Panics
+    /// Panics if the HTTP client cannot be initialised.
+    #[must_use]
+    pub fn new(secret: Secret<String>, verify_url: String, timeout: std::time::Duration) -> Self {
+        Self {
+            http_client: Client::builder().timeout(timeout).build().unwrap(),
+            verify_url,
+            secret,
+        }
+    }
+
+    async fn site_verify(&self, response: &str) -> Result<bool, anyhow::Error> {
+        #[derive(serde::Deserialize)]
+        struct Verification {
+            success: bool,
+        }
+
+        let verification: Verification = self
+            .http_client
+            .post(&self.verify_url)
+            .form(&[
+                ("secret", self.secret.expose_secret().as_str()),
+                ("response", response),
+            ])
+            .send()
+            .await
+            .context("Failed to reach the captcha provider.")?
+            .error_for_status()
+            .context("The captcha provider rejected the verification request.")?
+            .json()
+            .await
+            .context("Failed to parse the response of the captcha provider.")?;
+        Ok(verification.success)
+    }
+}
+
+impl CaptchaVerifier for SiteVerify {
+    fn verify<'a>(&'a self, response: &'a str) -> VerifyFuture<'a> {
+        Box::pin(self.site_verify(response))
+    }
+}
+
+impl CaptchaProvider {
+    #[must_use]
+    pub fn verify_url(self) -> &'static str {
+        match self {
+            CaptchaProvider::HCaptcha => "https://api.hcaptcha.com/siteverify",
+            CaptchaProvider::Turnstile => {
+                "https://challenges.cloudflare.com/turnstile/v0/siteverify"
+            }
+        }
+    }
+}
+
+impl CaptchaSettings {
+    #[must_use]
+    pub fn verifier(self) -> Box<dyn CaptchaVerifier> {
+        let verify_url = self
+            .verify_url
+            .unwrap_or_else(|| self.provider.verify_url().into());
+        Box::new(SiteVerify::new(
+            self.secret,
+            verify_url,
+            std::time::Duration::from_millis(self.timeout_milliseconds),
+        ))
+    }
+}
+
+/// Why a submission looks automated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Suspicion {
+    HoneypotFilled,
+    MissingFormToken,
+    InvalidFormToken,
+    SubmittedTooQuickly,
+    FormTooOld,
+    CaptchaFailed,
+}
+
+impl Suspicion {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Suspicion::HoneypotFilled => "honeypot_filled",
+            Suspicion::MissingFormToken => "missing_form_token",
+            Suspicion::InvalidFormToken => "invalid_form_token",
+            Suspicion::SubmittedTooQuickly => "submitted_too_quickly",
+            Suspicion::FormTooOld => "form_too_old",
+            Suspicion::CaptchaFailed => "captcha_failed",
+        }
+    }
+}
+
+/// The parts of a signup submission that tell people and bots apart.
+pub struct Submission<'a> {
+    pub honeypot: Option<&'a str>,
+    pub form_token: Option<&'a str>,
+    pub captcha_response: Option<&'a str>,
+}
+
+pub struct BotProtection {
+    form_token_secret: Secret<String>,
+    min_submit_seconds: Option<u64>,
+    max_form_age_seconds: u64,
+    captcha: Option<Box<dyn CaptchaVerifier>>,
+}
+
+impl BotProtection {
+    #[must_use]
+    pub fn new(settings: BotProtectionSettings) -> Self {
+        Self {
+            form_token_secret: settings.form_token_secret,
+            min_submit_seconds: settings.min_submit_seconds,
+            max_form_age_seconds: settings.max_form_age_seconds,
+            captcha: settings.captcha.map(CaptchaSettings::verifier),
+        }
+    }
+
+    /// A token recording when the signup form was rendered, signed so it cannot be backdated.
+    /// It only proves the form was not submitted too quickly: it is not single use, and can
+    /// be replayed until it is `max_form_age_seconds` old. Replays remain subject to the
+    /// per IP and per email rate limits.
+    #[must_use]
+    pub fn issue_form_token(&self) -> String {
+        let issued_at = Utc::now().timestamp().to_string();
+        let signature = URL_SAFE_NO_PAD.encode(
+            hmac_sha256(&self.form_token_secret, issued_at.as_bytes())
+                .finalize()
+                .into_bytes(),
+        );
+        format!("{issued_at}.{signature}")
+    }
+
+    fn form_token_age(&self, token: &str) -> Option<i64> {
+        let (issued_at, signature) = token.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        hmac_sha256(&self.form_token_secret, issued_at.as_bytes())
+            .verify_slice(&signature)
+            .ok()?;
+        let issued_at: i64 = issued_at.parse().ok()?;
+        Some(Utc::now().timestamp() - issued_at)
+    }
+
+    /// Returns why the submission looks automated, if it does. The captcha is only checked
+    /// once the cheaper checks have passed. Submissions are let through if the captcha
+    /// provider cannot be reached, so that an outage does not take the signup flow down.
+    pub async fn screen(&self, submission: &Submission<'_>) -> Option<Suspicion> {
+        if submission.honeypot.is_some_and(|value| !value.is_empty()) {
+            return Some(Suspicion::HoneypotFilled);
+        }
+        if let Some(min_submit_seconds) = self.min_submit_seconds {
+            let Some(token) = submission.form_token else {
+                return Some(Suspicion::MissingFormToken);
+            };
+            let Some(age) = self.form_token_age(token) else {
+                return Some(Suspicion::InvalidFormToken);
+            };
+            let age = u64::try_from(age).unwrap_or_default();
+            if age < min_submit_seconds {
+                return Some(Suspicion::SubmittedTooQuickly);
+            }
+            if age > self.max_form_age_seconds {
+                return Some(Suspicion::FormTooOld);
+            }
+        }
+        if let Some(captcha) = &self.captcha {
+            let passed = match submission.captcha_response {
+                Some(response) if !response.is_empty() => {
+                    captcha.verify(response).await.unwrap_or_else(|error| {
+                        tracing::error!(
+                            error.cause_chain = ?error,
+                            "Failed to verify a captcha response, letting the submission through",
+                        );
+                        true
+                    })
+                }
+                _ => false,
+            };
+            if !passed {
+                return Some(Suspicion::CaptchaFailed);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BotProtection, CaptchaVerifier, SiteVerify, Submission, Suspicion};
+    use crate::configuration::{BotProtectionSettings, CaptchaProvider, CaptchaSettings};
+    use claims::{assert_err, assert_ok_eq};
+    use secrecy::Secret;
+    use std::time::Duration;
+    use wiremock::matchers::{body_string_contains, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn settings(min_submit_seconds: Option<u64>) -> BotProtectionSettings {
+        BotProtectionSettings {
+            form_token_secret: Secret::new("form-secret".into()),
+            min_submit_seconds,
+            max_form_age_seconds: 3600,
+            captcha: None,
+        }
+    }
+
+    fn bot_protection(min_submit_seconds: Option<u64>) -> BotProtection {
+        BotProtection::new(settings(min_submit_seconds))
+    }
+
+    fn submission<'a>(honeypot: Option<&'a str>, form_token: Option<&'a str>) -> Submission<'a> {
+        Submission {
+            honeypot,
+            form_token,
+            captcha_response: None,
+        }
+    }
+
+    async fn mount_siteverify(server: &MockServer) {
+        Mock::given(method("POST"))
+            .and(path("/siteverify"))
+            .and(body_string_contains("secret=captcha-secret"))
+            .and(body_string_contains("response=good"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "success": true
+            })))
+            .mount(server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/siteverify"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "success": false,
+                "error-codes": ["invalid-input-response"]
+            })))
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn an_empty_honeypot_is_not_suspicious() {
+        let protection = bot_protection(None);
+        assert_eq!(protection.screen(&submission(Some(""), None)).await, None);
+        assert_eq!(
+            protection
+                .screen(&submission(Some("http://spam"), None))
+                .await,
+            Some(Suspicion::HoneypotFilled)
+        );
+    }
+
+    #[tokio::test]
+    async fn form_tokens_must_be_old_enough_and_genuine() {
+        let protection = bot_protection(Some(0));
+        let token = protection.issue_form_token();
+        assert_eq!(
+            protection.screen(&submission(None, Some(&token))).await,
+            None
+        );
+        assert_eq!(
+            protection.screen(&submission(None, None)).await,
+            Some(Suspicion::MissingFormToken)
+        );
+        let backdated = format!("1{}", &token[token.find('.').unwrap()..]);
+        assert_eq!(
+            protection.screen(&submission(None, Some(&backdated))).await,
+            Some(Suspicion::InvalidFormToken)
+        );
+
+        let protection = bot_protection(Some(60));
+        assert_eq!(
+            protection.screen(&submission(None, Some(&token))).await,
+            Some(Suspicion::SubmittedTooQuickly)
+        );
+    }
+
+    fn site_verify(server: &MockServer) -> SiteVerify {
+        SiteVerify::new(
+            Secret::new("captcha-secret".into()),
+            format!("{}/siteverify", server.uri()),
+            Duration::from_millis(200),
+        )
+    }
+
+    #[tokio::test]
+    async fn site_verify_accepts_the_responses_the_provider_validates() {
+        let server = MockServer::start().await;
+        mount_siteverify(&server).await;
+        let verifier = site_verify(&server);
+
+        assert_ok_eq!(verifier.verify("good").await, true);
+        assert_ok_eq!(verifier.verify("bad").await, false);
+    }
+
+    #[test]
+    fn providers_are_verified_at_their_own_endpoint() {
+        assert_eq!(
+            CaptchaProvider::HCaptcha.verify_url(),
+            "https://api.hcaptcha.com/siteverify"
+        );
+        assert_eq!(
+            CaptchaProvider::Turnstile.verify_url(),
+            "https://challenges.cloudflare.com/turnstile/v0/siteverify"
+        );
+    }
+
+    #[tokio::test]
+    async fn verification_fails_if_the_provider_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        assert_err!(site_verify(&server).verify("good").await);
+    }
+
+    #[tokio::test]
+    async fn submissions_are_let_through_if_the_provider_is_down() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&server)
+            .await;
+        let protection = BotProtection::new(BotProtectionSettings {
+            captcha: Some(CaptchaSettings {
+                provider: CaptchaProvider::Turnstile,
+                secret: Secret::new("captcha-secret".into()),
+                verify_url: Some(format!("{}/siteverify", server.uri())),
+                timeout_milliseconds: 200,
+            }),
+            ..settings(None)
+        });
+        let mut submission = submission(None, None);
+
+        assert_eq!(
+            protection.screen(&submission).await,
+            Some(Suspicion::CaptchaFailed),
+            "a response is still required"
+        );
+        submission.captcha_response = Some("anything");
+        assert_eq!(protection.screen(&submission).await, None);
+    }
+}
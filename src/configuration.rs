@@ -1,24 +1,233 @@
 use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::mailbox::Mailbox;
+use ipnet::IpNet;
 use secrecy::{ExposeSecret, Secret};
-use serde_aux::field_attributes::deserialize_number_from_string;
+use serde_aux::field_attributes::{
+    deserialize_number_from_string, deserialize_option_number_from_string,
+};
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
 use sqlx::ConnectOptions;
+use std::net::IpAddr;
+use std::path::PathBuf;
 
 #[derive(serde::Deserialize, Clone)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub feed: FeedSettings,
+    pub tracking: TrackingSettings,
+    pub confirmation: ConfirmationSettings,
+    pub rate_limit: RateLimitSettings,
+    pub bot_protection: BotProtectionSettings,
+    pub login_throttle: LoginThrottleSettings,
+    pub password_hashing: PasswordHashingSettings,
+    pub sessions: SessionSettings,
+    pub two_factor: TwoFactorSettings,
+    pub password_reset: PasswordResetSettings,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct PasswordResetSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub token_ttl_minutes: i64,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SessionSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub ttl_minutes: i64,
+    /// How long a login may wait for its second factor.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub second_factor_timeout_seconds: i64,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct TwoFactorSettings {
+    /// The name authenticator apps display next to the codes.
+    pub issuer: String,
+    /// Users must enable two-factor authentication before they can publish.
+    pub required_for_publishers: bool,
+}
+
+/// The Argon2id parameters of new password hashes. Stored hashes with weaker parameters
+/// are upgraded the next time their owner logs in.
+#[derive(serde::Deserialize, Clone)]
+pub struct PasswordHashingSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub memory_kib: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub iterations: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub parallelism: u32,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct LoginThrottleSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_failures_per_username: i32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_failures_per_ip: i32,
+    /// Failures older than this are forgotten.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub failure_window_seconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub lockout_seconds: u64,
+    /// The delay before verifying a password after a failure, doubled for every further
+    /// failure up to `max_delay_milliseconds`.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub base_delay_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_delay_milliseconds: u64,
+    /// Password hashes verified at once, the other attempts wait for their turn.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_concurrent_verifications: usize,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct BotProtectionSettings {
+    pub form_token_secret: Secret<String>,
+    /// Submissions must carry a form token issued at least this many seconds earlier.
+    /// Form tokens are not required when unset.
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub min_submit_seconds: Option<u64>,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_form_age_seconds: u64,
+    pub captcha: Option<CaptchaSettings>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct CaptchaSettings {
+    pub provider: CaptchaProvider,
+    pub secret: Secret<String>,
+    /// Overrides the provider's verification endpoint.
+    pub verify_url: Option<String>,
+    pub timeout_milliseconds: u64,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptchaProvider {
+    HCaptcha,
+    Turnstile,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct RateLimitSettings {
+    pub enabled: bool,
+    pub store: RateLimitStore,
+    /// Proxies whose `X-Forwarded-For` header is trusted to carry the client address,
+    /// as addresses or networks in CIDR notation.
+    #[serde(default, deserialize_with = "deserialize_networks")]
+    pub trusted_proxies: Vec<IpNet>,
+    #[serde(default)]
+    pub subscribe: RouteLimits,
+    #[serde(default)]
+    pub confirm: RouteLimits,
+    #[serde(default)]
+    pub password_reset: RouteLimits,
+}
+
+fn deserialize_networks<'de, D>(deserializer: D) -> Result<Vec<IpNet>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let values: Vec<String> = serde::Deserialize::deserialize(deserializer)?;
+    values
+        .iter()
+        .map(|value| {
+            value
+                .parse()
+                .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| {
+                    serde::de::Error::custom(format!("{value} is not an IP address or network."))
+                })
+        })
+        .collect()
+}
+
+/// Postgres shares the buckets between every instance of the application.
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitStore {
+    Memory,
+    Postgres,
+}
+
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct RouteLimits {
+    pub per_ip: Option<TokenBucket>,
+    pub per_email: Option<TokenBucket>,
+}
+
+/// Allows bursts of `capacity` requests, then one request every `refill_interval_seconds`.
+#[derive(serde::Deserialize, Clone, Copy)]
+pub struct TokenBucket {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub capacity: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub refill_interval_seconds: u64,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct ConfirmationSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub token_ttl_hours: i64,
+    /// Directory holding `<outcome>.html` files that replace the built-in pages.
+    pub templates_directory: Option<String>,
+    #[serde(default)]
+    pub redirects: ConfirmationRedirects,
+}
+
+/// External pages to send subscribers to instead of rendering a page, per outcome.
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct ConfirmationRedirects {
+    pub confirmed: Option<String>,
+    pub already_confirmed: Option<String>,
+    pub unknown_token: Option<String>,
+    pub expired_token: Option<String>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct TrackingSettings {
+    pub opens_enabled: bool,
+    pub clicks_enabled: bool,
+    pub hmac_secret: Secret<String>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct FeedSettings {
+    pub title: String,
+    pub description: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_items: i64,
 }
 
 #[derive(serde::Deserialize, Clone)]
 pub struct EmailClientSettings {
+    #[serde(default)]
+    pub backend: EmailBackend,
+    /// Where the `file` backend writes emails.
+    #[serde(default)]
+    pub mailbox_directory: Option<PathBuf>,
     pub base_url: String,
     pub sender_email: String,
     pub authorization_token: Secret<String>,
     pub timeout_milliseconds: u64,
 }
 
+/// Where emails go. `file` and `log` let the application run without Postmark during
+/// development.
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailBackend {
+    #[default]
+    Postmark,
+    File,
+    Log,
+}
+
 impl EmailClientSettings {
     /// # Errors
     /// Returns an error if `sender_email` fails to parse into an `SubscriberEmail`.
@@ -30,6 +239,46 @@ impl EmailClientSettings {
     pub fn timeout(&self) -> std::time::Duration {
         std::time::Duration::from_millis(self.timeout_milliseconds)
     }
+
+    /// The mailbox the `file` backend writes to, `None` for other backends.
+    /// # Panics
+    /// Panics if the `file` backend is selected without a `mailbox_directory`.
+    #[must_use]
+    pub fn mailbox(&self) -> Option<Mailbox> {
+        (self.backend == EmailBackend::File).then(|| {
+            let directory = self
+                .mailbox_directory
+                .clone()
+                .expect("The file email backend requires a mailbox directory.");
+            Mailbox::new(directory)
+        })
+    }
+
+    /// # Panics
+    /// Panics if `sender_email` is not a valid email address, or if the `file` backend is
+    /// selected without a `mailbox_directory`.
+    #[must_use]
+    pub fn client(self) -> EmailClient {
+        let sender_email = self.sender().expect("Invalid sender email address.");
+        match self.backend {
+            EmailBackend::Postmark => {
+                let timeout = self.timeout();
+                EmailClient::new(
+                    self.base_url,
+                    sender_email,
+                    self.authorization_token,
+                    timeout,
+                )
+            }
+            EmailBackend::File => {
+                let directory = self
+                    .mailbox_directory
+                    .expect("The file email backend requires a mailbox directory.");
+                EmailClient::mailbox(sender_email, Mailbox::new(directory))
+            }
+            EmailBackend::Log => EmailClient::log(sender_email),
+        }
+    }
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -38,6 +287,8 @@ pub struct ApplicationSettings {
     pub port: u16,
     pub host: String,
     pub base_url: String,
+    /// Set from `APP_ENVIRONMENT`.
+    pub environment: Environment,
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -49,9 +300,17 @@ pub struct DatabaseSettings {
     pub host: String,
     pub database_name: String,
     pub require_ssl: bool,
+    /// How long a request waits for a pooled connection before failing.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub acquire_timeout_seconds: u64,
 }
 
 impl DatabaseSettings {
+    #[must_use]
+    pub fn acquire_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.acquire_timeout_seconds)
+    }
+
     #[must_use]
     pub fn with_db(&self) -> PgConnectOptions {
         let options = self.without_db().database(&self.database_name);
@@ -74,6 +333,8 @@ impl DatabaseSettings {
     }
 }
 
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(try_from = "String")]
 pub enum Environment {
     Local,
     Production,
@@ -126,6 +387,7 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
                 .prefix_separator("_")
                 .separator("__"),
         )
+        .set_override("application.environment", environment.as_str())?
         .build()?;
     settings.try_deserialize()
 }
@@ -0,0 +1,289 @@
+use crate::configuration::Settings;
+use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailClient, EmailError};
+use crate::email_outbox::{retry_delay, ATTEMPT_LEASE_SECONDS};
+use crate::routes::add_preferences_footer;
+use crate::startup::get_connection_pool;
+use crate::utils::escape_html;
+use anyhow::Context;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Attempts after which a delivery is given up on.
+const MAX_ATTEMPTS: i32 = 5;
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+struct DueIssue {
+    newsletter_issue_id: Uuid,
+    title: String,
+    text_content: String,
+    html_content: String,
+    attempts: i32,
+}
+
+/// # Errors
+/// Never returns under normal operation; failed tasks are logged and retried.
+pub async fn run_worker_until_stopped(configuration: Settings) -> Result<(), anyhow::Error> {
+    let pool = get_connection_pool(&configuration.database);
+    let email_client = configuration.email_client.client();
+    worker_loop(&pool, &email_client, &configuration.application.base_url).await
+}
+
+async fn worker_loop(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+) -> Result<(), anyhow::Error> {
+    loop {
+        match try_execute_task(pool, email_client, base_url).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+/// Send a single digest combining every due issue of one subscriber. The deliveries are
+/// claimed with a lease, as in the outbox, so that no connection or lock is held while the
+/// email is sent: a digest whose outcome was never recorded is sent again once it expires.
+/// # Errors
+/// Returns an error if the database cannot be reached. Failing to send the email is
+/// recorded on the deliveries instead.
+#[tracing::instrument(
+    skip_all,
+    fields(subscriber_id=tracing::field::Empty, issues=tracing::field::Empty),
+    err
+)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let Some(recipient) = sqlx::query!(
+        r#"
+        SELECT d.subscriber_id, d.subscriber_email, s.preferences_token
+        FROM deliveries d
+        JOIN subscriptions s ON s.id = d.subscriber_id
+        WHERE d.status = 'queued' AND d.scheduled_for <= now()
+        LIMIT 1
+        FOR UPDATE OF d SKIP LOCKED
+        "#
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to dequeue a digest recipient.")?
+    else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+    tracing::Span::current().record(
+        "subscriber_id",
+        tracing::field::display(&recipient.subscriber_id),
+    );
+    cancel_ineligible_deliveries(&mut transaction, recipient.subscriber_id)
+        .await
+        .context("Failed to cancel the deliveries a digest recipient opted out of.")?;
+    let issues = claim_due_issues(&mut transaction, recipient.subscriber_id)
+        .await
+        .context("Failed to claim the issues due to a digest recipient.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to claim a digest.")?;
+    tracing::Span::current().record("issues", issues.len());
+    if issues.is_empty() {
+        return Ok(ExecutionOutcome::TaskCompleted);
+    }
+
+    let (subject, html, text) = render_digest(&issues);
+    let (html, text) = add_preferences_footer(&html, &text, base_url, &recipient.preferences_token);
+    let outcome = match SubscriberEmail::parse(recipient.subscriber_email) {
+        Ok(email) => email_client
+            .send_email(&email, &subject, &html, &text)
+            .await
+            .with_context(|| format!("Failed to send a digest to {email}")),
+        Err(error) => Err(anyhow::anyhow!(error)),
+    };
+    let issue_ids: Vec<Uuid> = issues.iter().map(|i| i.newsletter_issue_id).collect();
+    match outcome {
+        Ok(_) => sqlx::query!(
+            r#"
+            UPDATE deliveries
+            SET status = 'sent', attempts = attempts + 1, last_error = NULL, updated_at = now()
+            WHERE subscriber_id = $1 AND newsletter_issue_id = ANY($2)
+            "#,
+            recipient.subscriber_id,
+            &issue_ids,
+        )
+        .execute(pool)
+        .await
+        .map(|_| ()),
+        Err(error) => {
+            tracing::warn!(
+                error.cause_chain = ?error,
+                "Failed to deliver a digest to a confirmed subscriber",
+            );
+            let attempt = issues.iter().map(|i| i.attempts).max().unwrap_or(0) + 1;
+            record_failed_delivery(pool, recipient.subscriber_id, &issue_ids, attempt, &error).await
+        }
+    }
+    .context("Failed to record the outcome of a digest.")?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+/// Record that the email carrying `issue_ids` to a subscriber failed on its `attempt`-th
+/// attempt. Errors Postmark may get over keep the deliveries queued, for this worker to retry
+/// with the back-off of the outbox, and longer when Postmark asked to slow down. Recipients
+/// Postmark marked as inactive are recorded as bounced, which stops later deliveries to them.
+/// # Errors
+/// Returns an error if the deliveries cannot be updated.
+#[tracing::instrument(name = "Record a failed delivery", skip(executor, error))]
+pub async fn record_failed_delivery<'e>(
+    executor: impl Executor<'e, Database = Postgres>,
+    subscriber_id: Uuid,
+    issue_ids: &[Uuid],
+    attempt: i32,
+    error: &anyhow::Error,
+) -> Result<(), sqlx::Error> {
+    let (status, retry_after) = match error.downcast_ref::<EmailError>() {
+        Some(error) if error.is_permanent() => ("bounced", None),
+        Some(error) if error.is_transient() && attempt < MAX_ATTEMPTS => {
+            let delay = match error {
+                EmailError::RateLimited => retry_delay(attempt + 2),
+                _ => retry_delay(attempt),
+            };
+            ("queued", Some(delay.as_secs_f64()))
+        }
+        _ => ("failed", None),
+    };
+    sqlx::query!(
+        r#"
+        UPDATE deliveries
+        SET status = $3,
+            attempts = attempts + 1,
+            last_error = $4,
+            scheduled_for = COALESCE(now() + make_interval(secs => $5), scheduled_for),
+            updated_at = now()
+        WHERE subscriber_id = $1 AND newsletter_issue_id = ANY($2)
+        "#,
+        subscriber_id,
+        issue_ids,
+        status,
+        format!("{error:#}"),
+        retry_after,
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// The subscriber may have paused or left a list since the issues were published: their
+/// deliveries are dropped, as if the subscriber had not been eligible at publication.
+async fn cancel_ineligible_deliveries(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let cancelled = sqlx::query!(
+        r#"
+        DELETE FROM deliveries d
+        USING subscriptions s
+        WHERE s.id = d.subscriber_id
+            AND d.subscriber_id = $1
+            AND d.status = 'queued'
+            AND d.scheduled_for <= now()
+            AND (
+                s.paused_until > now()
+                OR NOT EXISTS (
+                    SELECT 1
+                    FROM newsletter_issue_lists il
+                    JOIN list_subscriptions ls ON ls.list_id = il.list_id
+                    WHERE il.newsletter_issue_id = d.newsletter_issue_id
+                        AND ls.subscriber_id = d.subscriber_id
+                        AND ls.status = 'confirmed'
+                )
+            )
+        "#,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await?
+    .rows_affected();
+    if cancelled > 0 {
+        tracing::info!(
+            cancelled,
+            "Cancelled deliveries to an ineligible subscriber"
+        );
+    }
+    Ok(())
+}
+
+/// Lease the due deliveries of a subscriber, pushing them back until the lease expires.
+async fn claim_due_issues(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<Vec<DueIssue>, sqlx::Error> {
+    sqlx::query_as!(
+        DueIssue,
+        r#"
+        WITH claimed AS (
+            UPDATE deliveries SET scheduled_for = now() + make_interval(secs => $2)
+            WHERE subscriber_id = $1 AND status = 'queued' AND scheduled_for <= now()
+            RETURNING newsletter_issue_id, attempts
+        )
+        SELECT
+            i.newsletter_issue_id,
+            i.title,
+            i.text_content,
+            i.html_content,
+            c.attempts AS "attempts!"
+        FROM claimed c
+        JOIN newsletter_issues i ON i.newsletter_issue_id = c.newsletter_issue_id
+        ORDER BY i.published_at
+        "#,
+        subscriber_id,
+        ATTEMPT_LEASE_SECONDS,
+    )
+    .fetch_all(&mut **transaction)
+    .await
+}
+
+/// A digest with a single issue is sent as that issue.
+fn render_digest(issues: &[DueIssue]) -> (String, String, String) {
+    if let [issue] = issues {
+        return (
+            issue.title.clone(),
+            issue.html_content.clone(),
+            issue.text_content.clone(),
+        );
+    }
+    let subject = format!("Your newsletter digest: {} new issues", issues.len());
+    let html = issues
+        .iter()
+        .map(|issue| {
+            format!(
+                "<h1>{}</h1>\n{}",
+                escape_html(&issue.title),
+                issue.html_content
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n<hr />\n");
+    let text = issues
+        .iter()
+        .map(|issue| format!("{}\n\n{}", issue.title, issue.text_content))
+        .collect::<Vec<_>>()
+        .join("\n\n---\n\n");
+    (subject, html, text)
+}
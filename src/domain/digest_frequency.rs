@@ -0,0 +1,51 @@
+/// How often a subscriber wants to receive newly published issues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestFrequency {
+    Immediate,
+    Daily,
+    Weekly,
+}
+
+impl DigestFrequency {
+    pub const ALL: [DigestFrequency; 3] = [
+        DigestFrequency::Immediate,
+        DigestFrequency::Daily,
+        DigestFrequency::Weekly,
+    ];
+
+    /// # Errors
+    /// Returns `Err` if the input is not one of `immediate`, `daily` or `weekly`.
+    pub fn parse(s: &str) -> Result<DigestFrequency, String> {
+        Self::ALL
+            .into_iter()
+            .find(|frequency| frequency.as_str() == s)
+            .ok_or_else(|| format!("{s} is not a valid digest frequency."))
+    }
+
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DigestFrequency::Immediate => "immediate",
+            DigestFrequency::Daily => "daily",
+            DigestFrequency::Weekly => "weekly",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DigestFrequency;
+    use claims::{assert_err, assert_ok_eq};
+
+    #[test]
+    fn known_frequencies_round_trip() {
+        for frequency in DigestFrequency::ALL {
+            assert_ok_eq!(DigestFrequency::parse(frequency.as_str()), frequency);
+        }
+    }
+    #[test]
+    fn unknown_frequencies_are_rejected() {
+        assert_err!(DigestFrequency::parse("hourly"));
+        assert_err!(DigestFrequency::parse("Daily"));
+    }
+}
@@ -0,0 +1,68 @@
+#[derive(Debug)]
+pub struct ListSlug(String);
+
+impl ListSlug {
+    /// The list every subscription and issue targets when none is specified.
+    pub const DEFAULT: &'static str = "default";
+
+    /// Returns an instance of `ListSlug` if the input satisfies all
+    /// our validation constraints on list identifiers.
+    /// # Errors
+    /// Returns `Err` if the input fails any of our validation constraints.
+    pub fn parse(s: String) -> Result<ListSlug, String> {
+        let is_valid_length = (1..=64).contains(&s.len());
+        let has_valid_characters = s
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        let has_valid_boundaries = !s.starts_with('-') && !s.ends_with('-');
+        if is_valid_length && has_valid_characters && has_valid_boundaries {
+            Ok(Self(s))
+        } else {
+            Err(format!("{s} is not a valid list identifier."))
+        }
+    }
+}
+
+impl AsRef<str> for ListSlug {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for ListSlug {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ListSlug;
+    use claims::{assert_err, assert_ok};
+
+    #[test]
+    fn the_default_slug_is_valid() {
+        assert_ok!(ListSlug::parse(ListSlug::DEFAULT.to_string()));
+    }
+    #[test]
+    fn lowercase_letters_digits_and_dashes_are_valid() {
+        assert_ok!(ListSlug::parse("rust-weekly-2".to_string()));
+    }
+    #[test]
+    fn empty_string_is_rejected() {
+        assert_err!(ListSlug::parse(String::new()));
+    }
+    #[test]
+    fn a_slug_longer_than_64_characters_is_rejected() {
+        assert_err!(ListSlug::parse("a".repeat(65)));
+    }
+    #[test]
+    fn uppercase_letters_and_spaces_are_rejected() {
+        assert_err!(ListSlug::parse("Rust Weekly".to_string()));
+    }
+    #[test]
+    fn leading_or_trailing_dashes_are_rejected() {
+        assert_err!(ListSlug::parse("-rust".to_string()));
+        assert_err!(ListSlug::parse("rust-".to_string()));
+    }
+}
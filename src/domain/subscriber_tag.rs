@@ -0,0 +1,50 @@
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriberTag(String);
+
+impl SubscriberTag {
+    /// Returns an instance of `SubscriberTag` if the input satisfies all
+    /// our validation constraints on subscriber tags.
+    /// # Errors
+    /// Returns `Err` if the input fails any of our validation constraints.
+    pub fn parse(s: String) -> Result<SubscriberTag, String> {
+        let is_valid_length = (1..=64).contains(&s.len());
+        let has_valid_characters = s
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+        if is_valid_length && has_valid_characters {
+            Ok(Self(s))
+        } else {
+            Err(format!("{s} is not a valid subscriber tag."))
+        }
+    }
+}
+
+impl AsRef<str> for SubscriberTag {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SubscriberTag;
+    use claims::{assert_err, assert_ok};
+
+    #[test]
+    fn lowercase_letters_digits_dashes_and_underscores_are_valid() {
+        assert_ok!(SubscriberTag::parse("beta-testers_2".to_string()));
+    }
+    #[test]
+    fn empty_string_is_rejected() {
+        assert_err!(SubscriberTag::parse(String::new()));
+    }
+    #[test]
+    fn a_tag_longer_than_64_characters_is_rejected() {
+        assert_err!(SubscriberTag::parse("a".repeat(65)));
+    }
+    #[test]
+    fn whitespace_and_punctuation_are_rejected() {
+        assert_err!(SubscriberTag::parse("beta testers".to_string()));
+        assert_err!(SubscriberTag::parse("beta'testers".to_string()));
+    }
+}
@@ -1,15 +1,109 @@
 use crate::domain::SubscriberEmail;
-use reqwest::Client;
+use crate::mailbox::{Mailbox, OutgoingEmail};
+use reqwest::{Client, StatusCode};
 use secrecy::{ExposeSecret, Secret};
+use std::sync::Arc;
+
+/// The most messages Postmark accepts in a single batch.
+const MAX_BATCH_SIZE: usize = 500;
 
 pub struct EmailClient {
-    http_client: Client,
-    base_url: String,
     sender: SubscriberEmail,
-    authorization_token: Secret<String>,
+    backend: Backend,
+}
+
+enum Backend {
+    Postmark {
+        http_client: Client,
+        base_url: String,
+        authorization_token: Secret<String>,
+    },
+    /// Development only: emails are written to a directory instead of being sent.
+    Mailbox(Mailbox),
+    /// Development only: emails are logged instead of being sent.
+    Log,
+}
+
+/// Why an email was not sent, as far as Postmark tells, so that callers can stop sending to
+/// bad addresses and back off when asked to. Cheap to clone, so that the failure of a batch
+/// can be reported for each of its emails.
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum EmailError {
+    /// The recipient bounced or complained before, and Postmark no longer sends to them.
+    #[error("The recipient is inactive: {message}")]
+    InactiveRecipient { message: String },
+    #[error("Postmark is rate limiting requests.")]
+    RateLimited,
+    #[error("Postmark does not accept the server token: {message}")]
+    UnauthorizedToken { message: String },
+    #[error("Postmark failed with status {status}.")]
+    ServerError { status: u16 },
+    #[error("Postmark rejected the email with error {error_code}: {message}")]
+    Rejected { error_code: i64, message: String },
+    #[error("Postmark did not report the outcome of the email.")]
+    MissingOutcome,
+    #[error("Failed to reach Postmark.")]
+    Transport(#[source] Arc<reqwest::Error>),
+    #[error("Failed to write the email.")]
+    Storage(#[source] Arc<std::io::Error>),
+}
+
+impl EmailError {
+    /// Sending the email again is bound to fail the same way.
+    #[must_use]
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, Self::InactiveRecipient { .. })
+    }
+
+    /// Postmark may accept the email if it is sent again later.
+    #[must_use]
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::RateLimited | Self::ServerError { .. } | Self::Transport(_)
+        )
+    }
+
+    /// Classify a failure from the HTTP status of the response and the `ErrorCode` and
+    /// `Message` Postmark returned, for the request or for one email of a batch.
+    fn from_postmark(status: StatusCode, error_code: i64, message: String) -> Self {
+        match (status, error_code) {
+            (StatusCode::UNAUTHORIZED, _) | (_, 10) => Self::UnauthorizedToken { message },
+            (StatusCode::TOO_MANY_REQUESTS, _) => Self::RateLimited,
+            (status, _) if status.is_server_error() => Self::ServerError {
+                status: status.as_u16(),
+            },
+            (_, 406) => Self::InactiveRecipient { message },
+            (_, error_code) => Self::Rejected {
+                error_code,
+                message,
+            },
+        }
+    }
+}
+
+impl From<reqwest::Error> for EmailError {
+    fn from(error: reqwest::Error) -> Self {
+        Self::Transport(Arc::new(error))
+    }
+}
+
+impl From<std::io::Error> for EmailError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Storage(Arc::new(error))
+    }
+}
+
+/// One of the emails of a batch, each with its own content.
+pub struct BatchEmail<'a> {
+    pub recipient: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
 }
 
 impl EmailClient {
+    /// A client sending emails through Postmark.
     /// # Panics
     /// This function panics if the provided `base_url` is not a valid URL.
     #[must_use]
@@ -21,44 +115,228 @@ impl EmailClient {
     ) -> Self {
         let http_client = Client::builder().timeout(timeout).build().unwrap();
         Self {
-            http_client,
-            base_url,
             sender,
-            authorization_token,
+            backend: Backend::Postmark {
+                http_client,
+                base_url,
+                authorization_token,
+            },
+        }
+    }
+
+    /// A client writing emails to `mailbox` as `.eml` files.
+    #[must_use]
+    pub fn mailbox(sender: SubscriberEmail, mailbox: Mailbox) -> Self {
+        Self {
+            sender,
+            backend: Backend::Mailbox(mailbox),
+        }
+    }
+
+    /// A client logging emails, with both of their bodies, instead of sending them.
+    #[must_use]
+    pub fn log(sender: SubscriberEmail) -> Self {
+        Self {
+            sender,
+            backend: Backend::Log,
         }
     }
 
+    /// Returns the message id the backend assigned to the email, if any: Postmark's if its
+    /// response carried one, the id of the file for the mailbox.
     /// # Errors
-    /// This function returns an error if the request fails or times out.
+    /// This function returns an error if the request fails or times out, or if the email
+    /// cannot be written.
     pub async fn send_email(
         &self,
         recipient: &SubscriberEmail,
         subject: &str,
         html_content: &str,
         text_content: &str,
-    ) -> Result<(), reqwest::Error> {
-        let url = format!("{}/email", self.base_url);
-        let request_body = SendEmailRequest {
-            from: self.sender.as_ref(),
-            to: recipient.as_ref(),
-            subject,
-            html_body: html_content,
-            text_body: text_content,
-        };
-        self.http_client
-            .post(&url)
-            .header(
-                "X-Postmark-Server-Token",
-                self.authorization_token.expose_secret(),
-            )
-            .json(&request_body)
-            .send()
-            .await?
-            .error_for_status()?;
-        Ok(())
+    ) -> Result<Option<String>, EmailError> {
+        match &self.backend {
+            Backend::Postmark {
+                http_client,
+                base_url,
+                authorization_token,
+            } => {
+                let url = format!("{base_url}/email");
+                let request_body = SendEmailRequest {
+                    from: self.sender.as_ref(),
+                    to: recipient.as_ref(),
+                    subject,
+                    html_body: html_content,
+                    text_body: text_content,
+                };
+                let response_body =
+                    post_to_postmark(http_client, &url, authorization_token, &request_body).await?;
+                let message_id = serde_json::from_slice::<SendEmailResponse>(&response_body)
+                    .ok()
+                    .map(|response| response.message_id);
+                Ok(message_id)
+            }
+            Backend::Mailbox(mailbox) => {
+                let email = OutgoingEmail {
+                    from: self.sender.as_ref(),
+                    to: recipient.as_ref(),
+                    subject,
+                    html_content,
+                    text_content,
+                };
+                let message_id = mailbox.store(&email).await?;
+                Ok(Some(message_id.to_string()))
+            }
+            Backend::Log => {
+                tracing::info!(
+                    email.from = %self.sender,
+                    email.to = %recipient,
+                    email.subject = subject,
+                    email.text_body = text_content,
+                    email.html_body = html_content,
+                    "Logged an email instead of sending it"
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Send `emails`, returning the outcome of each in the same order: the message id as
+    /// for [`EmailClient::send_email`], or why that email was not sent. Postmark receives
+    /// them in batches of up to 500; a batch that fails as a whole fails each of its emails.
+    pub async fn send_batch(
+        &self,
+        emails: &[BatchEmail<'_>],
+    ) -> Vec<Result<Option<String>, EmailError>> {
+        let mut outcomes = Vec::with_capacity(emails.len());
+        match &self.backend {
+            Backend::Postmark {
+                http_client,
+                base_url,
+                authorization_token,
+            } => {
+                for chunk in emails.chunks(MAX_BATCH_SIZE) {
+                    let request_body: Vec<_> = chunk
+                        .iter()
+                        .map(|email| SendEmailRequest {
+                            from: self.sender.as_ref(),
+                            to: email.recipient.as_ref(),
+                            subject: email.subject,
+                            html_body: email.html_content,
+                            text_body: email.text_content,
+                        })
+                        .collect();
+                    let response = send_postmark_batch(
+                        http_client,
+                        base_url,
+                        authorization_token,
+                        &request_body,
+                    )
+                    .await;
+                    match response {
+                        Ok(Some(results)) => {
+                            let mut results = results.into_iter();
+                            outcomes.extend(chunk.iter().map(|_| match results.next() {
+                                Some(result) if result.error_code == 0 => Ok(result.message_id),
+                                Some(result) => Err(EmailError::from_postmark(
+                                    StatusCode::OK,
+                                    result.error_code,
+                                    result.message,
+                                )),
+                                None => Err(EmailError::MissingOutcome),
+                            }));
+                        }
+                        // Accepted, as by `send_email`, without telling the message ids.
+                        Ok(None) => outcomes.extend(chunk.iter().map(|_| Ok(None))),
+                        Err(error) => outcomes.extend(chunk.iter().map(|_| Err(error.clone()))),
+                    }
+                }
+            }
+            Backend::Mailbox(_) | Backend::Log => {
+                for email in emails {
+                    let outcome = self
+                        .send_email(
+                            email.recipient,
+                            email.subject,
+                            email.html_content,
+                            email.text_content,
+                        )
+                        .await;
+                    outcomes.push(outcome);
+                }
+            }
+        }
+        outcomes
     }
 }
 
+/// The results of the batch, or `None` if the response did not carry them.
+async fn send_postmark_batch(
+    http_client: &Client,
+    base_url: &str,
+    authorization_token: &Secret<String>,
+    request_body: &[SendEmailRequest<'_>],
+) -> Result<Option<Vec<BatchResult>>, EmailError> {
+    let url = format!("{base_url}/email/batch");
+    let response_body =
+        post_to_postmark(http_client, &url, authorization_token, request_body).await?;
+    Ok(serde_json::from_slice(&response_body).ok())
+}
+
+/// Returns the body of a successful response.
+async fn post_to_postmark(
+    http_client: &Client,
+    url: &str,
+    authorization_token: &Secret<String>,
+    request_body: &(impl serde::Serialize + ?Sized),
+) -> Result<Vec<u8>, EmailError> {
+    let response = http_client
+        .post(url)
+        .header(
+            "X-Postmark-Server-Token",
+            authorization_token.expose_secret(),
+        )
+        .json(request_body)
+        .send()
+        .await?;
+    let status = response.status();
+    let response_body = response.bytes().await?;
+    if status.is_success() {
+        return Ok(response_body.to_vec());
+    }
+    let PostmarkError {
+        error_code,
+        message,
+    } = serde_json::from_slice(&response_body).unwrap_or_default();
+    let error = EmailError::from_postmark(status, error_code, message);
+    if let EmailError::UnauthorizedToken { .. } = error {
+        // Nothing gets sent until the configuration is fixed.
+        tracing::error!(error.message = %error, "Postmark rejected the server token");
+    }
+    Err(error)
+}
+
+#[derive(serde::Deserialize, Default)]
+#[serde(rename_all = "PascalCase", default)]
+struct PostmarkError {
+    error_code: i64,
+    message: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BatchResult {
+    error_code: i64,
+    message: String,
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct SendEmailResponse {
+    #[serde(rename = "MessageID")]
+    message_id: String,
+}
+
 #[derive(serde::Serialize)]
 #[serde(rename_all = "PascalCase")]
 struct SendEmailRequest<'a> {
@@ -72,8 +350,9 @@ struct SendEmailRequest<'a> {
 #[cfg(test)]
 mod tests {
     use crate::domain::SubscriberEmail;
-    use crate::email_client::EmailClient;
-    use claims::{assert_err, assert_ok};
+    use crate::email_client::{BatchEmail, EmailClient, EmailError};
+    use crate::mailbox::Mailbox;
+    use claims::{assert_err, assert_none, assert_ok, assert_some, assert_some_eq};
     use fake::faker::internet::en::SafeEmail;
     use fake::faker::lorem::en::{Paragraph, Sentence};
     use fake::{Fake, Faker};
@@ -161,6 +440,49 @@ mod tests {
         assert_ok!(outcome);
     }
 
+    #[tokio::test]
+    async fn send_email_returns_the_provider_message_id() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let response = ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "To": "receiver@example.com",
+            "SubmittedAt": "2014-02-17T07:25:01.4178645-05:00",
+            "MessageID": "0a129aee-e1cd-480d-b08d-4f48548ff48d",
+            "ErrorCode": 0,
+            "Message": "OK"
+        }));
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        assert_some_eq!(assert_ok!(outcome), "0a129aee-e1cd-480d-b08d-4f48548ff48d");
+    }
+
+    #[tokio::test]
+    async fn send_email_succeeds_without_a_message_id_if_the_body_is_unexpected() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        assert_none!(assert_ok!(outcome));
+    }
+
     #[tokio::test]
     async fn send_email_fails_if_the_server_returns_500() {
         let mock_server = MockServer::start().await;
@@ -197,4 +519,236 @@ mod tests {
 
         assert_err!(outcome);
     }
+
+    #[tokio::test]
+    async fn the_mailbox_backend_writes_an_eml_file() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let email_client = EmailClient::mailbox(email(), Mailbox::new(directory.clone()));
+
+        let message_id = email_client
+            .send_email(&email(), "Welcome!", "<p>Hi</p>", "Hi")
+            .await;
+
+        let message_id = assert_some!(assert_ok!(message_id));
+        let mut files = std::fs::read_dir(&directory).unwrap();
+        let file = files.next().unwrap().unwrap();
+        assert!(files.next().is_none());
+        let file_name = file.file_name().into_string().unwrap();
+        assert!(file_name.ends_with(&format!("-{message_id}.eml")));
+        let message = std::fs::read_to_string(file.path()).unwrap();
+        assert!(message.contains("Subject: Welcome!\r\n"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    fn batch(recipients: &[SubscriberEmail]) -> Vec<BatchEmail<'_>> {
+        recipients
+            .iter()
+            .map(|recipient| BatchEmail {
+                recipient,
+                subject: "Newsletter title",
+                html_content: "<p>Newsletter body</p>",
+                text_content: "Newsletter body",
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn send_batch_chunks_emails_by_500() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let recipients: Vec<_> = (0..501).map(|_| email()).collect();
+
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        let outcomes = email_client.send_batch(&batch(&recipients)).await;
+
+        assert_eq!(outcomes.len(), 501);
+        let sizes: Vec<_> = mock_server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| {
+                serde_json::from_slice::<Vec<serde_json::Value>>(&request.body)
+                    .unwrap()
+                    .len()
+            })
+            .collect();
+        assert_eq!(sizes, [500, 1]);
+    }
+
+    #[tokio::test]
+    async fn send_batch_maps_each_result_back_to_its_email() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let recipients: Vec<_> = (0..3).map(|_| email()).collect();
+
+        let response = ResponseTemplate::new(200).set_body_json(serde_json::json!([
+            { "ErrorCode": 0, "Message": "OK", "MessageID": "first" },
+            { "ErrorCode": 406, "Message": "Inactive recipient." },
+        ]));
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcomes = email_client.send_batch(&batch(&recipients)).await;
+
+        assert_eq!(assert_ok!(&outcomes[0]).as_deref(), Some("first"));
+        assert!(matches!(
+            outcomes[1],
+            Err(EmailError::InactiveRecipient { .. })
+        ));
+        assert!(matches!(outcomes[2], Err(EmailError::MissingOutcome)));
+    }
+
+    #[tokio::test]
+    async fn send_batch_fails_every_email_of_a_failed_batch() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let recipients: Vec<_> = (0..2).map(|_| email()).collect();
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcomes = email_client.send_batch(&batch(&recipients)).await;
+
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes.iter().all(Result::is_err));
+    }
+
+    #[tokio::test]
+    async fn send_batch_sends_nothing_without_emails() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        let outcomes = email_client.send_batch(&[]).await;
+
+        assert!(outcomes.is_empty());
+    }
+
+    /// The error `send_email` returns when Postmark answers with `response`.
+    async fn send_email_error(response: ResponseTemplate) -> EmailError {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content())
+            .await;
+
+        assert_err!(outcome)
+    }
+
+    fn postmark_error(status: u16, error_code: i64, message: &str) -> ResponseTemplate {
+        ResponseTemplate::new(status).set_body_json(serde_json::json!({
+            "ErrorCode": error_code,
+            "Message": message,
+        }))
+    }
+
+    #[tokio::test]
+    async fn inactive_recipients_are_reported() {
+        let error = send_email_error(postmark_error(
+            422,
+            406,
+            "You tried to send to a recipient that has been marked as inactive.",
+        ))
+        .await;
+
+        assert!(
+            matches!(&error, EmailError::InactiveRecipient { message } if message.contains("inactive"))
+        );
+        assert!(error.is_permanent());
+    }
+
+    #[tokio::test]
+    async fn invalid_requests_are_rejected_without_blaming_the_recipient() {
+        // Error code 300 covers any invalid request, not only invalid recipients.
+        let error = send_email_error(postmark_error(
+            422,
+            300,
+            "Error parsing 'To': Illegal email address 'nope'.",
+        ))
+        .await;
+
+        assert!(matches!(
+            error,
+            EmailError::Rejected {
+                error_code: 300,
+                ..
+            }
+        ));
+        assert!(!error.is_permanent());
+    }
+
+    #[tokio::test]
+    async fn unauthorized_tokens_are_reported() {
+        let error = send_email_error(postmark_error(
+            401,
+            10,
+            "The Server Token you provided in the X-Postmark-Server-Token request header was invalid.",
+        ))
+        .await;
+
+        assert!(matches!(error, EmailError::UnauthorizedToken { .. }));
+        assert!(!error.is_permanent());
+    }
+
+    #[tokio::test]
+    async fn rate_limits_are_reported() {
+        let error = send_email_error(ResponseTemplate::new(429)).await;
+
+        assert!(matches!(error, EmailError::RateLimited));
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_reported_with_their_status() {
+        let error = send_email_error(ResponseTemplate::new(503)).await;
+
+        assert!(matches!(error, EmailError::ServerError { status: 503 }));
+    }
+
+    #[tokio::test]
+    async fn other_rejections_keep_the_postmark_error() {
+        let error =
+            send_email_error(postmark_error(422, 412, "Account is pending approval.")).await;
+
+        assert!(matches!(
+            error,
+            EmailError::Rejected {
+                error_code: 412,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn timeouts_are_transport_errors() {
+        let response = ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(180));
+
+        let error = send_email_error(response).await;
+
+        assert!(matches!(error, EmailError::Transport(_)));
+        assert!(!error.is_permanent());
+    }
 }
@@ -0,0 +1,213 @@
+use crate::configuration::Settings;
+use crate::digest_worker::ExecutionOutcome;
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::startup::get_connection_pool;
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How long an attempt may take before another worker presumes it lost and sends the email
+/// again. Well above the timeout of the email client.
+pub(crate) const ATTEMPT_LEASE_SECONDS: f64 = 300.0;
+/// Attempts after which an email is given up on.
+const MAX_ATTEMPTS: i32 = 8;
+
+/// # Errors
+/// Never returns under normal operation; failed tasks are logged and retried.
+pub async fn run_worker_until_stopped(configuration: Settings) -> Result<(), anyhow::Error> {
+    let pool = get_connection_pool(&configuration.database);
+    let email_client = configuration.email_client.client();
+    worker_loop(&pool, &email_client).await
+}
+
+async fn worker_loop(pool: &PgPool, email_client: &EmailClient) -> Result<(), anyhow::Error> {
+    loop {
+        match try_execute_task(pool, email_client).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+/// Write an email to the outbox, to be sent once `transaction` is committed.
+/// # Errors
+/// Returns an error if the query fails.
+#[tracing::instrument(
+    name = "Write an email to the outbox",
+    skip(transaction, recipient, html_content, text_content)
+)]
+pub async fn enqueue_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    recipient: &SubscriberEmail,
+    subject: &str,
+    html_content: &str,
+    text_content: &str,
+) -> Result<Uuid, sqlx::Error> {
+    let outbox_message_id = Uuid::new_v4();
+    let now = Utc::now();
+    sqlx::query!(
+        r#"
+        INSERT INTO email_outbox (outbox_message_id, recipient, subject, html_content,
+            text_content, status, next_attempt_at, created_at)
+        VALUES ($1, $2, $3, $4, $5, 'pending', $6, $6)
+        "#,
+        outbox_message_id,
+        recipient.as_ref(),
+        subject,
+        html_content,
+        text_content,
+        now,
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(outbox_message_id)
+}
+
+/// Try to send an email right after the transaction that wrote it was committed, leaving
+/// it to the worker if it cannot be sent now.
+#[tracing::instrument(name = "Dispatch an email from the outbox", skip(pool, email_client))]
+pub async fn dispatch_email(pool: &PgPool, email_client: &EmailClient, outbox_message_id: Uuid) {
+    if let Err(error) = deliver(pool, email_client, Some(outbox_message_id)).await {
+        tracing::warn!(
+            error.cause_chain = ?error,
+            "Failed to dispatch an email from the outbox, leaving it to the worker",
+        );
+    }
+}
+
+/// Send the next due email of the outbox.
+/// # Errors
+/// Returns an error if the database cannot be reached. Failing to send the email is
+/// recorded on the outbox instead.
+#[tracing::instrument(skip_all, err)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &EmailClient,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    deliver(pool, email_client, None).await
+}
+
+/// Claim a due email, `outbox_message_id` if given, then send it. No connection is held
+/// while the email is sent: the claim is a lease, after which an attempt that never
+/// reported back is retried. Emails are therefore sent at least once.
+async fn deliver(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    outbox_message_id: Option<Uuid>,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let Some(message) = sqlx::query!(
+        r#"
+        UPDATE email_outbox
+        SET attempts = attempts + 1,
+            next_attempt_at = now() + make_interval(secs => $2)
+        WHERE outbox_message_id = (
+            SELECT outbox_message_id FROM email_outbox
+            WHERE status = 'pending'
+                AND next_attempt_at <= now()
+                AND ($1::uuid IS NULL OR outbox_message_id = $1)
+            ORDER BY next_attempt_at
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING outbox_message_id, recipient, subject, html_content, text_content, attempts
+        "#,
+        outbox_message_id,
+        ATTEMPT_LEASE_SECONDS,
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to claim an email from the outbox.")?
+    else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+    // Errors come with whether retrying could ever succeed.
+    let outcome = match SubscriberEmail::parse(message.recipient) {
+        Ok(email) => email_client
+            .send_email(
+                &email,
+                &message.subject,
+                &message.html_content,
+                &message.text_content,
+            )
+            .await
+            .map_err(|error| {
+                let permanent = error.is_permanent();
+                let error = anyhow::Error::new(error)
+                    .context(format!("Failed to send an email to {email}"));
+                (error, permanent)
+            }),
+        Err(error) => Err((anyhow::anyhow!(error), true)),
+    };
+    match outcome {
+        Ok(_) => {
+            sqlx::query!(
+                r#"
+                UPDATE email_outbox SET status = 'sent', sent_at = now(), last_error = NULL
+                WHERE outbox_message_id = $1
+                "#,
+                message.outbox_message_id,
+            )
+            .execute(pool)
+            .await
+        }
+        Err((error, permanent)) => {
+            tracing::warn!(
+                error.cause_chain = ?error,
+                attempts = message.attempts,
+                permanent,
+                "Failed to send an email from the outbox",
+            );
+            let status = if permanent || message.attempts >= MAX_ATTEMPTS {
+                "failed"
+            } else {
+                "pending"
+            };
+            sqlx::query!(
+                r#"
+                UPDATE email_outbox
+                SET status = $2, last_error = $3, next_attempt_at = now() + make_interval(secs => $4)
+                WHERE outbox_message_id = $1
+                "#,
+                message.outbox_message_id,
+                status,
+                format!("{error:#}"),
+                retry_delay(message.attempts).as_secs_f64(),
+            )
+            .execute(pool)
+            .await
+        }
+    }
+    .context("Failed to record the outcome of an email from the outbox.")?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+/// Exponential backoff, from 30 seconds after the first attempt up to an hour.
+pub(crate) fn retry_delay(attempts: i32) -> Duration {
+    let exponent = u32::try_from(attempts.saturating_sub(1))
+        .unwrap_or(0)
+        .min(7);
+    Duration::from_secs(30 * 2u64.pow(exponent)).min(Duration::from_secs(3600))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::retry_delay;
+    use std::time::Duration;
+
+    #[test]
+    fn retries_back_off_exponentially_up_to_an_hour() {
+        assert_eq!(retry_delay(1), Duration::from_secs(30));
+        assert_eq!(retry_delay(2), Duration::from_secs(60));
+        assert_eq!(retry_delay(4), Duration::from_secs(240));
+        assert_eq!(retry_delay(8), Duration::from_secs(3600));
+        assert_eq!(retry_delay(100), Duration::from_secs(3600));
+    }
+}
@@ -1,6 +1,22 @@
+pub mod api_key;
+pub mod audit;
+pub mod authentication;
+pub mod bot_protection;
 pub mod configuration;
+pub mod digest_worker;
 pub mod domain;
 pub mod email_client;
+pub mod email_outbox;
+pub mod login_throttle;
+pub mod mailbox;
+pub mod onboarding_worker;
+pub mod rate_limit;
+pub mod role;
 pub mod routes;
+pub mod segment;
+pub mod session;
 pub mod startup;
 pub mod telemetry;
+pub mod totp;
+pub mod tracking;
+pub mod utils;
@@ -0,0 +1,300 @@
+use crate::authentication::AuthError;
+use crate::configuration::LoginThrottleSettings;
+use crate::rate_limit::client_ip;
+use actix_web::HttpRequest;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use sqlx::PgPool;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use uuid::Uuid;
+
+/// Why a login attempt was rejected, as recorded in `failed_logins`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureReason {
+    InvalidCredentials,
+    LockedOut,
+}
+
+impl FailureReason {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FailureReason::InvalidCredentials => "invalid_credentials",
+            FailureReason::LockedOut => "locked_out",
+        }
+    }
+}
+
+/// The delay before verifying a password after `failures` recent failures: nothing for
+/// the first attempt, then `base` doubled for every further failure, up to `max`.
+fn progressive_delay(failures: i32, base: Duration, max: Duration) -> Duration {
+    let Ok(failures) = u32::try_from(failures) else {
+        return Duration::ZERO;
+    };
+    if failures == 0 {
+        return Duration::ZERO;
+    }
+    base.checked_mul(2u32.saturating_pow(failures - 1))
+        .map_or(max, |delay| delay.min(max))
+}
+
+struct ThrottleState {
+    failures: i32,
+    last_failure_at: DateTime<Utc>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+/// Slows down and then locks out repeated failed logins, per username and per client
+/// address, and caps how many password hashes are verified at once.
+pub struct LoginThrottle {
+    settings: LoginThrottleSettings,
+    trusted_proxies: Vec<IpNet>,
+    verifications: Arc<Semaphore>,
+}
+
+impl LoginThrottle {
+    /// `trusted_proxies` are the proxies whose `X-Forwarded-For` header carries the
+    /// client address, as for rate limits.
+    #[must_use]
+    pub fn new(settings: LoginThrottleSettings, trusted_proxies: Vec<IpNet>) -> Self {
+        let verifications = Arc::new(Semaphore::new(settings.max_concurrent_verifications.max(1)));
+        Self {
+            settings,
+            trusted_proxies,
+            verifications,
+        }
+    }
+
+    #[must_use]
+    pub fn client_ip(&self, request: &HttpRequest) -> Option<IpAddr> {
+        let forwarded_for = request
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|value| value.to_str().ok());
+        client_ip(
+            request.peer_addr().map(|address| address.ip()),
+            forwarded_for,
+            &self.trusted_proxies,
+        )
+    }
+
+    /// Wait for a turn to verify a password, or to upgrade its hash. Hashes are computed
+    /// on the blocking pool, so a flood of login attempts must not be allowed to fill it.
+    /// # Panics
+    /// Panics if the semaphore was closed, which never happens.
+    pub async fn verification_permit(&self) -> OwnedSemaphorePermit {
+        Arc::clone(&self.verifications)
+            .acquire_owned()
+            .await
+            .expect("The verification semaphore is never closed.")
+    }
+
+    /// Refuse attempts against a locked username or from a locked address, and make the
+    /// others wait longer after every recent failure.
+    /// # Errors
+    /// Returns `AuthError::LockedOut` while a lockout is in force.
+    #[tracing::instrument(name = "Admit a login attempt", skip(self, pool, username))]
+    pub async fn admit(
+        &self,
+        pool: &PgPool,
+        username: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<(), AuthError> {
+        let keys = throttle_keys(username, ip);
+        let states = sqlx::query_as!(
+            ThrottleState,
+            r#"
+            SELECT failures, last_failure_at, locked_until
+            FROM login_throttles
+            WHERE throttle_key = ANY($1)
+            "#,
+            &keys,
+        )
+        .fetch_all(pool)
+        .await
+        .context("Failed to retrieve the login throttles.")?;
+
+        let now = Utc::now();
+        if let Some(locked_until) = states
+            .iter()
+            .filter_map(|state| state.locked_until)
+            .filter(|locked_until| *locked_until > now)
+            .max()
+        {
+            self.record_failed_login(pool, username, ip, FailureReason::LockedOut)
+                .await?;
+            let retry_after = u64::try_from((locked_until - now).num_seconds()).unwrap_or(0) + 1;
+            return Err(AuthError::LockedOut { retry_after });
+        }
+
+        let window = chrono::Duration::seconds(
+            i64::try_from(self.settings.failure_window_seconds).unwrap_or(i64::MAX),
+        );
+        let failures = states
+            .iter()
+            .filter(|state| state.last_failure_at + window > now)
+            .map(|state| state.failures)
+            .max()
+            .unwrap_or(0);
+        let delay = progressive_delay(
+            failures,
+            Duration::from_millis(self.settings.base_delay_milliseconds),
+            Duration::from_millis(self.settings.max_delay_milliseconds),
+        );
+        if !delay.is_zero() {
+            tracing::info!(failures, ?delay, "Delaying a login attempt");
+            tokio::time::sleep(delay).await;
+        }
+        Ok(())
+    }
+
+    /// Count a failed attempt against the username and the client address, locking
+    /// either out once it reaches its limit.
+    /// # Errors
+    /// Returns an error if the throttles cannot be updated.
+    #[tracing::instrument(name = "Record a failed login", skip(self, pool, username))]
+    pub async fn record_failure(
+        &self,
+        pool: &PgPool,
+        username: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<(), anyhow::Error> {
+        self.record_failed_login(pool, username, ip, FailureReason::InvalidCredentials)
+            .await?;
+        self.count_failure(
+            pool,
+            &username_key(username),
+            self.settings.max_failures_per_username,
+        )
+        .await?;
+        if let Some(ip) = ip {
+            self.count_failure(pool, &ip_key(ip), self.settings.max_failures_per_ip)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// A successful login forgives the past failures of the username, but not those of
+    /// the address it came from.
+    /// # Errors
+    /// Returns an error if the throttle cannot be cleared.
+    #[tracing::instrument(name = "Record a successful login", skip(self, pool, username))]
+    pub async fn record_success(&self, pool: &PgPool, username: &str) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"DELETE FROM login_throttles WHERE throttle_key = $1"#,
+            username_key(username),
+        )
+        .execute(pool)
+        .await
+        .context("Failed to clear the login throttle of a username.")?;
+        Ok(())
+    }
+
+    async fn count_failure(
+        &self,
+        pool: &PgPool,
+        key: &str,
+        max_failures: i32,
+    ) -> Result<(), anyhow::Error> {
+        #[allow(clippy::cast_precision_loss)]
+        let window = self.settings.failure_window_seconds as f64;
+        #[allow(clippy::cast_precision_loss)]
+        let lockout = self.settings.lockout_seconds as f64;
+        let locked_until = sqlx::query_scalar!(
+            r#"
+            INSERT INTO login_throttles (throttle_key, failures, last_failure_at, locked_until)
+            VALUES ($1, 1, now(), CASE WHEN $3 <= 1 THEN now() + make_interval(secs => $4) END)
+            ON CONFLICT (throttle_key) DO UPDATE SET
+                failures = CASE
+                    WHEN login_throttles.last_failure_at < now() - make_interval(secs => $2) THEN 1
+                    ELSE login_throttles.failures + 1
+                END,
+                last_failure_at = now(),
+                locked_until = CASE
+                    WHEN login_throttles.last_failure_at >= now() - make_interval(secs => $2)
+                        AND login_throttles.failures + 1 >= $3
+                    THEN now() + make_interval(secs => $4)
+                    ELSE login_throttles.locked_until
+                END
+            RETURNING locked_until
+            "#,
+            key,
+            window,
+            max_failures,
+            lockout,
+        )
+        .fetch_one(pool)
+        .await
+        .context("Failed to count a failed login.")?;
+        if locked_until.is_some_and(|locked_until| locked_until > Utc::now()) {
+            tracing::warn!(throttle_key = key, "Locking out repeated failed logins");
+        }
+        Ok(())
+    }
+
+    async fn record_failed_login(
+        &self,
+        pool: &PgPool,
+        username: &str,
+        ip: Option<IpAddr>,
+        reason: FailureReason,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO failed_logins (failed_login_id, username, client_ip, reason, attempted_at)
+            VALUES ($1, $2, $3, $4, now())
+            "#,
+            Uuid::new_v4(),
+            username,
+            ip.map(|ip| ip.to_string()),
+            reason.as_str(),
+        )
+        .execute(pool)
+        .await
+        .context("Failed to record a failed login.")?;
+        Ok(())
+    }
+}
+
+fn username_key(username: &str) -> String {
+    format!("username:{username}")
+}
+
+fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{ip}")
+}
+
+fn throttle_keys(username: &str, ip: Option<IpAddr>) -> Vec<String> {
+    let mut keys = vec![username_key(username)];
+    keys.extend(ip.map(ip_key));
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::progressive_delay;
+    use std::time::Duration;
+
+    #[test]
+    fn the_delay_doubles_with_every_failure_up_to_the_maximum() {
+        let base = Duration::from_millis(250);
+        let max = Duration::from_secs(2);
+        let delays: Vec<_> = (0..6)
+            .map(|failures| progressive_delay(failures, base, max).as_millis())
+            .collect();
+        assert_eq!(delays, vec![0, 250, 500, 1000, 2000, 2000]);
+    }
+
+    #[test]
+    fn huge_failure_counts_do_not_overflow() {
+        let max = Duration::from_secs(2);
+        assert_eq!(
+            progressive_delay(i32::MAX, Duration::from_millis(250), max),
+            max
+        );
+    }
+}
@@ -0,0 +1,354 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Base64 lines are wrapped below the 78 characters RFC 5322 recommends.
+const LINE_LENGTH: usize = 76;
+
+/// A directory of `.eml` files, one per email sent, for development without Postmark.
+#[derive(Clone, Debug)]
+pub struct Mailbox {
+    directory: PathBuf,
+}
+
+/// An email read back from the mailbox.
+#[derive(serde::Serialize, Debug)]
+pub struct CapturedEmail {
+    pub message_id: Uuid,
+    pub sent_at: DateTime<Utc>,
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    /// Every header of the message, in order, as written to the file.
+    pub headers: Vec<(String, String)>,
+    pub text_content: String,
+    pub html_content: String,
+}
+
+/// An email on its way to the mailbox.
+pub struct OutgoingEmail<'a> {
+    pub from: &'a str,
+    pub to: &'a str,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+}
+
+impl Mailbox {
+    #[must_use]
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+
+    #[must_use]
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Write `email` to the mailbox, returning the id it is stored under. Files are named
+    /// after the time they were written, so that they list in the order they were sent.
+    /// # Errors
+    /// Returns an error if the directory cannot be created or written to.
+    pub async fn store(&self, email: &OutgoingEmail<'_>) -> Result<Uuid, std::io::Error> {
+        let message_id = Uuid::new_v4();
+        let sent_at = Utc::now();
+        tokio::fs::create_dir_all(&self.directory).await?;
+        let file_name = format!(
+            "{}-{}.eml",
+            sent_at.format("%Y%m%dT%H%M%S%.6fZ"),
+            message_id
+        );
+        // Readers never see a partially written file.
+        let partial = self.directory.join(format!(".{file_name}.tmp"));
+        tokio::fs::write(&partial, render(email, message_id, sent_at)).await?;
+        tokio::fs::rename(&partial, self.directory.join(file_name)).await?;
+        Ok(message_id)
+    }
+
+    /// Every email of the mailbox, the most recent first. Files that are not emails written
+    /// by [`Mailbox::store`] are skipped.
+    /// # Errors
+    /// Returns an error if the directory cannot be read.
+    pub async fn messages(&self) -> Result<Vec<CapturedEmail>, std::io::Error> {
+        let mut paths = self.message_paths().await?;
+        paths.sort_unstable_by(|a, b| b.cmp(a));
+        let mut messages = Vec::with_capacity(paths.len());
+        for path in paths {
+            match read_message(&path).await {
+                Ok(message) => messages.push(message),
+                Err(error) => tracing::warn!(
+                    error.cause_chain = ?error,
+                    path = %path.display(),
+                    "Skipping an unreadable email of the mailbox",
+                ),
+            }
+        }
+        Ok(messages)
+    }
+
+    /// # Errors
+    /// Returns an error if the directory or the email cannot be read.
+    pub async fn message(&self, message_id: Uuid) -> Result<Option<CapturedEmail>, std::io::Error> {
+        let suffix = format!("-{message_id}.eml");
+        let path = self
+            .message_paths()
+            .await?
+            .into_iter()
+            .find(|path| path.to_string_lossy().ends_with(&suffix));
+        match path {
+            Some(path) => read_message(&path).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Delete every email of the mailbox.
+    /// # Errors
+    /// Returns an error if the directory cannot be read or an email cannot be deleted.
+    pub async fn clear(&self) -> Result<(), std::io::Error> {
+        for path in self.message_paths().await? {
+            match tokio::fs::remove_file(path).await {
+                // Deleted concurrently, by another request.
+                Err(error) if error.kind() == ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The mailbox is empty until the first email creates its directory.
+    async fn message_paths(&self) -> Result<Vec<PathBuf>, std::io::Error> {
+        let mut entries = match tokio::fs::read_dir(&self.directory).await {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error),
+        };
+        let mut paths = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|extension| extension == "eml") {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+}
+
+/// A MIME `multipart/alternative` message carrying both versions of the email.
+fn render(email: &OutgoingEmail<'_>, message_id: Uuid, sent_at: DateTime<Utc>) -> String {
+    let boundary = format!("zero2prod-{}", Uuid::new_v4().simple());
+    let mut message = String::new();
+    for (name, value) in [
+        ("Message-ID", format!("<{message_id}@zero2prod.localhost>")),
+        ("Date", sent_at.to_rfc2822()),
+        ("From", header_value(email.from)),
+        ("To", header_value(email.to)),
+        ("Subject", header_value(email.subject)),
+        ("MIME-Version", "1.0".into()),
+        (
+            "Content-Type",
+            format!("multipart/alternative;\r\n boundary=\"{boundary}\""),
+        ),
+    ] {
+        message.push_str(&format!("{name}: {value}\r\n"));
+    }
+    message.push_str("\r\n");
+    for (content_type, content) in [
+        ("text/plain", email.text_content),
+        ("text/html", email.html_content),
+    ] {
+        message.push_str(&format!(
+            "--{boundary}\r\n\
+            Content-Type: {content_type}; charset=utf-8\r\n\
+            Content-Transfer-Encoding: base64\r\n\r\n"
+        ));
+        let encoded = STANDARD.encode(content);
+        for line in encoded.as_bytes().chunks(LINE_LENGTH) {
+            message.push_str(std::str::from_utf8(line).expect("Base64 is ASCII."));
+            message.push_str("\r\n");
+        }
+    }
+    message.push_str(&format!("--{boundary}--\r\n"));
+    message
+}
+
+async fn read_message(path: &Path) -> Result<CapturedEmail, std::io::Error> {
+    let content = tokio::fs::read_to_string(path).await?;
+    parse(&content).ok_or_else(|| {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            "Not an email written by the mailbox.",
+        )
+    })
+}
+
+/// Read back a message produced by [`render`]; it is not a general purpose MIME parser.
+fn parse(message: &str) -> Option<CapturedEmail> {
+    let (head, body) = message.split_once("\r\n\r\n")?;
+    let headers = parse_headers(head);
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    };
+    let message_id = header("Message-ID")?
+        .trim_start_matches('<')
+        .split('@')
+        .next()?
+        .parse()
+        .ok()?;
+    let sent_at = DateTime::parse_from_rfc2822(&header("Date")?)
+        .ok()?
+        .with_timezone(&Utc);
+    let boundary = header("Content-Type")?
+        .split_once("boundary=\"")?
+        .1
+        .trim_end_matches('"')
+        .to_owned();
+    let mut text_content = String::new();
+    let mut html_content = String::new();
+    for part in body.split(&format!("--{boundary}")).skip(1) {
+        let Some((part_head, part_body)) = part.split_once("\r\n\r\n") else {
+            continue;
+        };
+        let encoded: String = part_body.split_whitespace().collect();
+        let content = String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?;
+        if part_head.contains("text/plain") {
+            text_content = content;
+        } else if part_head.contains("text/html") {
+            html_content = content;
+        }
+    }
+    Some(CapturedEmail {
+        message_id,
+        sent_at,
+        from: header("From")?,
+        to: header("To")?,
+        subject: header("Subject")?,
+        headers,
+        text_content,
+        html_content,
+    })
+}
+
+/// Folded headers are unfolded and encoded values decoded.
+fn parse_headers(head: &str) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in head.split("\r\n") {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.to_owned(), value.trim().to_owned()));
+        }
+    }
+    for (_, value) in &mut headers {
+        if let Some(decoded) = decode_header_value(value) {
+            *value = decoded;
+        }
+    }
+    headers
+}
+
+fn decode_header_value(value: &str) -> Option<String> {
+    let encoded = value.strip_prefix("=?utf-8?B?")?.strip_suffix("?=")?;
+    String::from_utf8(STANDARD.decode(encoded).ok()?).ok()
+}
+
+/// Line breaks would end the header early, and anything but ASCII has to be encoded as
+/// described in RFC 2047.
+fn header_value(value: &str) -> String {
+    let value = value.replace(['\r', '\n'], " ");
+    if value.is_ascii() {
+        value
+    } else {
+        format!("=?utf-8?B?{}?=", STANDARD.encode(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{header_value, parse, render, OutgoingEmail};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    fn email<'a>(html_content: &'a str, text_content: &'a str) -> OutgoingEmail<'a> {
+        OutgoingEmail {
+            from: "sender@example.com",
+            to: "ursula@example.com",
+            subject: "Welcome!",
+            html_content,
+            text_content,
+        }
+    }
+
+    #[test]
+    fn messages_carry_both_versions_of_the_content() {
+        let message = render(&email("<p>Hi</p>", "Hi"), Uuid::new_v4(), Utc::now());
+
+        assert!(message.contains("To: ursula@example.com\r\n"));
+        assert!(message.contains("Subject: Welcome!\r\n"));
+        assert!(message.contains("Content-Type: multipart/alternative;\r\n boundary="));
+        assert!(message.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert!(message.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(message.contains(&STANDARD.encode("Hi")));
+        assert!(message.contains(&STANDARD.encode("<p>Hi</p>")));
+        assert!(message.ends_with("--\r\n"));
+    }
+
+    #[test]
+    fn long_contents_are_wrapped() {
+        let text = "a".repeat(1000);
+        let message = render(&email("", &text), Uuid::new_v4(), Utc::now());
+
+        assert!(message.split("\r\n").all(|line| line.len() <= 78));
+    }
+
+    #[test]
+    fn non_ascii_headers_are_encoded() {
+        assert_eq!(header_value("Bienvenue"), "Bienvenue");
+        assert_eq!(header_value("Café"), "=?utf-8?B?Q2Fmw6k=?=");
+    }
+
+    #[test]
+    fn line_breaks_cannot_add_headers() {
+        assert_eq!(
+            header_value("Hi\r\nBcc: x@example.com"),
+            "Hi  Bcc: x@example.com"
+        );
+    }
+
+    #[test]
+    fn rendered_messages_can_be_read_back() {
+        let message_id = Uuid::new_v4();
+        let text_content = "Café, ".repeat(40);
+        let mut email = email("<p>Café</p>", &text_content);
+        email.subject = "Bienvenue au café";
+
+        let captured = parse(&render(&email, message_id, Utc::now())).unwrap();
+
+        assert_eq!(captured.message_id, message_id);
+        assert_eq!(captured.from, "sender@example.com");
+        assert_eq!(captured.to, "ursula@example.com");
+        assert_eq!(captured.subject, "Bienvenue au café");
+        assert_eq!(captured.text_content, text_content);
+        assert_eq!(captured.html_content, "<p>Café</p>");
+        assert!(captured
+            .headers
+            .iter()
+            .any(|(name, value)| name == "MIME-Version" && value == "1.0"));
+    }
+
+    #[test]
+    fn other_files_are_not_read_as_messages() {
+        assert!(parse("Not an email").is_none());
+        assert!(parse("Subject: Hi\r\n\r\nHello").is_none());
+    }
+}
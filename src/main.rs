@@ -1,14 +1,55 @@
+use std::fmt::{Debug, Display};
+use tokio::task::JoinError;
 use zero2prod::configuration::get_configuration;
 use zero2prod::startup::Application;
 use zero2prod::telemetry::{get_subscriber, init_subscriber};
+use zero2prod::{digest_worker, email_outbox, onboarding_worker};
 
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
+async fn main() -> anyhow::Result<()> {
     let subscriber = get_subscriber("zero2prod".into(), "info".into(), std::io::stdout);
     init_subscriber(subscriber);
 
     let configuration = get_configuration().expect("Failed to read configuration.");
-    let application = Application::build(configuration)?;
-    application.run_until_stopped().await?;
+    let application = Application::build(configuration.clone())?;
+    let application_task = tokio::spawn(application.run_until_stopped());
+    let digest_worker_task = tokio::spawn(digest_worker::run_worker_until_stopped(
+        configuration.clone(),
+    ));
+    let onboarding_worker_task = tokio::spawn(onboarding_worker::run_worker_until_stopped(
+        configuration.clone(),
+    ));
+    let outbox_worker_task = tokio::spawn(email_outbox::run_worker_until_stopped(configuration));
+
+    tokio::select! {
+        o = application_task => report_exit("API", o),
+        o = digest_worker_task => report_exit("Digest delivery worker", o),
+        o = onboarding_worker_task => report_exit("Onboarding worker", o),
+        o = outbox_worker_task => report_exit("Email outbox worker", o),
+    };
     Ok(())
 }
+
+fn report_exit(task_name: &str, outcome: Result<Result<(), impl Debug + Display>, JoinError>) {
+    match outcome {
+        Ok(Ok(())) => {
+            tracing::info!("{} has exited", task_name);
+        }
+        Ok(Err(e)) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "{} failed",
+                task_name
+            );
+        }
+        Err(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "{}' task failed to complete",
+                task_name
+            );
+        }
+    }
+}
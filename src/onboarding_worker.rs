@@ -0,0 +1,171 @@
+use crate::configuration::Settings;
+use crate::digest_worker::ExecutionOutcome;
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::email_outbox::{dispatch_email, enqueue_email};
+use crate::routes::add_preferences_footer;
+use crate::startup::get_connection_pool;
+use crate::utils::escape_html;
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::PgPool;
+use std::time::Duration;
+
+/// # Errors
+/// Never returns under normal operation; failed tasks are logged and retried.
+pub async fn run_worker_until_stopped(configuration: Settings) -> Result<(), anyhow::Error> {
+    let pool = get_connection_pool(&configuration.database);
+    let email_client = configuration.email_client.client();
+    worker_loop(&pool, &email_client, &configuration.application.base_url).await
+}
+
+async fn worker_loop(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+) -> Result<(), anyhow::Error> {
+    loop {
+        match try_execute_task(pool, email_client, base_url).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+/// Hand the next due onboarding email over to the outbox, which sends and retries it. The
+/// rest of the sequence is cancelled instead if the subscriber no longer belongs to any
+/// list, and postponed until the end of their pause if they paused their subscription.
+/// # Errors
+/// Returns an error if the database cannot be reached.
+#[tracing::instrument(
+    skip_all,
+    fields(subscriber_id=tracing::field::Empty, step=tracing::field::Empty),
+    err
+)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let Some(task) = sqlx::query!(
+        r#"
+        SELECT
+            o.subscriber_id,
+            o.step,
+            o.scheduled_for,
+            s.email,
+            s.name,
+            s.preferences_token,
+            s.paused_until,
+            e.subject,
+            e.html_content,
+            e.text_content,
+            EXISTS (
+                SELECT 1 FROM list_subscriptions ls
+                WHERE ls.subscriber_id = s.id AND ls.status = 'confirmed'
+            ) AS "subscribed!"
+        FROM onboarding_deliveries o
+        JOIN subscriptions s ON s.id = o.subscriber_id
+        JOIN onboarding_emails e ON e.step = o.step
+        WHERE o.status = 'scheduled' AND o.scheduled_for <= now()
+        ORDER BY o.scheduled_for
+        LIMIT 1
+        FOR UPDATE OF o SKIP LOCKED
+        "#
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to dequeue an onboarding email.")?
+    else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+    tracing::Span::current()
+        .record(
+            "subscriber_id",
+            tracing::field::display(&task.subscriber_id),
+        )
+        .record("step", task.step);
+
+    let mut outbox_message_id = None;
+    if !task.subscribed {
+        sqlx::query!(
+            r#"
+            UPDATE onboarding_deliveries SET status = 'cancelled', updated_at = now()
+            WHERE subscriber_id = $1 AND status = 'scheduled'
+            "#,
+            task.subscriber_id
+        )
+        .execute(&mut *transaction)
+        .await
+        .context("Failed to cancel the onboarding sequence.")?;
+    } else if let Some(paused_until) = task.paused_until.filter(|&until| until > Utc::now()) {
+        // The remaining steps keep their spacing, the due one being sent when the pause ends.
+        sqlx::query!(
+            r#"
+            UPDATE onboarding_deliveries
+            SET scheduled_for = scheduled_for + ($2::timestamptz - $3::timestamptz),
+                updated_at = now()
+            WHERE subscriber_id = $1 AND status = 'scheduled'
+            "#,
+            task.subscriber_id,
+            paused_until,
+            task.scheduled_for,
+        )
+        .execute(&mut *transaction)
+        .await
+        .context("Failed to postpone the onboarding sequence.")?;
+    } else {
+        let (html, text) = add_preferences_footer(
+            &task
+                .html_content
+                .replace("{{name}}", &escape_html(&task.name)),
+            &task.text_content.replace("{{name}}", &task.name),
+            base_url,
+            &task.preferences_token,
+        );
+        // `sent` once handed over to the outbox.
+        let status = match SubscriberEmail::parse(task.email) {
+            Ok(email) => {
+                outbox_message_id = Some(
+                    enqueue_email(&mut transaction, &email, &task.subject, &html, &text)
+                        .await
+                        .context("Failed to write an onboarding email to the outbox.")?,
+                );
+                "sent"
+            }
+            Err(error) => {
+                tracing::warn!(error, "Skipping an onboarding email to an invalid address");
+                "failed"
+            }
+        };
+        sqlx::query!(
+            r#"
+            UPDATE onboarding_deliveries SET status = $3, updated_at = now()
+            WHERE subscriber_id = $1 AND step = $2
+            "#,
+            task.subscriber_id,
+            task.step,
+            status
+        )
+        .execute(&mut *transaction)
+        .await
+        .context("Failed to record the outcome of an onboarding email.")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to record an onboarding email.")?;
+    if let Some(outbox_message_id) = outbox_message_id {
+        dispatch_email(pool, email_client, outbox_message_id).await;
+    }
+    Ok(ExecutionOutcome::TaskCompleted)
+}
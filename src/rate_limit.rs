@@ -0,0 +1,479 @@
+use crate::configuration::{RateLimitSettings, RateLimitStore, RouteLimits, TokenBucket};
+use crate::routes::AppError;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::Data;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Mutex, Weak};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+/// How often buckets that have refilled are forgotten: a full bucket is the same as none.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct BucketState {
+    tokens: f64,
+    updated_at: DateTime<Utc>,
+}
+
+/// The seconds it takes to refill a single token, at least one so that every bucket refills.
+#[allow(clippy::cast_precision_loss)]
+fn seconds_per_token(bucket: TokenBucket) -> f64 {
+    bucket.refill_interval_seconds.max(1) as f64
+}
+
+/// The seconds until a bucket holding `tokens` is full again, which both stores use to
+/// tell the buckets they can forget.
+fn seconds_until_full(bucket: TokenBucket, tokens: f64) -> f64 {
+    (f64::from(bucket.capacity) - tokens).max(0.0) * seconds_per_token(bucket)
+}
+
+/// Refill the bucket for the time elapsed since it was last used, then try to take a
+/// token from it. Returns the new state and, if no token was available, the number of
+/// seconds until one will be.
+fn take_token(
+    bucket: TokenBucket,
+    previous: Option<BucketState>,
+    now: DateTime<Utc>,
+) -> (BucketState, Option<u64>) {
+    let capacity = f64::from(bucket.capacity);
+    let interval = seconds_per_token(bucket);
+    let tokens = previous.map_or(capacity, |previous| {
+        #[allow(clippy::cast_precision_loss)]
+        let elapsed = (now - previous.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        (previous.tokens + elapsed / interval).min(capacity)
+    });
+    if tokens >= 1.0 {
+        let state = BucketState {
+            tokens: tokens - 1.0,
+            updated_at: now,
+        };
+        (state, None)
+    } else {
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let retry_after = ((1.0 - tokens) * interval).ceil().max(1.0) as u64;
+        let state = BucketState {
+            tokens,
+            updated_at: now,
+        };
+        (state, Some(retry_after))
+    }
+}
+
+/// The address of the client. `X-Forwarded-For` is only honoured when the request comes
+/// from a trusted proxy, and is walked from the right until a hop is not a trusted proxy.
+pub(crate) fn client_ip(
+    peer: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let mut client = peer?;
+    for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+        if !trusted_proxies
+            .iter()
+            .any(|network| network.contains(&client))
+        {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(hop) => client = hop,
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+enum Store {
+    /// Buckets along with the time at which they will be full again.
+    Memory(Mutex<HashMap<String, (BucketState, DateTime<Utc>)>>),
+    Postgres(PgPool),
+}
+
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    store: Store,
+}
+
+impl RateLimiter {
+    #[must_use]
+    pub fn new(settings: RateLimitSettings, pool: PgPool) -> Self {
+        let store = match settings.store {
+            RateLimitStore::Memory => Store::Memory(Mutex::new(HashMap::new())),
+            RateLimitStore::Postgres => Store::Postgres(pool),
+        };
+        Self { settings, store }
+    }
+
+    #[must_use]
+    pub fn subscribe_limits(&self) -> &RouteLimits {
+        &self.settings.subscribe
+    }
+
+    #[must_use]
+    pub fn confirm_limits(&self) -> &RouteLimits {
+        &self.settings.confirm
+    }
+
+    #[must_use]
+    pub fn password_reset_limits(&self) -> &RouteLimits {
+        &self.settings.password_reset
+    }
+
+    /// Forget the buckets that have refilled.
+    #[tracing::instrument(name = "Sweep rate limit buckets", skip(self))]
+    pub async fn sweep(&self) {
+        match &self.store {
+            Store::Memory(buckets) => {
+                let now = Utc::now();
+                buckets
+                    .lock()
+                    .unwrap()
+                    .retain(|_, (_, full_at)| *full_at > now);
+            }
+            Store::Postgres(pool) => {
+                // Buckets do not record their limits: any bucket left alone for longer than
+                // the slowest of them takes to refill is full.
+                let longest_refill = self
+                    .buckets()
+                    .map(|bucket| seconds_until_full(bucket, 0.0))
+                    .fold(0.0, f64::max);
+                if let Err(error) = sqlx::query!(
+                    r#"
+                    DELETE FROM rate_limit_buckets
+                    WHERE updated_at < now() - make_interval(secs => $1)
+                    "#,
+                    longest_refill
+                )
+                .execute(pool)
+                .await
+                {
+                    tracing::warn!(
+                        error.cause_chain = ?error,
+                        "Failed to delete the rate limit buckets that have refilled",
+                    );
+                }
+            }
+        }
+    }
+
+    fn buckets(&self) -> impl Iterator<Item = TokenBucket> + '_ {
+        [
+            &self.settings.subscribe,
+            &self.settings.confirm,
+            &self.settings.password_reset,
+        ]
+        .into_iter()
+        .flat_map(|limits| [limits.per_ip, limits.per_email])
+        .flatten()
+    }
+
+    /// Take a token from the bucket of `key` within `scope`. Requests are let through if
+    /// the store cannot be reached, so that an outage does not take the signup flow down.
+    /// # Errors
+    /// Returns `AppError::TooManyRequests` if the bucket is empty.
+    #[tracing::instrument(name = "Check a rate limit", skip(self, key, bucket))]
+    pub async fn check(
+        &self,
+        scope: &str,
+        key: &str,
+        bucket: Option<TokenBucket>,
+    ) -> Result<(), AppError> {
+        let Some(bucket) = bucket.filter(|_| self.settings.enabled) else {
+            return Ok(());
+        };
+        // Keys are hashed so that addresses and IPs are not stored in clear.
+        let bucket_key = format!("{scope}:{:x}", Sha256::digest(key.as_bytes()));
+        let retry_after = match &self.store {
+            Store::Memory(buckets) => Ok(take_from_memory(buckets, bucket_key, bucket)),
+            Store::Postgres(pool) => take_from_postgres(pool, bucket_key, bucket).await,
+        };
+        match retry_after {
+            Ok(None) => Ok(()),
+            Ok(Some(retry_after)) => Err(AppError::TooManyRequests { retry_after }),
+            Err(error) => {
+                tracing::error!(
+                    error.cause_chain = ?error,
+                    "Failed to check a rate limit, letting the request through",
+                );
+                Ok(())
+            }
+        }
+    }
+
+    fn client_ip(&self, request: &ServiceRequest) -> Option<IpAddr> {
+        let forwarded_for = request
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|value| value.to_str().ok());
+        client_ip(
+            request.peer_addr().map(|address| address.ip()),
+            forwarded_for,
+            &self.settings.trusted_proxies,
+        )
+    }
+}
+
+fn take_from_memory(
+    buckets: &Mutex<HashMap<String, (BucketState, DateTime<Utc>)>>,
+    bucket_key: String,
+    bucket: TokenBucket,
+) -> Option<u64> {
+    let now = Utc::now();
+    let mut buckets = buckets.lock().unwrap();
+    let previous = buckets.get(&bucket_key).map(|(state, _)| *state);
+    let (state, retry_after) = take_token(bucket, previous, now);
+    #[allow(clippy::cast_possible_truncation)]
+    let refill_millis = (seconds_until_full(bucket, state.tokens) * 1000.0) as i64;
+    let full_at = now + chrono::Duration::milliseconds(refill_millis);
+    buckets.insert(bucket_key, (state, full_at));
+    retry_after
+}
+
+async fn take_from_postgres(
+    pool: &PgPool,
+    bucket_key: String,
+    bucket: TokenBucket,
+) -> Result<Option<u64>, anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let previous = sqlx::query!(
+        r#"
+        SELECT tokens, updated_at FROM rate_limit_buckets
+        WHERE bucket_key = $1
+        FOR UPDATE
+        "#,
+        bucket_key
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to retrieve a rate limit bucket.")?
+    .map(|r| BucketState {
+        tokens: r.tokens,
+        updated_at: r.updated_at,
+    });
+    let (state, retry_after) = take_token(bucket, previous, Utc::now());
+    sqlx::query!(
+        r#"
+        INSERT INTO rate_limit_buckets (bucket_key, tokens, updated_at)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (bucket_key) DO UPDATE
+        SET tokens = EXCLUDED.tokens, updated_at = EXCLUDED.updated_at
+        "#,
+        bucket_key,
+        state.tokens,
+        state.updated_at
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to update a rate limit bucket.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to update a rate limit bucket.")?;
+    Ok(retry_after)
+}
+
+/// Sweep the buckets of `limiter` periodically, until the application drops it.
+pub async fn sweep_until_stopped(limiter: Weak<RateLimiter>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let Some(limiter) = limiter.upgrade() else {
+            return;
+        };
+        limiter.sweep().await;
+    }
+}
+
+/// Middleware limiting the requests each client IP can make to the routes it wraps.
+pub struct RateLimitByIp {
+    limiter: Data<RateLimiter>,
+    scope: &'static str,
+    bucket: Option<TokenBucket>,
+}
+
+impl RateLimitByIp {
+    #[must_use]
+    pub fn new(
+        limiter: Data<RateLimiter>,
+        scope: &'static str,
+        bucket: Option<TokenBucket>,
+    ) -> Self {
+        Self {
+            limiter,
+            scope,
+            bucket,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimitByIp
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitByIpMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitByIpMiddleware {
+            service: Rc::new(service),
+            limiter: self.limiter.clone(),
+            scope: self.scope,
+            bucket: self.bucket,
+        }))
+    }
+}
+
+pub struct RateLimitByIpMiddleware<S> {
+    service: Rc<S>,
+    limiter: Data<RateLimiter>,
+    scope: &'static str,
+    bucket: Option<TokenBucket>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitByIpMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let limiter = self.limiter.clone();
+        let scope = self.scope;
+        let bucket = self.bucket;
+        Box::pin(async move {
+            if let Some(ip) = limiter.client_ip(&request) {
+                if let Err(e) = limiter.check(scope, &ip.to_string(), bucket).await {
+                    return Ok(request.error_response(e).map_into_right_body());
+                }
+            }
+            service
+                .call(request)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_ip, seconds_until_full, take_token, BucketState};
+    use crate::configuration::TokenBucket;
+    use chrono::{Duration, Utc};
+    use ipnet::IpNet;
+    use std::net::IpAddr;
+
+    const BUCKET: TokenBucket = TokenBucket {
+        capacity: 2,
+        refill_interval_seconds: 60,
+    };
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn network(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn a_full_bucket_allows_a_burst_up_to_its_capacity() {
+        let now = Utc::now();
+        let (state, retry_after) = take_token(BUCKET, None, now);
+        assert_eq!(retry_after, None);
+        let (state, retry_after) = take_token(BUCKET, Some(state), now);
+        assert_eq!(retry_after, None);
+        let (_, retry_after) = take_token(BUCKET, Some(state), now);
+        assert_eq!(retry_after, Some(60));
+    }
+
+    #[test]
+    fn tokens_are_refilled_over_time_up_to_the_capacity() {
+        let now = Utc::now();
+        let empty = BucketState {
+            tokens: 0.0,
+            updated_at: now - Duration::seconds(45),
+        };
+        assert_eq!(take_token(BUCKET, Some(empty), now).1, Some(15));
+
+        let long_ago = BucketState {
+            tokens: 0.0,
+            updated_at: now - Duration::days(1),
+        };
+        let (state, retry_after) = take_token(BUCKET, Some(long_ago), now);
+        assert_eq!(retry_after, None);
+        assert!((state.tokens - 1.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn buckets_without_a_refill_interval_refill_a_token_a_second() {
+        let bucket = TokenBucket {
+            capacity: 2,
+            refill_interval_seconds: 0,
+        };
+        let now = Utc::now();
+        let empty = BucketState {
+            tokens: 0.0,
+            updated_at: now - Duration::seconds(1),
+        };
+        assert_eq!(take_token(bucket, Some(empty), now).1, None);
+        assert!((seconds_until_full(bucket, 0.0) - 2.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let client = client_ip(Some(ip("10.0.0.1")), Some("1.2.3.4"), &[]);
+        assert_eq!(client, Some(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn the_rightmost_untrusted_hop_is_the_client() {
+        let trusted = [network("10.0.0.1/32"), network("10.0.0.2/32")];
+        let client = client_ip(
+            Some(ip("10.0.0.1")),
+            Some("6.6.6.6, 1.2.3.4, 10.0.0.2"),
+            &trusted,
+        );
+        assert_eq!(client, Some(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn malformed_forwarded_for_falls_back_to_the_peer() {
+        let client = client_ip(
+            Some(ip("10.0.0.1")),
+            Some("garbage"),
+            &[network("10.0.0.1/32")],
+        );
+        assert_eq!(client, Some(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn every_address_of_a_trusted_network_is_trusted() {
+        let trusted = [network("10.0.0.0/8")];
+        let client = client_ip(Some(ip("10.12.0.7")), Some("1.2.3.4, 10.244.1.9"), &trusted);
+        assert_eq!(client, Some(ip("1.2.3.4")));
+    }
+}
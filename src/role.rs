@@ -0,0 +1,108 @@
+use crate::api_key::Scope;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// What a user may do, whichever way they authenticate. Each role grants the scopes of
+/// the roles below it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Everything, including managing other users and reading the audit log.
+    Admin,
+    /// Sends newsletter issues.
+    Publisher,
+    /// Manages lists, subscribers and onboarding, and drafts issues without sending them.
+    Editor,
+    /// Reads everything, changes nothing but their own account.
+    Viewer,
+}
+
+impl Role {
+    pub const ALL: [Self; 4] = [Self::Admin, Self::Publisher, Self::Editor, Self::Viewer];
+
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Admin => "admin",
+            Self::Publisher => "publisher",
+            Self::Editor => "editor",
+            Self::Viewer => "viewer",
+        }
+    }
+
+    #[must_use]
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|role| role.as_str() == s)
+    }
+
+    #[must_use]
+    pub fn grants(self, scope: Scope) -> bool {
+        match scope {
+            Scope::IssuesRead
+            | Scope::ListsRead
+            | Scope::OnboardingRead
+            | Scope::SubscribersRead
+            | Scope::Account => true,
+            Scope::NewslettersDraft
+            | Scope::ListsWrite
+            | Scope::OnboardingWrite
+            | Scope::SubscribersWrite => self != Self::Viewer,
+            Scope::NewslettersPublish => matches!(self, Self::Admin | Self::Publisher),
+            Scope::Users | Scope::AuditRead => self == Self::Admin,
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// # Errors
+/// Returns an error if the query fails, the user does not exist or has an unknown role.
+#[tracing::instrument(name = "Get the role of a user", skip(pool))]
+pub async fn user_role(pool: &PgPool, user_id: Uuid) -> Result<Role, anyhow::Error> {
+    let role = sqlx::query_scalar!(r#"SELECT role FROM users WHERE user_id = $1"#, user_id)
+        .fetch_one(pool)
+        .await?;
+    Role::parse(&role).ok_or_else(|| anyhow::anyhow!("Unknown role {role}."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Role;
+    use crate::api_key::Scope;
+
+    #[test]
+    fn editors_draft_but_only_publishers_and_admins_send() {
+        assert!(Role::Editor.grants(Scope::NewslettersDraft));
+        assert!(!Role::Editor.grants(Scope::NewslettersPublish));
+        assert!(Role::Publisher.grants(Scope::NewslettersPublish));
+        assert!(Role::Admin.grants(Scope::NewslettersPublish));
+        assert!(!Role::Viewer.grants(Scope::NewslettersDraft));
+    }
+
+    #[test]
+    fn only_admins_manage_users() {
+        for role in Role::ALL {
+            assert_eq!(role.grants(Scope::Users), role == Role::Admin);
+        }
+    }
+
+    #[test]
+    fn higher_roles_grant_every_scope_of_lower_ones() {
+        let scopes = Scope::GRANTABLE
+            .into_iter()
+            .chain([Scope::Account, Scope::Users]);
+        for scope in scopes {
+            for pair in Role::ALL.windows(2) {
+                assert!(
+                    pair[0].grants(scope) || !pair[1].grants(scope),
+                    "{} does not grant {scope} while {} does",
+                    pair[0],
+                    pair[1]
+                );
+            }
+        }
+    }
+}
@@ -1,9 +1,27 @@
+mod admin;
+mod dev_mailbox;
+mod error;
+mod feeds;
 mod health_check;
+mod issues;
+mod login;
 mod newsletters;
+mod password_reset;
+mod preferences;
 mod subscriptions;
 mod subscriptions_confirm;
+mod tracking;
 
+pub use admin::*;
+pub use dev_mailbox::*;
+pub use error::*;
+pub use feeds::*;
 pub use health_check::*;
+pub use issues::*;
+pub use login::*;
 pub use newsletters::*;
+pub use password_reset::*;
+pub use preferences::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use tracking::*;
@@ -0,0 +1,21 @@
+mod api_keys;
+mod audit_events;
+mod drafts;
+mod issue_report;
+mod issue_stats;
+mod lists;
+mod onboarding;
+mod subscribers;
+mod two_factor;
+mod users;
+
+pub use api_keys::*;
+pub use audit_events::*;
+pub use drafts::*;
+pub use issue_report::*;
+pub use issue_stats::*;
+pub use lists::*;
+pub use onboarding::*;
+pub use subscribers::*;
+pub use two_factor::*;
+pub use users::*;
@@ -0,0 +1,225 @@
+use crate::api_key::{generate_api_key, Scope};
+use crate::audit::{record_audit_event, AuditEvent};
+use crate::authentication::authenticate;
+use crate::role::user_role;
+use crate::routes::AppError;
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct NewApiKeyData {
+    name: String,
+    scopes: Vec<String>,
+    /// Keys without an expiry are valid until they are revoked.
+    expires_in_days: Option<u32>,
+}
+
+#[derive(serde::Serialize)]
+struct ApiKeySummary {
+    api_key_id: Uuid,
+    name: String,
+    lookup_prefix: String,
+    scopes: Vec<String>,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Serialize)]
+struct CreatedApiKey {
+    /// The only time the key is shown, it cannot be retrieved later.
+    key: String,
+    #[serde(flatten)]
+    summary: ApiKeySummary,
+}
+
+fn parse_scopes(scopes: &[String]) -> Result<Vec<Scope>, AppError> {
+    if scopes.is_empty() {
+        return Err(AppError::ValidationError(
+            "An API key must be granted at least one scope.".into(),
+        ));
+    }
+    let unknown: Vec<&str> = scopes
+        .iter()
+        .filter(|scope| Scope::parse_grantable(scope).is_none())
+        .map(String::as_str)
+        .collect();
+    if !unknown.is_empty() {
+        let grantable: Vec<&str> = Scope::GRANTABLE.iter().map(|s| s.as_str()).collect();
+        return Err(AppError::ValidationError(format!(
+            "{} cannot be granted, the scopes are {}.",
+            unknown.join(", "),
+            grantable.join(", ")
+        )));
+    }
+    Ok(scopes
+        .iter()
+        .filter_map(|scope| Scope::parse_grantable(scope))
+        .collect())
+}
+
+#[tracing::instrument(
+    name = "Create an API key",
+    skip(body, pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn create_api_key(
+    body: web::Json<NewApiKeyData>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::Account)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let NewApiKeyData {
+        name,
+        scopes,
+        expires_in_days,
+    } = body.into_inner();
+    if name.trim().is_empty() {
+        return Err(AppError::ValidationError(
+            "An API key must have a name.".into(),
+        ));
+    }
+    let scopes = parse_scopes(&scopes)?;
+    let role = user_role(&pool, user_id)
+        .await
+        .context("Failed to retrieve the role of a user.")?;
+    // A key never grants more than its user could do.
+    if let Some(scope) = scopes.iter().find(|scope| !role.grants(**scope)) {
+        return Err(AppError::Forbidden(format!(
+            "The {role} role does not grant the {scope} scope."
+        )));
+    }
+    let scopes: Vec<String> = scopes
+        .into_iter()
+        .map(|scope| scope.as_str().to_owned())
+        .collect();
+    let generated = generate_api_key();
+    let created_at = Utc::now();
+    let expires_at =
+        expires_in_days.map(|days| created_at + chrono::Duration::days(i64::from(days)));
+    let api_key_id = Uuid::new_v4();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    sqlx::query!(
+        r#"
+        INSERT INTO api_keys
+            (api_key_id, user_id, name, lookup_prefix, key_hash, scopes, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+        api_key_id,
+        user_id,
+        name,
+        generated.lookup_prefix,
+        generated.key_hash,
+        &scopes,
+        created_at,
+        expires_at,
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to store a new API key.")?;
+    let summary = ApiKeySummary {
+        api_key_id,
+        name,
+        lookup_prefix: generated.lookup_prefix,
+        scopes,
+        created_at,
+        expires_at,
+        last_used_at: None,
+        revoked_at: None,
+    };
+    let event =
+        AuditEvent::new("api_key.create", "api_key", api_key_id).after(serde_json::json!(summary));
+    record_audit_event(&mut *transaction, &request, user_id, event)
+        .await
+        .context("Failed to record an audit event.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to create an API key.")?;
+    Ok(HttpResponse::Created().json(CreatedApiKey {
+        key: generated.key,
+        summary,
+    }))
+}
+
+#[tracing::instrument(
+    name = "List API keys",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn get_api_keys(
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::Account)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let api_keys = sqlx::query_as!(
+        ApiKeySummary,
+        r#"
+        SELECT api_key_id, name, lookup_prefix, scopes, created_at, expires_at, last_used_at,
+            revoked_at
+        FROM api_keys
+        WHERE user_id = $1
+        ORDER BY created_at
+        "#,
+        user_id,
+    )
+    .fetch_all(pool.as_ref())
+    .await
+    .context("Failed to retrieve API keys.")?;
+    Ok(HttpResponse::Ok().json(api_keys))
+}
+
+/// Revoked keys stop working immediately but are kept, so that their use can be audited.
+#[tracing::instrument(
+    name = "Revoke an API key",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn revoke_api_key(
+    api_key_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::Account)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let api_key_id = api_key_id.into_inner();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let revoked_at = sqlx::query_scalar!(
+        r#"
+        UPDATE api_keys SET revoked_at = COALESCE(revoked_at, now())
+        WHERE api_key_id = $1 AND user_id = $2
+        RETURNING revoked_at AS "revoked_at!"
+        "#,
+        api_key_id,
+        user_id,
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to revoke an API key.")?
+    .ok_or(AppError::NotFound)?;
+    let event = AuditEvent::new("api_key.revoke", "api_key", api_key_id)
+        .after(serde_json::json!({ "revoked_at": revoked_at }));
+    record_audit_event(&mut *transaction, &request, user_id, event)
+        .await
+        .context("Failed to record an audit event.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to revoke an API key.")?;
+    Ok(HttpResponse::NoContent().finish())
+}
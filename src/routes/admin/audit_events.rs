@@ -0,0 +1,114 @@
+use crate::api_key::Scope;
+use crate::authentication::authenticate;
+use crate::routes::AppError;
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, QueryBuilder};
+use uuid::Uuid;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+/// Every filter is optional. Pages run from the most recent event backwards, `before` being
+/// the `next_cursor` of the previous page.
+#[derive(serde::Deserialize)]
+pub struct AuditEventFilters {
+    actor_user_id: Option<Uuid>,
+    action: Option<String>,
+    target_type: Option<String>,
+    target_id: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    before: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(serde::Serialize, sqlx::FromRow)]
+struct AuditEventRecord {
+    audit_event_id: i64,
+    occurred_at: DateTime<Utc>,
+    actor_user_id: Uuid,
+    actor_api_key_id: Option<Uuid>,
+    action: String,
+    target_type: String,
+    target_id: String,
+    request_id: Option<String>,
+    client_ip: Option<String>,
+    diff: serde_json::Value,
+}
+
+#[derive(serde::Serialize)]
+struct AuditEventPage {
+    events: Vec<AuditEventRecord>,
+    /// `None` on the last page.
+    next_cursor: Option<i64>,
+}
+
+#[tracing::instrument(
+    name = "List audit events",
+    skip(filters, pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn get_audit_events(
+    filters: web::Query<AuditEventFilters>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    authenticate(&request, &pool, Scope::AuditRead)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let limit = filters.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(AppError::ValidationError(format!(
+            "The limit must be between 1 and {MAX_PAGE_SIZE}."
+        )));
+    }
+    let mut query = QueryBuilder::new(
+        "SELECT audit_event_id, occurred_at, actor_user_id, actor_api_key_id, action, \
+         target_type, target_id, request_id, client_ip, diff \
+         FROM audit_events WHERE TRUE",
+    );
+    if let Some(actor_user_id) = filters.actor_user_id {
+        query.push(" AND actor_user_id = ").push_bind(actor_user_id);
+    }
+    if let Some(action) = &filters.action {
+        query.push(" AND action = ").push_bind(action.clone());
+    }
+    if let Some(target_type) = &filters.target_type {
+        query
+            .push(" AND target_type = ")
+            .push_bind(target_type.clone());
+    }
+    if let Some(target_id) = &filters.target_id {
+        query.push(" AND target_id = ").push_bind(target_id.clone());
+    }
+    if let Some(since) = filters.since {
+        query.push(" AND occurred_at >= ").push_bind(since);
+    }
+    if let Some(until) = filters.until {
+        query.push(" AND occurred_at < ").push_bind(until);
+    }
+    if let Some(before) = filters.before {
+        query.push(" AND audit_event_id < ").push_bind(before);
+    }
+    // One more than asked for, to know whether there is a next page.
+    query
+        .push(" ORDER BY audit_event_id DESC LIMIT ")
+        .push_bind(limit + 1);
+    let mut events = query
+        .build_query_as::<AuditEventRecord>()
+        .fetch_all(pool.as_ref())
+        .await
+        .context("Failed to retrieve audit events.")?;
+    let next_cursor = if events.len() > usize::try_from(limit).unwrap_or(usize::MAX) {
+        events.pop();
+        events.last().map(|event| event.audit_event_id)
+    } else {
+        None
+    };
+    Ok(HttpResponse::Ok().json(AuditEventPage {
+        events,
+        next_cursor,
+    }))
+}
@@ -0,0 +1,340 @@
+use crate::api_key::Scope;
+use crate::audit::{record_audit_event, AuditEvent};
+use crate::authentication::authenticate;
+use crate::configuration::{TrackingSettings, TwoFactorSettings};
+use crate::email_client::EmailClient;
+use crate::routes::{
+    find_unknown_lists, parse_audience, publish_issue, require_two_factor, AppError, BodyData,
+    Content, IssueTracking,
+};
+use crate::startup::ApplicationBaseUrl;
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(serde::Serialize)]
+struct Draft {
+    newsletter_draft_id: Uuid,
+    title: String,
+    content: Content,
+    tracking: IssueTracking,
+    lists: Vec<String>,
+    segment: Option<String>,
+    created_by: Uuid,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    /// Set once the draft was published, after which it can no longer be changed.
+    newsletter_issue_id: Option<Uuid>,
+}
+
+#[derive(serde::Serialize)]
+struct DraftSummary {
+    newsletter_draft_id: Uuid,
+    title: String,
+    created_by: Uuid,
+    updated_at: DateTime<Utc>,
+    newsletter_issue_id: Option<Uuid>,
+}
+
+#[derive(serde::Serialize)]
+struct PublishedDraft {
+    newsletter_issue_id: Uuid,
+}
+
+/// Drafts are checked like issues being published, so that publishing them cannot fail
+/// on their content.
+async fn validate_draft(pool: &PgPool, body: &BodyData) -> Result<(), AppError> {
+    let (lists, _) = parse_audience(&body.lists, body.segment.as_deref())?;
+    let unknown_lists = find_unknown_lists(pool, &lists)
+        .await
+        .context("Failed to look up the lists targeted by a draft.")?;
+    if !unknown_lists.is_empty() {
+        return Err(AppError::ValidationError(format!(
+            "There are no lists identified by {}.",
+            unknown_lists.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+#[tracing::instrument(name = "Find a newsletter draft", skip(executor))]
+async fn find_draft<'c, E>(executor: E, newsletter_draft_id: Uuid) -> Result<Draft, AppError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let draft = sqlx::query!(
+        r#"
+        SELECT newsletter_draft_id, title, text_content, html_content, lists, segment,
+            track_opens, track_clicks, created_by, created_at, updated_at, newsletter_issue_id
+        FROM newsletter_drafts
+        WHERE newsletter_draft_id = $1
+        "#,
+        newsletter_draft_id,
+    )
+    .fetch_optional(executor)
+    .await
+    .context("Failed to retrieve a newsletter draft.")?
+    .ok_or(AppError::NotFound)?;
+    Ok(Draft {
+        newsletter_draft_id: draft.newsletter_draft_id,
+        title: draft.title,
+        content: Content {
+            html: draft.html_content,
+            text: draft.text_content,
+        },
+        tracking: IssueTracking {
+            opens: draft.track_opens,
+            clicks: draft.track_clicks,
+        },
+        lists: draft.lists,
+        segment: draft.segment,
+        created_by: draft.created_by,
+        created_at: draft.created_at,
+        updated_at: draft.updated_at,
+        newsletter_issue_id: draft.newsletter_issue_id,
+    })
+}
+
+#[tracing::instrument(
+    name = "Create a newsletter draft",
+    skip(body, pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn create_draft(
+    body: web::Json<BodyData>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::NewslettersDraft)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    validate_draft(&pool, &body).await?;
+    let newsletter_draft_id = Uuid::new_v4();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_drafts (newsletter_draft_id, title, text_content, html_content,
+            lists, segment, track_opens, track_clicks, created_by, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, now(), now())
+        "#,
+        newsletter_draft_id,
+        body.title,
+        body.content.text,
+        body.content.html,
+        &body.lists,
+        body.segment,
+        body.tracking.opens,
+        body.tracking.clicks,
+        user_id,
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to store a newsletter draft.")?;
+    let draft = find_draft(&mut *transaction, newsletter_draft_id).await?;
+    let event = AuditEvent::new(
+        "newsletter_draft.create",
+        "newsletter_draft",
+        newsletter_draft_id,
+    )
+    .after(serde_json::json!(draft));
+    record_audit_event(&mut *transaction, &request, user_id, event)
+        .await
+        .context("Failed to record an audit event.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to create a newsletter draft.")?;
+    Ok(HttpResponse::Created().json(draft))
+}
+
+#[tracing::instrument(
+    name = "List newsletter drafts",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn get_drafts(
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    authenticate(&request, &pool, Scope::NewslettersDraft)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let drafts = sqlx::query_as!(
+        DraftSummary,
+        r#"
+        SELECT newsletter_draft_id, title, created_by, updated_at, newsletter_issue_id
+        FROM newsletter_drafts
+        ORDER BY updated_at DESC
+        "#
+    )
+    .fetch_all(pool.as_ref())
+    .await
+    .context("Failed to retrieve newsletter drafts.")?;
+    Ok(HttpResponse::Ok().json(drafts))
+}
+
+#[tracing::instrument(
+    name = "Get a newsletter draft",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn get_draft(
+    newsletter_draft_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    authenticate(&request, &pool, Scope::NewslettersDraft)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let draft = find_draft(pool.as_ref(), newsletter_draft_id.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(draft))
+}
+
+#[tracing::instrument(
+    name = "Update a newsletter draft",
+    skip(body, pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn update_draft(
+    newsletter_draft_id: web::Path<Uuid>,
+    body: web::Json<BodyData>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::NewslettersDraft)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let newsletter_draft_id = newsletter_draft_id.into_inner();
+    validate_draft(&pool, &body).await?;
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    lock_draft(&mut transaction, newsletter_draft_id).await?;
+    let previous = find_draft(&mut *transaction, newsletter_draft_id).await?;
+    if previous.newsletter_issue_id.is_some() {
+        return Err(AppError::Conflict(
+            "The draft was already published.".into(),
+        ));
+    }
+    sqlx::query!(
+        r#"
+        UPDATE newsletter_drafts
+        SET title = $2, text_content = $3, html_content = $4, lists = $5, segment = $6,
+            track_opens = $7, track_clicks = $8, updated_at = now()
+        WHERE newsletter_draft_id = $1
+        "#,
+        newsletter_draft_id,
+        body.title,
+        body.content.text,
+        body.content.html,
+        &body.lists,
+        body.segment,
+        body.tracking.opens,
+        body.tracking.clicks,
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to update a newsletter draft.")?;
+    let draft = find_draft(&mut *transaction, newsletter_draft_id).await?;
+    let event = AuditEvent::new(
+        "newsletter_draft.update",
+        "newsletter_draft",
+        newsletter_draft_id,
+    )
+    .before(serde_json::json!(previous))
+    .after(serde_json::json!(draft));
+    record_audit_event(&mut *transaction, &request, user_id, event)
+        .await
+        .context("Failed to record an audit event.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to update a newsletter draft.")?;
+    Ok(HttpResponse::Ok().json(draft))
+}
+
+/// Concurrent changes to the draft wait for the transaction to complete.
+async fn lock_draft(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_draft_id: Uuid,
+) -> Result<(), AppError> {
+    sqlx::query!(
+        r#"SELECT newsletter_draft_id FROM newsletter_drafts WHERE newsletter_draft_id = $1 FOR UPDATE"#,
+        newsletter_draft_id,
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .context("Failed to lock a newsletter draft.")?;
+    Ok(())
+}
+
+/// Send a draft as a newsletter issue. A draft is only ever published once.
+#[tracing::instrument(
+    name = "Publish a newsletter draft",
+    skip(pool, email_client, base_url, tracking_settings, two_factor_settings, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn publish_draft(
+    newsletter_draft_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    tracking_settings: web::Data<TrackingSettings>,
+    two_factor_settings: web::Data<TwoFactorSettings>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::NewslettersPublish)
+        .await
+        .map_err(AppError::from_auth("publish"))?;
+    require_two_factor(&pool, &two_factor_settings, user_id).await?;
+    let newsletter_draft_id = newsletter_draft_id.into_inner();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    lock_draft(&mut transaction, newsletter_draft_id).await?;
+    let draft = find_draft(&mut *transaction, newsletter_draft_id).await?;
+    if draft.newsletter_issue_id.is_some() {
+        return Err(AppError::Conflict(
+            "The draft was already published.".into(),
+        ));
+    }
+    let body = BodyData {
+        title: draft.title,
+        content: draft.content,
+        tracking: draft.tracking,
+        lists: draft.lists,
+        segment: draft.segment,
+    };
+    let newsletter_issue_id = publish_issue(
+        &pool,
+        &email_client,
+        &base_url,
+        &tracking_settings,
+        &body,
+        &request,
+        user_id,
+    )
+    .await?;
+    sqlx::query!(
+        r#"UPDATE newsletter_drafts SET newsletter_issue_id = $2 WHERE newsletter_draft_id = $1"#,
+        newsletter_draft_id,
+        newsletter_issue_id,
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to mark a newsletter draft as published.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to publish a newsletter draft.")?;
+    Ok(HttpResponse::Ok().json(PublishedDraft {
+        newsletter_issue_id,
+    }))
+}
@@ -0,0 +1,99 @@
+use crate::api_key::Scope;
+use crate::authentication::authenticate;
+use crate::routes::AppError;
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Serialize)]
+struct IssueReport {
+    newsletter_issue_id: Uuid,
+    title: String,
+    total: i64,
+    queued: i64,
+    sent: i64,
+    failed: i64,
+    bounced: i64,
+    failures: Vec<FailedDelivery>,
+}
+
+#[derive(serde::Serialize)]
+struct FailedDelivery {
+    subscriber_email: String,
+    status: String,
+    attempts: i16,
+    last_error: Option<String>,
+}
+
+#[tracing::instrument(
+    name = "Get the delivery report of a newsletter issue",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn issue_delivery_report(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    authenticate(&request, &pool, Scope::IssuesRead)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let report = get_issue_report(&pool, newsletter_issue_id)
+        .await
+        .context("Failed to compute the delivery report.")?
+        .ok_or(AppError::NotFound)?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[tracing::instrument(name = "Compute delivery report", skip(pool))]
+async fn get_issue_report(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+) -> Result<Option<IssueReport>, sqlx::Error> {
+    let Some(counts) = sqlx::query!(
+        r#"
+        SELECT
+            i.title,
+            COUNT(d.subscriber_id) AS "total!",
+            COUNT(d.subscriber_id) FILTER (WHERE d.status = 'queued') AS "queued!",
+            COUNT(d.subscriber_id) FILTER (WHERE d.status = 'sent') AS "sent!",
+            COUNT(d.subscriber_id) FILTER (WHERE d.status = 'failed') AS "failed!",
+            COUNT(d.subscriber_id) FILTER (WHERE d.status = 'bounced') AS "bounced!"
+        FROM newsletter_issues i
+        LEFT JOIN deliveries d ON d.newsletter_issue_id = i.newsletter_issue_id
+        WHERE i.newsletter_issue_id = $1
+        GROUP BY i.newsletter_issue_id
+        "#,
+        newsletter_issue_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let failures = sqlx::query_as!(
+        FailedDelivery,
+        r#"
+        SELECT subscriber_email, status, attempts, last_error
+        FROM deliveries
+        WHERE newsletter_issue_id = $1 AND status IN ('failed', 'bounced')
+        ORDER BY subscriber_email
+        "#,
+        newsletter_issue_id,
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(Some(IssueReport {
+        newsletter_issue_id,
+        title: counts.title,
+        total: counts.total,
+        queued: counts.queued,
+        sent: counts.sent,
+        failed: counts.failed,
+        bounced: counts.bounced,
+        failures,
+    }))
+}
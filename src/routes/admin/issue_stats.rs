@@ -0,0 +1,110 @@
+use crate::api_key::Scope;
+use crate::authentication::authenticate;
+use crate::routes::AppError;
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Serialize)]
+struct IssueStats {
+    newsletter_issue_id: Uuid,
+    track_opens: bool,
+    track_clicks: bool,
+    delivered: i64,
+    opens: EngagementCount,
+    clicks: EngagementCount,
+    links: Vec<LinkStats>,
+}
+
+#[derive(serde::Serialize)]
+struct EngagementCount {
+    total: i64,
+    unique: i64,
+}
+
+#[derive(serde::Serialize)]
+struct LinkStats {
+    url: String,
+    total: i64,
+    unique: i64,
+}
+
+#[tracing::instrument(
+    name = "Get the engagement stats of a newsletter issue",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn issue_stats(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    authenticate(&request, &pool, Scope::IssuesRead)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+
+    let stats = get_issue_stats(&pool, newsletter_issue_id.into_inner())
+        .await
+        .context("Failed to compute the engagement stats.")?
+        .ok_or(AppError::NotFound)?;
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+#[tracing::instrument(name = "Compute engagement stats", skip(pool))]
+async fn get_issue_stats(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+) -> Result<Option<IssueStats>, sqlx::Error> {
+    let Some(totals) = sqlx::query!(
+        r#"
+        SELECT
+            i.track_opens,
+            i.track_clicks,
+            (SELECT COUNT(*) FROM deliveries d
+                WHERE d.newsletter_issue_id = i.newsletter_issue_id AND d.status = 'sent') AS "delivered!",
+            COUNT(e.tracking_event_id) FILTER (WHERE e.kind = 'open') AS "total_opens!",
+            COUNT(DISTINCT e.subscriber_id) FILTER (WHERE e.kind = 'open') AS "unique_opens!",
+            COUNT(e.tracking_event_id) FILTER (WHERE e.kind = 'click') AS "total_clicks!",
+            COUNT(DISTINCT e.subscriber_id) FILTER (WHERE e.kind = 'click') AS "unique_clicks!"
+        FROM newsletter_issues i
+        LEFT JOIN tracking_events e ON e.newsletter_issue_id = i.newsletter_issue_id
+        WHERE i.newsletter_issue_id = $1
+        GROUP BY i.newsletter_issue_id
+        "#,
+        newsletter_issue_id,
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let links = sqlx::query_as!(
+        LinkStats,
+        r#"
+        SELECT url AS "url!", COUNT(*) AS "total!", COUNT(DISTINCT subscriber_id) AS "unique!"
+        FROM tracking_events
+        WHERE newsletter_issue_id = $1 AND kind = 'click'
+        GROUP BY url
+        ORDER BY 2 DESC, url
+        "#,
+        newsletter_issue_id,
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(Some(IssueStats {
+        newsletter_issue_id,
+        track_opens: totals.track_opens,
+        track_clicks: totals.track_clicks,
+        delivered: totals.delivered,
+        opens: EngagementCount {
+            total: totals.total_opens,
+            unique: totals.unique_opens,
+        },
+        clicks: EngagementCount {
+            total: totals.total_clicks,
+            unique: totals.unique_clicks,
+        },
+        links,
+    }))
+}
@@ -0,0 +1,117 @@
+use crate::api_key::Scope;
+use crate::audit::{record_audit_event, AuditEvent};
+use crate::authentication::authenticate;
+use crate::domain::ListSlug;
+use crate::routes::AppError;
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct NewListData {
+    slug: String,
+    name: String,
+}
+
+#[derive(serde::Serialize)]
+struct ListSummary {
+    list_id: Uuid,
+    slug: String,
+    name: String,
+    confirmed_subscribers: i64,
+}
+
+#[tracing::instrument(
+    name = "List mailing lists",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn get_lists(
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    authenticate(&request, &pool, Scope::ListsRead)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let lists = sqlx::query_as!(
+        ListSummary,
+        r#"
+        SELECT
+            l.list_id,
+            l.slug,
+            l.name,
+            COUNT(ls.subscriber_id) FILTER (WHERE ls.status = 'confirmed') AS "confirmed_subscribers!"
+        FROM lists l
+        LEFT JOIN list_subscriptions ls ON ls.list_id = l.list_id
+        GROUP BY l.list_id
+        ORDER BY l.slug
+        "#
+    )
+    .fetch_all(pool.as_ref())
+    .await
+    .context("Failed to retrieve mailing lists.")?;
+    Ok(HttpResponse::Ok().json(lists))
+}
+
+#[tracing::instrument(
+    name = "Create a mailing list",
+    skip(body, pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn create_list(
+    body: web::Json<NewListData>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::ListsWrite)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let NewListData { slug, name } = body.into_inner();
+    let slug = ListSlug::parse(slug).map_err(AppError::ValidationError)?;
+    if name.trim().is_empty() {
+        return Err(AppError::ValidationError("A list must have a name.".into()));
+    }
+    let list_id = Uuid::new_v4();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let inserted = sqlx::query!(
+        r#"
+        INSERT INTO lists (list_id, slug, name, created_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (slug) DO NOTHING
+        "#,
+        list_id,
+        slug.as_ref(),
+        name,
+        Utc::now()
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to insert the new mailing list.")?
+    .rows_affected();
+    if inserted == 0 {
+        return Err(AppError::Conflict(format!(
+            "A list identified by {slug} already exists."
+        )));
+    }
+    let list = ListSummary {
+        list_id,
+        slug: slug.as_ref().to_owned(),
+        name,
+        confirmed_subscribers: 0,
+    };
+    let event = AuditEvent::new("list.create", "list", list_id)
+        .after(serde_json::json!({ "slug": list.slug, "name": list.name }));
+    record_audit_event(&mut *transaction, &request, user_id, event)
+        .await
+        .context("Failed to record an audit event.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to create a mailing list.")?;
+    Ok(HttpResponse::Created().json(list))
+}
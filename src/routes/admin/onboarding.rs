@@ -0,0 +1,189 @@
+use crate::api_key::Scope;
+use crate::audit::{record_audit_event, AuditEvent};
+use crate::authentication::authenticate;
+use crate::routes::AppError;
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use sqlx::{PgPool, Postgres, Transaction};
+
+/// One step of the onboarding sequence. `{{name}}` is replaced with the subscriber's name.
+#[derive(serde::Deserialize)]
+pub struct OnboardingEmailData {
+    delay_days: i32,
+    subject: String,
+    html_content: String,
+    text_content: String,
+}
+
+#[derive(serde::Serialize)]
+struct OnboardingEmail {
+    step: i16,
+    delay_days: i32,
+    subject: String,
+    html_content: String,
+    text_content: String,
+}
+
+#[tracing::instrument(
+    name = "List the onboarding sequence",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn get_onboarding_emails(
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    authenticate(&request, &pool, Scope::OnboardingRead)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let emails = sqlx::query_as!(
+        OnboardingEmail,
+        r#"
+        SELECT step, delay_days, subject, html_content, text_content
+        FROM onboarding_emails
+        ORDER BY delay_days, step
+        "#
+    )
+    .fetch_all(pool.as_ref())
+    .await
+    .context("Failed to retrieve the onboarding sequence.")?;
+    Ok(HttpResponse::Ok().json(emails))
+}
+
+/// Create or replace a step. Subscribers already enrolled keep their schedule.
+#[tracing::instrument(
+    name = "Save an onboarding email",
+    skip(body, pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn put_onboarding_email(
+    step: web::Path<i16>,
+    body: web::Json<OnboardingEmailData>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::OnboardingWrite)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let step = step.into_inner();
+    let OnboardingEmailData {
+        delay_days,
+        subject,
+        html_content,
+        text_content,
+    } = body.into_inner();
+    if step < 1 {
+        return Err(AppError::ValidationError(
+            "Steps are numbered from 1.".into(),
+        ));
+    }
+    if !(0..=365).contains(&delay_days) {
+        return Err(AppError::ValidationError(
+            "An onboarding email must be sent within 365 days.".into(),
+        ));
+    }
+    if subject.trim().is_empty() {
+        return Err(AppError::ValidationError(
+            "An onboarding email must have a subject.".into(),
+        ));
+    }
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let previous = find_onboarding_email(&mut transaction, step).await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO onboarding_emails (step, delay_days, subject, html_content, text_content)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (step) DO UPDATE
+        SET delay_days = EXCLUDED.delay_days,
+            subject = EXCLUDED.subject,
+            html_content = EXCLUDED.html_content,
+            text_content = EXCLUDED.text_content
+        "#,
+        step,
+        delay_days,
+        subject,
+        html_content,
+        text_content
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to save the onboarding email.")?;
+    let email = OnboardingEmail {
+        step,
+        delay_days,
+        subject,
+        html_content,
+        text_content,
+    };
+    let event = AuditEvent::new("onboarding_email.save", "onboarding_email", step)
+        .before(serde_json::json!(previous))
+        .after(serde_json::json!(email));
+    record_audit_event(&mut *transaction, &request, user_id, event)
+        .await
+        .context("Failed to record an audit event.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to save an onboarding email.")?;
+    Ok(HttpResponse::Ok().json(email))
+}
+
+#[tracing::instrument(
+    name = "Delete an onboarding email",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn delete_onboarding_email(
+    step: web::Path<i16>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::OnboardingWrite)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let step = step.into_inner();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let email = find_onboarding_email(&mut transaction, step)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    sqlx::query!(r#"DELETE FROM onboarding_emails WHERE step = $1"#, step)
+        .execute(&mut *transaction)
+        .await
+        .context("Failed to delete the onboarding email.")?;
+    let event = AuditEvent::new("onboarding_email.delete", "onboarding_email", step)
+        .before(serde_json::json!(email));
+    record_audit_event(&mut *transaction, &request, user_id, event)
+        .await
+        .context("Failed to record an audit event.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to delete an onboarding email.")?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn find_onboarding_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    step: i16,
+) -> Result<Option<OnboardingEmail>, AppError> {
+    let email = sqlx::query_as!(
+        OnboardingEmail,
+        r#"
+        SELECT step, delay_days, subject, html_content, text_content
+        FROM onboarding_emails
+        WHERE step = $1
+        FOR UPDATE
+        "#,
+        step
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .context("Failed to retrieve an onboarding email.")?;
+    Ok(email)
+}
@@ -0,0 +1,217 @@
+use crate::api_key::Scope;
+use crate::audit::{record_audit_event, AuditEvent};
+use crate::authentication::authenticate;
+use crate::domain::SubscriberTag;
+use crate::routes::AppError;
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Serialize)]
+struct SubscriberDetails {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+    tags: Vec<String>,
+    attributes: serde_json::Value,
+}
+
+#[tracing::instrument(
+    name = "Get subscriber details",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn get_subscriber(
+    subscriber_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    authenticate(&request, &pool, Scope::SubscribersRead)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let subscriber = sqlx::query_as!(
+        SubscriberDetails,
+        r#"
+        SELECT
+            s.id,
+            s.email,
+            s.name,
+            s.status,
+            s.subscribed_at,
+            ARRAY(SELECT t.tag FROM subscriber_tags t WHERE t.subscriber_id = s.id ORDER BY t.tag) AS "tags!",
+            s.attributes
+        FROM subscriptions s
+        WHERE s.id = $1
+        "#,
+        subscriber_id.into_inner()
+    )
+    .fetch_optional(pool.as_ref())
+    .await
+    .context("Failed to retrieve the subscriber.")?
+    .ok_or(AppError::NotFound)?;
+    Ok(HttpResponse::Ok().json(subscriber))
+}
+
+#[tracing::instrument(
+    name = "Tag a subscriber",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn add_subscriber_tag(
+    path: web::Path<(Uuid, String)>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::SubscribersWrite)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let (subscriber_id, tag) = path.into_inner();
+    let tag = SubscriberTag::parse(tag).map_err(AppError::ValidationError)?;
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let inserted = sqlx::query!(
+        r#"
+        INSERT INTO subscriber_tags (subscriber_id, tag)
+        SELECT id, $2 FROM subscriptions WHERE id = $1
+        ON CONFLICT DO NOTHING
+        RETURNING subscriber_id
+        "#,
+        subscriber_id,
+        tag.as_ref()
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to tag the subscriber.")?;
+    if inserted.is_none() {
+        if !subscriber_exists(&pool, subscriber_id).await? {
+            return Err(AppError::NotFound);
+        }
+        return Ok(HttpResponse::NoContent().finish());
+    }
+    let event = AuditEvent::new("subscriber.add_tag", "subscriber", subscriber_id)
+        .after(serde_json::json!({ "tag": tag.as_ref() }));
+    record_audit_event(&mut *transaction, &request, user_id, event)
+        .await
+        .context("Failed to record an audit event.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to tag a subscriber.")?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[tracing::instrument(
+    name = "Untag a subscriber",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn remove_subscriber_tag(
+    path: web::Path<(Uuid, String)>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::SubscribersWrite)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let (subscriber_id, tag) = path.into_inner();
+    if !subscriber_exists(&pool, subscriber_id).await? {
+        return Err(AppError::NotFound);
+    }
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let deleted = sqlx::query!(
+        r#"DELETE FROM subscriber_tags WHERE subscriber_id = $1 AND tag = $2"#,
+        subscriber_id,
+        tag
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to untag the subscriber.")?
+    .rows_affected();
+    if deleted > 0 {
+        let event = AuditEvent::new("subscriber.remove_tag", "subscriber", subscriber_id)
+            .before(serde_json::json!({ "tag": tag }));
+        record_audit_event(&mut *transaction, &request, user_id, event)
+            .await
+            .context("Failed to record an audit event.")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to untag a subscriber.")?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Merge the body into the subscriber's attributes; `null` values remove an attribute.
+#[tracing::instrument(
+    name = "Update subscriber attributes",
+    skip(body, pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn update_subscriber_attributes(
+    subscriber_id: web::Path<Uuid>,
+    body: web::Json<serde_json::Value>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::SubscribersWrite)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let subscriber_id = subscriber_id.into_inner();
+    let body = body.into_inner();
+    if !body.is_object() {
+        return Err(AppError::ValidationError(
+            "Attributes must be a JSON object.".into(),
+        ));
+    }
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let updated = sqlx::query!(
+        r#"
+        WITH previous AS (SELECT attributes FROM subscriptions WHERE id = $1 FOR UPDATE)
+        UPDATE subscriptions
+        SET attributes = jsonb_strip_nulls(subscriptions.attributes || $2)
+        FROM previous
+        WHERE id = $1
+        RETURNING previous.attributes AS before, subscriptions.attributes AS after
+        "#,
+        subscriber_id,
+        body
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to update the subscriber attributes.")?
+    .ok_or(AppError::NotFound)?;
+    let event = AuditEvent::new("subscriber.update_attributes", "subscriber", subscriber_id)
+        .before(updated.before)
+        .after(updated.after.clone());
+    record_audit_event(&mut *transaction, &request, user_id, event)
+        .await
+        .context("Failed to record an audit event.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to update subscriber attributes.")?;
+    Ok(HttpResponse::Ok().json(updated.after))
+}
+
+async fn subscriber_exists(pool: &PgPool, subscriber_id: Uuid) -> Result<bool, AppError> {
+    let exists = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM subscriptions WHERE id = $1) AS "exists!""#,
+        subscriber_id
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to look up the subscriber.")?;
+    Ok(exists)
+}
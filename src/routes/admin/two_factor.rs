@@ -0,0 +1,140 @@
+use crate::api_key::Scope;
+use crate::audit::{record_audit_event, AuditEvent};
+use crate::authentication::authenticate;
+use crate::configuration::TwoFactorSettings;
+use crate::routes::AppError;
+use crate::totp;
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct ConfirmationData {
+    code: String,
+}
+
+#[derive(serde::Serialize)]
+struct Enrolment {
+    /// For authenticator apps that cannot scan the provisioning URI.
+    secret: String,
+    provisioning_uri: String,
+}
+
+#[derive(serde::Serialize)]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+/// Start, or restart, an enrolment. It only takes effect once a first code is confirmed.
+#[tracing::instrument(
+    name = "Start a two-factor enrolment",
+    skip(pool, settings, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn start_two_factor_enrolment(
+    pool: web::Data<PgPool>,
+    settings: web::Data<TwoFactorSettings>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::Account)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let secret = totp::generate_secret();
+    let username = sqlx::query_scalar!(
+        r#"
+        INSERT INTO user_totp (user_id, secret, created_at)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (user_id) DO UPDATE
+            SET secret = EXCLUDED.secret, created_at = EXCLUDED.created_at
+            WHERE user_totp.confirmed_at IS NULL
+        RETURNING (SELECT username FROM users WHERE user_id = $1) AS "username!"
+        "#,
+        user_id,
+        &secret,
+        Utc::now(),
+    )
+    .fetch_optional(pool.as_ref())
+    .await
+    .context("Failed to store a new TOTP secret.")?
+    .ok_or_else(|| AppError::Conflict("Two-factor authentication is already enabled.".into()))?;
+    Ok(HttpResponse::Ok().json(Enrolment {
+        secret: totp::encode_secret(&secret),
+        provisioning_uri: totp::provisioning_uri(&secret, &settings.issuer, &username),
+    }))
+}
+
+/// Enable two-factor authentication once the user proves their authenticator app works.
+/// The recovery codes are only ever shown in this response.
+#[tracing::instrument(
+    name = "Confirm a two-factor enrolment",
+    skip(body, pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn confirm_two_factor_enrolment(
+    body: web::Json<ConfirmationData>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::Account)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let secret = sqlx::query_scalar!(
+        r#"SELECT secret FROM user_totp WHERE user_id = $1 AND confirmed_at IS NULL"#,
+        user_id,
+    )
+    .fetch_optional(pool.as_ref())
+    .await
+    .context("Failed to retrieve a pending TOTP secret.")?
+    .ok_or_else(|| AppError::Conflict("There is no two-factor enrolment to confirm.".into()))?;
+    let step = totp::verify(&secret, &body.code, Utc::now().timestamp())
+        .ok_or_else(|| AppError::ValidationError("The code is not valid.".into()))?;
+
+    let recovery_codes = totp::generate_recovery_codes();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    sqlx::query!(
+        r#"
+        UPDATE user_totp SET confirmed_at = now(), last_used_step = $2
+        WHERE user_id = $1 AND confirmed_at IS NULL
+        "#,
+        user_id,
+        step,
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to confirm a TOTP secret.")?;
+    sqlx::query!(r#"DELETE FROM recovery_codes WHERE user_id = $1"#, user_id)
+        .execute(&mut *transaction)
+        .await
+        .context("Failed to delete previous recovery codes.")?;
+    let ids: Vec<Uuid> = recovery_codes.iter().map(|_| Uuid::new_v4()).collect();
+    let hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| totp::hash_recovery_code(code))
+        .collect();
+    sqlx::query!(
+        r#"
+        INSERT INTO recovery_codes (recovery_code_id, user_id, code_hash)
+        SELECT id, $2, code_hash FROM UNNEST($1::uuid[], $3::text[]) AS c(id, code_hash)
+        "#,
+        &ids,
+        user_id,
+        &hashes,
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to store recovery codes.")?;
+    let event = AuditEvent::new("user.enable_two_factor", "user", user_id);
+    record_audit_event(&mut *transaction, &request, user_id, event)
+        .await
+        .context("Failed to record an audit event.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to enable two-factor authentication.")?;
+    Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes }))
+}
@@ -0,0 +1,208 @@
+use crate::api_key::Scope;
+use crate::audit::{record_audit_event, AuditEvent};
+use crate::authentication::authenticate;
+use crate::domain::SubscriberEmail;
+use crate::role::Role;
+use crate::routes::AppError;
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct RoleData {
+    role: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct EmailData {
+    email: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct UserSummary {
+    user_id: Uuid,
+    username: String,
+    email: Option<String>,
+    role: String,
+}
+
+#[tracing::instrument(
+    name = "List users",
+    skip(pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn get_users(
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    authenticate(&request, &pool, Scope::Users)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let users = sqlx::query_as!(
+        UserSummary,
+        r#"SELECT user_id, username, email, role FROM users ORDER BY username"#
+    )
+    .fetch_all(pool.as_ref())
+    .await
+    .context("Failed to retrieve users.")?;
+    Ok(HttpResponse::Ok().json(users))
+}
+
+/// Change the role of a user. There must always be an admin left to assign roles.
+#[tracing::instrument(
+    name = "Assign a role",
+    skip(body, pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn assign_role(
+    target_user_id: web::Path<Uuid>,
+    body: web::Json<RoleData>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::Users)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let role = Role::parse(&body.role).ok_or_else(|| {
+        let roles: Vec<&str> = Role::ALL.iter().map(|role| role.as_str()).collect();
+        AppError::ValidationError(format!(
+            "{} is not a role, the roles are {}.",
+            body.role,
+            roles.join(", ")
+        ))
+    })?;
+    let target_user_id = target_user_id.into_inner();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    // Locking the admins keeps concurrent demotions from leaving none.
+    let admins =
+        sqlx::query_scalar!(r#"SELECT user_id FROM users WHERE role = 'admin' FOR UPDATE"#)
+            .fetch_all(&mut *transaction)
+            .await
+            .context("Failed to retrieve the admins.")?;
+    if role != Role::Admin && admins == [target_user_id] {
+        return Err(AppError::Conflict(
+            "The last admin cannot be given another role.".into(),
+        ));
+    }
+    let previous_role = sqlx::query_scalar!(
+        r#"SELECT role FROM users WHERE user_id = $1 FOR UPDATE"#,
+        target_user_id,
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to retrieve the role of a user.")?
+    .ok_or(AppError::NotFound)?;
+    let user = sqlx::query_as!(
+        UserSummary,
+        r#"
+        UPDATE users SET role = $2 WHERE user_id = $1
+        RETURNING user_id, username, email, role
+        "#,
+        target_user_id,
+        role.as_str(),
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .context("Failed to update the role of a user.")?;
+    let event = AuditEvent::new("user.assign_role", "user", target_user_id)
+        .before(serde_json::json!({ "role": previous_role }))
+        .after(serde_json::json!({ "role": user.role }));
+    record_audit_event(&mut *transaction, &request, user_id, event)
+        .await
+        .context("Failed to record an audit event.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to assign a role.")?;
+    Ok(HttpResponse::Ok().json(user))
+}
+
+/// Set the email address password reset links are sent to, or clear it with `null`.
+/// No two users may share an address, whatever its case.
+#[tracing::instrument(
+    name = "Set the email address of a user",
+    skip(body, pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn set_user_email(
+    target_user_id: web::Path<Uuid>,
+    body: web::Json<EmailData>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::Users)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let email = body
+        .into_inner()
+        .email
+        .map(SubscriberEmail::parse)
+        .transpose()
+        .map_err(AppError::ValidationError)?;
+    let email = email.as_ref().map(AsRef::<str>::as_ref);
+    let target_user_id = target_user_id.into_inner();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let previous_email = sqlx::query_scalar!(
+        r#"SELECT email FROM users WHERE user_id = $1 FOR UPDATE"#,
+        target_user_id,
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to retrieve the email address of a user.")?
+    .ok_or(AppError::NotFound)?;
+    let in_use = || {
+        AppError::Conflict(format!(
+            "Another user has the email address {}.",
+            email.unwrap_or_default()
+        ))
+    };
+    let taken = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM users WHERE lower(email) = lower($1) AND user_id <> $2
+        ) AS "taken!"
+        "#,
+        email,
+        target_user_id,
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .context("Failed to look up a user by email address.")?;
+    if taken {
+        return Err(in_use());
+    }
+    let updated = sqlx::query_as!(
+        UserSummary,
+        r#"
+        UPDATE users SET email = $2 WHERE user_id = $1
+        RETURNING user_id, username, email, role
+        "#,
+        target_user_id,
+        email,
+    )
+    .fetch_one(&mut *transaction)
+    .await;
+    // Another transaction may have taken the address since it was looked up.
+    let user = match updated {
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => return Err(in_use()),
+        updated => updated.context("Failed to update the email address of a user.")?,
+    };
+    let event = AuditEvent::new("user.set_email", "user", target_user_id)
+        .before(serde_json::json!({ "email": previous_email }))
+        .after(serde_json::json!({ "email": user.email }));
+    record_audit_event(&mut *transaction, &request, user_id, event)
+        .await
+        .context("Failed to record an audit event.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to set an email address.")?;
+    Ok(HttpResponse::Ok().json(user))
+}
@@ -0,0 +1,177 @@
+use crate::mailbox::{CapturedEmail, Mailbox};
+use crate::routes::AppError;
+use crate::utils::escape_html;
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use std::fmt::Write;
+use uuid::Uuid;
+
+/// The routes of the mail catcher, reading back what the `file` email backend wrote.
+/// Only mounted in the local environment: it exposes every email sent.
+pub fn dev_mailbox_routes(config: &mut web::ServiceConfig, mailbox: web::Data<Mailbox>) {
+    config
+        .app_data(mailbox)
+        .route("/dev/mailbox", web::get().to(mailbox_page))
+        .route("/dev/mailbox/messages", web::get().to(get_messages))
+        .route("/dev/mailbox/messages", web::delete().to(clear_messages))
+        .route(
+            "/dev/mailbox/messages/{message_id}",
+            web::get().to(get_message),
+        )
+        .route("/dev/mailbox/{message_id}", web::get().to(message_page));
+}
+
+#[tracing::instrument(name = "List captured emails", skip(mailbox))]
+async fn get_messages(mailbox: web::Data<Mailbox>) -> Result<HttpResponse, AppError> {
+    let messages = mailbox
+        .messages()
+        .await
+        .context("Failed to read the mailbox.")?;
+    Ok(HttpResponse::Ok().json(messages))
+}
+
+#[tracing::instrument(name = "Get a captured email", skip(mailbox))]
+async fn get_message(
+    message_id: web::Path<Uuid>,
+    mailbox: web::Data<Mailbox>,
+) -> Result<HttpResponse, AppError> {
+    let message = find_message(&mailbox, message_id.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(message))
+}
+
+#[tracing::instrument(name = "Clear captured emails", skip(mailbox))]
+async fn clear_messages(mailbox: web::Data<Mailbox>) -> Result<HttpResponse, AppError> {
+    mailbox
+        .clear()
+        .await
+        .context("Failed to clear the mailbox.")?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn find_message(mailbox: &Mailbox, message_id: Uuid) -> Result<CapturedEmail, AppError> {
+    mailbox
+        .message(message_id)
+        .await
+        .context("Failed to read an email of the mailbox.")?
+        .ok_or(AppError::NotFound)
+}
+
+#[tracing::instrument(name = "Show the mailbox", skip(mailbox))]
+async fn mailbox_page(mailbox: web::Data<Mailbox>) -> Result<HttpResponse, AppError> {
+    let messages = mailbox
+        .messages()
+        .await
+        .context("Failed to read the mailbox.")?;
+    let mut body = format!(
+        r#"<h1>Mailbox</h1>
+    <p>Emails written to <code>{}</code>, the most recent first.
+    <button onclick="fetch('/dev/mailbox/messages', {{ method: 'DELETE' }}).then(() => location.reload())">Clear</button></p>
+    <table>
+        <tr><th>Sent at</th><th>To</th><th>Subject</th></tr>"#,
+        escape_html(&mailbox.directory().display().to_string()),
+    );
+    for message in &messages {
+        writeln!(
+            body,
+            r#"        <tr><td>{}</td><td>{}</td><td><a href="/dev/mailbox/{}">{}</a></td></tr>"#,
+            message.sent_at.format("%Y-%m-%d %H:%M:%S"),
+            escape_html(&message.to),
+            message.message_id,
+            escape_html(&message.subject),
+        )
+        .unwrap();
+    }
+    body.push_str("    </table>");
+    if messages.is_empty() {
+        body.push_str("\n    <p>No emails yet.</p>");
+    }
+    Ok(render_page("Mailbox", &body))
+}
+
+#[tracing::instrument(name = "Show a captured email", skip(mailbox))]
+async fn message_page(
+    message_id: web::Path<Uuid>,
+    mailbox: web::Data<Mailbox>,
+) -> Result<HttpResponse, AppError> {
+    let message = find_message(&mailbox, message_id.into_inner()).await?;
+    let mut body = format!(
+        r#"<p><a href="/dev/mailbox">Back to the mailbox</a></p>
+    <h1>{}</h1>
+    <h2>HTML</h2>
+    <iframe sandbox="allow-popups allow-top-navigation-by-user-activation" style="width: 100%; height: 24em" srcdoc="{}"></iframe>
+    <h2>Text</h2>
+    <pre>{}</pre>
+    <h2>Headers</h2>
+    <table>"#,
+        escape_html(&message.subject),
+        // Links of the email open in place of the mailbox.
+        escape_html(&format!(r#"<base target="_top">{}"#, message.html_content)),
+        linkify(&message.text_content),
+    );
+    for (name, value) in &message.headers {
+        writeln!(
+            body,
+            "        <tr><th>{}</th><td>{}</td></tr>",
+            escape_html(name),
+            escape_html(value),
+        )
+        .unwrap();
+    }
+    body.push_str("    </table>");
+    Ok(render_page(&message.subject, &body))
+}
+
+/// Escape `text`, turning the URLs it contains into links.
+fn linkify(text: &str) -> String {
+    let mut linked = String::with_capacity(text.len());
+    for word in text.split_inclusive(char::is_whitespace) {
+        let token = word.trim_end();
+        let url = token.trim_end_matches(['.', ',', ')', ';', '!', '?']);
+        if url.starts_with("http://") || url.starts_with("https://") {
+            let escaped = escape_html(url);
+            write!(linked, r#"<a href="{escaped}">{escaped}</a>"#).unwrap();
+            linked.push_str(&escape_html(&word[url.len()..]));
+        } else {
+            linked.push_str(&escape_html(word));
+        }
+    }
+    linked
+}
+
+fn render_page(title: &str, body: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>{}</title>
+</head>
+<body>
+    {body}
+</body>
+</html>"#,
+            escape_html(title)
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::linkify;
+
+    #[test]
+    fn urls_in_text_become_links() {
+        assert_eq!(
+            linkify("Visit https://example.com/confirm?token=a&b=c.\nThanks"),
+            "Visit <a href=\"https://example.com/confirm?token=a&amp;b=c\">\
+            https://example.com/confirm?token=a&amp;b=c</a>.\nThanks"
+        );
+    }
+
+    #[test]
+    fn text_is_escaped() {
+        assert_eq!(linkify("<b>Hi</b>"), "&lt;b&gt;Hi&lt;/b&gt;");
+    }
+}
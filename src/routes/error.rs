@@ -0,0 +1,175 @@
+use crate::authentication::AuthError;
+use actix_web::body::BoxBody;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use tracing_actix_web::RequestId;
+
+/// # Errors
+/// This function will return the error produced by the `writeln!` macro, if it encounters any error.
+/// This error is usually of type `std::fmt::Error`. It is produced in situations where the `std::fmt::Formatter` cannot write
+/// the formatted string into a destination, most commonly when the destination is full or does not exist.
+pub fn error_chain_fmt(
+    e: &impl std::error::Error,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result {
+    writeln!(f, "{e}\n")?;
+    let mut current = e.source();
+    while let Some(cause) = current {
+        writeln!(f, "Caused by:\n\t{cause}")?;
+        current = cause.source();
+    }
+    Ok(())
+}
+
+/// A problem with a single field of the request, in the shape of the RFC 9457
+/// `errors` extension member.
+#[derive(Debug, serde::Serialize)]
+pub struct FieldError {
+    pointer: String,
+    detail: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, detail: impl Into<String>) -> Self {
+        Self {
+            pointer: format!("#/{field}"),
+            detail: detail.into(),
+        }
+    }
+}
+
+/// The error returned by every handler, rendered as an RFC 9457 `application/problem+json`
+/// document. The cause of unexpected errors is logged but never sent to the client.
+#[derive(thiserror::Error)]
+pub enum AppError {
+    #[error("Authentication failed.")]
+    AuthError {
+        realm: &'static str,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("{}", .0.iter().map(|e| e.detail.as_str()).collect::<Vec<_>>().join(" "))]
+    InvalidFields(Vec<FieldError>),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("The requested resource does not exist.")]
+    NotFound,
+    #[error("{0}")]
+    Conflict(String),
+    #[error("Too many requests, try again in {retry_after} seconds.")]
+    TooManyRequests { retry_after: u64 },
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl AppError {
+    /// Failed authentications challenge the client for `Basic` credentials of `realm`,
+    /// locked out clients are told when to retry and API keys lacking a scope are forbidden.
+    pub fn from_auth(realm: &'static str) -> impl FnOnce(AuthError) -> Self {
+        move |e| match e {
+            AuthError::InvalidCredentials(_) => AppError::AuthError {
+                realm,
+                source: e.into(),
+            },
+            AuthError::LockedOut { retry_after } => AppError::TooManyRequests { retry_after },
+            AuthError::MissingScope(_) | AuthError::NotPermitted { .. } => {
+                AppError::Forbidden(e.to_string())
+            }
+            AuthError::UnexpectedError(_) => AppError::UnexpectedError(e.into()),
+        }
+    }
+
+    /// The stable, machine-readable problem type and its human-readable title.
+    fn kind(&self) -> (&'static str, &'static str) {
+        match self {
+            AppError::AuthError { .. } => ("/problems/unauthorized", "Authentication required."),
+            AppError::ValidationError(_) | AppError::InvalidFields(_) => {
+                ("/problems/validation-error", "The request is invalid.")
+            }
+            AppError::Forbidden(_) => ("/problems/forbidden", "Access denied."),
+            AppError::NotFound => ("/problems/not-found", "Resource not found."),
+            AppError::Conflict(_) => (
+                "/problems/conflict",
+                "The request conflicts with the current state of the resource.",
+            ),
+            AppError::TooManyRequests { .. } => ("/problems/rate-limited", "Too many requests."),
+            AppError::UnexpectedError(_) => (
+                "/problems/internal-error",
+                "Something went wrong on our side.",
+            ),
+        }
+    }
+
+    fn problem(&self, request_id: Option<RequestId>) -> serde_json::Value {
+        let (problem_type, title) = self.kind();
+        let mut problem = serde_json::json!({
+            "type": problem_type,
+            "title": title,
+            "status": self.status_code().as_u16(),
+        });
+        if !matches!(self, AppError::UnexpectedError(_)) {
+            problem["detail"] = self.to_string().into();
+        }
+        if let AppError::InvalidFields(errors) = self {
+            problem["errors"] = serde_json::json!(errors);
+        }
+        if let Some(request_id) = request_id {
+            problem["request_id"] = request_id.to_string().into();
+        }
+        problem
+    }
+}
+
+impl std::fmt::Debug for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::AuthError { .. } => StatusCode::UNAUTHORIZED,
+            AppError::ValidationError(_) | AppError::InvalidFields(_) => StatusCode::BAD_REQUEST,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        response.content_type("application/problem+json");
+        if let AppError::AuthError { realm, .. } = self {
+            let header_value = HeaderValue::from_str(&format!(r#"Basic realm="{realm}""#)).unwrap();
+            response.insert_header((header::WWW_AUTHENTICATE, header_value));
+        }
+        if let AppError::TooManyRequests { retry_after } = self {
+            response.insert_header((header::RETRY_AFTER, *retry_after));
+        }
+        response.body(self.problem(None).to_string())
+    }
+}
+
+/// Add the id of the request to problem documents, so that they can be correlated with
+/// the logs. The id is assigned by `TracingLogger`, which must wrap this step.
+pub fn with_request_id(
+    response: ServiceResponse<BoxBody>,
+    request_id: Option<RequestId>,
+) -> ServiceResponse<BoxBody> {
+    let Some(problem) = response
+        .response()
+        .error()
+        .and_then(|e| e.as_error::<AppError>())
+        .map(|e| e.problem(request_id))
+    else {
+        return response;
+    };
+    response.map_body(|_, _| BoxBody::new(problem.to_string()))
+}
@@ -0,0 +1,321 @@
+use crate::configuration::FeedSettings;
+use crate::domain::ListSlug;
+use crate::routes::AppError;
+use crate::startup::ApplicationBaseUrl;
+use crate::utils::escape_html;
+use actix_web::http::header::{
+    CacheControl, CacheDirective, ETag, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch,
+    LastModified,
+};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, SecondsFormat, Utc};
+use sqlx::PgPool;
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+struct PublishedIssue {
+    newsletter_issue_id: Uuid,
+    title: String,
+    text_content: String,
+    html_content: String,
+    published_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy)]
+enum FeedFormat {
+    Rss,
+    Atom,
+    Json,
+}
+
+impl FeedFormat {
+    fn path(self) -> &'static str {
+        match self {
+            FeedFormat::Rss => "/feed.rss",
+            FeedFormat::Atom => "/feed.atom",
+            FeedFormat::Json => "/feed.json",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            FeedFormat::Rss => "application/rss+xml; charset=utf-8",
+            FeedFormat::Atom => "application/atom+xml; charset=utf-8",
+            FeedFormat::Json => "application/feed+json; charset=utf-8",
+        }
+    }
+
+    fn tag(self) -> &'static str {
+        match self {
+            FeedFormat::Rss => "rss",
+            FeedFormat::Atom => "atom",
+            FeedFormat::Json => "json",
+        }
+    }
+}
+
+#[tracing::instrument(name = "Serve the RSS feed", skip_all)]
+pub async fn rss_feed(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    settings: web::Data<FeedSettings>,
+) -> Result<HttpResponse, AppError> {
+    serve_feed(FeedFormat::Rss, &request, &pool, &base_url.0, &settings).await
+}
+
+#[tracing::instrument(name = "Serve the Atom feed", skip_all)]
+pub async fn atom_feed(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    settings: web::Data<FeedSettings>,
+) -> Result<HttpResponse, AppError> {
+    serve_feed(FeedFormat::Atom, &request, &pool, &base_url.0, &settings).await
+}
+
+#[tracing::instrument(name = "Serve the JSON feed", skip_all)]
+pub async fn json_feed(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    settings: web::Data<FeedSettings>,
+) -> Result<HttpResponse, AppError> {
+    serve_feed(FeedFormat::Json, &request, &pool, &base_url.0, &settings).await
+}
+
+async fn serve_feed(
+    format: FeedFormat,
+    request: &HttpRequest,
+    pool: &PgPool,
+    base_url: &str,
+    settings: &FeedSettings,
+) -> Result<HttpResponse, AppError> {
+    let issues = get_published_issues(pool, settings.max_items)
+        .await
+        .context("Failed to retrieve the most recent newsletter issues.")?;
+    let entity_tag = entity_tag(format, &issues);
+    let last_modified = issues.first().map(|issue| truncate(issue.published_at));
+
+    let is_fresh = is_fresh(request, &entity_tag, last_modified);
+    let mut response = if is_fresh {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .insert_header(ETag(entity_tag))
+        .insert_header(CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::NoCache,
+        ]));
+    if let Some(last_modified) = last_modified {
+        response.insert_header(LastModified(HttpDate::from(last_modified)));
+    }
+    if is_fresh {
+        return Ok(response.finish());
+    }
+
+    let base_url = base_url.trim_end_matches('/');
+    let body = match format {
+        FeedFormat::Rss => render_rss(&issues, base_url, settings),
+        FeedFormat::Atom => render_atom(&issues, base_url, settings),
+        FeedFormat::Json => {
+            render_json(&issues, base_url, settings).context("Failed to serialize JSON feed.")?
+        }
+    };
+    Ok(response.content_type(format.content_type()).body(body))
+}
+
+/// Feeds are public: only issues sent to the whole default list are listed, never those
+/// targeting another list or a segment of the subscribers.
+#[tracing::instrument(name = "Get most recent newsletter issues", skip(pool))]
+async fn get_published_issues(
+    pool: &PgPool,
+    max_items: i64,
+) -> Result<Vec<PublishedIssue>, sqlx::Error> {
+    sqlx::query_as!(
+        PublishedIssue,
+        r#"
+        SELECT newsletter_issue_id, title, text_content, html_content, published_at
+        FROM newsletter_issues
+        WHERE segment IS NULL
+            AND NOT EXISTS (
+                SELECT 1
+                FROM newsletter_issue_lists
+                JOIN lists USING (list_id)
+                WHERE newsletter_issue_lists.newsletter_issue_id = newsletter_issues.newsletter_issue_id
+                    AND lists.slug <> $2
+            )
+        ORDER BY published_at DESC
+        LIMIT $1
+        "#,
+        max_items,
+        ListSlug::DEFAULT,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Issues are immutable once published, so the most recent one identifies
+/// the whole window of items served by a feed.
+fn entity_tag(format: FeedFormat, issues: &[PublishedIssue]) -> EntityTag {
+    let tag = match issues.first() {
+        Some(latest) => format!(
+            "{}-{}-{}",
+            format.tag(),
+            latest.newsletter_issue_id.simple(),
+            issues.len()
+        ),
+        None => format!("{}-empty", format.tag()),
+    };
+    EntityTag::new_strong(tag)
+}
+
+/// HTTP dates have a one second resolution.
+fn truncate(timestamp: DateTime<Utc>) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(u64::try_from(timestamp.timestamp()).unwrap_or_default())
+}
+
+/// `If-None-Match` takes precedence over `If-Modified-Since`, as mandated by RFC 9110.
+fn is_fresh(
+    request: &HttpRequest,
+    entity_tag: &EntityTag,
+    last_modified: Option<SystemTime>,
+) -> bool {
+    if let Some(if_none_match) = request.get_header::<IfNoneMatch>() {
+        return match if_none_match {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Items(tags) => tags.iter().any(|tag| tag.weak_eq(entity_tag)),
+        };
+    }
+    match (last_modified, request.get_header::<IfModifiedSince>()) {
+        (Some(last_modified), Some(IfModifiedSince(since))) => {
+            last_modified <= SystemTime::from(since)
+        }
+        _ => false,
+    }
+}
+
+fn issue_url(base_url: &str, issue: &PublishedIssue) -> String {
+    format!("{base_url}/issues/{}", issue.newsletter_issue_id)
+}
+
+fn render_rss(issues: &[PublishedIssue], base_url: &str, settings: &FeedSettings) -> String {
+    let mut body = String::new();
+    body.push_str(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    body.push_str(r#"<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom"><channel>"#);
+    let _ = write!(
+        body,
+        r#"<title>{}</title><link>{}/</link><description>{}</description><atom:link href="{}{}" rel="self" type="application/rss+xml"/>"#,
+        escape_html(&settings.title),
+        escape_html(base_url),
+        escape_html(&settings.description),
+        escape_html(base_url),
+        FeedFormat::Rss.path(),
+    );
+    if let Some(latest) = issues.first() {
+        let _ = write!(
+            body,
+            "<lastBuildDate>{}</lastBuildDate>",
+            latest.published_at.to_rfc2822()
+        );
+    }
+    for issue in issues {
+        let url = escape_html(&issue_url(base_url, issue));
+        let _ = write!(
+            body,
+            r#"<item><title>{}</title><link>{url}</link><guid isPermaLink="true">{url}</guid><pubDate>{}</pubDate><description>{}</description></item>"#,
+            escape_html(&issue.title),
+            issue.published_at.to_rfc2822(),
+            escape_html(&issue.html_content),
+        );
+    }
+    body.push_str("</channel></rss>");
+    body
+}
+
+fn render_atom(issues: &[PublishedIssue], base_url: &str, settings: &FeedSettings) -> String {
+    let feed_url = escape_html(&format!("{base_url}{}", FeedFormat::Atom.path()));
+    let updated = issues
+        .first()
+        .map_or(DateTime::UNIX_EPOCH, |latest| latest.published_at);
+    let mut body = String::new();
+    body.push_str(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    body.push_str(r#"<feed xmlns="http://www.w3.org/2005/Atom">"#);
+    let _ = write!(
+        body,
+        r#"<title>{}</title><subtitle>{}</subtitle><id>{feed_url}</id><link rel="self" type="application/atom+xml" href="{feed_url}"/><link rel="alternate" type="text/html" href="{}/"/><updated>{}</updated><author><name>{}</name></author>"#,
+        escape_html(&settings.title),
+        escape_html(&settings.description),
+        escape_html(base_url),
+        updated.to_rfc3339_opts(SecondsFormat::Secs, true),
+        escape_html(&settings.title),
+    );
+    for issue in issues {
+        let published_at = issue
+            .published_at
+            .to_rfc3339_opts(SecondsFormat::Secs, true);
+        let _ = write!(
+            body,
+            r#"<entry><title>{}</title><id>urn:uuid:{}</id><link rel="alternate" type="text/html" href="{}"/><published>{published_at}</published><updated>{published_at}</updated><summary type="text">{}</summary><content type="html">{}</content></entry>"#,
+            escape_html(&issue.title),
+            issue.newsletter_issue_id,
+            escape_html(&issue_url(base_url, issue)),
+            escape_html(&issue.text_content),
+            escape_html(&issue.html_content),
+        );
+    }
+    body.push_str("</feed>");
+    body
+}
+
+#[derive(serde::Serialize)]
+struct JsonFeed<'a> {
+    version: &'static str,
+    title: &'a str,
+    description: &'a str,
+    home_page_url: String,
+    feed_url: String,
+    items: Vec<JsonFeedItem<'a>>,
+}
+
+#[derive(serde::Serialize)]
+struct JsonFeedItem<'a> {
+    id: String,
+    url: String,
+    title: &'a str,
+    content_html: &'a str,
+    content_text: &'a str,
+    date_published: String,
+}
+
+fn render_json(
+    issues: &[PublishedIssue],
+    base_url: &str,
+    settings: &FeedSettings,
+) -> Result<String, serde_json::Error> {
+    let feed = JsonFeed {
+        version: "https://jsonfeed.org/version/1.1",
+        title: &settings.title,
+        description: &settings.description,
+        home_page_url: format!("{base_url}/"),
+        feed_url: format!("{base_url}{}", FeedFormat::Json.path()),
+        items: issues
+            .iter()
+            .map(|issue| JsonFeedItem {
+                id: issue.newsletter_issue_id.to_string(),
+                url: issue_url(base_url, issue),
+                title: &issue.title,
+                content_html: &issue.html_content,
+                content_text: &issue.text_content,
+                date_published: issue
+                    .published_at
+                    .to_rfc3339_opts(SecondsFormat::Secs, true),
+            })
+            .collect(),
+    };
+    serde_json::to_string(&feed)
+}
@@ -0,0 +1,40 @@
+use crate::routes::AppError;
+use crate::utils::escape_html;
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[tracing::instrument(name = "Show a published newsletter issue", skip(pool))]
+pub async fn issue_page(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    let issue = sqlx::query!(
+        r#"SELECT title, html_content FROM newsletter_issues WHERE newsletter_issue_id = $1"#,
+        newsletter_issue_id.into_inner(),
+    )
+    .fetch_optional(pool.as_ref())
+    .await
+    .context("Failed to retrieve the newsletter issue.")?
+    .ok_or(AppError::NotFound)?;
+
+    let title = escape_html(&issue.title);
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>{title}</title>
+</head>
+<body>
+    <h1>{title}</h1>
+    {}
+</body>
+</html>"#,
+            issue.html_content
+        )))
+}
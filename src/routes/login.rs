@@ -0,0 +1,201 @@
+use crate::authentication::{check_password, two_factor_enabled, AuthError, Credentials};
+use crate::configuration::SessionSettings;
+use crate::login_throttle::LoginThrottle;
+use crate::routes::AppError;
+use crate::session::{create_session, delete_session, find_session, SessionStatus};
+use crate::totp;
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use secrecy::Secret;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct LoginData {
+    username: String,
+    password: Secret<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct SecondFactorData {
+    login_token: String,
+    /// Either a code from the authenticator app or a recovery code.
+    code: String,
+}
+
+#[derive(serde::Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum LoginOutcome {
+    Authenticated {
+        session_token: String,
+        expires_at: DateTime<Utc>,
+    },
+    /// The `login_token` must be sent along with a code to `/login/second-factor`.
+    SecondFactorRequired {
+        login_token: String,
+        expires_at: DateTime<Utc>,
+    },
+}
+
+fn invalid_second_factor(reason: &'static str) -> AppError {
+    AppError::from_auth("admin")(AuthError::InvalidCredentials(anyhow::anyhow!(reason)))
+}
+
+#[tracing::instrument(
+    name = "Log in",
+    skip(body, pool, settings, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn login(
+    body: web::Json<LoginData>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<SessionSettings>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let LoginData { username, password } = body.into_inner();
+    let user_id = check_password(&request, &pool, Credentials { username, password })
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    let outcome = if two_factor_enabled(&pool, user_id).await? {
+        let (login_token, expires_at) = create_session(
+            pool.as_ref(),
+            user_id,
+            SessionStatus::PendingSecondFactor,
+            chrono::Duration::seconds(settings.second_factor_timeout_seconds),
+        )
+        .await
+        .context("Failed to store a pending login.")?;
+        LoginOutcome::SecondFactorRequired {
+            login_token,
+            expires_at,
+        }
+    } else {
+        let (session_token, expires_at) = create_session(
+            pool.as_ref(),
+            user_id,
+            SessionStatus::Active,
+            chrono::Duration::minutes(settings.ttl_minutes),
+        )
+        .await
+        .context("Failed to store a new session.")?;
+        LoginOutcome::Authenticated {
+            session_token,
+            expires_at,
+        }
+    };
+    Ok(HttpResponse::Ok().json(outcome))
+}
+
+#[tracing::instrument(
+    name = "Complete a login with its second factor",
+    skip(body, pool, settings, throttle, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn login_second_factor(
+    body: web::Json<SecondFactorData>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<SessionSettings>,
+    throttle: web::Data<LoginThrottle>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let pending = find_session(&pool, &body.login_token, SessionStatus::PendingSecondFactor)
+        .await
+        .context("Failed to retrieve a pending login.")?
+        .ok_or_else(|| invalid_second_factor("Unknown or expired login token."))?;
+    let username = get_username(&pool, pending.user_id).await?;
+    tracing::Span::current().record("username", tracing::field::display(&username));
+    // Codes are short, so guessing them is throttled like guessing passwords.
+    let ip = throttle.client_ip(&request);
+    throttle
+        .admit(&pool, &username, ip)
+        .await
+        .map_err(AppError::from_auth("admin"))?;
+    if !verify_second_factor(&pool, pending.user_id, &body.code).await? {
+        throttle.record_failure(&pool, &username, ip).await?;
+        return Err(invalid_second_factor("Invalid second factor."));
+    }
+    throttle.record_success(&pool, &username).await?;
+    tracing::Span::current().record("user_id", tracing::field::display(&pending.user_id));
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    delete_session(&mut *transaction, pending.session_id)
+        .await
+        .context("Failed to delete a pending login.")?;
+    let (session_token, expires_at) = create_session(
+        &mut *transaction,
+        pending.user_id,
+        SessionStatus::Active,
+        chrono::Duration::minutes(settings.ttl_minutes),
+    )
+    .await
+    .context("Failed to store a new session.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to complete a login.")?;
+    Ok(HttpResponse::Ok().json(LoginOutcome::Authenticated {
+        session_token,
+        expires_at,
+    }))
+}
+
+async fn get_username(pool: &PgPool, user_id: Uuid) -> Result<String, anyhow::Error> {
+    sqlx::query_scalar!(r#"SELECT username FROM users WHERE user_id = $1"#, user_id)
+        .fetch_one(pool)
+        .await
+        .context("Failed to retrieve the username of a pending login.")
+}
+
+/// Accept a code from the authenticator app, unless a code of the same or a later
+/// period was already accepted, or an unused recovery code, which is then used up.
+#[tracing::instrument(name = "Verify a second factor", skip(pool, code))]
+async fn verify_second_factor(
+    pool: &PgPool,
+    user_id: Uuid,
+    code: &str,
+) -> Result<bool, anyhow::Error> {
+    let secret = sqlx::query_scalar!(
+        r#"SELECT secret FROM user_totp WHERE user_id = $1 AND confirmed_at IS NOT NULL"#,
+        user_id,
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to retrieve a TOTP secret.")?
+    .context("The user has no confirmed TOTP secret.")?;
+
+    if let Some(step) = totp::verify(&secret, code, Utc::now().timestamp()) {
+        let accepted = sqlx::query!(
+            r#"
+            UPDATE user_totp SET last_used_step = $2
+            WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)
+            "#,
+            user_id,
+            step,
+        )
+        .execute(pool)
+        .await
+        .context("Failed to record the use of a TOTP code.")?
+        .rows_affected();
+        return Ok(accepted == 1);
+    }
+    let used = sqlx::query!(
+        r#"
+        UPDATE recovery_codes SET used_at = now()
+        WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+        "#,
+        user_id,
+        totp::hash_recovery_code(code),
+    )
+    .execute(pool)
+    .await
+    .context("Failed to use a recovery code.")?
+    .rows_affected();
+    if used == 1 {
+        tracing::warn!("A recovery code was used to log in");
+    }
+    Ok(used == 1)
+}
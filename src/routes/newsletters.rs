@@ -1,217 +1,485 @@
-use crate::domain::SubscriberEmail;
-use crate::email_client::EmailClient;
-use crate::routes::error_chain_fmt;
-use crate::telemetry::spawn_blocking_with_tracing;
-use actix_web::http::header::{HeaderMap, HeaderValue};
-use actix_web::http::{header, StatusCode};
-use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use crate::api_key::Scope;
+use crate::audit::{record_audit_event, AuditEvent};
+use crate::authentication::{authenticate, two_factor_enabled};
+use crate::configuration::{TrackingSettings, TwoFactorSettings};
+use crate::digest_worker::record_failed_delivery;
+use crate::domain::{ListSlug, SubscriberEmail};
+use crate::email_client::{BatchEmail, EmailClient};
+use crate::routes::{add_preferences_footer, AppError};
+use crate::segment::Segment;
+use crate::startup::ApplicationBaseUrl;
+use crate::tracking::IssueTracker;
+use actix_web::{web, HttpRequest, HttpResponse};
 use anyhow::Context;
-use argon2::{Argon2, PasswordHash, PasswordVerifier};
-use base64::Engine;
-use secrecy::{ExposeSecret, Secret};
-use sqlx::PgPool;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, QueryBuilder, Transaction};
+use uuid::Uuid;
 
 #[derive(serde::Deserialize)]
 pub struct BodyData {
-    title: String,
-    content: Content,
+    pub(crate) title: String,
+    pub(crate) content: Content,
+    #[serde(default)]
+    pub(crate) tracking: IssueTracking,
+    #[serde(default = "default_lists")]
+    pub(crate) lists: Vec<String>,
+    pub(crate) segment: Option<String>,
 }
 
 #[derive(serde::Deserialize)]
+pub struct DryRunData {
+    #[serde(default = "default_lists")]
+    lists: Vec<String>,
+    segment: Option<String>,
+}
+
+pub(crate) fn default_lists() -> Vec<String> {
+    vec![ListSlug::DEFAULT.into()]
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
 pub struct Content {
-    html: String,
-    text: String,
+    pub(crate) html: String,
+    pub(crate) text: String,
 }
 
-struct ConfirmedSubscriber {
-    email: SubscriberEmail,
+/// Per-issue opt-in to open and click tracking, both off unless asked for.
+#[derive(serde::Deserialize, serde::Serialize, Default)]
+#[serde(default)]
+pub struct IssueTracking {
+    pub(crate) opens: bool,
+    pub(crate) clicks: bool,
 }
 
-struct Credentials {
-    username: String,
-    password: Secret<String>,
+#[derive(serde::Serialize)]
+struct PublishedIssue {
+    newsletter_issue_id: Uuid,
 }
 
-#[derive(thiserror::Error)]
-pub enum PublishError {
-    #[error("Authentication failed.")]
-    AuthError(#[source] anyhow::Error),
-    #[error(transparent)]
-    UnexpectedError(#[from] anyhow::Error),
+#[derive(serde::Serialize)]
+struct DryRun {
+    recipients: i64,
 }
 
-impl std::fmt::Debug for PublishError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        error_chain_fmt(self, f)
-    }
+#[derive(sqlx::FromRow)]
+struct QueuedDelivery {
+    subscriber_id: Uuid,
+    subscriber_email: String,
+    preferences_token: String,
+    scheduled_for: Option<DateTime<Utc>>,
 }
-impl ResponseError for PublishError {
-    fn error_response(&self) -> HttpResponse {
-        match self {
-            PublishError::UnexpectedError(_) => {
-                HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-            }
-            PublishError::AuthError(_) => {
-                let mut response = HttpResponse::new(StatusCode::UNAUTHORIZED);
-                let header_value = HeaderValue::from_str(r#"Basic realm="publish""#).unwrap();
-                response
-                    .headers_mut()
-                    .insert(header::WWW_AUTHENTICATE, header_value);
-                response
-            }
-        }
-    }
+
+/// The issue as sent to one subscriber, with their tracking and preferences links.
+struct PersonalizedIssue {
+    subscriber_id: Uuid,
+    email: SubscriberEmail,
+    html: String,
+    text: String,
 }
 
 #[tracing::instrument(
     name = "Publish a newsletter issue",
-    skip(body, pool, email_client, request),
+    skip(body, pool, email_client, base_url, tracking_settings, two_factor_settings, request),
     fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
 )]
 pub async fn publish_newsletter(
     body: web::Json<BodyData>,
     pool: web::Data<PgPool>,
     email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    tracking_settings: web::Data<TrackingSettings>,
+    two_factor_settings: web::Data<TwoFactorSettings>,
     request: HttpRequest,
-) -> Result<HttpResponse, PublishError> {
-    let credentials = basic_authentication(request.headers()).map_err(PublishError::AuthError)?;
-    tracing::Span::current().record("username", &tracing::field::display(&credentials.username));
-    let user_id = validate_credentials(credentials, &pool).await?;
-    tracing::Span::current().record("user_id", &tracing::field::display(&user_id));
-    let subscribers = get_confirmed_subscribers(&pool).await?;
-    for subscriber in subscribers {
-        match subscriber {
-            Ok(subscriber) => {
-                email_client
-                    .send_email(
-                        &subscriber.email,
-                        &body.title,
-                        &body.content.html,
-                        &body.content.text,
-                    )
-                    .await
-                    .with_context(|| {
-                        format!("Failed to send newsletter issue to {}", subscriber.email)
-                    })?;
+) -> Result<HttpResponse, AppError> {
+    let user_id = authenticate(&request, &pool, Scope::NewslettersPublish)
+        .await
+        .map_err(AppError::from_auth("publish"))?;
+    require_two_factor(&pool, &two_factor_settings, user_id).await?;
+    let newsletter_issue_id = publish_issue(
+        &pool,
+        &email_client,
+        &base_url,
+        &tracking_settings,
+        &body,
+        &request,
+        user_id,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(PublishedIssue {
+        newsletter_issue_id,
+    }))
+}
+
+/// Publishers may be required to protect their account with a second factor.
+pub(crate) async fn require_two_factor(
+    pool: &PgPool,
+    settings: &TwoFactorSettings,
+    user_id: Uuid,
+) -> Result<(), AppError> {
+    if settings.required_for_publishers && !two_factor_enabled(pool, user_id).await? {
+        return Err(AppError::Forbidden(
+            "Two-factor authentication must be enabled to publish.".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Store an issue published by `user_id` and deliver it to the confirmed subscribers of the
+/// lists it targets.
+pub(crate) async fn publish_issue(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &ApplicationBaseUrl,
+    tracking_settings: &TrackingSettings,
+    body: &BodyData,
+    request: &HttpRequest,
+    user_id: Uuid,
+) -> Result<Uuid, AppError> {
+    let (lists, segment) = parse_audience(&body.lists, body.segment.as_deref())?;
+    let track_opens = tracking_settings.opens_enabled && body.tracking.opens;
+    let track_clicks = tracking_settings.clicks_enabled && body.tracking.clicks;
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let newsletter_issue_id = insert_newsletter_issue(
+        &mut transaction,
+        body,
+        segment.as_ref(),
+        track_opens,
+        track_clicks,
+    )
+    .await
+    .context("Failed to store newsletter issue details.")?;
+    let unknown_lists =
+        insert_newsletter_issue_lists(&mut transaction, newsletter_issue_id, &lists)
+            .await
+            .context("Failed to store the lists targeted by the newsletter issue.")?;
+    if !unknown_lists.is_empty() {
+        return Err(AppError::ValidationError(format!(
+            "There are no lists identified by {}.",
+            unknown_lists.join(", ")
+        )));
+    }
+    let event = AuditEvent::new(
+        "newsletter_issue.publish",
+        "newsletter_issue",
+        newsletter_issue_id,
+    )
+    .after(serde_json::json!({
+        "title": body.title,
+        "lists": body.lists,
+        "segment": body.segment,
+        "track_opens": track_opens,
+        "track_clicks": track_clicks,
+    }));
+    record_audit_event(&mut *transaction, request, user_id, event)
+        .await
+        .context("Failed to record an audit event.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to store a newsletter issue.")?;
+    let tracker = IssueTracker {
+        base_url: &base_url.0,
+        secret: &tracking_settings.hmac_secret,
+        newsletter_issue_id,
+        track_opens,
+        track_clicks,
+    };
+    let deliveries = enqueue_deliveries(pool, newsletter_issue_id, &lists, segment.as_ref())
+        .await
+        .context("Failed to enqueue deliveries for the newsletter issue.")?;
+    // Digest subscribers are left to the background worker.
+    let mut issues = Vec::new();
+    for delivery in deliveries.into_iter().filter(|d| d.scheduled_for.is_none()) {
+        let (html, text) = add_preferences_footer(
+            &tracker.personalize(&body.content.html, delivery.subscriber_id),
+            &body.content.text,
+            &base_url.0,
+            &delivery.preferences_token,
+        );
+        match SubscriberEmail::parse(delivery.subscriber_email) {
+            Ok(email) => issues.push(PersonalizedIssue {
+                subscriber_id: delivery.subscriber_id,
+                email,
+                html,
+                text,
+            }),
+            Err(error) => {
+                tracing::warn!(
+                    error,
+                    "Failed to deliver a newsletter issue to a confirmed subscriber",
+                );
+                record_failed_delivery(
+                    pool,
+                    delivery.subscriber_id,
+                    &[newsletter_issue_id],
+                    1,
+                    &anyhow::anyhow!(error),
+                )
+                .await
+                .context("Failed to record the outcome of a delivery.")?;
+            }
+        }
+    }
+    let batch: Vec<_> = issues
+        .iter()
+        .map(|issue| BatchEmail {
+            recipient: &issue.email,
+            subject: &body.title,
+            html_content: &issue.html,
+            text_content: &issue.text,
+        })
+        .collect();
+    let outcomes = email_client.send_batch(&batch).await;
+    for (issue, outcome) in issues.iter().zip(outcomes) {
+        match outcome {
+            Ok(provider_message_id) => {
+                mark_delivery_as_sent(
+                    pool,
+                    newsletter_issue_id,
+                    issue.subscriber_id,
+                    provider_message_id.as_deref(),
+                )
+                .await
             }
             Err(error) => {
+                let error = anyhow::Error::new(error).context(format!(
+                    "Failed to send newsletter issue to {}",
+                    issue.email
+                ));
                 tracing::warn!(
                     error.cause_chain = ?error,
-                    "Skipping a confirmed subscriber. Their stored contact details are invalid",
+                    "Failed to deliver a newsletter issue to a confirmed subscriber",
                 );
+                // Deliveries kept queued are retried by the digest worker, as a digest.
+                record_failed_delivery(pool, issue.subscriber_id, &[newsletter_issue_id], 1, &error)
+                    .await
             }
         }
+        .context("Failed to record the outcome of a delivery.")?;
     }
-    Ok(HttpResponse::Ok().finish())
+    Ok(newsletter_issue_id)
 }
 
-#[tracing::instrument(name = "Get confirmed subscribers", skip(pool))]
-async fn get_confirmed_subscribers(
-    pool: &PgPool,
-) -> Result<Vec<Result<ConfirmedSubscriber, anyhow::Error>>, anyhow::Error> {
-    let rows = sqlx::query!(r#"SELECT email FROM subscriptions WHERE status = 'confirmed'"#,)
-        .fetch_all(pool)
-        .await?;
+/// Count the recipients an issue would be delivered to, without publishing it.
+#[tracing::instrument(
+    name = "Dry-run a newsletter issue",
+    skip(body, pool, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn newsletter_dry_run(
+    body: web::Json<DryRunData>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    authenticate(&request, &pool, Scope::NewslettersDraft)
+        .await
+        .map_err(AppError::from_auth("publish"))?;
+    let (lists, segment) = parse_audience(&body.lists, body.segment.as_deref())?;
+    let unknown_lists = find_unknown_lists(&pool, &lists)
+        .await
+        .context("Failed to look up the lists targeted by the dry run.")?;
+    if !unknown_lists.is_empty() {
+        return Err(AppError::ValidationError(format!(
+            "There are no lists identified by {}.",
+            unknown_lists.join(", ")
+        )));
+    }
+    let mut query = QueryBuilder::new("SELECT COUNT(*) FROM (");
+    push_recipients(&mut query, &lists, segment.as_ref());
+    query.push(") r");
+    let recipients = query
+        .build_query_scalar::<i64>()
+        .fetch_one(pool.as_ref())
+        .await
+        .context("Failed to count the recipients of the dry run.")?;
+    Ok(HttpResponse::Ok().json(DryRun { recipients }))
+}
+
+pub(crate) fn parse_audience(
+    lists: &[String],
+    segment: Option<&str>,
+) -> Result<(Vec<ListSlug>, Option<Segment>), AppError> {
+    let lists = lists
+        .iter()
+        .map(|list| ListSlug::parse(list.clone()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(AppError::ValidationError)?;
+    if lists.is_empty() {
+        return Err(AppError::ValidationError(
+            "An issue must target at least one list.".into(),
+        ));
+    }
+    let segment = segment
+        .map(Segment::parse)
+        .transpose()
+        .map_err(AppError::ValidationError)?;
+    Ok((lists, segment))
+}
+
+#[tracing::instrument(
+    name = "Saving newsletter issue details in the database",
+    skip(transaction, body, segment)
+)]
+async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    body: &BodyData,
+    segment: Option<&Segment>,
+    track_opens: bool,
+    track_clicks: bool,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"INSERT INTO newsletter_issues (newsletter_issue_id, title, text_content, html_content, published_at, track_opens, track_clicks, segment) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"#,
+        newsletter_issue_id,
+        body.title,
+        body.content.text,
+        body.content.html,
+        Utc::now(),
+        track_opens,
+        track_clicks,
+        segment.map(AsRef::as_ref),
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(newsletter_issue_id)
+}
 
-    let confirmed_subscribers = rows
+/// Returns the slugs that do not match any existing list.
+#[tracing::instrument(
+    name = "Saving the lists targeted by a newsletter issue",
+    skip(transaction, lists)
+)]
+async fn insert_newsletter_issue_lists(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    lists: &[ListSlug],
+) -> Result<Vec<String>, sqlx::Error> {
+    let slugs: Vec<String> = lists.iter().map(|list| list.as_ref().to_owned()).collect();
+    let inserted = sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issue_lists (newsletter_issue_id, list_id)
+        SELECT $1, list_id FROM lists WHERE slug = ANY($2)
+        RETURNING (SELECT slug FROM lists WHERE lists.list_id = newsletter_issue_lists.list_id) AS "slug!"
+        "#,
+        newsletter_issue_id,
+        &slugs,
+    )
+    .fetch_all(&mut **transaction)
+    .await?;
+    let unknown_lists = slugs
         .into_iter()
-        .map(|r| match SubscriberEmail::parse(r.email) {
-            Ok(email) => Ok(ConfirmedSubscriber { email }),
-            Err(error) => Err(anyhow::anyhow!(error)),
-        })
+        .filter(|slug| !inserted.iter().any(|row| &row.slug == slug))
         .collect();
-    Ok(confirmed_subscribers)
-}
-
-fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, anyhow::Error> {
-    let header_value = headers
-        .get("Authorization")
-        .context("The 'Authorization' header was missing")?
-        .to_str()
-        .context("The 'Authorization' header was not a valid UTF8 string.")?;
-    let base64encoded_segment = header_value
-        .strip_prefix("Basic ")
-        .context("The authorization scheme was not 'Basic'.")?;
-    let decoded_bytes = base64::engine::general_purpose::STANDARD
-        .decode(base64encoded_segment)
-        .context("Failed to base64-decode 'Basic' credentials.")?;
-    let decoded_credentials = String::from_utf8(decoded_bytes)
-        .context("The decoded credential string is not valid UTF8.")?;
-    let mut credentials = decoded_credentials.splitn(2, ':');
-    let username = credentials
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("A username must be provided in 'Basic' auth."))?
-        .to_string();
-    let password = credentials
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("A password must be provided in 'Basic' auth."))?
-        .to_string();
-    Ok(Credentials {
-        username,
-        password: Secret::new(password),
-    })
-}
-
-#[tracing::instrument(name = "Validate credentials", skip(credentials, pool))]
-async fn validate_credentials(
-    credentials: Credentials,
+    Ok(unknown_lists)
+}
+
+#[tracing::instrument(name = "Find unknown lists", skip(pool, lists))]
+pub(crate) async fn find_unknown_lists(
     pool: &PgPool,
-) -> Result<uuid::Uuid, PublishError> {
-    let mut user_id = None;
-    let mut expected_password_hash = Secret::new(
-        "$argon2id$v=19$m=15000,t=2,p=1$gZiV/M1gPc22ElAH/Jh1Hw$CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno"
-            .to_string(),
-    );
-    if let Some((stored_user_id, stored_password_hash)) =
-        get_stored_credentials(&credentials.username, pool)
-            .await
-            .map_err(PublishError::UnexpectedError)?
-    {
-        user_id = Some(stored_user_id);
-        expected_password_hash = stored_password_hash;
-    }
+    lists: &[ListSlug],
+) -> Result<Vec<String>, sqlx::Error> {
+    let slugs: Vec<String> = lists.iter().map(|list| list.as_ref().to_owned()).collect();
+    let known = sqlx::query_scalar!(r#"SELECT slug FROM lists WHERE slug = ANY($1)"#, &slugs)
+        .fetch_all(pool)
+        .await?;
+    Ok(slugs
+        .into_iter()
+        .filter(|slug| !known.contains(slug))
+        .collect())
+}
 
-    spawn_blocking_with_tracing(move || {
-        verify_password_hash(expected_password_hash, credentials.password)
-    })
-    .await
-    .context("Failed to spawn blocking task.")
-    .map_err(PublishError::UnexpectedError)??;
-    user_id.ok_or_else(|| PublishError::AuthError(anyhow::anyhow!("Unknown username.")))
+/// Push a query selecting the distinct confirmed subscribers of `lists` matching `segment`,
+/// leaving out those who paused their subscription and those whose address bounced the last
+/// time it was sent to.
+fn push_recipients(
+    query: &mut QueryBuilder<'_, Postgres>,
+    lists: &[ListSlug],
+    segment: Option<&Segment>,
+) {
+    let slugs: Vec<String> = lists.iter().map(|list| list.as_ref().to_owned()).collect();
+    query
+        .push(
+            "SELECT DISTINCT s.id, s.email, s.digest_frequency \
+             FROM subscriptions s \
+             JOIN list_subscriptions ls ON ls.subscriber_id = s.id \
+             JOIN lists l ON l.list_id = ls.list_id \
+             WHERE ls.status = 'confirmed' \
+             AND (s.paused_until IS NULL OR s.paused_until <= now()) \
+             AND (SELECT d.status FROM deliveries d \
+                 WHERE d.subscriber_id = s.id AND d.subscriber_email = s.email \
+                 ORDER BY d.updated_at DESC LIMIT 1) IS DISTINCT FROM 'bounced' \
+             AND l.slug = ANY(",
+        )
+        .push_bind(slugs)
+        .push(")");
+    if let Some(segment) = segment {
+        query.push(" AND (");
+        segment.push_condition(query);
+        query.push(")");
+    }
 }
 
+/// Subscribers of several targeted lists receive the issue only once. Deliveries to digest
+/// subscribers are scheduled for the start of their next day or week.
 #[tracing::instrument(
-    name = "Verify password hash",
-    skip(expected_password_hash, password_candidate)
+    name = "Enqueue deliveries to confirmed subscribers",
+    skip(pool, lists, segment)
 )]
-fn verify_password_hash(
-    expected_password_hash: Secret<String>,
-    password_candidate: Secret<String>,
-) -> Result<(), PublishError> {
-    let expected_password_hash = PasswordHash::new(expected_password_hash.expose_secret())
-        .context("Failed to parse hash in PHC string format.")
-        .map_err(PublishError::UnexpectedError)?;
-    Argon2::default()
-        .verify_password(
-            password_candidate.expose_secret().as_bytes(),
-            &expected_password_hash,
-        )
-        .context("Invalid password.")
-        .map_err(PublishError::AuthError)
+async fn enqueue_deliveries(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    lists: &[ListSlug],
+    segment: Option<&Segment>,
+) -> Result<Vec<QueuedDelivery>, sqlx::Error> {
+    let mut query = QueryBuilder::new(
+        "WITH queued AS (INSERT INTO deliveries \
+         (newsletter_issue_id, subscriber_id, subscriber_email, status, updated_at, scheduled_for) \
+         SELECT ",
+    );
+    query.push_bind(newsletter_issue_id).push(
+        ", r.id, r.email, 'queued', now(), \
+         CASE r.digest_frequency \
+         WHEN 'daily' THEN date_trunc('day', now()) + interval '1 day' \
+         WHEN 'weekly' THEN date_trunc('week', now()) + interval '1 week' \
+         END FROM (",
+    );
+    push_recipients(&mut query, lists, segment);
+    query.push(
+        ") r RETURNING subscriber_id, subscriber_email, scheduled_for) \
+         SELECT q.subscriber_id, q.subscriber_email, q.scheduled_for, s.preferences_token \
+         FROM queued q JOIN subscriptions s ON s.id = q.subscriber_id",
+    );
+    query
+        .build_query_as::<QueuedDelivery>()
+        .fetch_all(pool)
+        .await
 }
 
-#[tracing::instrument(name = "Get stored credentials", skip(username, pool))]
-async fn get_stored_credentials(
-    username: &str,
+#[tracing::instrument(name = "Mark delivery as sent", skip(pool))]
+async fn mark_delivery_as_sent(
     pool: &PgPool,
-) -> Result<Option<(uuid::Uuid, Secret<String>)>, anyhow::Error> {
-    let row = sqlx::query!(
-        r#"SELECT user_id, password_hash FROM users WHERE username = $1"#,
-        username,
+    newsletter_issue_id: Uuid,
+    subscriber_id: Uuid,
+    provider_message_id: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE deliveries
+        SET status = 'sent',
+            attempts = attempts + 1,
+            last_error = NULL,
+            provider_message_id = $3,
+            updated_at = now()
+        WHERE newsletter_issue_id = $1 AND subscriber_id = $2
+        "#,
+        newsletter_issue_id,
+        subscriber_id,
+        provider_message_id,
     )
-    .fetch_optional(pool)
-    .await
-    .context("Failed to perform a query to retrieve stored credentials.")?
-    .map(|row| (row.user_id, Secret::new(row.password_hash)));
-    Ok(row)
+    .execute(pool)
+    .await?;
+    Ok(())
 }
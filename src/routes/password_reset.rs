@@ -0,0 +1,315 @@
+use crate::audit::{record_audit_event, AuditEvent};
+use crate::authentication::PasswordPolicy;
+use crate::configuration::PasswordResetSettings;
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::login_throttle::LoginThrottle;
+use crate::rate_limit::RateLimiter;
+use crate::routes::AppError;
+use crate::session::{delete_user_sessions, generate_token, hash_token};
+use crate::startup::ApplicationBaseUrl;
+use crate::telemetry::spawn_blocking_with_tracing;
+use crate::utils::escape_html;
+use actix_web::dev::Payload;
+use actix_web::http::header::{ContentType, REFERRER_POLICY};
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use anyhow::Context;
+use chrono::Utc;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use std::future::Future;
+use std::pin::Pin;
+use unicode_segmentation::UnicodeSegmentation;
+use uuid::Uuid;
+
+const MIN_PASSWORD_LENGTH: usize = 12;
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+#[derive(serde::Deserialize)]
+pub struct ForgotPasswordData {
+    email: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResetPasswordParameters {
+    token: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResetPasswordData {
+    token: String,
+    new_password: Secret<String>,
+}
+
+/// New passwords are accepted as JSON as well as from the form of the reset page.
+pub struct ResetPasswordBody {
+    data: ResetPasswordData,
+    from_form: bool,
+}
+
+impl FromRequest for ResetPasswordBody {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(request: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        if request.content_type() == "application/json" {
+            let json = web::Json::<ResetPasswordData>::from_request(request, payload);
+            Box::pin(async move {
+                Ok(Self {
+                    data: json.await?.into_inner(),
+                    from_form: false,
+                })
+            })
+        } else {
+            let form = web::Form::<ResetPasswordData>::from_request(request, payload);
+            Box::pin(async move {
+                Ok(Self {
+                    data: form.await?.into_inner(),
+                    from_form: true,
+                })
+            })
+        }
+    }
+}
+
+fn validate_password(password: &Secret<String>) -> Result<(), AppError> {
+    let length = password.expose_secret().graphemes(true).count();
+    if length < MIN_PASSWORD_LENGTH {
+        return Err(AppError::ValidationError(format!(
+            "The new password must be at least {MIN_PASSWORD_LENGTH} characters long."
+        )));
+    }
+    if length > MAX_PASSWORD_LENGTH {
+        return Err(AppError::ValidationError(format!(
+            "The new password must be at most {MAX_PASSWORD_LENGTH} characters long."
+        )));
+    }
+    Ok(())
+}
+
+/// Email a reset link to the user with this address, if there is one. The response does
+/// not depend on it, and the email is sent in the background so that neither does the
+/// time it takes to respond.
+#[tracing::instrument(
+    name = "Request a password reset",
+    skip(body, pool, email_client, base_url, settings, rate_limiter)
+)]
+pub async fn forgot_password(
+    body: web::Json<ForgotPasswordData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    settings: web::Data<PasswordResetSettings>,
+    rate_limiter: web::Data<RateLimiter>,
+) -> Result<HttpResponse, AppError> {
+    let email =
+        SubscriberEmail::parse(body.into_inner().email).map_err(AppError::ValidationError)?;
+    rate_limiter
+        .check(
+            "password_reset:email",
+            &email.as_ref().to_lowercase(),
+            rate_limiter.password_reset_limits().per_email,
+        )
+        .await?;
+    let user_id = sqlx::query_scalar!(
+        r#"SELECT user_id FROM users WHERE lower(email) = lower($1)"#,
+        email.as_ref(),
+    )
+    .fetch_optional(pool.as_ref())
+    .await
+    .context("Failed to look up a user by email address.")?;
+
+    if let Some(user_id) = user_id {
+        let token = store_reset_token(&pool, user_id, settings.token_ttl_minutes)
+            .await
+            .context("Failed to store a password reset token.")?;
+        let reset_link = format!("{}/login/reset-password?token={token}", base_url.0);
+        tokio::spawn(send_reset_email(email_client, email, reset_link));
+    } else {
+        tracing::info!("No user has this email address, no reset link is sent");
+    }
+    Ok(HttpResponse::Accepted().json(serde_json::json!({
+        "detail": "If an account uses this email address, a reset link is on its way."
+    })))
+}
+
+/// The page the emailed reset link opens, with a form to choose the new password. The
+/// link itself is only checked once the form is submitted.
+#[tracing::instrument(name = "Show the reset password page", skip(parameters))]
+pub async fn reset_password_page(parameters: web::Query<ResetPasswordParameters>) -> HttpResponse {
+    let body = format!(
+        r#"<form action="/login/reset-password" method="post">
+        <input type="hidden" name="token" value="{}">
+        <label>New password <input type="password" name="new_password" autocomplete="new-password" minlength="{MIN_PASSWORD_LENGTH}" maxlength="{MAX_PASSWORD_LENGTH}" required></label><br>
+        <button type="submit">Reset password</button>
+    </form>"#,
+        escape_html(&parameters.token),
+    );
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        // The token is in the address of the page: it must not leak to other sites.
+        .insert_header((REFERRER_POLICY, "no-referrer"))
+        .body(render_page(&body))
+}
+
+/// Set a new password with a reset link. The link cannot be used again, and every
+/// session of the user is ended. The password is only hashed once the link is known to be
+/// valid, with a turn from the [`LoginThrottle`] like any other password hash.
+#[tracing::instrument(name = "Reset a password", skip(body, pool, policy, throttle, request))]
+pub async fn reset_password(
+    body: ResetPasswordBody,
+    pool: web::Data<PgPool>,
+    policy: web::Data<PasswordPolicy>,
+    throttle: web::Data<LoginThrottle>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let ResetPasswordBody {
+        data: ResetPasswordData {
+            token,
+            new_password,
+        },
+        from_form,
+    } = body;
+    validate_password(&new_password)?;
+    let token_hash = hash_token(&token);
+    let invalid_link =
+        || AppError::ValidationError("The reset link is invalid or has expired.".into());
+    sqlx::query_scalar!(
+        r#"
+        SELECT user_id FROM password_reset_tokens
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > now()
+        "#,
+        token_hash,
+    )
+    .fetch_optional(pool.as_ref())
+    .await
+    .context("Failed to look up a password reset token.")?
+    .ok_or_else(invalid_link)?;
+    let password_hash = {
+        let _permit = throttle.verification_permit().await;
+        let policy = policy.get_ref().clone();
+        spawn_blocking_with_tracing(move || policy.hash(&new_password))
+            .await
+            .context("Failed to spawn blocking task.")??
+    };
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    // The link may have been used or have expired while the password was hashed.
+    let user_id = sqlx::query_scalar!(
+        r#"
+        UPDATE password_reset_tokens SET used_at = now()
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > now()
+        RETURNING user_id
+        "#,
+        token_hash,
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to use a password reset token.")?
+    .ok_or_else(invalid_link)?;
+    sqlx::query!(
+        r#"UPDATE users SET password_hash = $1 WHERE user_id = $2"#,
+        password_hash.expose_secret(),
+        user_id,
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to store the new password.")?;
+    // Other links sent before this reset must not be usable either.
+    sqlx::query!(
+        r#"UPDATE password_reset_tokens SET used_at = now() WHERE user_id = $1 AND used_at IS NULL"#,
+        user_id,
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to invalidate the other password reset tokens.")?;
+    let sessions = delete_user_sessions(&mut *transaction, user_id)
+        .await
+        .context("Failed to end the sessions of a user.")?;
+    let event = AuditEvent::new("user.reset_password", "user", user_id)
+        .after(serde_json::json!({ "ended_sessions": sessions }));
+    record_audit_event(&mut *transaction, &request, user_id, event)
+        .await
+        .context("Failed to record an audit event.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to reset a password.")?;
+    tracing::info!(%user_id, sessions, "Reset a password");
+    if from_form {
+        return Ok(HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(render_page(
+                "<p>Your password has been reset. You can now log in with it.</p>",
+            )));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn render_page(body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Reset your password</title>
+</head>
+<body>
+    {body}
+</body>
+</html>"#
+    )
+}
+
+async fn store_reset_token(
+    pool: &PgPool,
+    user_id: Uuid,
+    ttl_minutes: i64,
+) -> Result<String, sqlx::Error> {
+    let token = generate_token();
+    let now = Utc::now();
+    sqlx::query!(
+        r#"
+        INSERT INTO password_reset_tokens (token_hash, user_id, created_at, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        hash_token(&token),
+        user_id,
+        now,
+        now + chrono::Duration::minutes(ttl_minutes),
+    )
+    .execute(pool)
+    .await?;
+    Ok(token)
+}
+
+#[tracing::instrument(name = "Send a password reset email", skip(email_client, reset_link))]
+async fn send_reset_email(
+    email_client: web::Data<EmailClient>,
+    recipient: SubscriberEmail,
+    reset_link: String,
+) {
+    let plain_body = format!(
+        "Someone asked to reset the password of your account.\n\
+        Visit {reset_link} to choose a new password.\n\
+        If it was not you, you can ignore this email."
+    );
+    let html_body = format!(
+        "Someone asked to reset the password of your account.<br />\
+        Click <a href=\"{reset_link}\">here</a> to choose a new password.<br />\
+        If it was not you, you can ignore this email."
+    );
+    if let Err(error) = email_client
+        .send_email(&recipient, "Reset your password", &html_body, &plain_body)
+        .await
+    {
+        tracing::error!(
+            error.cause_chain = ?error,
+            error.message = %error,
+            "Failed to send a password reset email",
+        );
+    }
+}
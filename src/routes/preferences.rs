@@ -0,0 +1,589 @@
+use crate::domain::{DigestFrequency, ListSlug, SubscriberEmail, SubscriberName};
+use crate::email_client::EmailClient;
+use crate::email_outbox::{dispatch_email, enqueue_email};
+use crate::routes::{generate_subscription_token, AppError};
+use crate::startup::ApplicationBaseUrl;
+use crate::utils::escape_html;
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::fmt::Write;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct EmailChangeParameters {
+    token: String,
+}
+
+struct Subscriber {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    digest_frequency: String,
+    paused_until: Option<DateTime<Utc>>,
+}
+
+struct ListChoice {
+    slug: String,
+    name: String,
+    subscribed: bool,
+}
+
+/// The submitted preferences form. Checkboxes share the `lists` field name, so the
+/// body is deserialized as key-value pairs rather than into a struct.
+struct PreferencesForm {
+    name: SubscriberName,
+    email: SubscriberEmail,
+    lists: Vec<ListSlug>,
+    digest_frequency: DigestFrequency,
+    paused_until: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<Vec<(String, String)>> for PreferencesForm {
+    type Error = String;
+
+    fn try_from(fields: Vec<(String, String)>) -> Result<Self, Self::Error> {
+        let field = |key: &str| {
+            fields
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.trim().to_owned())
+                .ok_or_else(|| format!("The {key} field is missing."))
+        };
+        let name = SubscriberName::parse(field("name")?)?;
+        let email = SubscriberEmail::parse(field("email")?)?;
+        let digest_frequency = DigestFrequency::parse(&field("digest_frequency")?)?;
+        let paused_until = match field("paused_until").unwrap_or_default().as_str() {
+            "" => None,
+            date => Some(
+                NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                    .map_err(|_| format!("{date} is not a valid date."))?
+                    .and_hms_opt(0, 0, 0)
+                    .expect("Midnight is a valid time.")
+                    .and_utc(),
+            ),
+        };
+        let lists = fields
+            .iter()
+            .filter(|(k, _)| k == "lists")
+            .map(|(_, v)| ListSlug::parse(v.clone()))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            name,
+            email,
+            lists,
+            digest_frequency,
+            paused_until,
+        })
+    }
+}
+
+#[tracing::instrument(name = "Show the preference center", skip(token, pool))]
+pub async fn preferences_page(
+    token: web::Path<String>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    let subscriber = get_subscriber_from_token(&pool, &token)
+        .await
+        .context("Failed to retrieve the subscriber preferences.")?
+        .ok_or(AppError::NotFound)?;
+    let lists = get_list_choices(&pool, subscriber.id)
+        .await
+        .context("Failed to retrieve the mailing lists.")?;
+    Ok(render_preferences(&token, &subscriber, &lists, None))
+}
+
+#[tracing::instrument(
+    name = "Update subscriber preferences",
+    skip(token, form, pool, email_client, base_url)
+)]
+pub async fn update_preferences(
+    token: web::Path<String>,
+    form: web::Form<Vec<(String, String)>>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> Result<HttpResponse, AppError> {
+    let form = PreferencesForm::try_from(form.into_inner()).map_err(AppError::ValidationError)?;
+    let subscriber = get_subscriber_from_token(&pool, &token)
+        .await
+        .context("Failed to retrieve the subscriber preferences.")?
+        .ok_or(AppError::NotFound)?;
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    update_subscriber(&mut transaction, subscriber.id, &form)
+        .await
+        .context("Failed to update the subscriber preferences.")?;
+    let unknown_lists = update_list_subscriptions(&mut transaction, &subscriber, &form.lists)
+        .await
+        .context("Failed to update the subscriber's lists.")?;
+    if !unknown_lists.is_empty() {
+        return Err(AppError::ValidationError(format!(
+            "There are no lists identified by {}.",
+            unknown_lists.join(", ")
+        )));
+    }
+    let mut notice = "Your preferences have been saved.".to_owned();
+    let mut outbox_message_ids = Vec::new();
+    if form.email.as_ref() != subscriber.email {
+        if email_in_use(&mut transaction, &form.email)
+            .await
+            .context("Failed to check whether the new email address is in use.")?
+        {
+            return Err(AppError::ValidationError(format!(
+                "{} is already subscribed.",
+                form.email
+            )));
+        }
+        outbox_message_ids =
+            request_email_change(&mut transaction, &base_url.0, &subscriber, &form.email).await?;
+        notice.push_str(" Check your inbox to confirm your new email address.");
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to update subscriber preferences.")?;
+    for outbox_message_id in outbox_message_ids {
+        dispatch_email(&pool, &email_client, outbox_message_id).await;
+    }
+
+    let subscriber = get_subscriber_from_token(&pool, &token)
+        .await
+        .context("Failed to retrieve the subscriber preferences.")?
+        .ok_or(AppError::NotFound)?;
+    let lists = get_list_choices(&pool, subscriber.id)
+        .await
+        .context("Failed to retrieve the mailing lists.")?;
+    Ok(render_preferences(
+        &token,
+        &subscriber,
+        &lists,
+        Some(&notice),
+    ))
+}
+
+#[tracing::instrument(name = "Confirm an email address change", skip(parameters, pool))]
+pub async fn confirm_email_change(
+    parameters: web::Query<EmailChangeParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let request = sqlx::query!(
+        r#"
+        DELETE FROM subscription_tokens
+        WHERE subscription_token = $1 AND purpose = 'email_change'
+        RETURNING subscriber_id, new_email AS "new_email!"
+        "#,
+        parameters.token
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to retrieve the email change request.")?
+    .ok_or(AppError::NotFound)?;
+    let email = SubscriberEmail::parse(request.new_email).map_err(AppError::ValidationError)?;
+    // The address may have subscribed on its own since the change was requested.
+    let updated = sqlx::query!(
+        r#"UPDATE subscriptions SET email = $2 WHERE id = $1"#,
+        request.subscriber_id,
+        email.as_ref()
+    )
+    .execute(&mut *transaction)
+    .await;
+    match updated {
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            return Err(AppError::Conflict(format!(
+                "{email} is already subscribed."
+            )));
+        }
+        updated => {
+            updated.context("Failed to update the subscriber email address.")?;
+        }
+    }
+    sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1 AND purpose = 'email_change'"#,
+        request.subscriber_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to discard other email change requests.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to change an email address.")?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(render_page(&format!(
+            "<p>Your email address has been changed to {}.</p>",
+            escape_html(email.as_ref())
+        ))))
+}
+
+/// Append a link to the preference center to both bodies of an email.
+#[must_use]
+pub fn add_preferences_footer(
+    html: &str,
+    text: &str,
+    base_url: &str,
+    preferences_token: &str,
+) -> (String, String) {
+    let link = format!(
+        "{}/preferences/{preferences_token}",
+        base_url.trim_end_matches('/')
+    );
+    let footer = format!(
+        r#"<p><a href="{}">Manage your subscription</a></p>"#,
+        escape_html(&link)
+    );
+    let mut html = html.to_owned();
+    match html.to_ascii_lowercase().rfind("</body>") {
+        Some(position) => html.insert_str(position, &footer),
+        None => html.push_str(&footer),
+    }
+    (html, format!("{text}\n\nManage your subscription: {link}"))
+}
+
+#[tracing::instrument(name = "Get subscriber from preferences token", skip(pool, token))]
+async fn get_subscriber_from_token(
+    pool: &PgPool,
+    token: &str,
+) -> Result<Option<Subscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT id, email, name, status, digest_frequency, paused_until
+        FROM subscriptions
+        WHERE preferences_token = $1
+        "#,
+        token
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+#[tracing::instrument(name = "Get the lists a subscriber can choose from", skip(pool))]
+async fn get_list_choices(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Vec<ListChoice>, sqlx::Error> {
+    sqlx::query_as!(
+        ListChoice,
+        r#"
+        SELECT l.slug, l.name, ls.subscriber_id IS NOT NULL AS "subscribed!"
+        FROM lists l
+        LEFT JOIN list_subscriptions ls
+            ON ls.list_id = l.list_id AND ls.subscriber_id = $1
+        ORDER BY l.slug
+        "#,
+        subscriber_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[tracing::instrument(name = "Update subscriber details", skip(transaction, form))]
+async fn update_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    form: &PreferencesForm,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET name = $2, digest_frequency = $3, paused_until = $4
+        WHERE id = $1
+        "#,
+        subscriber_id,
+        form.name.as_ref(),
+        form.digest_frequency.as_str(),
+        form.paused_until,
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// Subscribe to the chosen lists and leave the others. Lists are joined as confirmed
+/// if the subscriber already verified their email address; leaving every list
+/// cancels the rest of the onboarding sequence.
+/// Returns the slugs that do not match any existing list.
+#[tracing::instrument(
+    name = "Update list subscriptions",
+    skip(transaction, subscriber, lists)
+)]
+async fn update_list_subscriptions(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber: &Subscriber,
+    lists: &[ListSlug],
+) -> Result<Vec<String>, sqlx::Error> {
+    let slugs: Vec<String> = lists.iter().map(|list| list.as_ref().to_owned()).collect();
+    let known = sqlx::query_scalar!(r#"SELECT slug FROM lists WHERE slug = ANY($1)"#, &slugs)
+        .fetch_all(&mut **transaction)
+        .await?;
+    let unknown_lists: Vec<String> = slugs
+        .iter()
+        .filter(|slug| !known.contains(slug))
+        .cloned()
+        .collect();
+    if !unknown_lists.is_empty() {
+        return Ok(unknown_lists);
+    }
+    sqlx::query!(
+        r#"
+        DELETE FROM list_subscriptions ls
+        USING lists l
+        WHERE ls.list_id = l.list_id AND ls.subscriber_id = $1 AND NOT (l.slug = ANY($2))
+        "#,
+        subscriber.id,
+        &slugs
+    )
+    .execute(&mut **transaction)
+    .await?;
+    let status = if subscriber.status == "confirmed" {
+        "confirmed"
+    } else {
+        "pending_confirmation"
+    };
+    sqlx::query!(
+        r#"
+        INSERT INTO list_subscriptions (list_id, subscriber_id, status, subscribed_at)
+        SELECT list_id, $1, $3, now() FROM lists WHERE slug = ANY($2)
+        ON CONFLICT DO NOTHING
+        "#,
+        subscriber.id,
+        &slugs,
+        status
+    )
+    .execute(&mut **transaction)
+    .await?;
+    if slugs.is_empty() {
+        sqlx::query!(
+            r#"
+            UPDATE onboarding_deliveries SET status = 'cancelled', updated_at = now()
+            WHERE subscriber_id = $1 AND status = 'scheduled'
+            "#,
+            subscriber.id
+        )
+        .execute(&mut **transaction)
+        .await?;
+    }
+    Ok(unknown_lists)
+}
+
+async fn email_in_use(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM subscriptions WHERE email = $1) AS "exists!""#,
+        email.as_ref()
+    )
+    .fetch_one(&mut **transaction)
+    .await
+}
+
+/// Ask the subscriber to confirm `new_email` from that inbox and let the current
+/// address know about the request. Any earlier pending change is superseded.
+/// Both emails are written to the outbox, returning their ids to dispatch once
+/// `transaction` is committed.
+#[tracing::instrument(
+    name = "Request an email address change",
+    skip(transaction, base_url, subscriber)
+)]
+async fn request_email_change(
+    transaction: &mut Transaction<'_, Postgres>,
+    base_url: &str,
+    subscriber: &Subscriber,
+    new_email: &SubscriberEmail,
+) -> Result<Vec<Uuid>, AppError> {
+    let email_change_token = generate_subscription_token();
+    sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1 AND purpose = 'email_change'"#,
+        subscriber.id
+    )
+    .execute(&mut **transaction)
+    .await
+    .context("Failed to discard earlier email change requests.")?;
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_tokens (subscription_token, subscriber_id, purpose, new_email)
+        VALUES ($1, $2, 'email_change', $3)
+        "#,
+        email_change_token,
+        subscriber.id,
+        new_email.as_ref()
+    )
+    .execute(&mut **transaction)
+    .await
+    .context("Failed to store the email change token.")?;
+    let mut outbox_message_ids = vec![enqueue_email_change_confirmation(
+        transaction,
+        new_email,
+        base_url,
+        &email_change_token,
+    )
+    .await
+    .context("Failed to write the email change confirmation to the outbox.")?];
+    match SubscriberEmail::parse(subscriber.email.clone()) {
+        Ok(current_email) => {
+            outbox_message_ids.push(
+                enqueue_email_change_notice(transaction, &current_email, new_email)
+                    .await
+                    .context("Failed to write the email change notice to the outbox.")?,
+            );
+        }
+        Err(error) => {
+            tracing::warn!(
+                error,
+                "Skipping the email change notice to an invalid address"
+            );
+        }
+    }
+    Ok(outbox_message_ids)
+}
+
+#[tracing::instrument(
+    name = "Write an email change confirmation to the outbox",
+    skip(transaction, base_url, email_change_token)
+)]
+async fn enqueue_email_change_confirmation(
+    transaction: &mut Transaction<'_, Postgres>,
+    new_email: &SubscriberEmail,
+    base_url: &str,
+    email_change_token: &str,
+) -> Result<Uuid, sqlx::Error> {
+    let confirmation_link =
+        format!("{base_url}/preferences/email/confirm?token={email_change_token}");
+    let plain_body = format!(
+        "You asked to receive our newsletter at this address.\n\
+        Visit {confirmation_link} to confirm the change."
+    );
+    let html_body = format!(
+        "You asked to receive our newsletter at this address.<br />\
+        Click <a href=\"{confirmation_link}\">here</a> to confirm the change."
+    );
+    enqueue_email(
+        transaction,
+        new_email,
+        "Confirm your new email address",
+        &html_body,
+        &plain_body,
+    )
+    .await
+}
+
+#[tracing::instrument(name = "Write an email change notice to the outbox", skip(transaction))]
+async fn enqueue_email_change_notice(
+    transaction: &mut Transaction<'_, Postgres>,
+    current_email: &SubscriberEmail,
+    new_email: &SubscriberEmail,
+) -> Result<Uuid, sqlx::Error> {
+    let plain_body = format!(
+        "Someone asked to send our newsletter to {new_email} instead of this address.\n\
+        Nothing changes until the new address is confirmed. \
+        If you did not ask for this, you can ignore this email."
+    );
+    let html_body = format!(
+        "Someone asked to send our newsletter to {} instead of this address.<br />\
+        Nothing changes until the new address is confirmed. \
+        If you did not ask for this, you can ignore this email.",
+        escape_html(new_email.as_ref())
+    );
+    enqueue_email(
+        transaction,
+        current_email,
+        "Your email address is being changed",
+        &html_body,
+        &plain_body,
+    )
+    .await
+}
+
+fn render_preferences(
+    token: &str,
+    subscriber: &Subscriber,
+    lists: &[ListChoice],
+    notice: Option<&str>,
+) -> HttpResponse {
+    let mut body = String::new();
+    if let Some(notice) = notice {
+        writeln!(body, "<p><i>{}</i></p>", escape_html(notice)).unwrap();
+    }
+    writeln!(
+        body,
+        r#"<form action="/preferences/{}" method="post">
+        <label>Name <input type="text" name="name" value="{}"></label><br>
+        <label>Email <input type="email" name="email" value="{}"></label><br>
+        <fieldset><legend>Lists</legend>"#,
+        escape_html(token),
+        escape_html(&subscriber.name),
+        escape_html(&subscriber.email),
+    )
+    .unwrap();
+    for list in lists {
+        writeln!(
+            body,
+            r#"<label><input type="checkbox" name="lists" value="{}"{}> {}</label><br>"#,
+            escape_html(&list.slug),
+            if list.subscribed { " checked" } else { "" },
+            escape_html(&list.name),
+        )
+        .unwrap();
+    }
+    writeln!(
+        body,
+        r#"</fieldset>
+        <label>Frequency <select name="digest_frequency">"#
+    )
+    .unwrap();
+    for frequency in DigestFrequency::ALL {
+        writeln!(
+            body,
+            r#"<option value="{0}"{1}>{0}</option>"#,
+            frequency.as_str(),
+            if frequency.as_str() == subscriber.digest_frequency {
+                " selected"
+            } else {
+                ""
+            },
+        )
+        .unwrap();
+    }
+    writeln!(
+        body,
+        r#"</select></label><br>
+        <label>Pause until <input type="date" name="paused_until" value="{}"></label><br>
+        <button type="submit">Save</button>
+    </form>"#,
+        subscriber
+            .paused_until
+            .map(|date| date.format("%Y-%m-%d").to_string())
+            .unwrap_or_default(),
+    )
+    .unwrap();
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(render_page(&body))
+}
+
+fn render_page(body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Your preferences</title>
+</head>
+<body>
+    {body}
+</body>
+</html>"#
+    )
+}
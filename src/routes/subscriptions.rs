@@ -1,67 +1,79 @@
-use crate::domain::{NewSubscriber, SubscriberEmail, SubscriberName};
+use crate::bot_protection::{BotProtection, Submission};
+use crate::domain::{ListSlug, NewSubscriber, SubscriberEmail, SubscriberName};
 use crate::email_client::EmailClient;
+use crate::email_outbox::{dispatch_email, enqueue_email};
+use crate::rate_limit::RateLimiter;
+use crate::routes::{error_chain_fmt, AppError, FieldError};
 use crate::startup::ApplicationBaseUrl;
-use actix_web::http::StatusCode;
-use actix_web::{web, HttpResponse, ResponseError};
+use actix_web::dev::Payload;
+use actix_web::http::header::{CacheControl, CacheDirective};
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
 use anyhow::Context;
 use chrono::Utc;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use sqlx::{PgPool, Postgres, Transaction};
+use std::future::Future;
+use std::pin::Pin;
 use uuid::Uuid;
 
 #[derive(serde::Deserialize)]
 pub struct FormData {
     email: String,
     name: String,
+    list: Option<String>,
+    /// Hidden from people by the signup form, so only bots fill it in.
+    website: Option<String>,
+    form_token: Option<String>,
+    #[serde(alias = "h-captcha-response", alias = "cf-turnstile-response")]
+    captcha_response: Option<String>,
 }
 
-impl TryFrom<FormData> for NewSubscriber {
-    type Error = String;
-
-    fn try_from(value: FormData) -> Result<Self, Self::Error> {
-        let name = SubscriberName::parse(value.name)?;
-        let email = SubscriberEmail::parse(value.email)?;
-        Ok(Self { email, name })
+impl FormData {
+    fn submission(&self) -> Submission<'_> {
+        Submission {
+            honeypot: self.website.as_deref(),
+            form_token: self.form_token.as_deref(),
+            captcha_response: self.captcha_response.as_deref(),
+        }
     }
 }
 
-/// # Errors
-/// This function will return the error produced by the `writeln!` macro, if it encounters any error.
-/// This error is usually of type `std::fmt::Error`. It is produced in situations where the `std::fmt::Formatter` cannot write
-/// the formatted string into a destination, most commonly when the destination is full or does not exist.
-pub fn error_chain_fmt(
-    e: &impl std::error::Error,
-    f: &mut std::fmt::Formatter<'_>,
-) -> std::fmt::Result {
-    writeln!(f, "{e}\n")?;
-    let mut current = e.source();
-    while let Some(cause) = current {
-        writeln!(f, "Caused by:\n\t{cause}")?;
-        current = cause.source();
-    }
-    Ok(())
-}
+/// Subscriptions are accepted as JSON as well as forms, depending on the content type.
+pub struct SubscriptionBody(FormData);
 
-#[derive(thiserror::Error)]
-pub enum SubscribeError {
-    #[error("{0}")]
-    ValidationError(String),
-    #[error(transparent)]
-    UnexpectedError(#[from] anyhow::Error),
-}
+impl FromRequest for SubscriptionBody {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
 
-impl std::fmt::Debug for SubscribeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        error_chain_fmt(self, f)
+    fn from_request(request: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        if request.content_type() == "application/json" {
+            let json = web::Json::<FormData>::from_request(request, payload);
+            Box::pin(async move { Ok(Self(json.await?.into_inner())) })
+        } else {
+            let form = web::Form::<FormData>::from_request(request, payload);
+            Box::pin(async move { Ok(Self(form.await?.into_inner())) })
+        }
     }
 }
 
-impl ResponseError for SubscribeError {
-    fn status_code(&self) -> StatusCode {
-        match self {
-            SubscribeError::ValidationError(_) => StatusCode::BAD_REQUEST,
-            SubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+impl TryFrom<FormData> for NewSubscriber {
+    type Error = Vec<FieldError>;
+
+    /// Every invalid field is reported, not only the first one.
+    fn try_from(value: FormData) -> Result<Self, Self::Error> {
+        match (
+            SubscriberName::parse(value.name),
+            SubscriberEmail::parse(value.email),
+        ) {
+            (Ok(name), Ok(email)) => Ok(Self { email, name }),
+            (name, email) => Err([
+                email.err().map(|detail| FieldError::new("email", detail)),
+                name.err().map(|detail| FieldError::new("name", detail)),
+            ]
+            .into_iter()
+            .flatten()
+            .collect()),
         }
     }
 }
@@ -91,48 +103,113 @@ impl std::fmt::Display for StoreTokenError {
 
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form, pool, email_client, base_url),
+    skip(body, pool, email_client, base_url, rate_limiter, bot_protection),
     fields(
-        subscriber_email = %form.email,
-        subscriber_name= %form.name
+        subscriber_email = %body.0.email,
+        subscriber_name= %body.0.name
     )
 )]
 pub async fn subscribe(
-    form: web::Form<FormData>,
+    body: SubscriptionBody,
     pool: web::Data<PgPool>,
     email_client: web::Data<EmailClient>,
     base_url: web::Data<ApplicationBaseUrl>,
-) -> Result<HttpResponse, SubscribeError> {
-    let new_subscriber = form
-        .into_inner()
-        .try_into()
-        .map_err(SubscribeError::ValidationError)?;
+    rate_limiter: web::Data<RateLimiter>,
+    bot_protection: web::Data<BotProtection>,
+) -> Result<HttpResponse, AppError> {
+    let SubscriptionBody(mut form) = body;
+    // Suspected bots get the same response as everyone else, so they cannot tell they were caught.
+    if let Some(suspicion) = bot_protection.screen(&form.submission()).await {
+        tracing::warn!(
+            suspicion = suspicion.as_str(),
+            "Dropping a subscription that looks automated"
+        );
+        return Ok(HttpResponse::Ok().finish());
+    }
+    let list = ListSlug::parse(form.list.take().unwrap_or_else(|| ListSlug::DEFAULT.into()))
+        .map_err(|e| AppError::InvalidFields(vec![FieldError::new("list", e)]))?;
+    let new_subscriber: NewSubscriber = form.try_into().map_err(AppError::InvalidFields)?;
+    rate_limiter
+        .check(
+            "subscribe:email",
+            &new_subscriber.email.as_ref().to_lowercase(),
+            rate_limiter.subscribe_limits().per_email,
+        )
+        .await?;
     let mut transaction = pool
         .begin()
         .await
         .context("Failed to acquire a Postgres connection from the pool")?;
+    let list_id = get_list_id(&mut transaction, &list)
+        .await
+        .context("Failed to look up the requested mailing list.")?
+        .ok_or_else(|| {
+            AppError::InvalidFields(vec![FieldError::new(
+                "list",
+                format!("There is no list identified by {list}."),
+            )])
+        })?;
     let subscriber_id = insert_subscriber(&mut transaction, &new_subscriber)
         .await
         .context("Failed to insert new subscriber in the database.")?;
-    let subscription_token = generate_subscription_token();
-    store_token(&mut transaction, subscriber_id, &subscription_token)
+    let status = insert_list_subscription(&mut transaction, list_id, subscriber_id)
         .await
-        .context("Failed to store the confirmation token for a new subscriber.")?;
-    send_confirmation_email(
-        &email_client,
-        new_subscriber,
+        .context("Failed to subscribe the new subscriber to the mailing list.")?;
+    if status == "confirmed" {
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit SQL transaction to store a new subscriber.")?;
+        return Ok(HttpResponse::Ok().finish());
+    }
+    let subscription_token = generate_subscription_token();
+    store_token(
+        &mut transaction,
+        subscriber_id,
+        list_id,
+        &subscription_token,
+    )
+    .await
+    .context("Failed to store the confirmation token for a new subscriber.")?;
+    let outbox_message_id = enqueue_confirmation_email(
+        &mut transaction,
+        &new_subscriber,
         base_url.as_ref().0.as_str(),
         &subscription_token,
     )
     .await
-    .context("Failed to send a confirmation email.")?;
+    .context("Failed to write a confirmation email to the outbox.")?;
     transaction
         .commit()
         .await
         .context("Failed to commit SQL transaction to store a new subscriber.")?;
+    dispatch_email(&pool, &email_client, outbox_message_id).await;
     Ok(HttpResponse::Ok().finish())
 }
 
+/// A token for the signup form to submit, proving it was rendered long enough ago.
+#[tracing::instrument(name = "Issue a signup form token", skip(bot_protection))]
+pub async fn form_token(bot_protection: web::Data<BotProtection>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .json(serde_json::json!({ "form_token": bot_protection.issue_form_token() }))
+}
+
+#[tracing::instrument(name = "Get list_id from slug", skip(transaction))]
+pub async fn get_list_id(
+    transaction: &mut Transaction<'_, Postgres>,
+    list: &ListSlug,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT list_id FROM lists WHERE slug = $1"#,
+        list.as_ref()
+    )
+    .fetch_optional(&mut **transaction)
+    .await?;
+    Ok(result.map(|r| r.list_id))
+}
+
+/// Returns the id of the subscriber, reusing the existing row if the email is already known.
 #[tracing::instrument(
     name = "Saving new subscriber details in the database",
     skip(new_subscriber, transaction)
@@ -141,29 +218,58 @@ pub async fn insert_subscriber(
     transaction: &mut Transaction<'_, Postgres>,
     new_subscriber: &NewSubscriber,
 ) -> Result<Uuid, sqlx::Error> {
-    let subscriber_id = Uuid::new_v4();
-    sqlx::query!(
-        r#"INSERT INTO subscriptions (id, email, name, subscribed_at, status) VALUES ($1, $2, $3, $4, 'pending_confirmation')"#,
-        subscriber_id,
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status, preferences_token)
+        VALUES ($1, $2, $3, $4, 'pending_confirmation', $5)
+        ON CONFLICT (email) DO UPDATE SET email = EXCLUDED.email
+        RETURNING id
+        "#,
+        Uuid::new_v4(),
         new_subscriber.email.as_ref(),
         new_subscriber.name.as_ref(),
+        Utc::now(),
+        generate_subscription_token()
+    )
+    .fetch_one(&mut **transaction)
+    .await?;
+    Ok(result.id)
+}
+
+/// Returns the status of the subscription, which is left untouched if it already exists.
+#[tracing::instrument(name = "Subscribe to a mailing list", skip(transaction))]
+pub async fn insert_list_subscription(
+    transaction: &mut Transaction<'_, Postgres>,
+    list_id: Uuid,
+    subscriber_id: Uuid,
+) -> Result<String, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO list_subscriptions (list_id, subscriber_id, status, subscribed_at)
+        VALUES ($1, $2, 'pending_confirmation', $3)
+        ON CONFLICT (list_id, subscriber_id) DO UPDATE SET status = list_subscriptions.status
+        RETURNING status
+        "#,
+        list_id,
+        subscriber_id,
         Utc::now()
     )
-    .execute(&mut **transaction)
+    .fetch_one(&mut **transaction)
     .await?;
-    Ok(subscriber_id)
+    Ok(result.status)
 }
 
+/// The confirmation email is sent once the subscriber and their token are committed.
 #[tracing::instrument(
-    name = "Send a confirmation email to a new subscriber",
-    skip(email_client, new_subscriber, base_url, subscription_token)
+    name = "Write a confirmation email for a new subscriber to the outbox",
+    skip(transaction, new_subscriber, base_url, subscription_token)
 )]
-pub async fn send_confirmation_email(
-    email_client: &EmailClient,
-    new_subscriber: NewSubscriber,
+pub async fn enqueue_confirmation_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    new_subscriber: &NewSubscriber,
     base_url: &str,
     subscription_token: &str,
-) -> Result<(), reqwest::Error> {
+) -> Result<Uuid, sqlx::Error> {
     let confirmation_link =
         format!("{base_url}/subscriptions/confirm?subscription_token={subscription_token}");
     let plain_body = format!(
@@ -173,12 +279,17 @@ pub async fn send_confirmation_email(
         "Welcome to our newsletter!<br />\
         Click <a href=\"{confirmation_link}\">here</a> to confirm your subscription."
     );
-    email_client
-        .send_email(&new_subscriber.email, "Welcome!", &html_body, &plain_body)
-        .await
+    enqueue_email(
+        transaction,
+        &new_subscriber.email,
+        "Welcome!",
+        &html_body,
+        &plain_body,
+    )
+    .await
 }
 
-fn generate_subscription_token() -> String {
+pub fn generate_subscription_token() -> String {
     let mut rng = thread_rng();
     std::iter::repeat_with(|| rng.sample(Alphanumeric))
         .map(char::from)
@@ -193,12 +304,14 @@ fn generate_subscription_token() -> String {
 pub async fn store_token(
     transaction: &mut Transaction<'_, Postgres>,
     subscriber_id: Uuid,
+    list_id: Uuid,
     subscription_token: &str,
 ) -> Result<(), StoreTokenError> {
     sqlx::query!(
-        r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id) VALUES ($1, $2)"#,
+        r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id, list_id) VALUES ($1, $2, $3)"#,
         subscription_token,
-        subscriber_id
+        subscriber_id,
+        list_id
     )
     .execute(&mut **transaction)
     .await
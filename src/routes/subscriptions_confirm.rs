@@ -1,5 +1,13 @@
+use crate::configuration::ConfirmationSettings;
+use crate::routes::AppError;
+use actix_web::http::header::{ContentType, LOCATION};
+use actix_web::http::StatusCode;
 use actix_web::{web, HttpResponse};
-use sqlx::PgPool;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::path::Path;
 use uuid::Uuid;
 
 #[derive(serde::Deserialize)]
@@ -7,29 +15,235 @@ pub struct Parameters {
     subscription_token: String,
 }
 
-#[tracing::instrument(name = "Confirm a pending subscriber", skip(parameters, pool))]
-pub async fn confirm(parameters: web::Query<Parameters>, pool: web::Data<PgPool>) -> HttpResponse {
-    let Ok(id) = get_subscriber_id_from_token(&pool, &parameters.subscription_token).await else {
-        return HttpResponse::InternalServerError().finish();
-    };
-    match id {
-        None => HttpResponse::Unauthorized().finish(),
-        Some(subscriber_id) => {
-            if confirm_subscriber(&pool, subscriber_id).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
-            }
-            HttpResponse::Ok().finish()
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfirmationOutcome {
+    Confirmed,
+    AlreadyConfirmed,
+    UnknownToken,
+    ExpiredToken,
+}
+
+impl ConfirmationOutcome {
+    pub const ALL: [Self; 4] = [
+        Self::Confirmed,
+        Self::AlreadyConfirmed,
+        Self::UnknownToken,
+        Self::ExpiredToken,
+    ];
+
+    /// Used both as the template file name and as the `outcome` query parameter.
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Confirmed => "confirmed",
+            Self::AlreadyConfirmed => "already_confirmed",
+            Self::UnknownToken => "unknown_token",
+            Self::ExpiredToken => "expired_token",
+        }
+    }
+
+    fn status_code(self) -> StatusCode {
+        match self {
+            Self::Confirmed | Self::AlreadyConfirmed => StatusCode::OK,
+            Self::UnknownToken => StatusCode::UNAUTHORIZED,
+            Self::ExpiredToken => StatusCode::GONE,
+        }
+    }
+
+    fn default_page(self) -> String {
+        let (title, message) = match self {
+            Self::Confirmed => (
+                "Subscription confirmed",
+                "Thanks for confirming your subscription, the next issue will be in your inbox.",
+            ),
+            Self::AlreadyConfirmed => (
+                "Already confirmed",
+                "Your subscription was already confirmed, there is nothing else to do.",
+            ),
+            Self::UnknownToken => (
+                "Invalid confirmation link",
+                "We could not find this confirmation link. Please check that you copied it in full.",
+            ),
+            Self::ExpiredToken => (
+                "Confirmation link expired",
+                "This confirmation link has expired. Please subscribe again to receive a new one.",
+            ),
+        };
+        format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>{title}</title>
+</head>
+<body>
+    <h1>{title}</h1>
+    <p>{message}</p>
+</body>
+</html>"#
+        )
+    }
+}
+
+/// The responses to a confirmation link, resolved once at startup and indexed by outcome.
+pub struct ConfirmationPages {
+    token_ttl: chrono::Duration,
+    pages: Vec<String>,
+    redirects: Vec<Option<Url>>,
+}
+
+impl ConfirmationPages {
+    /// Pages found in the templates directory replace the built-in ones.
+    /// # Errors
+    /// Returns an error if a template cannot be read or a redirect is not a valid URL.
+    pub fn load(settings: &ConfirmationSettings) -> Result<Self, std::io::Error> {
+        let pages = ConfirmationOutcome::ALL
+            .iter()
+            .map(|outcome| match &settings.templates_directory {
+                Some(directory) => {
+                    let path = Path::new(directory).join(format!("{}.html", outcome.as_str()));
+                    if path.exists() {
+                        std::fs::read_to_string(path)
+                    } else {
+                        Ok(outcome.default_page())
+                    }
+                }
+                None => Ok(outcome.default_page()),
+            })
+            .collect::<Result<_, _>>()?;
+        let redirects = ConfirmationOutcome::ALL
+            .iter()
+            .map(|outcome| {
+                let redirects = &settings.redirects;
+                let url = match outcome {
+                    ConfirmationOutcome::Confirmed => &redirects.confirmed,
+                    ConfirmationOutcome::AlreadyConfirmed => &redirects.already_confirmed,
+                    ConfirmationOutcome::UnknownToken => &redirects.unknown_token,
+                    ConfirmationOutcome::ExpiredToken => &redirects.expired_token,
+                };
+                url.as_deref()
+                    .map(|url| {
+                        Url::parse(url).map_err(|e| {
+                            std::io::Error::new(
+                                std::io::ErrorKind::InvalidInput,
+                                format!("Invalid confirmation redirect {url}: {e}"),
+                            )
+                        })
+                    })
+                    .transpose()
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            token_ttl: chrono::Duration::hours(settings.token_ttl_hours),
+            pages,
+            redirects,
+        })
+    }
+
+    /// Redirects carry the outcome as a query parameter, e.g. `?outcome=confirmed`.
+    fn respond(&self, outcome: ConfirmationOutcome) -> HttpResponse {
+        let index = outcome as usize;
+        if let Some(url) = &self.redirects[index] {
+            let mut url = url.clone();
+            url.query_pairs_mut()
+                .append_pair("outcome", outcome.as_str());
+            return HttpResponse::SeeOther()
+                .insert_header((LOCATION, url.as_str()))
+                .finish();
         }
+        HttpResponse::build(outcome.status_code())
+            .content_type(ContentType::html())
+            .body(self.pages[index].clone())
     }
 }
 
+struct PendingSubscription {
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    created_at: DateTime<Utc>,
+    list_status: Option<String>,
+}
+
+#[tracing::instrument(name = "Confirm a pending subscriber", skip(parameters, pool, pages))]
+pub async fn confirm(
+    parameters: web::Query<Parameters>,
+    pool: web::Data<PgPool>,
+    pages: web::Data<ConfirmationPages>,
+) -> Result<HttpResponse, AppError> {
+    let subscription = get_subscription_from_token(&pool, &parameters.subscription_token)
+        .await
+        .context("Failed to retrieve the subscription of a confirmation token.")?;
+    let outcome = match subscription {
+        None
+        | Some(PendingSubscription {
+            list_status: None, ..
+        }) => ConfirmationOutcome::UnknownToken,
+        Some(PendingSubscription {
+            list_status: Some(status),
+            ..
+        }) if status == "confirmed" => ConfirmationOutcome::AlreadyConfirmed,
+        Some(subscription) if subscription.created_at + pages.token_ttl < Utc::now() => {
+            ConfirmationOutcome::ExpiredToken
+        }
+        Some(subscription) => {
+            confirm_subscriber(&pool, subscription.subscriber_id, subscription.list_id)
+                .await
+                .context("Failed to confirm a pending subscriber.")?;
+            ConfirmationOutcome::Confirmed
+        }
+    };
+    Ok(pages.respond(outcome))
+}
+
 #[tracing::instrument(name = "Mark subscriber as confirmed", skip(subscriber_id, pool))]
-pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
+pub async fn confirm_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
     sqlx::query!(
         r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1"#,
         subscriber_id,
     )
-    .execute(pool)
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    sqlx::query!(
+        r#"UPDATE list_subscriptions SET status = 'confirmed' WHERE subscriber_id = $1 AND list_id = $2"#,
+        subscriber_id,
+        list_id,
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    schedule_onboarding(&mut transaction, subscriber_id).await?;
+    transaction.commit().await
+}
+
+/// Schedule every step of the onboarding sequence, counting from now. Confirming
+/// another list later does not restart the sequence.
+#[tracing::instrument(name = "Schedule the onboarding sequence", skip(transaction))]
+async fn schedule_onboarding(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO onboarding_deliveries (subscriber_id, step, scheduled_for, status, updated_at)
+        SELECT $1, step, now() + make_interval(days => delay_days), 'scheduled', now()
+        FROM onboarding_emails
+        ON CONFLICT DO NOTHING
+        "#,
+        subscriber_id,
+    )
+    .execute(&mut **transaction)
     .await
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
@@ -38,13 +252,22 @@ pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<()
     Ok(())
 }
 
-#[tracing::instrument(name = "Get subscriber_id from token", skip(subscription_token, pool))]
-pub async fn get_subscriber_id_from_token(
+/// Returns the subscription the token was issued for, along with the current status
+/// of the list subscription if it still exists.
+#[tracing::instrument(name = "Get subscription from token", skip(subscription_token, pool))]
+async fn get_subscription_from_token(
     pool: &PgPool,
     subscription_token: &str,
-) -> Result<Option<Uuid>, sqlx::Error> {
-    let result = sqlx::query!(
-        r#"SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1"#,
+) -> Result<Option<PendingSubscription>, sqlx::Error> {
+    sqlx::query_as!(
+        PendingSubscription,
+        r#"
+        SELECT t.subscriber_id, t.list_id AS "list_id!", t.created_at, ls.status AS "list_status?"
+        FROM subscription_tokens t
+        LEFT JOIN list_subscriptions ls
+            ON ls.subscriber_id = t.subscriber_id AND ls.list_id = t.list_id
+        WHERE t.subscription_token = $1 AND t.purpose = 'subscribe'
+        "#,
         subscription_token,
     )
     .fetch_optional(pool)
@@ -52,6 +275,5 @@ pub async fn get_subscriber_id_from_token(
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
         e
-    })?;
-    Ok(result.map(|r| r.subscriber_id))
+    })
 }
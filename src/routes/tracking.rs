@@ -0,0 +1,84 @@
+use crate::configuration::TrackingSettings;
+use crate::routes::AppError;
+use crate::tracking::TrackingClaims;
+use actix_web::http::header::{self, CacheControl, CacheDirective};
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// A transparent 1x1 GIF.
+const PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+#[tracing::instrument(name = "Track an email open", skip(token, pool, settings))]
+pub async fn track_open(
+    token: web::Path<String>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<TrackingSettings>,
+) -> Result<HttpResponse, AppError> {
+    let Some(claims) = TrackingClaims::verify(&token, &settings.hmac_secret) else {
+        return Err(AppError::NotFound);
+    };
+    if claims.url.is_some() {
+        return Err(AppError::NotFound);
+    }
+    if let Err(e) = store_tracking_event(&pool, &claims, "open").await {
+        tracing::error!("Failed to execute query: {:?}", e);
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("image/gif")
+        .insert_header(CacheControl(vec![
+            CacheDirective::NoStore,
+            CacheDirective::Private,
+        ]))
+        .body(PIXEL))
+}
+
+/// Only links signed while delivering an issue are followed, so this endpoint
+/// cannot be abused as an open redirect.
+#[tracing::instrument(name = "Track a link click", skip(token, pool, settings))]
+pub async fn track_click(
+    token: web::Path<String>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<TrackingSettings>,
+) -> Result<HttpResponse, AppError> {
+    let Some(claims) = TrackingClaims::verify(&token, &settings.hmac_secret) else {
+        return Err(AppError::NotFound);
+    };
+    let Some(url) = claims.url.as_deref() else {
+        return Err(AppError::NotFound);
+    };
+    if let Err(e) = store_tracking_event(&pool, &claims, "click").await {
+        tracing::error!("Failed to execute query: {:?}", e);
+    }
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, url))
+        .finish())
+}
+
+#[tracing::instrument(name = "Store tracking event in the database", skip(pool, claims))]
+async fn store_tracking_event(
+    pool: &PgPool,
+    claims: &TrackingClaims,
+    kind: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO tracking_events (tracking_event_id, newsletter_issue_id, subscriber_id, kind, url, occurred_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        Uuid::new_v4(),
+        claims.newsletter_issue_id,
+        claims.subscriber_id,
+        kind,
+        claims.url,
+        Utc::now()
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
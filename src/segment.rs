@@ -0,0 +1,505 @@
+//! A small filter language used to send an issue to a subset of subscribers.
+//!
+//! ```text
+//! segment    := or
+//! or         := and ("or" and)*
+//! and        := unary ("and" unary)*
+//! unary      := "not" unary | "(" segment ")" | predicate
+//! predicate  := "tag" ("=" | "!=") value
+//!             | "joined" "within" <days> "days"
+//!             | "attribute." <key> ("=" | "!=" | "<" | "<=" | ">" | ">=") value
+//! value      := "quoted string" | number | true | false | bare-word
+//! ```
+//!
+//! e.g. `tag = beta-testers and (joined within 30 days or attribute.plan = "pro")`.
+use crate::domain::SubscriberTag;
+use sqlx::{Postgres, QueryBuilder};
+
+const MAX_LENGTH: usize = 1024;
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug)]
+pub struct Segment {
+    source: String,
+    expression: Expression,
+}
+
+#[derive(Debug, PartialEq)]
+enum Expression {
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    Tag {
+        tag: SubscriberTag,
+        negated: bool,
+    },
+    JoinedWithinDays(i32),
+    Attribute {
+        key: String,
+        comparison: Comparison,
+        value: serde_json::Value,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+}
+
+impl Comparison {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Comparison::Equal => "IS NOT DISTINCT FROM",
+            Comparison::NotEqual => "IS DISTINCT FROM",
+            Comparison::LessThan => "<",
+            Comparison::LessThanOrEqual => "<=",
+            Comparison::GreaterThan => ">",
+            Comparison::GreaterThanOrEqual => ">=",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    OpenParenthesis,
+    CloseParenthesis,
+    Comparison(Comparison),
+    Quoted(String),
+    Word(String),
+}
+
+impl Segment {
+    /// # Errors
+    /// Returns a human-readable message if the input is not a valid segment.
+    pub fn parse(s: &str) -> Result<Segment, String> {
+        if s.len() > MAX_LENGTH {
+            return Err(format!(
+                "A segment cannot be longer than {MAX_LENGTH} characters."
+            ));
+        }
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            position: 0,
+            depth: 0,
+        };
+        let expression = parser.parse_or()?;
+        if let Some(token) = parser.next() {
+            return Err(format!("Unexpected {token:?} in segment."));
+        }
+        Ok(Segment {
+            source: s.to_owned(),
+            expression,
+        })
+    }
+
+    /// Push the segment as a boolean SQL condition on a `subscriptions` row aliased as `s`.
+    /// Every user-provided value is bound as a query parameter.
+    pub fn push_condition(&self, builder: &mut QueryBuilder<'_, Postgres>) {
+        push_expression(&self.expression, builder);
+    }
+}
+
+impl AsRef<str> for Segment {
+    fn as_ref(&self) -> &str {
+        &self.source
+    }
+}
+
+fn push_expression(expression: &Expression, builder: &mut QueryBuilder<'_, Postgres>) {
+    match expression {
+        Expression::And(lhs, rhs) | Expression::Or(lhs, rhs) => {
+            let operator = if matches!(expression, Expression::And(..)) {
+                " AND "
+            } else {
+                " OR "
+            };
+            builder.push("(");
+            push_expression(lhs, builder);
+            builder.push(operator);
+            push_expression(rhs, builder);
+            builder.push(")");
+        }
+        Expression::Not(inner) => {
+            builder.push("NOT (");
+            push_expression(inner, builder);
+            builder.push(")");
+        }
+        Expression::Tag { tag, negated } => {
+            if *negated {
+                builder.push("NOT ");
+            }
+            builder
+                .push("EXISTS (SELECT 1 FROM subscriber_tags t WHERE t.subscriber_id = s.id AND t.tag = ")
+                .push_bind(tag.as_ref().to_owned())
+                .push(")");
+        }
+        Expression::JoinedWithinDays(days) => {
+            builder
+                .push("s.subscribed_at >= now() - make_interval(days => ")
+                .push_bind(*days)
+                .push(")");
+        }
+        Expression::Attribute {
+            key,
+            comparison,
+            value,
+        } => match comparison {
+            Comparison::Equal | Comparison::NotEqual => {
+                builder
+                    .push("s.attributes -> ")
+                    .push_bind(key.clone())
+                    .push(format!(" {} ", comparison.as_sql()))
+                    .push_bind(value.clone());
+            }
+            // Ordering is only meaningful between values of the same JSON type.
+            // A missing attribute never matches, even under `not`.
+            _ => {
+                builder
+                    .push("COALESCE(jsonb_typeof(s.attributes -> ")
+                    .push_bind(key.clone())
+                    .push(") = jsonb_typeof(")
+                    .push_bind(value.clone())
+                    .push(") AND s.attributes -> ")
+                    .push_bind(key.clone())
+                    .push(format!(" {} ", comparison.as_sql()))
+                    .push_bind(value.clone())
+                    .push(", false)");
+            }
+        },
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::OpenParenthesis);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::CloseParenthesis);
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let or_equal = chars.next_if_eq(&'=').is_some();
+                let comparison = match (c, or_equal) {
+                    ('=', false) => Comparison::Equal,
+                    ('!', true) => Comparison::NotEqual,
+                    ('<', false) => Comparison::LessThan,
+                    ('<', true) => Comparison::LessThanOrEqual,
+                    ('>', false) => Comparison::GreaterThan,
+                    ('>', true) => Comparison::GreaterThanOrEqual,
+                    _ => return Err(format!("Unexpected character '{c}' in segment.")),
+                };
+                tokens.push(Token::Comparison(comparison));
+            }
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(escaped) => value.push(escaped),
+                            None => return Err("Unterminated string in segment.".into()),
+                        },
+                        Some(c) => value.push(c),
+                        None => return Err("Unterminated string in segment.".into()),
+                    }
+                }
+                tokens.push(Token::Quoted(value));
+            }
+            c if is_word_character(c) => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(|c| is_word_character(*c)) {
+                    word.push(c);
+                }
+                tokens.push(Token::Word(word));
+            }
+            c => return Err(format!("Unexpected character '{c}' in segment.")),
+        }
+    }
+    Ok(tokens)
+}
+
+fn is_word_character(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '+')
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.tokens.get(self.position) {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), String> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(format!("Expected '{keyword}' in segment."))
+        }
+    }
+
+    fn descend(&mut self) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err("The segment is nested too deeply.".into());
+        }
+        Ok(())
+    }
+
+    fn parse_or(&mut self) -> Result<Expression, String> {
+        let mut lhs = self.parse_and()?;
+        while self.eat_keyword("or") {
+            let rhs = self.parse_and()?;
+            lhs = Expression::Or(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expression, String> {
+        let mut lhs = self.parse_unary()?;
+        while self.eat_keyword("and") {
+            let rhs = self.parse_unary()?;
+            lhs = Expression::And(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, String> {
+        if self.eat_keyword("not") {
+            self.descend()?;
+            let inner = self.parse_unary()?;
+            self.depth -= 1;
+            return Ok(Expression::Not(Box::new(inner)));
+        }
+        if self.tokens.get(self.position) == Some(&Token::OpenParenthesis) {
+            self.position += 1;
+            self.descend()?;
+            let inner = self.parse_or()?;
+            self.depth -= 1;
+            return match self.next() {
+                Some(Token::CloseParenthesis) => Ok(inner),
+                _ => Err("Expected ')' in segment.".into()),
+            };
+        }
+        self.parse_predicate()
+    }
+
+    fn parse_predicate(&mut self) -> Result<Expression, String> {
+        let Some(Token::Word(word)) = self.next() else {
+            return Err("Expected a condition in segment.".into());
+        };
+        if word.eq_ignore_ascii_case("tag") {
+            let negated = match self.next() {
+                Some(Token::Comparison(Comparison::Equal)) => false,
+                Some(Token::Comparison(Comparison::NotEqual)) => true,
+                _ => return Err("Tags can only be compared with '=' or '!='.".into()),
+            };
+            let tag = match self.next() {
+                Some(Token::Word(value) | Token::Quoted(value)) => SubscriberTag::parse(value)?,
+                _ => return Err("Expected a tag in segment.".into()),
+            };
+            Ok(Expression::Tag { tag, negated })
+        } else if word.eq_ignore_ascii_case("joined") {
+            self.expect_keyword("within")?;
+            let days = match self.next() {
+                Some(Token::Word(value)) => value
+                    .parse::<i32>()
+                    .ok()
+                    .filter(|days| (1..=36_500).contains(days)),
+                _ => None,
+            }
+            .ok_or("Expected a number of days between 1 and 36500 in segment.")?;
+            if !self.eat_keyword("days") {
+                self.expect_keyword("day")?;
+            }
+            Ok(Expression::JoinedWithinDays(days))
+        } else if let Some(key) = word.strip_prefix("attribute.") {
+            if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("{key} is not a valid attribute name."));
+            }
+            let Some(Token::Comparison(comparison)) = self.next() else {
+                return Err(format!("Expected a comparison after attribute.{key}."));
+            };
+            let value = match self.next() {
+                Some(Token::Quoted(value)) => serde_json::Value::String(value),
+                Some(Token::Word(value)) => parse_bare_value(value),
+                _ => return Err(format!("Expected a value to compare attribute.{key} with.")),
+            };
+            Ok(Expression::Attribute {
+                key: key.to_owned(),
+                comparison,
+                value,
+            })
+        } else {
+            Err(format!("Unknown condition '{word}' in segment."))
+        }
+    }
+}
+
+fn parse_bare_value(value: String) -> serde_json::Value {
+    if value.eq_ignore_ascii_case("true") {
+        return serde_json::Value::Bool(true);
+    }
+    if value.eq_ignore_ascii_case("false") {
+        return serde_json::Value::Bool(false);
+    }
+    match serde_json::from_str::<serde_json::Number>(&value) {
+        Ok(number) => serde_json::Value::Number(number),
+        Err(_) => serde_json::Value::String(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Comparison, Expression, Segment};
+    use crate::domain::SubscriberTag;
+    use claims::{assert_err, assert_ok};
+    use sqlx::{Postgres, QueryBuilder};
+
+    fn tag(tag: &str) -> Expression {
+        Expression::Tag {
+            tag: SubscriberTag::parse(tag.to_string()).unwrap(),
+            negated: false,
+        }
+    }
+
+    fn sql(segment: &str) -> String {
+        let segment = Segment::parse(segment).unwrap();
+        let mut builder = QueryBuilder::<Postgres>::new("");
+        segment.push_condition(&mut builder);
+        builder.sql().to_owned()
+    }
+
+    #[test]
+    fn a_tag_condition_is_parsed() {
+        let segment = assert_ok!(Segment::parse("tag = beta-testers"));
+        assert_eq!(segment.expression, tag("beta-testers"));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let segment = assert_ok!(Segment::parse("tag = a or tag = b and tag = c"));
+        assert_eq!(
+            segment.expression,
+            Expression::Or(
+                Box::new(tag("a")),
+                Box::new(Expression::And(Box::new(tag("b")), Box::new(tag("c"))))
+            )
+        );
+    }
+
+    #[test]
+    fn parentheses_and_negation_are_supported() {
+        let segment = assert_ok!(Segment::parse("NOT (tag = \"a\" OR tag = b)"));
+        assert_eq!(
+            segment.expression,
+            Expression::Not(Box::new(Expression::Or(
+                Box::new(tag("a")),
+                Box::new(tag("b"))
+            )))
+        );
+    }
+
+    #[test]
+    fn attribute_values_are_typed() {
+        let segment = assert_ok!(Segment::parse("attribute.age >= 18"));
+        assert_eq!(
+            segment.expression,
+            Expression::Attribute {
+                key: "age".into(),
+                comparison: Comparison::GreaterThanOrEqual,
+                value: serde_json::json!(18),
+            }
+        );
+        let segment = assert_ok!(Segment::parse("attribute.vip = true"));
+        assert!(matches!(
+            segment.expression,
+            Expression::Attribute {
+                value: serde_json::Value::Bool(true),
+                ..
+            }
+        ));
+        let segment = assert_ok!(Segment::parse(r#"attribute.plan != "pro plan""#));
+        assert!(matches!(
+            segment.expression,
+            Expression::Attribute { value: serde_json::Value::String(ref s), .. } if s == "pro plan"
+        ));
+    }
+
+    #[test]
+    fn joined_within_is_parsed() {
+        let segment = assert_ok!(Segment::parse("joined within 30 days"));
+        assert_eq!(segment.expression, Expression::JoinedWithinDays(30));
+    }
+
+    #[test]
+    fn invalid_segments_are_rejected() {
+        for segment in [
+            "",
+            "tag",
+            "tag > a",
+            "tag = 'quoted'",
+            "tag = a and",
+            "(tag = a",
+            "tag = a)",
+            "joined within many days",
+            "joined within 0 days",
+            "attribute. = 1",
+            "attribute.na-me = 1",
+            "email = x",
+            "\"unterminated",
+        ] {
+            assert_err!(Segment::parse(segment), "{segment} should be rejected");
+        }
+    }
+
+    #[test]
+    fn deeply_nested_segments_are_rejected() {
+        let segment = format!("{}tag = a{}", "(".repeat(40), ")".repeat(40));
+        assert_err!(Segment::parse(&segment));
+    }
+
+    #[test]
+    fn values_are_bound_as_parameters() {
+        assert_eq!(
+            sql("tag = a and not attribute.plan = \"x'; DROP TABLE subscriptions; --\""),
+            "(EXISTS (SELECT 1 FROM subscriber_tags t WHERE t.subscriber_id = s.id AND t.tag = $1) \
+             AND NOT (s.attributes -> $2 IS NOT DISTINCT FROM $3))"
+        );
+        assert_eq!(
+            sql("tag != a or joined within 7 days"),
+            "(NOT EXISTS (SELECT 1 FROM subscriber_tags t WHERE t.subscriber_id = s.id AND t.tag = $1) \
+             OR s.subscribed_at >= now() - make_interval(days => $2))"
+        );
+    }
+}
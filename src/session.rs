@@ -0,0 +1,119 @@
+use chrono::{DateTime, Utc};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use sqlx::{Executor, PgPool, Postgres};
+use uuid::Uuid;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionStatus {
+    /// The password was verified, the second factor of the login was not yet.
+    PendingSecondFactor,
+    Active,
+}
+
+impl SessionStatus {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SessionStatus::PendingSecondFactor => "pending_second_factor",
+            SessionStatus::Active => "active",
+        }
+    }
+}
+
+pub struct Session {
+    pub session_id: Uuid,
+    pub user_id: Uuid,
+}
+
+/// A random token, long enough that it cannot be guessed.
+pub(crate) fn generate_token() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(32)
+        .collect()
+}
+
+/// Tokens are only stored hashed, so that a leaked table cannot be used to log in.
+pub(crate) fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Start a session for `user_id`. Returns its token, which is not stored anywhere, and
+/// the time at which it expires.
+#[tracing::instrument(name = "Create a session", skip(executor))]
+pub async fn create_session<'e>(
+    executor: impl Executor<'e, Database = Postgres>,
+    user_id: Uuid,
+    status: SessionStatus,
+    ttl: chrono::Duration,
+) -> Result<(String, DateTime<Utc>), sqlx::Error> {
+    let token = generate_token();
+    let now = Utc::now();
+    let expires_at = now + ttl;
+    sqlx::query!(
+        r#"
+        INSERT INTO user_sessions (session_id, user_id, token_hash, status, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        Uuid::new_v4(),
+        user_id,
+        hash_token(&token),
+        status.as_str(),
+        now,
+        expires_at,
+    )
+    .execute(executor)
+    .await?;
+    Ok((token, expires_at))
+}
+
+/// The unexpired session with `status` identified by `token`, if any.
+#[tracing::instrument(name = "Find a session", skip(pool, token))]
+pub async fn find_session(
+    pool: &PgPool,
+    token: &str,
+    status: SessionStatus,
+) -> Result<Option<Session>, sqlx::Error> {
+    sqlx::query_as!(
+        Session,
+        r#"
+        SELECT session_id, user_id
+        FROM user_sessions
+        WHERE token_hash = $1 AND status = $2 AND expires_at > now()
+        "#,
+        hash_token(token),
+        status.as_str(),
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+#[tracing::instrument(name = "Delete a session", skip(executor))]
+pub async fn delete_session<'e>(
+    executor: impl Executor<'e, Database = Postgres>,
+    session_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM user_sessions WHERE session_id = $1"#,
+        session_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Log `user_id` out everywhere, including logins waiting for their second factor.
+#[tracing::instrument(name = "Delete the sessions of a user", skip(executor))]
+pub async fn delete_user_sessions<'e>(
+    executor: impl Executor<'e, Database = Postgres>,
+    user_id: Uuid,
+) -> Result<u64, sqlx::Error> {
+    let deleted = sqlx::query!(r#"DELETE FROM user_sessions WHERE user_id = $1"#, user_id)
+        .execute(executor)
+        .await?
+        .rows_affected();
+    Ok(deleted)
+}
@@ -1,17 +1,38 @@
-use crate::configuration::{DatabaseSettings, Settings};
+use crate::authentication::PasswordPolicy;
+use crate::bot_protection::BotProtection;
+use crate::configuration::{
+    DatabaseSettings, Environment, FeedSettings, PasswordResetSettings, SessionSettings, Settings,
+    TrackingSettings, TwoFactorSettings,
+};
 use crate::email_client::EmailClient;
-use crate::routes::{confirm, health_check, publish_newsletter, subscribe};
-use actix_web::dev::Server;
+use crate::login_throttle::LoginThrottle;
+use crate::mailbox::Mailbox;
+use crate::rate_limit::{sweep_until_stopped, RateLimitByIp, RateLimiter};
+use crate::routes::{
+    add_subscriber_tag, assign_role, atom_feed, confirm, confirm_email_change,
+    confirm_two_factor_enrolment, create_api_key, create_draft, create_list,
+    delete_onboarding_email, dev_mailbox_routes, forgot_password, form_token, get_api_keys,
+    get_audit_events, get_draft, get_drafts, get_lists, get_onboarding_emails, get_subscriber,
+    get_users, health_check, issue_delivery_report, issue_page, issue_stats, json_feed, login,
+    login_second_factor, newsletter_dry_run, preferences_page, publish_draft, publish_newsletter,
+    put_onboarding_email, remove_subscriber_tag, reset_password, reset_password_page,
+    revoke_api_key, rss_feed, set_user_email, start_two_factor_enrolment, subscribe, track_click,
+    track_open, update_draft, update_preferences, update_subscriber_attributes, with_request_id,
+    AppError, ConfirmationPages,
+};
+use actix_web::dev::{Server, Service};
 use actix_web::web::Data;
-use actix_web::{web, App, HttpServer};
+use actix_web::{web, App, HttpMessage, HttpServer};
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
 use std::net::TcpListener;
-use tracing_actix_web::TracingLogger;
+use std::sync::{Arc, Weak};
+use tracing_actix_web::{RequestId, TracingLogger};
 
 pub struct Application {
     port: u16,
     server: Server,
+    rate_limiter: Weak<RateLimiter>,
 }
 
 pub struct ApplicationBaseUrl(pub String);
@@ -19,7 +40,7 @@ pub struct ApplicationBaseUrl(pub String);
 #[must_use]
 pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
     PgPoolOptions::new()
-        .acquire_timeout(std::time::Duration::from_secs(2))
+        .acquire_timeout(configuration.acquire_timeout())
         .connect_lazy_with(configuration.with_db())
 }
 
@@ -33,23 +54,34 @@ impl Application {
     pub fn build(configuration: Settings) -> Result<Self, std::io::Error> {
         let connection_pool = get_connection_pool(&configuration.database);
 
-        let sender_email = configuration
-            .email_client
-            .sender()
-            .expect("Invalid sender email address.");
-
-        let timeout = configuration.email_client.timeout();
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email,
-            configuration.email_client.authorization_token,
-            timeout,
-        );
+        // The mail catcher shows every email sent: it never leaves development.
+        let dev_mailbox = match configuration.application.environment {
+            Environment::Local => configuration.email_client.mailbox(),
+            Environment::Production => None,
+        };
+        let email_client = configuration.email_client.client();
 
         let address = format!(
             "{}:{}",
             configuration.application.host, configuration.application.port
         );
+        let confirmation_pages = ConfirmationPages::load(&configuration.confirmation)?;
+        let login_throttle = LoginThrottle::new(
+            configuration.login_throttle,
+            configuration.rate_limit.trusted_proxies.clone(),
+        );
+        let rate_limiter = Data::new(RateLimiter::new(
+            configuration.rate_limit,
+            connection_pool.clone(),
+        ));
+        let password_policy =
+            PasswordPolicy::new(&configuration.password_hashing).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid password hashing parameters: {e}"),
+                )
+            })?;
+        let bot_protection = BotProtection::new(configuration.bot_protection);
         let listener = TcpListener::bind(address)?;
         let port = listener.local_addr().unwrap().port();
         let server = run(
@@ -57,9 +89,24 @@ impl Application {
             connection_pool,
             email_client,
             configuration.application.base_url,
+            configuration.feed,
+            configuration.tracking,
+            confirmation_pages,
+            rate_limiter.clone(),
+            bot_protection,
+            login_throttle,
+            password_policy,
+            configuration.sessions,
+            configuration.two_factor,
+            configuration.password_reset,
+            dev_mailbox,
         )?;
 
-        Ok(Self { port, server })
+        Ok(Self {
+            port,
+            server,
+            rate_limiter: Arc::downgrade(&rate_limiter.into_inner()),
+        })
     }
 
     #[must_use]
@@ -70,6 +117,7 @@ impl Application {
     /// # Errors
     /// This function returns `std::io::Error` if the server fails to start.
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        tokio::spawn(sweep_until_stopped(self.rate_limiter));
         self.server.await
     }
 }
@@ -78,27 +126,203 @@ impl Application {
 ///
 /// This function returns `std::io::Error` if the TCP listener provided as an argument
 /// fails to bind to the address.
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     listener: TcpListener,
     db_pool: PgPool,
     email_client: EmailClient,
     base_url: String,
+    feed_settings: FeedSettings,
+    tracking_settings: TrackingSettings,
+    confirmation_pages: ConfirmationPages,
+    rate_limiter: Data<RateLimiter>,
+    bot_protection: BotProtection,
+    login_throttle: LoginThrottle,
+    password_policy: PasswordPolicy,
+    session_settings: SessionSettings,
+    two_factor_settings: TwoFactorSettings,
+    password_reset_settings: PasswordResetSettings,
+    dev_mailbox: Option<Mailbox>,
 ) -> Result<Server, std::io::Error> {
     let db_pool = Data::new(db_pool);
     let email_client = Data::new(email_client);
     let base_url = Data::new(ApplicationBaseUrl(base_url));
+    let feed_settings = Data::new(feed_settings);
+    let tracking_settings = Data::new(tracking_settings);
+    let confirmation_pages = Data::new(confirmation_pages);
+    let bot_protection = Data::new(bot_protection);
+    let login_throttle = Data::new(login_throttle);
+    let password_policy = Data::new(password_policy);
+    let session_settings = Data::new(session_settings);
+    let two_factor_settings = Data::new(two_factor_settings);
+    let password_reset_settings = Data::new(password_reset_settings);
+    let dev_mailbox = dev_mailbox.map(Data::new);
     let server = HttpServer::new(move || {
         App::new()
+            .wrap_fn(|request, service| {
+                let request_id = request.extensions().get::<RequestId>().copied();
+                let response = service.call(request);
+                async move {
+                    let response = response.await?.map_into_boxed_body();
+                    Ok(with_request_id(response, request_id))
+                }
+            })
             .wrap(TracingLogger::default())
             .route("/health_check", web::get().to(health_check))
-            .route("/subscriptions", web::post().to(subscribe))
-            .route("/subscriptions/confirm", web::get().to(confirm))
+            .service(
+                web::resource("/subscriptions")
+                    .wrap(RateLimitByIp::new(
+                        rate_limiter.clone(),
+                        "subscribe:ip",
+                        rate_limiter.subscribe_limits().per_ip,
+                    ))
+                    .route(web::post().to(subscribe)),
+            )
+            .route("/subscriptions/form-token", web::get().to(form_token))
+            .service(
+                web::resource("/subscriptions/confirm")
+                    .wrap(RateLimitByIp::new(
+                        rate_limiter.clone(),
+                        "confirm:ip",
+                        rate_limiter.confirm_limits().per_ip,
+                    ))
+                    .route(web::get().to(confirm)),
+            )
+            .route(
+                "/preferences/email/confirm",
+                web::get().to(confirm_email_change),
+            )
+            .route("/preferences/{token}", web::get().to(preferences_page))
+            .route("/preferences/{token}", web::post().to(update_preferences))
+            .route("/login", web::post().to(login))
+            .route("/login/second-factor", web::post().to(login_second_factor))
+            .service(
+                web::resource("/login/forgot-password")
+                    .wrap(RateLimitByIp::new(
+                        rate_limiter.clone(),
+                        "password_reset:ip",
+                        rate_limiter.password_reset_limits().per_ip,
+                    ))
+                    .route(web::post().to(forgot_password)),
+            )
+            // Only submitting a new password is rate limited, not opening the emailed link.
+            .route("/login/reset-password", web::get().to(reset_password_page))
+            .service(
+                web::resource("/login/reset-password")
+                    .wrap(RateLimitByIp::new(
+                        rate_limiter.clone(),
+                        "reset_password:ip",
+                        rate_limiter.password_reset_limits().per_ip,
+                    ))
+                    .route(web::post().to(reset_password)),
+            )
             .route("/newsletters", web::post().to(publish_newsletter))
+            .route("/newsletters/dry-run", web::post().to(newsletter_dry_run))
+            .route("/issues/{newsletter_issue_id}", web::get().to(issue_page))
+            .route(
+                "/admin/issues/{newsletter_issue_id}/report",
+                web::get().to(issue_delivery_report),
+            )
+            .route(
+                "/admin/issues/{newsletter_issue_id}/stats",
+                web::get().to(issue_stats),
+            )
+            .route("/admin/api-keys", web::get().to(get_api_keys))
+            .route("/admin/api-keys", web::post().to(create_api_key))
+            .route(
+                "/admin/api-keys/{api_key_id}",
+                web::delete().to(revoke_api_key),
+            )
+            .route("/admin/audit-events", web::get().to(get_audit_events))
+            .route("/admin/drafts", web::get().to(get_drafts))
+            .route("/admin/drafts", web::post().to(create_draft))
+            .route(
+                "/admin/drafts/{newsletter_draft_id}",
+                web::get().to(get_draft),
+            )
+            .route(
+                "/admin/drafts/{newsletter_draft_id}",
+                web::put().to(update_draft),
+            )
+            .route(
+                "/admin/drafts/{newsletter_draft_id}/publish",
+                web::post().to(publish_draft),
+            )
+            .route("/admin/lists", web::get().to(get_lists))
+            .route("/admin/lists", web::post().to(create_list))
+            .route(
+                "/admin/two-factor",
+                web::post().to(start_two_factor_enrolment),
+            )
+            .route(
+                "/admin/two-factor/confirm",
+                web::post().to(confirm_two_factor_enrolment),
+            )
+            .route("/admin/onboarding", web::get().to(get_onboarding_emails))
+            .route(
+                "/admin/onboarding/{step}",
+                web::put().to(put_onboarding_email),
+            )
+            .route(
+                "/admin/onboarding/{step}",
+                web::delete().to(delete_onboarding_email),
+            )
+            .route(
+                "/admin/subscribers/{subscriber_id}",
+                web::get().to(get_subscriber),
+            )
+            .route(
+                "/admin/subscribers/{subscriber_id}/tags/{tag}",
+                web::put().to(add_subscriber_tag),
+            )
+            .route(
+                "/admin/subscribers/{subscriber_id}/tags/{tag}",
+                web::delete().to(remove_subscriber_tag),
+            )
+            .route(
+                "/admin/subscribers/{subscriber_id}/attributes",
+                web::patch().to(update_subscriber_attributes),
+            )
+            .route("/admin/users", web::get().to(get_users))
+            .route("/admin/users/{user_id}/role", web::put().to(assign_role))
+            .route(
+                "/admin/users/{user_id}/email",
+                web::put().to(set_user_email),
+            )
+            .route("/o/{token}", web::get().to(track_open))
+            .route("/r/{token}", web::get().to(track_click))
+            .route("/feed.rss", web::get().to(rss_feed))
+            .route("/feed.atom", web::get().to(atom_feed))
+            .route("/feed.json", web::get().to(json_feed))
+            .configure(|config| {
+                if let Some(mailbox) = &dev_mailbox {
+                    dev_mailbox_routes(config, mailbox.clone());
+                }
+            })
+            .app_data(web::JsonConfig::default().error_handler(|e, _| invalid_payload(e)))
+            .app_data(web::FormConfig::default().error_handler(|e, _| invalid_payload(e)))
+            .app_data(web::QueryConfig::default().error_handler(|e, _| invalid_payload(e)))
+            .app_data(web::PathConfig::default().error_handler(|_, _| AppError::NotFound.into()))
             .app_data(db_pool.clone())
             .app_data(email_client.clone())
             .app_data(base_url.clone())
+            .app_data(feed_settings.clone())
+            .app_data(tracking_settings.clone())
+            .app_data(confirmation_pages.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(bot_protection.clone())
+            .app_data(login_throttle.clone())
+            .app_data(password_policy.clone())
+            .app_data(session_settings.clone())
+            .app_data(two_factor_settings.clone())
+            .app_data(password_reset_settings.clone())
     })
     .listen(listener)?
     .run();
     Ok(server)
 }
+
+/// Payloads that cannot be deserialized are reported like any other invalid request.
+fn invalid_payload(e: impl std::fmt::Display) -> actix_web::Error {
+    AppError::ValidationError(e.to_string()).into()
+}
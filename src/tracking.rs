@@ -0,0 +1,233 @@
+use crate::utils::{escape_html, hmac_sha256};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::Mac;
+use secrecy::Secret;
+use uuid::Uuid;
+
+/// What a tracking token vouches for: the recipient of an issue and, for clicks,
+/// the link they followed.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Eq)]
+pub struct TrackingClaims {
+    #[serde(rename = "i")]
+    pub newsletter_issue_id: Uuid,
+    #[serde(rename = "s")]
+    pub subscriber_id: Uuid,
+    #[serde(rename = "u", default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+impl TrackingClaims {
+    /// Serialize the claims into an URL-safe token, signed with `secret`.
+    #[must_use]
+    pub fn sign(&self, secret: &Secret<String>) -> String {
+        let payload = URL_SAFE_NO_PAD
+            .encode(serde_json::to_vec(self).expect("Tracking claims are always serializable."));
+        let signature = URL_SAFE_NO_PAD.encode(
+            hmac_sha256(secret, payload.as_bytes())
+                .finalize()
+                .into_bytes(),
+        );
+        format!("{payload}.{signature}")
+    }
+
+    /// Returns `None` if the token is malformed or its signature does not match.
+    #[must_use]
+    pub fn verify(token: &str, secret: &Secret<String>) -> Option<Self> {
+        let (payload, signature) = token.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        hmac_sha256(secret, payload.as_bytes())
+            .verify_slice(&signature)
+            .ok()?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+        serde_json::from_slice(&payload).ok()
+    }
+}
+
+/// Rewrites the HTML body of an issue for a specific recipient.
+pub struct IssueTracker<'a> {
+    pub base_url: &'a str,
+    pub secret: &'a Secret<String>,
+    pub newsletter_issue_id: Uuid,
+    pub track_opens: bool,
+    pub track_clicks: bool,
+}
+
+impl IssueTracker<'_> {
+    #[must_use]
+    pub fn personalize(&self, html: &str, subscriber_id: Uuid) -> String {
+        let base_url = self.base_url.trim_end_matches('/');
+        let mut html = if self.track_clicks {
+            rewrite_links(html, |url| {
+                let claims = TrackingClaims {
+                    newsletter_issue_id: self.newsletter_issue_id,
+                    subscriber_id,
+                    url: Some(url.to_owned()),
+                };
+                format!("{base_url}/r/{}", claims.sign(self.secret))
+            })
+        } else {
+            html.to_owned()
+        };
+        if self.track_opens {
+            let claims = TrackingClaims {
+                newsletter_issue_id: self.newsletter_issue_id,
+                subscriber_id,
+                url: None,
+            };
+            let pixel = format!(
+                r#"<img src="{base_url}/o/{}" width="1" height="1" alt="" />"#,
+                claims.sign(self.secret)
+            );
+            match html.to_ascii_lowercase().rfind("</body>") {
+                Some(position) => html.insert_str(position, &pixel),
+                None => html.push_str(&pixel),
+            }
+        }
+        html
+    }
+}
+
+/// Replace the target of every absolute `http(s)` link found in an `href` attribute.
+fn rewrite_links(html: &str, rewrite: impl Fn(&str) -> String) -> String {
+    const ATTRIBUTE: &str = "href=";
+    // ASCII lowercasing preserves byte offsets, so indices are valid in both strings.
+    let lowercase = html.to_ascii_lowercase();
+    let mut output = String::with_capacity(html.len());
+    let mut cursor = 0;
+    let mut search_from = 0;
+    while let Some(offset) = lowercase[search_from..].find(ATTRIBUTE) {
+        let attribute_start = search_from + offset;
+        let value_start = attribute_start + ATTRIBUTE.len();
+        search_from = value_start;
+        let preceded_by_whitespace = html[..attribute_start]
+            .chars()
+            .next_back()
+            .is_some_and(char::is_whitespace);
+        let Some(quote) = html[value_start..]
+            .chars()
+            .next()
+            .filter(|c| *c == '"' || *c == '\'')
+        else {
+            continue;
+        };
+        let Some(length) = html[value_start + 1..].find(quote) else {
+            continue;
+        };
+        let value_end = value_start + 1 + length;
+        let url = unescape_attribute(&html[value_start + 1..value_end]);
+        let is_absolute = lowercase[value_start + 1..value_end].starts_with("http://")
+            || lowercase[value_start + 1..value_end].starts_with("https://");
+        if preceded_by_whitespace && is_absolute {
+            output.push_str(&html[cursor..=value_start]);
+            output.push_str(&escape_html(&rewrite(&url)));
+            cursor = value_end;
+        }
+        search_from = value_end;
+    }
+    output.push_str(&html[cursor..]);
+    output
+}
+
+fn unescape_attribute(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rewrite_links, IssueTracker, TrackingClaims};
+    use claims::{assert_none, assert_some_eq};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn secret() -> Secret<String> {
+        Secret::new("a-very-secret-key".to_string())
+    }
+
+    fn claims() -> TrackingClaims {
+        TrackingClaims {
+            newsletter_issue_id: Uuid::new_v4(),
+            subscriber_id: Uuid::new_v4(),
+            url: Some("https://example.com/?a=1&b=2".to_string()),
+        }
+    }
+
+    #[test]
+    fn a_signed_token_is_verified() {
+        let claims = claims();
+        let token = claims.sign(&secret());
+        assert_some_eq!(TrackingClaims::verify(&token, &secret()), claims);
+    }
+
+    #[test]
+    fn a_token_signed_with_another_key_is_rejected() {
+        let token = claims().sign(&Secret::new("another-key".to_string()));
+        assert_none!(TrackingClaims::verify(&token, &secret()));
+    }
+
+    #[test]
+    fn a_tampered_token_is_rejected() {
+        let token = claims().sign(&secret());
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = TrackingClaims {
+            url: Some("https://evil.example.com".to_string()),
+            ..claims()
+        }
+        .sign(&secret());
+        let (forged_payload, _) = forged.split_once('.').unwrap();
+        assert_none!(TrackingClaims::verify(
+            &format!("{forged_payload}.{signature}"),
+            &secret()
+        ));
+    }
+
+    #[test]
+    fn absolute_links_are_rewritten() {
+        let html = r#"<p><a href="https://example.com/?a=1&amp;b=2">link</a> <A HREF='http://example.com'>other</A></p>"#;
+        let rewritten = rewrite_links(html, |url| format!("https://t.example/{}", url.len()));
+        assert_eq!(
+            rewritten,
+            r#"<p><a href="https://t.example/28">link</a> <A HREF='https://t.example/18'>other</A></p>"#
+        );
+    }
+
+    #[test]
+    fn relative_and_non_http_links_are_left_untouched() {
+        let html = r##"<a href="#top">top</a><a href="mailto:x@example.com">mail</a><a data-href="https://example.com">x</a>"##;
+        assert_eq!(rewrite_links(html, |_| unreachable!()), html);
+    }
+
+    #[test]
+    fn the_open_pixel_is_inserted_before_the_end_of_the_body() {
+        let secret = secret();
+        let tracker = IssueTracker {
+            base_url: "https://newsletter.example",
+            secret: &secret,
+            newsletter_issue_id: Uuid::new_v4(),
+            track_opens: true,
+            track_clicks: false,
+        };
+        let html = tracker.personalize("<html><body><p>Hi</p></body></html>", Uuid::new_v4());
+        assert!(html.starts_with(r#"<html><body><p>Hi</p><img src="https://newsletter.example/o/"#));
+        assert!(html.ends_with("</body></html>"));
+    }
+
+    #[test]
+    fn nothing_changes_when_tracking_is_disabled() {
+        let secret = secret();
+        let tracker = IssueTracker {
+            base_url: "https://newsletter.example",
+            secret: &secret,
+            newsletter_issue_id: Uuid::new_v4(),
+            track_opens: false,
+            track_clicks: false,
+        };
+        let html = r#"<p><a href="https://example.com">link</a></p>"#;
+        assert_eq!(tracker.personalize(html, Uuid::new_v4()), html);
+    }
+}
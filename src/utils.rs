@@ -0,0 +1,49 @@
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+
+/// Escape the characters that carry meaning in HTML and XML text or attribute
+/// values, so that user-provided content can be embedded verbatim.
+#[must_use]
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// An HMAC-SHA256 of `payload`, to sign or verify tokens with `secret`.
+#[must_use]
+pub fn hmac_sha256(secret: &Secret<String>, payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.expose_secret().as_bytes())
+        .expect("HMAC can take a key of any size.");
+    mac.update(payload);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape_html;
+
+    #[test]
+    fn markup_characters_are_escaped() {
+        let escaped = escape_html(r#"<a href="x?a=1&b='2'">link</a>"#);
+        assert_eq!(
+            escaped,
+            "&lt;a href=&quot;x?a=1&amp;b=&#39;2&#39;&quot;&gt;link&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn plain_text_is_left_untouched() {
+        assert_eq!(escape_html("Ursula Le Guin"), "Ursula Le Guin");
+    }
+}
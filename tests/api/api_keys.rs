@@ -0,0 +1,203 @@
+use crate::helpers::{spawn_app, TestApp};
+use uuid::Uuid;
+
+async fn create_api_key(app: &TestApp, body: serde_json::Value) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/admin/api-keys", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+/// Create a key granting `scopes`, returning the key and its id.
+async fn api_key(app: &TestApp, scopes: &[&str]) -> (String, String) {
+    let body: serde_json::Value =
+        create_api_key(app, serde_json::json!({ "name": "CI", "scopes": scopes }))
+            .await
+            .json()
+            .await
+            .unwrap();
+    (
+        body["key"].as_str().unwrap().to_owned(),
+        body["api_key_id"].as_str().unwrap().to_owned(),
+    )
+}
+
+async fn get_with_key(app: &TestApp, path: &str, key: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("{}{path}", &app.address))
+        .bearer_auth(key)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn a_new_key_is_shown_once() {
+    let app = spawn_app().await;
+
+    let response = create_api_key(
+        &app,
+        serde_json::json!({
+            "name": "CI",
+            "scopes": ["newsletters:publish"],
+            "expires_in_days": 90,
+        }),
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 201);
+    let created: serde_json::Value = response.json().await.unwrap();
+    let key = created["key"].as_str().unwrap();
+    assert!(key.starts_with(&format!(
+        "nlk_{}_",
+        created["lookup_prefix"].as_str().unwrap()
+    )));
+    assert!(created["expires_at"].is_string());
+
+    let keys: serde_json::Value = reqwest::Client::new()
+        .get(format!("{}/admin/api-keys", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(keys.as_array().unwrap().len(), 1);
+    assert_eq!(keys[0]["name"], "CI");
+    assert_eq!(
+        keys[0]["scopes"],
+        serde_json::json!(["newsletters:publish"])
+    );
+    assert!(keys[0].get("key").is_none());
+}
+
+#[tokio::test]
+async fn keys_can_only_be_granted_known_scopes() {
+    let app = spawn_app().await;
+
+    for scopes in [
+        serde_json::json!([]),
+        serde_json::json!(["everything"]),
+        serde_json::json!(["account"]),
+    ] {
+        let response =
+            create_api_key(&app, serde_json::json!({ "name": "CI", "scopes": scopes })).await;
+        assert_eq!(response.status().as_u16(), 400, "Scopes: {scopes}");
+    }
+}
+
+#[tokio::test]
+async fn keys_authenticate_requests_within_their_scopes() {
+    let app = spawn_app().await;
+    let (key, _) = api_key(&app, &["lists:read"]).await;
+
+    let response = get_with_key(&app, "/admin/lists", &key).await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = get_with_key(
+        &app,
+        &format!("/admin/subscribers/{}", Uuid::new_v4()),
+        &key,
+    )
+    .await;
+    assert_eq!(response.status().as_u16(), 403);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["type"], "/problems/forbidden");
+    assert_eq!(
+        body["detail"],
+        "The API key does not grant the subscribers:read scope."
+    );
+}
+
+#[tokio::test]
+async fn keys_can_publish_with_the_publish_scope() {
+    let app = spawn_app().await;
+    let (key, _) = api_key(&app, &["newsletters:publish"]).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .bearer_auth(&key)
+        .json(&serde_json::json!({
+            "title": "Release notes",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn keys_cannot_manage_keys() {
+    let app = spawn_app().await;
+    let (key, _) = api_key(&app, &["lists:read"]).await;
+
+    let response = get_with_key(&app, "/admin/api-keys", &key).await;
+
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[tokio::test]
+async fn revoked_keys_are_rejected() {
+    let app = spawn_app().await;
+    let (key, api_key_id) = api_key(&app, &["lists:read"]).await;
+
+    let response = reqwest::Client::new()
+        .delete(format!("{}/admin/api-keys/{api_key_id}", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 204);
+
+    let response = get_with_key(&app, "/admin/lists", &key).await;
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn expired_keys_are_rejected() {
+    let app = spawn_app().await;
+    let (key, _) = api_key(&app, &["lists:read"]).await;
+    sqlx::query!("UPDATE api_keys SET expires_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = get_with_key(&app, "/admin/lists", &key).await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn tampered_keys_are_rejected() {
+    let app = spawn_app().await;
+    let (key, _) = api_key(&app, &["lists:read"]).await;
+    let last = if key.ends_with('x') { 'y' } else { 'x' };
+    let tampered = format!("{}{last}", &key[..key.len() - 1]);
+
+    let response = get_with_key(&app, "/admin/lists", &tampered).await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn using_a_key_records_when_it_was_last_used() {
+    let app = spawn_app().await;
+    let (key, _) = api_key(&app, &["lists:read"]).await;
+
+    get_with_key(&app, "/admin/lists", &key).await;
+
+    let last_used_at = sqlx::query_scalar!("SELECT last_used_at FROM api_keys")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(last_used_at.is_some());
+}
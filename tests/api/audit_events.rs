@@ -0,0 +1,232 @@
+use crate::helpers::{spawn_app, TestApp, TestUser};
+
+async fn get_audit_events(app: &TestApp, query: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("{}/admin/audit-events?{query}", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn audit_events(app: &TestApp, query: &str) -> serde_json::Value {
+    let response = get_audit_events(app, query).await;
+    assert_eq!(response.status().as_u16(), 200);
+    response.json().await.unwrap()
+}
+
+async fn create_list(app: &TestApp, slug: &str) -> reqwest::Response {
+    app.post_lists(serde_json::json!({ "slug": slug, "name": "A list" }))
+        .await
+}
+
+#[tokio::test]
+async fn publishing_an_issue_is_recorded() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+    let issue: serde_json::Value = response.json().await.unwrap();
+
+    let page = audit_events(&app, "").await;
+    let event = &page["events"][0];
+    assert_eq!(event["action"], "newsletter_issue.publish");
+    assert_eq!(event["actor_user_id"], app.test_user.user_id.to_string());
+    assert!(event["actor_api_key_id"].is_null());
+    assert_eq!(event["target_type"], "newsletter_issue");
+    assert_eq!(event["target_id"], issue["newsletter_issue_id"]);
+    assert!(event["request_id"].is_string());
+    assert_eq!(event["client_ip"], "127.0.0.1");
+    assert_eq!(event["diff"]["after"]["title"], "Newsletter title");
+}
+
+#[tokio::test]
+async fn role_changes_are_recorded_with_the_previous_role() {
+    let app = spawn_app().await;
+    let editor = TestUser::with_role("editor");
+    editor.store(&app.db_pool).await;
+
+    reqwest::Client::new()
+        .put(format!(
+            "{}/admin/users/{}/role",
+            &app.address, editor.user_id
+        ))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .json(&serde_json::json!({ "role": "publisher" }))
+        .send()
+        .await
+        .unwrap();
+
+    let page = audit_events(&app, "action=user.assign_role").await;
+    let event = &page["events"][0];
+    assert_eq!(event["target_id"], editor.user_id.to_string());
+    assert_eq!(
+        event["diff"],
+        serde_json::json!({
+            "before": { "role": "editor" },
+            "after": { "role": "publisher" },
+        })
+    );
+}
+
+#[tokio::test]
+async fn email_changes_are_recorded_with_the_previous_address() {
+    let app = spawn_app().await;
+    let editor = TestUser::with_role("editor");
+    editor.store(&app.db_pool).await;
+
+    reqwest::Client::new()
+        .put(format!(
+            "{}/admin/users/{}/email",
+            &app.address, editor.user_id
+        ))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .json(&serde_json::json!({ "email": "editor@gmail.com" }))
+        .send()
+        .await
+        .unwrap();
+
+    let page = audit_events(&app, "action=user.set_email").await;
+    let event = &page["events"][0];
+    assert_eq!(event["target_id"], editor.user_id.to_string());
+    assert_eq!(
+        event["diff"],
+        serde_json::json!({
+            "before": { "email": null },
+            "after": { "email": "editor@gmail.com" },
+        })
+    );
+}
+
+#[tokio::test]
+async fn actions_through_an_api_key_record_the_key() {
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let body: serde_json::Value = client
+        .post(format!("{}/admin/api-keys", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .json(&serde_json::json!({ "name": "CI", "scopes": ["lists:write"] }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    client
+        .post(format!("{}/admin/lists", &app.address))
+        .bearer_auth(body["key"].as_str().unwrap())
+        .json(&serde_json::json!({ "slug": "weekly", "name": "Weekly" }))
+        .send()
+        .await
+        .unwrap();
+
+    let page = audit_events(&app, "action=list.create").await;
+    let event = &page["events"][0];
+    assert_eq!(event["actor_user_id"], app.test_user.user_id.to_string());
+    assert_eq!(event["actor_api_key_id"], body["api_key_id"]);
+}
+
+#[tokio::test]
+async fn rejected_actions_are_not_recorded() {
+    let app = spawn_app().await;
+    create_list(&app, "weekly").await;
+
+    let response = create_list(&app, "weekly").await;
+
+    assert_eq!(response.status().as_u16(), 409);
+    let page = audit_events(&app, "action=list.create").await;
+    assert_eq!(page["events"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn events_are_paginated_from_the_most_recent() {
+    let app = spawn_app().await;
+    for slug in ["first", "second", "third"] {
+        create_list(&app, slug).await;
+    }
+
+    let first_page = audit_events(&app, "action=list.create&limit=2").await;
+    let slugs: Vec<_> = first_page["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|event| event["diff"]["after"]["slug"].as_str().unwrap())
+        .collect();
+    assert_eq!(slugs, ["third", "second"]);
+
+    let cursor = first_page["next_cursor"].as_i64().unwrap();
+    let last_page =
+        audit_events(&app, &format!("action=list.create&limit=2&before={cursor}")).await;
+    assert_eq!(last_page["events"].as_array().unwrap().len(), 1);
+    assert_eq!(last_page["events"][0]["diff"]["after"]["slug"], "first");
+    assert!(last_page["next_cursor"].is_null());
+}
+
+#[tokio::test]
+async fn events_can_be_filtered_by_target() {
+    let app = spawn_app().await;
+    let created: serde_json::Value = create_list(&app, "weekly").await.json().await.unwrap();
+    create_list(&app, "monthly").await;
+
+    let page = audit_events(
+        &app,
+        &format!(
+            "target_type=list&target_id={}",
+            created["list_id"].as_str().unwrap()
+        ),
+    )
+    .await;
+
+    assert_eq!(page["events"].as_array().unwrap().len(), 1);
+    assert_eq!(page["events"][0]["diff"]["after"]["slug"], "weekly");
+}
+
+#[tokio::test]
+async fn out_of_range_limits_are_rejected() {
+    let app = spawn_app().await;
+
+    for limit in [0, 201] {
+        let response = get_audit_events(&app, &format!("limit={limit}")).await;
+        assert_eq!(response.status().as_u16(), 400, "Limit: {limit}");
+    }
+}
+
+#[tokio::test]
+async fn only_admins_read_the_audit_log() {
+    let app = spawn_app().await;
+    let publisher = TestUser::with_role("publisher");
+    publisher.store(&app.db_pool).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/audit-events", &app.address))
+        .basic_auth(&publisher.username, Some(&publisher.password))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[tokio::test]
+async fn the_audit_log_is_append_only() {
+    let app = spawn_app().await;
+    create_list(&app, "weekly").await;
+
+    let updated = sqlx::query!("UPDATE audit_events SET action = 'list.delete'")
+        .execute(&app.db_pool)
+        .await;
+    let deleted = sqlx::query!("DELETE FROM audit_events")
+        .execute(&app.db_pool)
+        .await;
+
+    assert!(updated.is_err());
+    assert!(deleted.is_err());
+}
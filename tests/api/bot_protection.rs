@@ -0,0 +1,188 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use secrecy::Secret;
+use wiremock::matchers::{any, body_string_contains, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use zero2prod::configuration::{CaptchaProvider, CaptchaSettings};
+
+async fn subscriber_count(app: &TestApp) -> i64 {
+    sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+}
+
+async fn form_token(app: &TestApp) -> String {
+    let response = reqwest::get(format!("{}/subscriptions/form-token", app.address))
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    body["form_token"].as_str().unwrap().to_owned()
+}
+
+async fn expect_emails(app: &TestApp, expected: u64) {
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(expected)
+        .mount(&app.email_server)
+        .await;
+}
+
+#[tokio::test]
+async fn filled_honeypots_are_silently_dropped() {
+    let app = spawn_app().await;
+    expect_emails(&app, 0).await;
+
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&website=http%3A%2F%2Fspam.example"
+                .into(),
+        )
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_count(&app).await, 0);
+}
+
+#[tokio::test]
+async fn empty_honeypots_are_accepted() {
+    let app = spawn_app().await;
+    expect_emails(&app, 1).await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&website=".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_count(&app).await, 1);
+}
+
+#[tokio::test]
+async fn forms_without_a_token_are_accepted_by_default() {
+    let app = spawn_app().await;
+    expect_emails(&app, 1).await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_count(&app).await, 1);
+}
+
+#[tokio::test]
+async fn forms_submitted_too_quickly_are_silently_dropped() {
+    let app = spawn_app_with(|c| c.bot_protection.min_submit_seconds = Some(1)).await;
+    expect_emails(&app, 1).await;
+    let token = form_token(&app).await;
+    let body = serde_json::json!({
+        "name": "le guin",
+        "email": "ursula_le_guin@gmail.com",
+        "form_token": token,
+    });
+
+    let too_quick = app.post_subscriptions_json(body.clone()).await;
+    assert_eq!(too_quick.status().as_u16(), 200);
+    assert_eq!(subscriber_count(&app).await, 0);
+
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let response = app.post_subscriptions_json(body).await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_count(&app).await, 1);
+}
+
+#[tokio::test]
+async fn forms_without_a_genuine_token_are_silently_dropped_when_required() {
+    let app = spawn_app_with(|c| c.bot_protection.min_submit_seconds = Some(0)).await;
+    expect_emails(&app, 0).await;
+
+    let missing = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let forged = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&form_token=1.forged".into(),
+        )
+        .await;
+
+    assert_eq!(missing.status().as_u16(), 200);
+    assert_eq!(forged.status().as_u16(), 200);
+    assert_eq!(subscriber_count(&app).await, 0);
+}
+
+#[tokio::test]
+async fn captcha_responses_are_verified_with_the_provider() {
+    let captcha_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/siteverify"))
+        .and(body_string_contains("response=good"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "success": true
+        })))
+        .mount(&captcha_server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/siteverify"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "success": false
+        })))
+        .mount(&captcha_server)
+        .await;
+    let app = spawn_app_with(|c| {
+        c.bot_protection.captcha = Some(CaptchaSettings {
+            provider: CaptchaProvider::HCaptcha,
+            secret: Secret::new("captcha-secret".into()),
+            verify_url: Some(format!("{}/siteverify", captcha_server.uri())),
+            timeout_milliseconds: 1000,
+        });
+    })
+    .await;
+    expect_emails(&app, 1).await;
+
+    let failed = app
+        .post_subscriptions("name=le%20guin&email=ursula%40gmail.com&h-captcha-response=bad".into())
+        .await;
+    let missing = app
+        .post_subscriptions("name=le%20guin&email=ursula%40gmail.com".into())
+        .await;
+    assert_eq!(failed.status().as_u16(), 200);
+    assert_eq!(missing.status().as_u16(), 200);
+    assert_eq!(subscriber_count(&app).await, 0);
+
+    let passed = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula%40gmail.com&h-captcha-response=good".into(),
+        )
+        .await;
+    assert_eq!(passed.status().as_u16(), 200);
+    assert_eq!(subscriber_count(&app).await, 1);
+}
+
+#[tokio::test]
+async fn subscriptions_are_accepted_if_the_captcha_provider_is_down() {
+    let captcha_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/siteverify"))
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&captcha_server)
+        .await;
+    let app = spawn_app_with(|c| {
+        c.bot_protection.captcha = Some(CaptchaSettings {
+            provider: CaptchaProvider::Turnstile,
+            secret: Secret::new("captcha-secret".into()),
+            verify_url: Some(format!("{}/siteverify", captcha_server.uri())),
+            timeout_milliseconds: 1000,
+        });
+    })
+    .await;
+    expect_emails(&app, 1).await;
+
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula%40gmail.com&cf-turnstile-response=anything".into(),
+        )
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_count(&app).await, 1);
+}
@@ -0,0 +1,147 @@
+use crate::helpers::{spawn_app_with, TestApp};
+use std::path::PathBuf;
+use zero2prod::configuration::{EmailBackend, Environment};
+
+const BODY: &str = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+async fn spawn_app_with_mailbox(environment: Environment) -> (TestApp, PathBuf) {
+    let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    let mailbox_directory = directory.clone();
+    let app = spawn_app_with(|c| {
+        c.application.environment = environment;
+        c.email_client.backend = EmailBackend::File;
+        c.email_client.mailbox_directory = Some(mailbox_directory);
+    })
+    .await;
+    (app, directory)
+}
+
+async fn get(app: &TestApp, path: &str) -> reqwest::Response {
+    reqwest::get(format!("{}{path}", &app.address))
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn captured_emails_are_listed_most_recent_first() {
+    let (app, directory) = spawn_app_with_mailbox(Environment::Local).await;
+    app.post_subscriptions(BODY.into()).await;
+    app.post_subscriptions("name=ann&email=ann%40example.com".into())
+        .await;
+
+    let response = get(&app, "/dev/mailbox/messages").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let messages: serde_json::Value = response.json().await.unwrap();
+    let recipients: Vec<_> = messages
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["to"].as_str().unwrap())
+        .collect();
+    assert_eq!(recipients, ["ann@example.com", "ursula_le_guin@gmail.com"]);
+    assert_eq!(messages[0]["subject"], "Welcome!");
+    assert!(messages[0]["text_content"]
+        .as_str()
+        .unwrap()
+        .contains("/subscriptions/confirm?"));
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn a_captured_email_can_be_fetched() {
+    let (app, directory) = spawn_app_with_mailbox(Environment::Local).await;
+    app.post_subscriptions(BODY.into()).await;
+    let messages: serde_json::Value = get(&app, "/dev/mailbox/messages")
+        .await
+        .json()
+        .await
+        .unwrap();
+    let message_id = messages[0]["message_id"].as_str().unwrap();
+
+    let message: serde_json::Value = get(&app, &format!("/dev/mailbox/messages/{message_id}"))
+        .await
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(message["to"], "ursula_le_guin@gmail.com");
+    assert!(message["html_content"]
+        .as_str()
+        .unwrap()
+        .contains("/subscriptions/confirm?"));
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn unknown_emails_are_not_found() {
+    let (app, _) = spawn_app_with_mailbox(Environment::Local).await;
+
+    let path = format!("/dev/mailbox/messages/{}", uuid::Uuid::new_v4());
+    let response = get(&app, &path).await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn the_page_of_an_email_links_to_the_confirmation() {
+    let (app, directory) = spawn_app_with_mailbox(Environment::Local).await;
+    app.post_subscriptions(BODY.into()).await;
+    let messages: serde_json::Value = get(&app, "/dev/mailbox/messages")
+        .await
+        .json()
+        .await
+        .unwrap();
+    let message_id = messages[0]["message_id"].as_str().unwrap();
+
+    let mailbox_page = get(&app, "/dev/mailbox").await.text().await.unwrap();
+    assert!(mailbox_page.contains(&format!(r#"href="/dev/mailbox/{message_id}""#)));
+    let response = get(&app, &format!("/dev/mailbox/{message_id}")).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let page = response.text().await.unwrap();
+    assert!(page.contains(r#"<a href="http://127.0.0.1/subscriptions/confirm?"#));
+    assert!(page.contains("<th>To</th><td>ursula_le_guin@gmail.com</td>"));
+    assert!(page.contains("<iframe"));
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn the_mailbox_can_be_cleared() {
+    let (app, directory) = spawn_app_with_mailbox(Environment::Local).await;
+    app.post_subscriptions(BODY.into()).await;
+
+    let response = reqwest::Client::new()
+        .delete(format!("{}/dev/mailbox/messages", &app.address))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 204);
+    let messages: serde_json::Value = get(&app, "/dev/mailbox/messages")
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(messages, serde_json::json!([]));
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn the_mailbox_is_not_mounted_in_production() {
+    let (app, _) = spawn_app_with_mailbox(Environment::Production).await;
+
+    for path in ["/dev/mailbox", "/dev/mailbox/messages"] {
+        let response = get(&app, path).await;
+        assert_eq!(response.status().as_u16(), 404, "Path: {path}");
+    }
+}
+
+#[tokio::test]
+async fn the_mailbox_is_not_mounted_without_the_file_backend() {
+    let app = crate::helpers::spawn_app().await;
+
+    let response = get(&app, "/dev/mailbox").await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
@@ -0,0 +1,166 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app, BatchResponder, TestApp, TestUser};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+fn draft_body(title: &str) -> serde_json::Value {
+    serde_json::json!({
+        "title": title,
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+async fn create_draft(
+    app: &TestApp,
+    user: &TestUser,
+    body: serde_json::Value,
+) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/admin/drafts", &app.address))
+        .basic_auth(&user.username, Some(&user.password))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn publish_draft(app: &TestApp, user: &TestUser, draft_id: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/admin/drafts/{draft_id}/publish", &app.address))
+        .basic_auth(&user.username, Some(&user.password))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+/// An editor's new draft, returning the editor and the draft id.
+async fn editor_draft(app: &TestApp) -> (TestUser, String) {
+    let editor = TestUser::with_role("editor");
+    editor.store(&app.db_pool).await;
+    let body: serde_json::Value = create_draft(app, &editor, draft_body("Draft title"))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let draft_id = body["newsletter_draft_id"].as_str().unwrap().to_owned();
+    (editor, draft_id)
+}
+
+#[tokio::test]
+async fn editors_draft_issues_without_sending_them() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    let editor = TestUser::with_role("editor");
+    editor.store(&app.db_pool).await;
+
+    let response = create_draft(&app, &editor, draft_body("Draft title")).await;
+
+    assert_eq!(response.status().as_u16(), 201);
+    let draft: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(draft["title"], "Draft title");
+    assert_eq!(draft["lists"], serde_json::json!(["default"]));
+    assert_eq!(draft["created_by"], editor.user_id.to_string());
+    assert!(draft["newsletter_issue_id"].is_null());
+}
+
+#[tokio::test]
+async fn drafts_targeting_unknown_lists_are_rejected() {
+    let app = spawn_app().await;
+    let mut body = draft_body("Draft title");
+    body["lists"] = serde_json::json!(["no-such-list"]);
+
+    let response = create_draft(&app, &app.test_user, body).await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn drafts_can_be_updated_until_they_are_published() {
+    let app = spawn_app().await;
+    let (editor, draft_id) = editor_draft(&app).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .put(format!("{}/admin/drafts/{draft_id}", &app.address))
+        .basic_auth(&editor.username, Some(&editor.password))
+        .json(&draft_body("Better title"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let draft: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(draft["title"], "Better title");
+
+    publish_draft(&app, &app.test_user, &draft_id).await;
+    let response = client
+        .put(format!("{}/admin/drafts/{draft_id}", &app.address))
+        .basic_auth(&editor.username, Some(&editor.password))
+        .json(&draft_body("Too late"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn editors_cannot_publish_drafts() {
+    let app = spawn_app().await;
+    let (editor, draft_id) = editor_draft(&app).await;
+
+    let response = publish_draft(&app, &editor, &draft_id).await;
+
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[tokio::test]
+async fn publishers_send_drafts_once() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchResponder::accepting_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let (_, draft_id) = editor_draft(&app).await;
+    let publisher = TestUser::with_role("publisher");
+    publisher.store(&app.db_pool).await;
+
+    let response = publish_draft(&app, &publisher, &draft_id).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let published: serde_json::Value = response.json().await.unwrap();
+
+    let draft: serde_json::Value = reqwest::Client::new()
+        .get(format!("{}/admin/drafts/{draft_id}", &app.address))
+        .basic_auth(&publisher.username, Some(&publisher.password))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        draft["newsletter_issue_id"],
+        published["newsletter_issue_id"]
+    );
+
+    let response = publish_draft(&app, &publisher, &draft_id).await;
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn unknown_drafts_are_not_found() {
+    let app = spawn_app().await;
+
+    let response = publish_draft(&app, &app.test_user, &uuid::Uuid::new_v4().to_string()).await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
@@ -0,0 +1,166 @@
+use crate::helpers::{spawn_app, TestApp};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+const BODY: &str = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+struct OutboxMessage {
+    status: String,
+    attempts: i32,
+    last_error: Option<String>,
+}
+
+async fn outbox_message(app: &TestApp) -> OutboxMessage {
+    sqlx::query_as!(
+        OutboxMessage,
+        "SELECT status, attempts, last_error FROM email_outbox"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch the outbox message.")
+}
+
+/// Make every pending email due, as if their retry delay had elapsed.
+async fn make_pending_emails_due(app: &TestApp) {
+    sqlx::query!(
+        "UPDATE email_outbox SET next_attempt_at = now() - interval '1 second' WHERE status = 'pending'"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn confirmation_emails_are_sent_right_away_and_marked_as_sent() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(BODY.into()).await;
+    app.dispatch_all_pending_outbox_emails().await;
+
+    let message = outbox_message(&app).await;
+    assert_eq!(message.status, "sent");
+    assert_eq!(message.attempts, 1);
+}
+
+#[tokio::test]
+async fn failing_to_send_a_confirmation_email_does_not_fail_the_subscription() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscriptions(BODY.into()).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let message = outbox_message(&app).await;
+    assert_eq!(message.status, "pending");
+    assert_eq!(message.attempts, 1);
+    assert!(message.last_error.is_some());
+}
+
+#[tokio::test]
+async fn pending_emails_are_retried_once_due() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(BODY.into()).await;
+
+    // Not due yet: nothing is sent.
+    app.dispatch_all_pending_outbox_emails().await;
+    assert_eq!(app.email_server.received_requests().await.unwrap().len(), 1);
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    make_pending_emails_due(&app).await;
+    app.dispatch_all_pending_outbox_emails().await;
+
+    let message = outbox_message(&app).await;
+    assert_eq!(message.status, "sent");
+    assert_eq!(message.attempts, 2);
+    assert!(message.last_error.is_none());
+}
+
+#[tokio::test]
+async fn emails_are_given_up_on_after_too_many_attempts() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(BODY.into()).await;
+
+    for _ in 0..10 {
+        make_pending_emails_due(&app).await;
+        app.dispatch_all_pending_outbox_emails().await;
+    }
+
+    let message = outbox_message(&app).await;
+    assert_eq!(message.status, "failed");
+    assert_eq!(message.attempts, 8);
+    assert_eq!(app.email_server.received_requests().await.unwrap().len(), 8);
+}
+
+#[tokio::test]
+async fn emails_to_inactive_recipients_are_not_retried() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+            "ErrorCode": 406,
+            "Message": "You tried to send to a recipient that has been marked as inactive.",
+        })))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(BODY.into()).await;
+
+    make_pending_emails_due(&app).await;
+    app.dispatch_all_pending_outbox_emails().await;
+
+    let message = outbox_message(&app).await;
+    assert_eq!(message.status, "failed");
+    assert_eq!(message.attempts, 1);
+}
+
+#[tokio::test]
+async fn no_email_is_sent_when_the_subscription_is_not_stored() {
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token;")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app.post_subscriptions(BODY.into()).await;
+
+    assert_eq!(response.status().as_u16(), 500);
+    app.dispatch_all_pending_outbox_emails().await;
+    let pending = sqlx::query!("SELECT COUNT(*) AS count FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(pending.count, Some(0));
+}
@@ -0,0 +1,93 @@
+use crate::helpers::spawn_app;
+use uuid::Uuid;
+
+async fn problem(response: reqwest::Response) -> serde_json::Value {
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/problem+json"
+    );
+    response.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn unexpected_errors_do_not_leak_their_cause() {
+    let app = spawn_app().await;
+    sqlx::query!("ALTER TABLE subscriptions DROP COLUMN email;")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 500);
+    let problem = problem(response).await;
+    assert_eq!(problem["type"], "/problems/internal-error");
+    assert_eq!(problem["status"], 500);
+    assert!(problem.get("detail").is_none());
+    assert!(!problem.to_string().contains("email"));
+}
+
+#[tokio::test]
+async fn problems_carry_the_id_of_the_request() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/issues/{}", app.address, Uuid::new_v4()))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 404);
+    let problem = problem(response).await;
+    assert_eq!(problem["type"], "/problems/not-found");
+    let request_id = problem["request_id"].as_str().unwrap();
+    assert!(Uuid::parse_str(request_id).is_ok());
+}
+
+#[tokio::test]
+async fn malformed_payloads_are_reported_as_validation_problems() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/json")
+        .body("{not json")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 400);
+    let problem = problem(response).await;
+    assert_eq!(problem["type"], "/problems/validation-error");
+    assert!(problem["detail"].is_string());
+}
+
+#[tokio::test]
+async fn invalid_path_parameters_are_not_found_problems() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/issues/not-a-uuid", app.address))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 404);
+    assert_eq!(problem(response).await["type"], "/problems/not-found");
+}
+
+#[tokio::test]
+async fn authentication_failures_keep_their_challenge() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/lists", &app.address))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        response.headers()["WWW-Authenticate"],
+        r#"Basic realm="admin""#
+    );
+    assert_eq!(problem(response).await["type"], "/problems/unauthorized");
+}
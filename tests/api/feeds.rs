@@ -0,0 +1,192 @@
+use crate::helpers::{spawn_app, TestApp};
+use test_case::test_case;
+
+async fn publish_issue(app: &TestApp, title: &str, html: &str) {
+    let newsletter_request_body = serde_json::json!({
+        "title": title,
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": html,
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[test_case("/feed.rss", "application/rss+xml; charset=utf-8"; "rss")]
+#[test_case("/feed.atom", "application/atom+xml; charset=utf-8"; "atom")]
+#[test_case("/feed.json", "application/feed+json; charset=utf-8"; "json feed")]
+#[tokio::test]
+async fn feeds_are_served_with_the_correct_content_type(feed_path: &str, content_type: &str) {
+    let app = spawn_app().await;
+
+    let response = app.get_feed(feed_path, None).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["Content-Type"], content_type);
+    assert!(response.headers().contains_key("ETag"));
+}
+
+#[test_case("/feed.rss"; "rss")]
+#[test_case("/feed.atom"; "atom")]
+#[tokio::test]
+async fn xml_feeds_contain_published_issues_with_escaped_html(feed_path: &str) {
+    let app = spawn_app().await;
+    publish_issue(&app, "Fish & Chips", "<p>Newsletter body as HTML</p>").await;
+
+    let response = app.get_feed(feed_path, None).await;
+    let body = response.text().await.unwrap();
+
+    assert!(body.contains("Fish &amp; Chips"), "title should be escaped");
+    assert!(
+        body.contains("&lt;p&gt;Newsletter body as HTML&lt;/p&gt;"),
+        "html content should be escaped"
+    );
+    assert!(
+        !body.contains("<p>"),
+        "html content should not be embedded raw"
+    );
+    assert!(
+        body.contains("http://127.0.0.1/issues/"),
+        "links should be absolute"
+    );
+}
+
+#[tokio::test]
+async fn json_feed_contains_published_issues() {
+    let app = spawn_app().await;
+    publish_issue(&app, "First issue", "<p>First</p>").await;
+    publish_issue(&app, "Second issue", "<p>Second</p>").await;
+
+    let response = app.get_feed("/feed.json", None).await;
+    let feed: serde_json::Value = response.json().await.unwrap();
+
+    assert_eq!(feed["version"], "https://jsonfeed.org/version/1.1");
+    assert_eq!(feed["feed_url"], "http://127.0.0.1/feed.json");
+    let items = feed["items"].as_array().unwrap();
+    assert_eq!(items.len(), 2);
+    assert_eq!(
+        items[0]["title"], "Second issue",
+        "newest issue comes first"
+    );
+    assert_eq!(items[0]["content_html"], "<p>Second</p>");
+    assert!(items[0]["url"]
+        .as_str()
+        .unwrap()
+        .starts_with("http://127.0.0.1/issues/"));
+}
+
+#[tokio::test]
+async fn issues_sent_to_another_list_or_a_segment_are_not_in_the_feed() {
+    let app = spawn_app().await;
+    let response = app
+        .post_lists(serde_json::json!({"slug": "members", "name": "Members only"}))
+        .await;
+    assert_eq!(response.status().as_u16(), 201);
+    publish_issue(&app, "Public issue", "<p>Public</p>").await;
+    for targeting in [
+        serde_json::json!({"title": "Members issue", "lists": ["members"]}),
+        serde_json::json!({"title": "Beta issue", "segment": "tag = beta-testers"}),
+    ] {
+        let mut body = serde_json::json!({
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Private</p>",
+            }
+        });
+        body.as_object_mut()
+            .unwrap()
+            .extend(targeting.as_object().unwrap().clone());
+        let response = app.post_newsletters(body).await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    let feed: serde_json::Value = app.get_feed("/feed.json", None).await.json().await.unwrap();
+
+    let titles: Vec<_> = feed["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["title"].as_str().unwrap())
+        .collect();
+    assert_eq!(titles, ["Public issue"]);
+}
+
+#[test_case("/feed.rss"; "rss")]
+#[test_case("/feed.atom"; "atom")]
+#[test_case("/feed.json"; "json feed")]
+#[tokio::test]
+async fn feeds_return_304_when_the_etag_matches(feed_path: &str) {
+    let app = spawn_app().await;
+    publish_issue(&app, "Newsletter title", "<p>Newsletter body as HTML</p>").await;
+    let response = app.get_feed(feed_path, None).await;
+    let etag = response.headers()["ETag"].to_str().unwrap().to_owned();
+
+    let response = app
+        .get_feed(feed_path, Some(("If-None-Match", etag.as_str())))
+        .await;
+    assert_eq!(response.status().as_u16(), 304);
+
+    publish_issue(&app, "Another title", "<p>Newsletter body as HTML</p>").await;
+    let response = app
+        .get_feed(feed_path, Some(("If-None-Match", etag.as_str())))
+        .await;
+    assert_eq!(
+        response.status().as_u16(),
+        200,
+        "a new issue should invalidate the etag"
+    );
+}
+
+#[tokio::test]
+async fn feeds_return_304_when_not_modified_since() {
+    let app = spawn_app().await;
+    publish_issue(&app, "Newsletter title", "<p>Newsletter body as HTML</p>").await;
+    let response = app.get_feed("/feed.rss", None).await;
+    let last_modified = response.headers()["Last-Modified"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    let response = app
+        .get_feed(
+            "/feed.rss",
+            Some(("If-Modified-Since", last_modified.as_str())),
+        )
+        .await;
+    assert_eq!(response.status().as_u16(), 304);
+
+    let response = app
+        .get_feed(
+            "/feed.rss",
+            Some(("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT")),
+        )
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn issue_links_point_to_the_published_issue() {
+    let app = spawn_app().await;
+    publish_issue(&app, "Newsletter title", "<p>Newsletter body as HTML</p>").await;
+    let feed: serde_json::Value = app.get_feed("/feed.json", None).await.json().await.unwrap();
+    let mut link = reqwest::Url::parse(feed["items"][0]["url"].as_str().unwrap()).unwrap();
+    link.set_port(Some(app.port)).unwrap();
+
+    let response = reqwest::get(link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body = response.text().await.unwrap();
+    assert!(body.contains("<p>Newsletter body as HTML</p>"));
+}
+
+#[tokio::test]
+async fn unknown_issues_return_404() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/issues/{}", app.address, uuid::Uuid::new_v4()))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 404);
+}
@@ -3,8 +3,13 @@ use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
 use once_cell::sync::Lazy;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use uuid::Uuid;
-use wiremock::MockServer;
-use zero2prod::configuration::{get_configuration, DatabaseSettings};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use zero2prod::configuration::{get_configuration, DatabaseSettings, EmailBackend, Settings};
+use zero2prod::digest_worker::{self, ExecutionOutcome};
+use zero2prod::email_client::EmailClient;
+use zero2prod::email_outbox;
+use zero2prod::onboarding_worker;
 use zero2prod::startup::Application;
 use zero2prod::telemetry::{get_subscriber, init_subscriber};
 
@@ -31,23 +36,32 @@ pub struct TestApp {
     pub db_pool: PgPool,
     pub email_server: MockServer,
     pub test_user: TestUser,
+    pub email_client: EmailClient,
+    pub base_url: String,
 }
 
 pub struct TestUser {
     pub user_id: Uuid,
     pub username: String,
     pub password: String,
+    pub role: &'static str,
 }
 
 impl TestUser {
     pub fn generate() -> Self {
+        Self::with_role("admin")
+    }
+
+    pub fn with_role(role: &'static str) -> Self {
         Self {
             user_id: Uuid::new_v4(),
             username: Uuid::new_v4().to_string(),
             password: Uuid::new_v4().to_string(),
+            role,
         }
     }
-    async fn store(&self, pool: &PgPool) {
+
+    pub async fn store(&self, pool: &PgPool) {
         let salt = SaltString::generate(&mut rand::thread_rng());
         let password_hash = Argon2::new(
             Algorithm::Argon2id,
@@ -58,10 +72,11 @@ impl TestUser {
         .unwrap()
         .to_string();
         sqlx::query!(
-            "INSERT INTO users (user_id, username, password_hash) VALUES ($1, $2, $3)",
+            "INSERT INTO users (user_id, username, password_hash, role) VALUES ($1, $2, $3, $4)",
             self.user_id,
             self.username,
             password_hash,
+            self.role,
         )
         .execute(pool)
         .await
@@ -72,7 +87,7 @@ impl TestUser {
 impl TestApp {
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
         reqwest::Client::new()
-            .post(&format!("{}/subscriptions", &self.address))
+            .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
@@ -80,9 +95,28 @@ impl TestApp {
             .expect("Failed to execute request.")
     }
 
+    pub async fn post_subscriptions_json(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", &self.address))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
     pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
         reqwest::Client::new()
-            .post(&format!("{}/newsletters", &self.address))
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_lists(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/lists", &self.address))
             .basic_auth(&self.test_user.username, Some(&self.test_user.password))
             .json(&body)
             .send()
@@ -90,6 +124,176 @@ impl TestApp {
             .expect("Failed to execute request.")
     }
 
+    pub async fn dispatch_all_pending_digests(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                digest_worker::try_execute_task(&self.db_pool, &self.email_client, &self.base_url)
+                    .await
+                    .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    pub async fn dispatch_all_pending_onboarding_emails(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue = onboarding_worker::try_execute_task(
+                &self.db_pool,
+                &self.email_client,
+                &self.base_url,
+            )
+            .await
+            .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    pub async fn dispatch_all_pending_outbox_emails(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                email_outbox::try_execute_task(&self.db_pool, &self.email_client)
+                    .await
+                    .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    pub async fn put_onboarding_email(
+        &self,
+        step: i16,
+        body: serde_json::Value,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .put(format!("{}/admin/onboarding/{step}", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_preferences(&self, token: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/preferences/{token}", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_preferences(&self, token: &str, body: String) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/preferences/{token}", &self.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_newsletters_dry_run(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters/dry-run", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_subscriber(&self, subscriber_id: &Uuid) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!(
+                "{}/admin/subscribers/{subscriber_id}",
+                &self.address
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn put_subscriber_tag(&self, subscriber_id: &Uuid, tag: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .put(format!(
+                "{}/admin/subscribers/{subscriber_id}/tags/{tag}",
+                &self.address
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn delete_subscriber_tag(
+        &self,
+        subscriber_id: &Uuid,
+        tag: &str,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .delete(format!(
+                "{}/admin/subscribers/{subscriber_id}/tags/{tag}",
+                &self.address
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn patch_subscriber_attributes(
+        &self,
+        subscriber_id: &Uuid,
+        body: serde_json::Value,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .patch(format!(
+                "{}/admin/subscribers/{subscriber_id}/attributes",
+                &self.address
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_issue_report(&self, newsletter_issue_id: &Uuid) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!(
+                "{}/admin/issues/{}/report",
+                &self.address, newsletter_issue_id
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_issue_stats(&self, newsletter_issue_id: &Uuid) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!(
+                "{}/admin/issues/{}/stats",
+                &self.address, newsletter_issue_id
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_feed(&self, path: &str, header: Option<(&str, &str)>) -> reqwest::Response {
+        let mut request = reqwest::Client::new().get(format!("{}{}", &self.address, path));
+        if let Some((name, value)) = header {
+            request = request.header(name, value);
+        }
+        request.send().await.expect("Failed to execute request.")
+    }
+
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
         let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
 
@@ -117,7 +321,54 @@ impl TestApp {
     }
 }
 
+/// Answers Postmark batch requests the way Postmark does, with one result per email,
+/// rejecting the first `rejected` emails of each batch.
+pub struct BatchResponder {
+    rejected: usize,
+}
+
+impl BatchResponder {
+    pub fn accepting_all() -> Self {
+        Self { rejected: 0 }
+    }
+
+    pub fn rejecting_first(rejected: usize) -> Self {
+        Self { rejected }
+    }
+}
+
+impl wiremock::Respond for BatchResponder {
+    fn respond(&self, request: &wiremock::Request) -> ResponseTemplate {
+        let emails: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+        let results: Vec<_> = emails
+            .iter()
+            .enumerate()
+            .map(|(position, email)| {
+                if position < self.rejected {
+                    serde_json::json!({
+                        "ErrorCode": 300,
+                        "Message": "Invalid 'To' address.",
+                    })
+                } else {
+                    serde_json::json!({
+                        "To": email["To"],
+                        "MessageID": Uuid::new_v4().to_string(),
+                        "ErrorCode": 0,
+                        "Message": "OK",
+                    })
+                }
+            })
+            .collect();
+        ResponseTemplate::new(200).set_body_json(results)
+    }
+}
+
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Spawn the application after letting the test tweak its configuration.
+pub async fn spawn_app_with(configure: impl FnOnce(&mut Settings)) -> TestApp {
     Lazy::force(&TRACING);
 
     let email_server = MockServer::start().await;
@@ -125,8 +376,18 @@ pub async fn spawn_app() -> TestApp {
     let configuration = {
         let mut c = get_configuration().expect("Failed to read configuration.");
         c.database.database_name = Uuid::new_v4().to_string();
+        // Every test opens its own pool against the same server, so the first connection
+        // can take longer than the production timeout when the whole suite runs at once.
+        c.database.acquire_timeout_seconds = 30;
         c.application.port = 0;
+        c.email_client.backend = EmailBackend::Postmark;
         c.email_client.base_url = email_server.uri();
+        c.rate_limit.enabled = false;
+        // The parameters of `TestUser` hashes, so that logins do not upgrade them.
+        c.password_hashing.memory_kib = 15000;
+        c.password_hashing.iterations = 2;
+        c.password_hashing.parallelism = 1;
+        configure(&mut c);
         c
     };
 
@@ -143,6 +404,8 @@ pub async fn spawn_app() -> TestApp {
         db_pool: configure_database(&configuration.database).await,
         email_server,
         test_user: TestUser::generate(),
+        email_client: configuration.email_client.client(),
+        base_url: configuration.application.base_url,
     };
     test_app.test_user.store(&test_app.db_pool).await;
     test_app
@@ -165,3 +428,39 @@ async fn configure_database(config: &DatabaseSettings) -> PgPool {
         .expect("Failed to migrate the database");
     connection_pool
 }
+
+/// Use the public API of the application under test to create
+/// an unconfirmed subscriber.
+pub async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
+    let body = format!("name=le%20guin&email={}%40gmail.com", Uuid::new_v4());
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Create unconfirmed subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscriptions(body)
+        .await
+        .error_for_status()
+        .unwrap();
+    let email_request = &app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.get_confirmation_links(email_request)
+}
+
+/// Use the public API of the application under test to create
+/// an confirmed subscriber.
+pub async fn create_confirmed_subscriber(app: &TestApp) {
+    let confirmation_link = create_unconfirmed_subscriber(app).await;
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
@@ -0,0 +1,97 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app, BatchResponder, TestApp};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::Mock;
+
+async fn publish_issue(app: &TestApp) -> Uuid {
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    body["newsletter_issue_id"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn report_contains_aggregate_counts_and_failing_addresses() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchResponder::rejecting_first(1))
+        .mount(&app.email_server)
+        .await;
+    let newsletter_issue_id = publish_issue(&app).await;
+
+    let response = app.get_issue_report(&newsletter_issue_id).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["title"], "Newsletter title");
+    assert_eq!(report["total"], 2);
+    assert_eq!(report["sent"], 1);
+    assert_eq!(report["failed"], 1);
+    assert_eq!(report["queued"], 0);
+    assert_eq!(report["bounced"], 0);
+    let failures = report["failures"].as_array().unwrap();
+    assert_eq!(failures.len(), 1);
+    assert!(failures[0]["subscriber_email"]
+        .as_str()
+        .unwrap()
+        .ends_with("@gmail.com"));
+    assert_eq!(failures[0]["attempts"], 1);
+}
+
+#[tokio::test]
+async fn report_for_an_issue_without_recipients_is_empty() {
+    let app = spawn_app().await;
+    let newsletter_issue_id = publish_issue(&app).await;
+
+    let response = app.get_issue_report(&newsletter_issue_id).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["total"], 0);
+    assert_eq!(report["failures"].as_array().unwrap().len(), 0);
+}
+
+#[tokio::test]
+async fn report_for_an_unknown_issue_returns_404() {
+    let app = spawn_app().await;
+
+    let response = app.get_issue_report(&Uuid::new_v4()).await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn report_requires_authentication() {
+    let app = spawn_app().await;
+    let newsletter_issue_id = publish_issue(&app).await;
+
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/admin/issues/{}/report",
+            &app.address, newsletter_issue_id
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        r#"Basic realm="admin""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
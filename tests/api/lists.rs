@@ -0,0 +1,244 @@
+use crate::helpers::{spawn_app, BatchResponder, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn create_list(app: &TestApp, slug: &str) {
+    let response = app
+        .post_lists(serde_json::json!({"slug": slug, "name": "Rust weekly"}))
+        .await;
+    assert_eq!(response.status().as_u16(), 201);
+}
+
+/// Subscribe `email` to `list` and click on the confirmation link.
+async fn subscribe_and_confirm(app: &TestApp, email: &str, list: &str) {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let body = format!(
+        "name=le%20guin&email={}&list={list}",
+        email.replace('@', "%40")
+    );
+    app.post_subscriptions(body)
+        .await
+        .error_for_status()
+        .unwrap();
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+fn newsletter_request_body(lists: &[&str]) -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        },
+        "lists": lists,
+    })
+}
+
+#[tokio::test]
+async fn lists_can_be_created_and_listed() {
+    let app = spawn_app().await;
+    create_list(&app, "rust-weekly").await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/lists", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let lists: serde_json::Value = response.json().await.unwrap();
+    let slugs: Vec<_> = lists
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|l| l["slug"].as_str().unwrap())
+        .collect();
+    assert_eq!(slugs, vec!["default", "rust-weekly"]);
+}
+
+#[tokio::test]
+async fn creating_a_duplicate_list_returns_409() {
+    let app = spawn_app().await;
+    create_list(&app, "rust-weekly").await;
+
+    let response = app
+        .post_lists(serde_json::json!({"slug": "rust-weekly", "name": "Another"}))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn creating_a_list_with_an_invalid_slug_returns_400() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_lists(serde_json::json!({"slug": "Rust Weekly", "name": "Rust weekly"}))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn subscribing_to_an_unknown_list_returns_400() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com&list=nope".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn confirmation_is_scoped_to_the_subscribed_list() {
+    let app = spawn_app().await;
+    create_list(&app, "rust-weekly").await;
+
+    subscribe_and_confirm(&app, "ursula_le_guin@gmail.com", "rust-weekly").await;
+
+    let subscriptions = sqlx::query!(
+        r#"
+        SELECT l.slug, ls.status
+        FROM list_subscriptions ls JOIN lists l ON l.list_id = ls.list_id
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(subscriptions.len(), 1);
+    assert_eq!(subscriptions[0].slug, "rust-weekly");
+    assert_eq!(subscriptions[0].status, "confirmed");
+}
+
+#[tokio::test]
+async fn an_existing_subscriber_can_join_another_list() {
+    let app = spawn_app().await;
+    create_list(&app, "rust-weekly").await;
+
+    subscribe_and_confirm(&app, "ursula_le_guin@gmail.com", "default").await;
+    subscribe_and_confirm(&app, "ursula_le_guin@gmail.com", "rust-weekly").await;
+
+    let subscribers = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers.count, 1);
+    let confirmed = sqlx::query!(
+        "SELECT COUNT(*) AS \"count!\" FROM list_subscriptions WHERE status = 'confirmed'"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(confirmed.count, 2);
+}
+
+#[tokio::test]
+async fn resubscribing_to_a_confirmed_list_does_not_send_another_email() {
+    let app = spawn_app().await;
+    subscribe_and_confirm(&app, "ursula_le_guin@gmail.com", "default").await;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn issues_are_only_delivered_to_the_targeted_lists() {
+    let app = spawn_app().await;
+    create_list(&app, "rust-weekly").await;
+    subscribe_and_confirm(&app, "ursula_le_guin@gmail.com", "default").await;
+    subscribe_and_confirm(&app, "octavia_butler@gmail.com", "rust-weekly").await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchResponder::accepting_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_newsletters(newsletter_request_body(&["rust-weekly"]))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let delivery = sqlx::query!("SELECT subscriber_email FROM deliveries")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(delivery.subscriber_email, "octavia_butler@gmail.com");
+}
+
+#[tokio::test]
+async fn subscribers_of_several_targeted_lists_receive_an_issue_once() {
+    let app = spawn_app().await;
+    create_list(&app, "rust-weekly").await;
+    subscribe_and_confirm(&app, "ursula_le_guin@gmail.com", "default").await;
+    subscribe_and_confirm(&app, "ursula_le_guin@gmail.com", "rust-weekly").await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchResponder::accepting_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_newsletters(newsletter_request_body(&["default", "rust-weekly"]))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let batch_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let batch: Vec<serde_json::Value> = serde_json::from_slice(&batch_request.body).unwrap();
+    assert_eq!(batch.len(), 1);
+}
+
+#[tokio::test]
+async fn publishing_to_an_unknown_list_returns_400() {
+    let app = spawn_app().await;
+    Mock::given(path("/email/batch"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_newsletters(newsletter_request_body(&["default", "nope"]))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let issues = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(issues.count, 0, "the issue should not be stored");
+}
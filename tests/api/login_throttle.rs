@@ -0,0 +1,122 @@
+use crate::helpers::{spawn_app_with, TestApp};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+async fn get_lists_as(app: &TestApp, username: &str, password: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("{}/admin/lists", &app.address))
+        .basic_auth(username, Some(password))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn spawn_throttled_app(max_failures_per_username: i32, max_failures_per_ip: i32) -> TestApp {
+    spawn_app_with(|c| {
+        c.login_throttle.max_failures_per_username = max_failures_per_username;
+        c.login_throttle.max_failures_per_ip = max_failures_per_ip;
+        c.login_throttle.base_delay_milliseconds = 1;
+        c.login_throttle.max_delay_milliseconds = 10;
+    })
+    .await
+}
+
+#[tokio::test]
+async fn repeated_failures_lock_the_username_out() {
+    let app = spawn_throttled_app(3, 100).await;
+    let username = app.test_user.username.clone();
+
+    for _ in 0..3 {
+        let response = get_lists_as(&app, &username, "wrong-password").await;
+        assert_eq!(response.status().as_u16(), 401);
+    }
+    // Even the right password is refused during the lockout.
+    let response = get_lists_as(&app, &username, &app.test_user.password).await;
+
+    assert_eq!(response.status().as_u16(), 429);
+    let retry_after: u64 = response.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0 && retry_after <= 901);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["type"], "/problems/rate-limited");
+}
+
+#[tokio::test]
+async fn repeated_failures_lock_the_client_address_out() {
+    let app = spawn_throttled_app(100, 3).await;
+
+    for _ in 0..3 {
+        let response = get_lists_as(&app, &Uuid::new_v4().to_string(), "password").await;
+        assert_eq!(response.status().as_u16(), 401);
+    }
+    let response = get_lists_as(&app, &app.test_user.username, &app.test_user.password).await;
+
+    assert_eq!(response.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn a_successful_login_forgives_past_failures() {
+    let app = spawn_throttled_app(3, 100).await;
+    let username = app.test_user.username.clone();
+
+    for _ in 0..2 {
+        get_lists_as(&app, &username, "wrong-password").await;
+    }
+    let response = get_lists_as(&app, &username, &app.test_user.password).await;
+    assert_eq!(response.status().as_u16(), 200);
+    for _ in 0..2 {
+        get_lists_as(&app, &username, "wrong-password").await;
+    }
+    let response = get_lists_as(&app, &username, &app.test_user.password).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn attempts_are_delayed_after_failures() {
+    let app = spawn_app_with(|c| {
+        c.login_throttle.base_delay_milliseconds = 300;
+        c.login_throttle.max_delay_milliseconds = 1000;
+    })
+    .await;
+    let username = app.test_user.username.clone();
+
+    for _ in 0..2 {
+        get_lists_as(&app, &username, "wrong-password").await;
+    }
+    let start = Instant::now();
+    let response = get_lists_as(&app, &username, &app.test_user.password).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(start.elapsed() >= Duration::from_millis(600));
+}
+
+#[tokio::test]
+async fn failed_logins_are_recorded() {
+    let app = spawn_throttled_app(2, 100).await;
+    let username = app.test_user.username.clone();
+
+    for _ in 0..3 {
+        get_lists_as(&app, &username, "wrong-password").await;
+    }
+
+    let reasons: Vec<String> = sqlx::query_scalar!(
+        "SELECT reason FROM failed_logins WHERE username = $1 ORDER BY attempted_at",
+        username,
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        reasons,
+        vec!["invalid_credentials", "invalid_credentials", "locked_out"]
+    );
+    let client_ip = sqlx::query_scalar!("SELECT client_ip FROM failed_logins LIMIT 1")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(client_ip.as_deref(), Some("127.0.0.1"));
+}
@@ -1,5 +1,25 @@
+mod api_keys;
+mod audit_events;
+mod bot_protection;
+mod dev_mailbox;
+mod drafts;
+mod email_outbox;
+mod errors;
+mod feeds;
 mod health_check;
 mod helpers;
+mod issue_report;
+mod lists;
+mod login_throttle;
 mod newsletter;
+mod onboarding;
+mod password_hashing;
+mod password_reset;
+mod preferences;
+mod rate_limit;
+mod roles;
+mod segments;
 mod subscriptions;
 mod subscriptions_confirm;
+mod tracking;
+mod two_factor;
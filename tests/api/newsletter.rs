@@ -1,46 +1,12 @@
-use crate::helpers::{spawn_app, ConfirmationLinks, TestApp};
+use crate::helpers::{
+    create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app, BatchResponder,
+};
 use serde_json::Value;
 use test_case::test_case;
 use uuid::Uuid;
 use wiremock::matchers::{any, method, path};
 use wiremock::{Mock, ResponseTemplate};
 
-/// Use the public API of the application under test to create
-/// an unconfirmed subscriber.
-async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
-    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
-    let _mock_guard = Mock::given(path("/email"))
-        .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
-        .named("Create unconfirmed subscriber")
-        .expect(1)
-        .mount_as_scoped(&app.email_server)
-        .await;
-    app.post_subscriptions(body.into())
-        .await
-        .error_for_status()
-        .unwrap();
-    let email_request = &app
-        .email_server
-        .received_requests()
-        .await
-        .unwrap()
-        .pop()
-        .unwrap();
-    app.get_confirmation_links(email_request)
-}
-
-/// Use the public API of the application under test to create
-/// an confirmed subscriber.
-async fn create_confirmed_subscriber(app: &TestApp) {
-    let confirmation_link = create_unconfirmed_subscriber(app).await;
-    reqwest::get(confirmation_link.html)
-        .await
-        .unwrap()
-        .error_for_status()
-        .unwrap();
-}
-
 #[tokio::test]
 async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
     let app = spawn_app().await;
@@ -66,9 +32,9 @@ async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
 async fn newsletters_are_delivered_to_confirmed_subscribers() {
     let app = spawn_app().await;
     create_confirmed_subscriber(&app).await;
-    Mock::given(path("/email"))
+    Mock::given(path("/email/batch"))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(BatchResponder::accepting_all())
         .expect(1)
         .mount(&app.email_server)
         .await;
@@ -84,6 +50,227 @@ async fn newsletters_are_delivered_to_confirmed_subscribers() {
     assert_eq!(response.status().as_u16(), 200);
 }
 
+#[tokio::test]
+async fn deliveries_are_recorded_with_the_provider_message_id() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!([{
+                "To": "ursula_le_guin@gmail.com",
+                "MessageID": "0a129aee-e1cd-480d-b08d-4f48548ff48d",
+                "ErrorCode": 0,
+                "Message": "OK"
+            }])),
+        )
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    let newsletter_issue_id: Uuid = body["newsletter_issue_id"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    let delivery = sqlx::query!(
+        "SELECT status, attempts, provider_message_id FROM deliveries WHERE newsletter_issue_id = $1",
+        newsletter_issue_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch saved delivery.");
+    assert_eq!(delivery.status, "sent");
+    assert_eq!(delivery.attempts, 1);
+    assert_eq!(
+        delivery.provider_message_id.as_deref(),
+        Some("0a129aee-e1cd-480d-b08d-4f48548ff48d")
+    );
+}
+
+#[tokio::test]
+async fn a_failed_delivery_does_not_prevent_delivery_to_other_subscribers() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchResponder::rejecting_first(1))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let deliveries = sqlx::query!("SELECT status, last_error FROM deliveries ORDER BY status")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved deliveries.");
+    assert_eq!(deliveries.len(), 2);
+    assert_eq!(deliveries[0].status, "failed");
+    assert!(deliveries[0].last_error.is_some());
+    assert_eq!(deliveries[1].status, "sent");
+    assert!(deliveries[1].last_error.is_none());
+}
+
+#[tokio::test]
+async fn a_failed_batch_keeps_the_delivery_to_each_of_its_recipients_queued_for_a_retry() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let deliveries = sqlx::query!(
+        r#"SELECT status, attempts, scheduled_for > now() AS "retry_later!" FROM deliveries"#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .expect("Failed to fetch saved deliveries.");
+    assert_eq!(deliveries.len(), 2);
+    assert!(deliveries.iter().all(|delivery| delivery.status == "queued"
+        && delivery.attempts == 1
+        && delivery.retry_later));
+}
+
+#[tokio::test]
+async fn queued_deliveries_are_retried_once_due() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_newsletters(newsletter_request_body()).await;
+
+    sqlx::query!("UPDATE deliveries SET scheduled_for = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.dispatch_all_pending_digests().await;
+
+    let delivery = sqlx::query!("SELECT status, attempts FROM deliveries")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the saved delivery.");
+    assert_eq!(delivery.status, "sent");
+    assert_eq!(delivery.attempts, 2);
+}
+
+#[tokio::test]
+async fn rate_limited_deliveries_wait_longer_before_a_retry() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(429))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters(newsletter_request_body()).await;
+
+    let delivery = sqlx::query!(
+        r#"
+        SELECT status, scheduled_for > now() + interval '1 minute' AS "waits_longer!"
+        FROM deliveries LIMIT 1
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch the saved delivery.");
+    assert_eq!(delivery.status, "queued");
+    assert!(delivery.waits_longer);
+}
+
+#[tokio::test]
+async fn deliveries_to_inactive_recipients_are_marked_as_bounced() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!([{
+                "ErrorCode": 406,
+                "Message": "You tried to send to a recipient that has been marked as inactive.",
+            }])),
+        )
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let delivery = sqlx::query!("SELECT status, last_error FROM deliveries")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the saved delivery.");
+    assert_eq!(delivery.status, "bounced");
+    assert!(delivery.last_error.unwrap().contains("inactive"));
+}
+
+#[tokio::test]
+async fn subscribers_whose_address_bounced_are_not_sent_later_issues() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let inactive = Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!([{
+                "ErrorCode": 406,
+                "Message": "You tried to send to a recipient that has been marked as inactive.",
+            }])),
+        )
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_newsletters(newsletter_request_body()).await;
+    drop(inactive);
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchResponder::accepting_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_newsletters(newsletter_request_body()).await;
+
+    let batch: Vec<Value> = serde_json::from_slice(
+        &app.email_server
+            .received_requests()
+            .await
+            .unwrap()
+            .pop()
+            .unwrap()
+            .body,
+    )
+    .unwrap();
+    assert_eq!(batch.len(), 1);
+}
+
 #[test_case(
     serde_json::json!({}), "empty body";
     "empty body"
@@ -142,7 +329,7 @@ async fn newsletters_returns_400_for_invalid_data(invalid_body: Value, error_mes
 async fn requests_missing_authorization_are_rejected() {
     let app = spawn_app().await;
     let response = reqwest::Client::new()
-        .post(&format!("{}/newsletters", &app.address))
+        .post(format!("{}/newsletters", &app.address))
         .json(&serde_json::json!({
             "title": "Newsletter title",
             "content": {
@@ -168,7 +355,7 @@ async fn non_existing_user_is_rejected() {
     let username = Uuid::new_v4().to_string();
     let password = Uuid::new_v4().to_string();
     let response = reqwest::Client::new()
-        .post(&format!("{}/newsletters", &app.address))
+        .post(format!("{}/newsletters", &app.address))
         .basic_auth(username, Some(password))
         .json(&serde_json::json!({
             "title": "Newsletter title",
@@ -196,7 +383,7 @@ async fn invalid_password_is_rejected() {
     let password = Uuid::new_v4().to_string();
     assert_ne!(app.test_user.password, password);
     let response = reqwest::Client::new()
-        .post(&format!("{}/newsletters", &app.address))
+        .post(format!("{}/newsletters", &app.address))
         .basic_auth(username, Some(password))
         .json(&serde_json::json!({
             "title": "Newsletter title",
@@ -215,3 +402,13 @@ async fn invalid_password_is_rejected() {
         response.headers()["WWW-Authenticate"]
     );
 }
+
+fn newsletter_request_body() -> Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
@@ -0,0 +1,273 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app, TestApp};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+fn drip_email(delay_days: i32) -> serde_json::Value {
+    serde_json::json!({
+        "delay_days": delay_days,
+        "subject": "Getting the most out of the newsletter",
+        "html_content": "<p>Hi {{name}}, here are some tips.</p>",
+        "text_content": "Hi {{name}}, here are some tips.",
+    })
+}
+
+async fn onboarding_statuses(app: &TestApp) -> Vec<(i16, String)> {
+    sqlx::query!("SELECT step, status FROM onboarding_deliveries ORDER BY step")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| (r.step, r.status))
+        .collect()
+}
+
+async fn mount_email_mock(app: &TestApp, expected: u64) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(expected)
+        .mount(&app.email_server)
+        .await;
+}
+
+#[tokio::test]
+async fn a_welcome_email_is_sent_after_confirmation() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    mount_email_mock(&app, 1).await;
+
+    app.dispatch_all_pending_onboarding_emails().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let email: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(email["Subject"], "Welcome aboard!");
+    assert!(email["TextBody"]
+        .as_str()
+        .unwrap()
+        .starts_with("Hi le guin,"));
+    assert_eq!(onboarding_statuses(&app).await, vec![(1, "sent".into())]);
+}
+
+#[tokio::test]
+async fn drip_emails_are_sent_once_due() {
+    let app = spawn_app().await;
+    app.put_onboarding_email(2, drip_email(2))
+        .await
+        .error_for_status()
+        .unwrap();
+    create_confirmed_subscriber(&app).await;
+    mount_email_mock(&app, 2).await;
+
+    app.dispatch_all_pending_onboarding_emails().await;
+    assert_eq!(
+        onboarding_statuses(&app).await,
+        vec![(1, "sent".into()), (2, "scheduled".into())]
+    );
+    let delay = sqlx::query_scalar!(
+        r#"SELECT EXTRACT(DAY FROM scheduled_for - updated_at)::int AS "days!" FROM onboarding_deliveries WHERE step = 2"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(delay, 2);
+
+    sqlx::query!("UPDATE onboarding_deliveries SET scheduled_for = now() WHERE step = 2")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.dispatch_all_pending_onboarding_emails().await;
+
+    assert_eq!(
+        onboarding_statuses(&app).await,
+        vec![(1, "sent".into()), (2, "sent".into())]
+    );
+}
+
+#[tokio::test]
+async fn the_sequence_is_cancelled_when_the_subscriber_leaves_every_list() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = sqlx::query!("SELECT email, preferences_token FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+
+    app.post_preferences(
+        &subscriber.preferences_token,
+        format!(
+            "name=Ursula&email={}&digest_frequency=immediate",
+            subscriber.email.replace('@', "%40")
+        ),
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    app.dispatch_all_pending_onboarding_emails().await;
+
+    assert_eq!(
+        onboarding_statuses(&app).await,
+        vec![(1, "cancelled".into())]
+    );
+}
+
+#[tokio::test]
+async fn the_sequence_is_postponed_while_the_subscriber_is_paused() {
+    let app = spawn_app().await;
+    app.put_onboarding_email(2, drip_email(2))
+        .await
+        .error_for_status()
+        .unwrap();
+    create_confirmed_subscriber(&app).await;
+    sqlx::query!("UPDATE subscriptions SET paused_until = now() + interval '3 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    mount_email_mock(&app, 0).await;
+
+    app.dispatch_all_pending_onboarding_emails().await;
+
+    assert_eq!(
+        onboarding_statuses(&app).await,
+        vec![(1, "scheduled".into()), (2, "scheduled".into())]
+    );
+    let delays = sqlx::query_scalar!(
+        r#"
+        SELECT EXTRACT(DAY FROM o.scheduled_for - s.paused_until)::int AS "days!"
+        FROM onboarding_deliveries o JOIN subscriptions s ON s.id = o.subscriber_id
+        ORDER BY o.step
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(delays, vec![0, 2]);
+}
+
+#[tokio::test]
+async fn onboarding_emails_that_cannot_be_sent_yet_are_retried_from_the_outbox() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.dispatch_all_pending_onboarding_emails().await;
+
+    let message =
+        sqlx::query!("SELECT status, attempts FROM email_outbox WHERE subject = 'Welcome aboard!'")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(message.status, "pending");
+    assert_eq!(message.attempts, 1);
+}
+
+#[tokio::test]
+async fn due_emails_of_subscribers_without_lists_are_cancelled_by_the_worker() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    sqlx::query!("DELETE FROM list_subscriptions")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    mount_email_mock(&app, 0).await;
+
+    app.dispatch_all_pending_onboarding_emails().await;
+
+    assert_eq!(
+        onboarding_statuses(&app).await,
+        vec![(1, "cancelled".into())]
+    );
+}
+
+#[tokio::test]
+async fn the_sequence_can_be_managed_by_admins() {
+    let app = spawn_app().await;
+
+    let response = app.put_onboarding_email(3, drip_email(7)).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = app.put_onboarding_email(2, drip_email(2)).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = reqwest::Client::new()
+        .delete(format!("{}/admin/onboarding/1", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 204);
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/onboarding", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let sequence: serde_json::Value = response.json().await.unwrap();
+    let delays: Vec<_> = sequence
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| {
+            (
+                e["step"].as_i64().unwrap(),
+                e["delay_days"].as_i64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(delays, vec![(2, 2), (3, 7)]);
+}
+
+#[tokio::test]
+async fn invalid_onboarding_emails_are_rejected() {
+    let app = spawn_app().await;
+
+    assert_eq!(
+        app.put_onboarding_email(0, drip_email(1))
+            .await
+            .status()
+            .as_u16(),
+        400
+    );
+    assert_eq!(
+        app.put_onboarding_email(2, drip_email(-1))
+            .await
+            .status()
+            .as_u16(),
+        400
+    );
+    let mut body = drip_email(1);
+    body["subject"] = " ".into();
+    assert_eq!(
+        app.put_onboarding_email(2, body).await.status().as_u16(),
+        400
+    );
+}
+
+#[tokio::test]
+async fn managing_the_sequence_requires_authentication() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .put(format!("{}/admin/onboarding/2", &app.address))
+        .json(&drip_email(2))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
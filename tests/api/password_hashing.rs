@@ -0,0 +1,77 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use std::time::Duration;
+
+async fn stored_password_hash(app: &TestApp) -> String {
+    sqlx::query_scalar!(
+        "SELECT password_hash FROM users WHERE user_id = $1",
+        app.test_user.user_id,
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+}
+
+async fn get_lists(app: &TestApp) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("{}/admin/lists", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn weaker_hashes_are_upgraded_after_a_successful_login() {
+    let app = spawn_app_with(|c| {
+        c.password_hashing.memory_kib = 16384;
+        c.password_hashing.iterations = 3;
+    })
+    .await;
+    assert!(stored_password_hash(&app).await.contains("m=15000,t=2,p=1"));
+
+    let response = get_lists(&app).await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    // The upgrade happens in the background.
+    let mut upgraded = false;
+    for _ in 0..50 {
+        if stored_password_hash(&app).await.contains("m=16384,t=3,p=1") {
+            upgraded = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(upgraded, "The password hash was not upgraded.");
+    // The password still works against the new hash.
+    let response = get_lists(&app).await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn current_hashes_are_left_alone() {
+    let app = spawn_app().await;
+    let password_hash = stored_password_hash(&app).await;
+
+    let response = get_lists(&app).await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(stored_password_hash(&app).await, password_hash);
+}
+
+#[tokio::test]
+async fn failed_logins_do_not_upgrade_hashes() {
+    let app = spawn_app_with(|c| c.password_hashing.memory_kib = 16384).await;
+    let password_hash = stored_password_hash(&app).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/lists", &app.address))
+        .basic_auth(&app.test_user.username, Some("wrong-password"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(response.status().as_u16(), 401);
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(stored_password_hash(&app).await, password_hash);
+}
@@ -0,0 +1,312 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp, TestUser};
+use std::time::Duration;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::configuration::TokenBucket;
+
+const EMAIL: &str = "ursula_le_guin@gmail.com";
+const NEW_PASSWORD: &str = "a-much-longer-and-better-password";
+
+async fn put_user_email(
+    app: &TestApp,
+    as_user: &TestUser,
+    user: &TestUser,
+    email: Option<&str>,
+) -> reqwest::Response {
+    reqwest::Client::new()
+        .put(format!(
+            "{}/admin/users/{}/email",
+            &app.address, user.user_id
+        ))
+        .basic_auth(&as_user.username, Some(&as_user.password))
+        .json(&serde_json::json!({ "email": email }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn set_email(app: &TestApp) {
+    let response = put_user_email(app, &app.test_user, &app.test_user, Some(EMAIL)).await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+async fn forgot_password(app: &TestApp, email: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/login/forgot-password", &app.address))
+        .json(&serde_json::json!({ "email": email }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn reset_password(app: &TestApp, token: &str, new_password: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/login/reset-password", &app.address))
+        .json(&serde_json::json!({
+            "token": token,
+            "new_password": new_password,
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn login(app: &TestApp, password: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/login", &app.address))
+        .json(&serde_json::json!({
+            "username": app.test_user.username,
+            "password": password,
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+/// Request a reset link and return its token, once the email was sent in the background.
+async fn reset_token(app: &TestApp) -> String {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    forgot_password(app, EMAIL).await;
+    for _ in 0..50 {
+        if let Some(request) = app.email_server.received_requests().await.unwrap().last() {
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            let links: Vec<_> = linkify::LinkFinder::new()
+                .links(body["TextBody"].as_str().unwrap())
+                .filter(|l| *l.kind() == linkify::LinkKind::Url)
+                .collect();
+            let link = reqwest::Url::parse(links[0].as_str()).unwrap();
+            assert_eq!(link.path(), "/login/reset-password");
+            return link
+                .query_pairs()
+                .find(|(key, _)| key == "token")
+                .unwrap()
+                .1
+                .into_owned();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("No password reset email was sent.");
+}
+
+#[tokio::test]
+async fn the_response_is_the_same_whether_or_not_the_account_exists() {
+    let app = spawn_app().await;
+    set_email(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let known = forgot_password(&app, EMAIL).await;
+    let unknown = forgot_password(&app, "someone_else@gmail.com").await;
+
+    assert_eq!(known.status().as_u16(), 202);
+    assert_eq!(unknown.status().as_u16(), 202);
+    assert_eq!(known.text().await.unwrap(), unknown.text().await.unwrap());
+    // Give the background email a chance to be sent before the mock is verified.
+    tokio::time::sleep(Duration::from_millis(500)).await;
+}
+
+#[tokio::test]
+async fn a_reset_link_sets_a_new_password() {
+    let app = spawn_app().await;
+    set_email(&app).await;
+    let token = reset_token(&app).await;
+
+    let response = reset_password(&app, &token, NEW_PASSWORD).await;
+
+    assert_eq!(response.status().as_u16(), 204);
+    assert_eq!(
+        login(&app, &app.test_user.password.clone())
+            .await
+            .status()
+            .as_u16(),
+        401
+    );
+    assert_eq!(login(&app, NEW_PASSWORD).await.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn the_reset_link_opens_a_page_with_a_form() {
+    let app = spawn_app().await;
+    set_email(&app).await;
+    let token = reset_token(&app).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/login/reset-password", &app.address))
+        .query(&[("token", &token)])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["referrer-policy"], "no-referrer");
+    let page = response.text().await.unwrap();
+    assert!(page.contains(r#"<form action="/login/reset-password" method="post">"#));
+    assert!(page.contains(&format!(
+        r#"<input type="hidden" name="token" value="{token}">"#
+    )));
+    assert!(page.contains(r#"name="new_password""#));
+}
+
+#[tokio::test]
+async fn the_form_of_the_reset_page_sets_a_new_password() {
+    let app = spawn_app().await;
+    set_email(&app).await;
+    let token = reset_token(&app).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/login/reset-password", &app.address))
+        .form(&[("token", token.as_str()), ("new_password", NEW_PASSWORD)])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("Your password has been reset."));
+    assert_eq!(login(&app, NEW_PASSWORD).await.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn admins_set_and_clear_the_email_address_of_a_user() {
+    let app = spawn_app().await;
+    let editor = TestUser::with_role("editor");
+    editor.store(&app.db_pool).await;
+
+    let response = put_user_email(&app, &app.test_user, &editor, Some(EMAIL)).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["email"], EMAIL);
+
+    let response = put_user_email(&app, &app.test_user, &editor, None).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["email"], serde_json::Value::Null);
+}
+
+#[tokio::test]
+async fn users_cannot_share_an_email_address() {
+    let app = spawn_app().await;
+    let editor = TestUser::with_role("editor");
+    editor.store(&app.db_pool).await;
+    set_email(&app).await;
+
+    let response = put_user_email(&app, &app.test_user, &editor, Some(&EMAIL.to_uppercase())).await;
+
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn invalid_email_addresses_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = put_user_email(&app, &app.test_user, &app.test_user, Some("not-an-email")).await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn only_admins_set_email_addresses() {
+    let app = spawn_app().await;
+    let editor = TestUser::with_role("editor");
+    editor.store(&app.db_pool).await;
+
+    let response = put_user_email(&app, &editor, &editor, Some(EMAIL)).await;
+
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[tokio::test]
+async fn reset_links_are_single_use() {
+    let app = spawn_app().await;
+    set_email(&app).await;
+    let token = reset_token(&app).await;
+
+    reset_password(&app, &token, NEW_PASSWORD).await;
+    let response = reset_password(&app, &token, "yet-another-new-password").await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(login(&app, NEW_PASSWORD).await.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn expired_reset_links_are_rejected() {
+    let app = spawn_app().await;
+    set_email(&app).await;
+    let token = reset_token(&app).await;
+    sqlx::query!("UPDATE password_reset_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = reset_password(&app, &token, NEW_PASSWORD).await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn short_passwords_are_rejected() {
+    let app = spawn_app().await;
+    set_email(&app).await;
+    let token = reset_token(&app).await;
+
+    let response = reset_password(&app, &token, "short").await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    // The link can still be used with a better password.
+    let response = reset_password(&app, &token, NEW_PASSWORD).await;
+    assert_eq!(response.status().as_u16(), 204);
+}
+
+#[tokio::test]
+async fn a_reset_ends_every_session() {
+    let app = spawn_app().await;
+    set_email(&app).await;
+    let body: serde_json::Value = login(&app, &app.test_user.password.clone())
+        .await
+        .json()
+        .await
+        .unwrap();
+    let session_token = body["session_token"].as_str().unwrap().to_owned();
+    let token = reset_token(&app).await;
+
+    reset_password(&app, &token, NEW_PASSWORD).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/lists", &app.address))
+        .bearer_auth(session_token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn reset_attempts_are_limited_per_client_ip() {
+    let app = spawn_app_with(|c| {
+        c.rate_limit.enabled = true;
+        c.rate_limit.password_reset.per_ip = Some(TokenBucket {
+            capacity: 2,
+            refill_interval_seconds: 86_400,
+        });
+    })
+    .await;
+
+    for guess in ["first-guess", "second-guess"] {
+        let response = reset_password(&app, guess, NEW_PASSWORD).await;
+        assert_eq!(response.status().as_u16(), 400);
+    }
+    let response = reset_password(&app, "third-guess", NEW_PASSWORD).await;
+
+    assert_eq!(response.status().as_u16(), 429);
+}
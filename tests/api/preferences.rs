@@ -0,0 +1,537 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app, BatchResponder, TestApp};
+use std::time::Duration;
+use test_case::test_case;
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::digest_worker;
+
+/// Create a confirmed subscriber and return its preferences token.
+async fn confirmed_subscriber_token(app: &TestApp) -> String {
+    create_confirmed_subscriber(app).await;
+    sqlx::query_scalar!(
+        "SELECT preferences_token FROM subscriptions ORDER BY subscribed_at DESC LIMIT 1"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+}
+
+async fn publish_issue(app: &TestApp, title: &str) {
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": title,
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            },
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+fn preferences_form(email: &str, extra: &str) -> String {
+    format!(
+        "name=Ursula&email={}&lists=default&digest_frequency=immediate{extra}",
+        email.replace('@', "%40")
+    )
+}
+
+async fn subscriber_email(app: &TestApp) -> String {
+    sqlx::query_scalar!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn the_preferences_page_shows_the_current_preferences() {
+    let app = spawn_app().await;
+    let token = confirmed_subscriber_token(&app).await;
+
+    let response = app.get_preferences(&token).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"name="name" value="le guin""#));
+    assert!(html.contains(r#"name="lists" value="default" checked"#));
+    assert!(html.contains(r#"<option value="immediate" selected>"#));
+}
+
+#[tokio::test]
+async fn an_unknown_token_is_rejected_with_a_404() {
+    let app = spawn_app().await;
+
+    let response = app.get_preferences("not-a-token").await;
+    assert_eq!(response.status().as_u16(), 404);
+
+    let response = app
+        .post_preferences("not-a-token", preferences_form("ursula@example.com", ""))
+        .await;
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn preferences_are_persisted() {
+    let app = spawn_app().await;
+    let token = confirmed_subscriber_token(&app).await;
+    let email = subscriber_email(&app).await;
+
+    let response = app
+        .post_preferences(
+            &token,
+            format!(
+                "name=Ursula&email={}&digest_frequency=weekly&paused_until=2031-01-01",
+                email.replace('@', "%40")
+            ),
+        )
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!(
+        "SELECT name, digest_frequency, paused_until::text AS paused_until FROM subscriptions"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(saved.name, "Ursula");
+    assert_eq!(saved.digest_frequency, "weekly");
+    assert_eq!(
+        saved.paused_until.as_deref(),
+        Some("2031-01-01 00:00:00+00")
+    );
+    let list_subscriptions =
+        sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM list_subscriptions"#)
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(list_subscriptions, 0);
+}
+
+#[tokio::test]
+async fn invalid_preferences_are_rejected_with_a_400() {
+    let app = spawn_app().await;
+    let token = confirmed_subscriber_token(&app).await;
+    let email = subscriber_email(&app).await;
+
+    for (body, description) in [
+        (
+            preferences_form(&email, "").replace("name=Ursula", "name=%3Cscript%3E"),
+            "invalid name",
+        ),
+        (preferences_form("not-an-email", ""), "invalid email"),
+        (
+            preferences_form(&email, "").replace("immediate", "hourly"),
+            "unknown frequency",
+        ),
+        (
+            preferences_form(&email, "&paused_until=tomorrow"),
+            "invalid pause date",
+        ),
+        (
+            preferences_form(&email, "&lists=no-such-list"),
+            "unknown list",
+        ),
+    ] {
+        let response = app.post_preferences(&token, body).await;
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "The API did not fail with 400 Bad Request when the payload had an {description}."
+        );
+    }
+}
+
+#[tokio::test]
+async fn newsletters_link_to_the_preferences_page() {
+    let app = spawn_app().await;
+    let token = confirmed_subscriber_token(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchResponder::accepting_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    publish_issue(&app, "Newsletter title").await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let batch: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let link = format!("/preferences/{token}");
+    assert!(batch[0]["HtmlBody"].as_str().unwrap().contains(&link));
+    assert!(batch[0]["TextBody"].as_str().unwrap().contains(&link));
+}
+
+#[tokio::test]
+async fn unsubscribed_and_paused_subscribers_do_not_receive_newsletters() {
+    let app = spawn_app().await;
+    let unsubscribed = confirmed_subscriber_token(&app).await;
+    let paused = confirmed_subscriber_token(&app).await;
+    let emails: Vec<String> =
+        sqlx::query_scalar!("SELECT email FROM subscriptions ORDER BY subscribed_at DESC")
+            .fetch_all(&app.db_pool)
+            .await
+            .unwrap();
+    app.post_preferences(
+        &unsubscribed,
+        format!(
+            "name=Ursula&email={}&digest_frequency=immediate",
+            emails[1].replace('@', "%40")
+        ),
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+    app.post_preferences(
+        &paused,
+        preferences_form(&emails[0], "&paused_until=2999-01-01"),
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    publish_issue(&app, "Newsletter title").await;
+}
+
+#[tokio::test]
+async fn a_past_pause_date_does_not_stop_deliveries() {
+    let app = spawn_app().await;
+    let token = confirmed_subscriber_token(&app).await;
+    let email = subscriber_email(&app).await;
+    app.post_preferences(&token, preferences_form(&email, "&paused_until=2001-01-01"))
+        .await
+        .error_for_status()
+        .unwrap();
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchResponder::accepting_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_issue(&app, "Newsletter title").await;
+}
+
+#[tokio::test]
+async fn digest_subscribers_receive_due_issues_in_a_single_email() {
+    let app = spawn_app().await;
+    let token = confirmed_subscriber_token(&app).await;
+    let email = subscriber_email(&app).await;
+    app.post_preferences(
+        &token,
+        preferences_form(&email, "").replace("immediate", "daily"),
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let received_before = app.email_server.received_requests().await.unwrap().len();
+    publish_issue(&app, "First issue").await;
+    publish_issue(&app, "Second issue").await;
+    // Nothing is due until tomorrow
+    app.dispatch_all_pending_digests().await;
+    assert_eq!(
+        app.email_server.received_requests().await.unwrap().len(),
+        received_before
+    );
+
+    sqlx::query!("UPDATE deliveries SET scheduled_for = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.dispatch_all_pending_digests().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let email: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(email["Subject"], "Your newsletter digest: 2 new issues");
+    let html = email["HtmlBody"].as_str().unwrap();
+    assert!(html.find("First issue").unwrap() < html.find("Second issue").unwrap());
+    let statuses = sqlx::query_scalar!("SELECT status FROM deliveries")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(statuses, vec!["sent", "sent"]);
+}
+
+#[tokio::test]
+async fn deliveries_are_leased_rather_than_locked_while_a_digest_is_sent() {
+    let app = spawn_app().await;
+    let token = confirmed_subscriber_token(&app).await;
+    let email = subscriber_email(&app).await;
+    app.post_preferences(
+        &token,
+        preferences_form(&email, "").replace("immediate", "daily"),
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+    publish_issue(&app, "Newsletter title").await;
+    sqlx::query!("UPDATE deliveries SET scheduled_for = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(3)))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let worker = digest_worker::try_execute_task(&app.db_pool, &app.email_client, &app.base_url);
+    let while_sending = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        sqlx::query!(
+            r#"
+            SELECT status, scheduled_for > now() AS "leased!"
+            FROM deliveries
+            FOR UPDATE NOWAIT
+            "#
+        )
+        .fetch_one(&app.db_pool)
+        .await
+    };
+    let (outcome, delivery) = tokio::join!(worker, while_sending);
+
+    outcome.unwrap();
+    let delivery = delivery.expect("The delivery was locked while the digest was sent.");
+    assert_eq!(delivery.status, "queued");
+    assert!(delivery.leased);
+    let status = sqlx::query_scalar!("SELECT status FROM deliveries")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(status, "sent");
+}
+
+#[test_case("&lists=default&paused_until=2999-01-01"; "paused")]
+#[test_case(""; "unsubscribed")]
+#[tokio::test]
+async fn queued_digests_are_cancelled_when_the_subscriber_opts_out(opt_out: &str) {
+    let app = spawn_app().await;
+    let token = confirmed_subscriber_token(&app).await;
+    let email = subscriber_email(&app).await;
+    let digest_form = |extra: &str| {
+        format!(
+            "name=Ursula&email={}&digest_frequency=daily{extra}",
+            email.replace('@', "%40")
+        )
+    };
+    app.post_preferences(&token, digest_form("&lists=default"))
+        .await
+        .error_for_status()
+        .unwrap();
+    publish_issue(&app, "Newsletter title").await;
+
+    app.post_preferences(&token, digest_form(opt_out))
+        .await
+        .error_for_status()
+        .unwrap();
+    sqlx::query!("UPDATE deliveries SET scheduled_for = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    app.dispatch_all_pending_digests().await;
+
+    let deliveries = sqlx::query_scalar!("SELECT COUNT(*) FROM deliveries")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(deliveries, Some(0));
+}
+
+/// Ask to move the subscriber to `new_email`, returning the emails sent to the new
+/// and to the current address.
+async fn request_email_change(
+    app: &TestApp,
+    token: &str,
+    new_email: &str,
+) -> (wiremock::Request, wiremock::Request) {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let response = app
+        .post_preferences(token, preferences_form(new_email, ""))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    let mut requests = app.email_server.received_requests().await.unwrap();
+    let notice = requests.pop().unwrap();
+    let confirmation = requests.pop().unwrap();
+    (confirmation, notice)
+}
+
+fn recipient(email_request: &wiremock::Request) -> String {
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    body["To"].as_str().unwrap().to_owned()
+}
+
+#[tokio::test]
+async fn changing_the_email_address_requires_confirming_the_new_one() {
+    let app = spawn_app().await;
+    let token = confirmed_subscriber_token(&app).await;
+    let old_email = subscriber_email(&app).await;
+    let new_email = format!("{}@example.com", Uuid::new_v4());
+
+    let (confirmation, _) = request_email_change(&app, &token, &new_email).await;
+    assert_eq!(subscriber_email(&app).await, old_email);
+    assert_eq!(recipient(&confirmation), new_email);
+    let link = app.get_confirmation_links(&confirmation).html;
+    assert_eq!(link.path(), "/preferences/email/confirm");
+
+    let response = reqwest::get(link.clone()).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_email(&app).await, new_email);
+    // The link can only be used once
+    let response = reqwest::get(link).await.unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn email_change_requests_are_saved_even_if_the_emails_cannot_be_sent_yet() {
+    let app = spawn_app().await;
+    let token = confirmed_subscriber_token(&app).await;
+    let new_email = format!("{}@example.com", Uuid::new_v4());
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_preferences(&token, preferences_form(&new_email, ""))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let pending = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM email_outbox WHERE status = 'pending' AND subject <> 'Welcome!'"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(pending, Some(2));
+    let tokens = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM subscription_tokens WHERE purpose = 'email_change'"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(tokens, Some(1));
+}
+
+#[tokio::test]
+async fn the_current_address_is_notified_of_a_change_request() {
+    let app = spawn_app().await;
+    let token = confirmed_subscriber_token(&app).await;
+    let old_email = subscriber_email(&app).await;
+    let new_email = format!("{}@example.com", Uuid::new_v4());
+
+    let (_, notice) = request_email_change(&app, &token, &new_email).await;
+
+    assert_eq!(recipient(&notice), old_email);
+    let body: serde_json::Value = serde_json::from_slice(&notice.body).unwrap();
+    assert!(body["TextBody"].as_str().unwrap().contains(&new_email));
+}
+
+#[tokio::test]
+async fn a_newer_change_request_supersedes_an_older_one() {
+    let app = spawn_app().await;
+    let token = confirmed_subscriber_token(&app).await;
+    let first_email = format!("{}@example.com", Uuid::new_v4());
+    let second_email = format!("{}@example.com", Uuid::new_v4());
+
+    let (first, _) = request_email_change(&app, &token, &first_email).await;
+    let (second, _) = request_email_change(&app, &token, &second_email).await;
+
+    let response = reqwest::get(app.get_confirmation_links(&first).html)
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+    let response = reqwest::get(app.get_confirmation_links(&second).html)
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_email(&app).await, second_email);
+}
+
+#[tokio::test]
+async fn confirming_a_change_to_an_address_subscribed_in_the_meantime_returns_409() {
+    let app = spawn_app().await;
+    let token = confirmed_subscriber_token(&app).await;
+    let old_email = subscriber_email(&app).await;
+    let new_email = format!("{}@example.com", Uuid::new_v4());
+    let (confirmation, _) = request_email_change(&app, &token, &new_email).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(format!(
+        "name=le%20guin&email={}",
+        new_email.replace('@', "%40")
+    ))
+    .await
+    .error_for_status()
+    .unwrap();
+
+    let response = reqwest::get(app.get_confirmation_links(&confirmation).html)
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 409);
+    let emails: Vec<String> = sqlx::query_scalar!("SELECT email FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    let mut expected = vec![old_email, new_email];
+    expected.sort();
+    assert_eq!(emails, expected);
+}
+
+#[tokio::test]
+async fn changing_to_an_address_already_subscribed_is_rejected() {
+    let app = spawn_app().await;
+    let token = confirmed_subscriber_token(&app).await;
+    confirmed_subscriber_token(&app).await;
+    let other_email: String =
+        sqlx::query_scalar!("SELECT email FROM subscriptions ORDER BY subscribed_at DESC LIMIT 1")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+
+    let response = app
+        .post_preferences(&token, preferences_form(&other_email, ""))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
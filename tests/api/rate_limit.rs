@@ -0,0 +1,202 @@
+use crate::helpers::{spawn_app_with, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::configuration::{get_configuration, RateLimitStore, TokenBucket};
+use zero2prod::rate_limit::RateLimiter;
+
+const ONE_A_DAY: u64 = 86_400;
+
+fn bucket(capacity: u32) -> Option<TokenBucket> {
+    Some(TokenBucket {
+        capacity,
+        refill_interval_seconds: ONE_A_DAY,
+    })
+}
+
+async fn mount_email_mock(app: &TestApp, expected: u64) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(expected)
+        .mount(&app.email_server)
+        .await;
+}
+
+async fn subscribe_from(app: &TestApp, email: &str, forwarded_for: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("X-Forwarded-For", forwarded_for)
+        .form(&[("name", "le guin"), ("email", email)])
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn subscriptions_are_limited_per_client_ip() {
+    let app = spawn_app_with(|c| {
+        c.rate_limit.enabled = true;
+        c.rate_limit.subscribe.per_ip = bucket(2);
+        c.rate_limit.subscribe.per_email = None;
+    })
+    .await;
+    mount_email_mock(&app, 2).await;
+
+    for i in 0..2 {
+        let response = app
+            .post_subscriptions(format!("name=le%20guin&email=ursula{i}%40gmail.com"))
+            .await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula2%40gmail.com".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 429);
+    let retry_after: u64 = response.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0 && retry_after <= ONE_A_DAY);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "/problems/rate-limited");
+}
+
+#[tokio::test]
+async fn subscriptions_are_limited_per_target_email() {
+    let app = spawn_app_with(|c| {
+        c.rate_limit.enabled = true;
+        c.rate_limit.subscribe.per_ip = None;
+        c.rate_limit.subscribe.per_email = bucket(1);
+    })
+    .await;
+    mount_email_mock(&app, 2).await;
+
+    let first = app
+        .post_subscriptions("name=le%20guin&email=ursula%40gmail.com".into())
+        .await;
+    let same_email = app
+        .post_subscriptions("name=le%20guin&email=URSULA%40gmail.com".into())
+        .await;
+    let other_email = app
+        .post_subscriptions("name=le%20guin&email=octavia%40gmail.com".into())
+        .await;
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(same_email.status().as_u16(), 429);
+    assert_eq!(other_email.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn forwarded_addresses_are_only_trusted_from_known_proxies() {
+    let app = spawn_app_with(|c| {
+        c.rate_limit.enabled = true;
+        c.rate_limit.trusted_proxies = vec!["127.0.0.1/32".parse().unwrap()];
+        c.rate_limit.subscribe.per_ip = bucket(1);
+        c.rate_limit.subscribe.per_email = None;
+    })
+    .await;
+    mount_email_mock(&app, 2).await;
+
+    let first = subscribe_from(&app, "a@gmail.com", "1.1.1.1").await;
+    let second = subscribe_from(&app, "b@gmail.com", "2.2.2.2").await;
+    let repeated = subscribe_from(&app, "c@gmail.com", "1.1.1.1").await;
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+    assert_eq!(repeated.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn forwarded_addresses_from_untrusted_peers_are_ignored() {
+    let app = spawn_app_with(|c| {
+        c.rate_limit.enabled = true;
+        c.rate_limit.subscribe.per_ip = bucket(1);
+        c.rate_limit.subscribe.per_email = None;
+    })
+    .await;
+    mount_email_mock(&app, 1).await;
+
+    let first = subscribe_from(&app, "a@gmail.com", "1.1.1.1").await;
+    let spoofed = subscribe_from(&app, "b@gmail.com", "2.2.2.2").await;
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(spoofed.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn confirmations_are_limited_per_client_ip() {
+    let app = spawn_app_with(|c| {
+        c.rate_limit.enabled = true;
+        c.rate_limit.confirm.per_ip = bucket(1);
+    })
+    .await;
+    let confirm = || {
+        reqwest::get(format!(
+            "{}/subscriptions/confirm?subscription_token=unknown",
+            app.address
+        ))
+    };
+
+    assert_eq!(confirm().await.unwrap().status().as_u16(), 401);
+    assert_eq!(confirm().await.unwrap().status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn buckets_can_be_shared_through_postgres() {
+    let app = spawn_app_with(|c| {
+        c.rate_limit.enabled = true;
+        c.rate_limit.store = RateLimitStore::Postgres;
+        c.rate_limit.subscribe.per_ip = bucket(1);
+        c.rate_limit.subscribe.per_email = bucket(1);
+    })
+    .await;
+    mount_email_mock(&app, 1).await;
+
+    let first = app
+        .post_subscriptions("name=le%20guin&email=ursula%40gmail.com".into())
+        .await;
+    let second = app
+        .post_subscriptions("name=le%20guin&email=octavia%40gmail.com".into())
+        .await;
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 429);
+    let keys = sqlx::query_scalar!("SELECT bucket_key FROM rate_limit_buckets ORDER BY bucket_key")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(keys.len(), 2);
+    assert!(keys[0].starts_with("subscribe:email:"));
+    assert!(keys[1].starts_with("subscribe:ip:"));
+    assert!(keys.iter().all(|key| !key.contains("ursula")));
+}
+
+#[tokio::test]
+async fn buckets_that_have_refilled_are_deleted_from_postgres() {
+    let app = spawn_app_with(|_| {}).await;
+    let mut settings = get_configuration().unwrap().rate_limit;
+    settings.store = RateLimitStore::Postgres;
+    settings.subscribe.per_ip = bucket(1);
+    sqlx::query!(
+        r#"
+        INSERT INTO rate_limit_buckets (bucket_key, tokens, updated_at)
+        VALUES ('subscribe:ip:stale', 0, now() - interval '2 days'),
+            ('subscribe:ip:recent', 0, now() - interval '2 hours')
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    RateLimiter::new(settings, app.db_pool.clone())
+        .sweep()
+        .await;
+
+    let keys = sqlx::query_scalar!("SELECT bucket_key FROM rate_limit_buckets")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(keys, ["subscribe:ip:recent"]);
+}
@@ -0,0 +1,201 @@
+use crate::helpers::{spawn_app, TestApp, TestUser};
+
+async fn user_with_role(app: &TestApp, role: &'static str) -> TestUser {
+    let user = TestUser::with_role(role);
+    user.store(&app.db_pool).await;
+    user
+}
+
+async fn assign_role(
+    app: &TestApp,
+    as_user: &TestUser,
+    user: &TestUser,
+    role: &str,
+) -> reqwest::Response {
+    reqwest::Client::new()
+        .put(format!(
+            "{}/admin/users/{}/role",
+            &app.address, user.user_id
+        ))
+        .basic_auth(&as_user.username, Some(&as_user.password))
+        .json(&serde_json::json!({ "role": role }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+#[tokio::test]
+async fn viewers_can_read_but_not_change_anything() {
+    let app = spawn_app().await;
+    let viewer = user_with_role(&app, "viewer").await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("{}/admin/lists", &app.address))
+        .basic_auth(&viewer.username, Some(&viewer.password))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = client
+        .post(format!("{}/admin/lists", &app.address))
+        .basic_auth(&viewer.username, Some(&viewer.password))
+        .json(&serde_json::json!({ "slug": "weekly", "name": "Weekly" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["type"], "/problems/forbidden");
+    assert_eq!(
+        body["detail"],
+        "The viewer role does not grant the lists:write scope."
+    );
+}
+
+#[tokio::test]
+async fn editors_cannot_publish() {
+    let app = spawn_app().await;
+    let editor = user_with_role(&app, "editor").await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(&editor.username, Some(&editor.password))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[tokio::test]
+async fn publishers_can_publish() {
+    let app = spawn_app().await;
+    let publisher = user_with_role(&app, "publisher").await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(&publisher.username, Some(&publisher.password))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn admins_assign_roles() {
+    let app = spawn_app().await;
+    let editor = user_with_role(&app, "editor").await;
+
+    let response = assign_role(&app, &app.test_user, &editor, "publisher").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let users: serde_json::Value = reqwest::Client::new()
+        .get(format!("{}/admin/users", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let promoted = users
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|user| user["username"] == editor.username.as_str())
+        .unwrap();
+    assert_eq!(promoted["role"], "publisher");
+}
+
+#[tokio::test]
+async fn only_admins_assign_roles() {
+    let app = spawn_app().await;
+    let publisher = user_with_role(&app, "publisher").await;
+
+    let response = assign_role(&app, &publisher, &publisher, "admin").await;
+
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[tokio::test]
+async fn unknown_roles_are_rejected() {
+    let app = spawn_app().await;
+    let editor = user_with_role(&app, "editor").await;
+
+    let response = assign_role(&app, &app.test_user, &editor, "owner").await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn the_last_admin_cannot_be_demoted() {
+    let app = spawn_app().await;
+
+    let response = assign_role(&app, &app.test_user, &app.test_user, "viewer").await;
+    assert_eq!(response.status().as_u16(), 409);
+
+    // With a second admin, the first one can step down.
+    let admin = user_with_role(&app, "admin").await;
+    let response = assign_role(&app, &admin, &app.test_user, "viewer").await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn api_keys_cannot_grant_more_than_the_role_of_their_user() {
+    let app = spawn_app().await;
+    let editor = user_with_role(&app, "editor").await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/api-keys", &app.address))
+        .basic_auth(&editor.username, Some(&editor.password))
+        .json(&serde_json::json!({ "name": "CI", "scopes": ["newsletters:publish"] }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 403);
+}
+
+#[tokio::test]
+async fn demotions_apply_to_existing_api_keys() {
+    let app = spawn_app().await;
+    let publisher = user_with_role(&app, "publisher").await;
+    let client = reqwest::Client::new();
+    let body: serde_json::Value = client
+        .post(format!("{}/admin/api-keys", &app.address))
+        .basic_auth(&publisher.username, Some(&publisher.password))
+        .json(&serde_json::json!({ "name": "CI", "scopes": ["newsletters:publish"] }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let key = body["key"].as_str().unwrap();
+
+    assign_role(&app, &app.test_user, &publisher, "editor").await;
+
+    let response = client
+        .post(format!("{}/newsletters", &app.address))
+        .bearer_auth(key)
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+}
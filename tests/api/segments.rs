@@ -0,0 +1,234 @@
+use crate::helpers::{
+    create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app, BatchResponder, TestApp,
+};
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// Create a confirmed subscriber and return its id.
+async fn confirmed_subscriber_id(app: &TestApp) -> Uuid {
+    create_confirmed_subscriber(app).await;
+    sqlx::query_scalar!("SELECT id FROM subscriptions ORDER BY subscribed_at DESC LIMIT 1")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+}
+
+async fn dry_run(app: &TestApp, segment: &str) -> i64 {
+    let response = app
+        .post_newsletters_dry_run(serde_json::json!({ "segment": segment }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    body["recipients"].as_i64().unwrap()
+}
+
+#[tokio::test]
+async fn tags_can_be_added_and_removed() {
+    let app = spawn_app().await;
+    let subscriber_id = confirmed_subscriber_id(&app).await;
+
+    assert_eq!(
+        app.put_subscriber_tag(&subscriber_id, "beta-testers")
+            .await
+            .status()
+            .as_u16(),
+        204
+    );
+    // Tagging is idempotent
+    assert_eq!(
+        app.put_subscriber_tag(&subscriber_id, "beta-testers")
+            .await
+            .status()
+            .as_u16(),
+        204
+    );
+    app.put_subscriber_tag(&subscriber_id, "vip").await;
+    let subscriber: serde_json::Value = app
+        .get_subscriber(&subscriber_id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        subscriber["tags"],
+        serde_json::json!(["beta-testers", "vip"])
+    );
+
+    assert_eq!(
+        app.delete_subscriber_tag(&subscriber_id, "vip")
+            .await
+            .status()
+            .as_u16(),
+        204
+    );
+    let subscriber: serde_json::Value = app
+        .get_subscriber(&subscriber_id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(subscriber["tags"], serde_json::json!(["beta-testers"]));
+}
+
+#[tokio::test]
+async fn attributes_are_merged_and_null_removes_them() {
+    let app = spawn_app().await;
+    let subscriber_id = confirmed_subscriber_id(&app).await;
+
+    app.patch_subscriber_attributes(
+        &subscriber_id,
+        serde_json::json!({"plan": "pro", "age": 30}),
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+    let response = app
+        .patch_subscriber_attributes(
+            &subscriber_id,
+            serde_json::json!({"age": null, "vip": true}),
+        )
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let attributes: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(attributes, serde_json::json!({"plan": "pro", "vip": true}));
+}
+
+#[tokio::test]
+async fn invalid_admin_subscriber_requests_are_rejected() {
+    let app = spawn_app().await;
+    let subscriber_id = confirmed_subscriber_id(&app).await;
+    let unknown_id = Uuid::new_v4();
+
+    assert_eq!(app.get_subscriber(&unknown_id).await.status().as_u16(), 404);
+    assert_eq!(
+        app.put_subscriber_tag(&unknown_id, "vip")
+            .await
+            .status()
+            .as_u16(),
+        404
+    );
+    assert_eq!(
+        app.put_subscriber_tag(&subscriber_id, "Not%20A%20Tag")
+            .await
+            .status()
+            .as_u16(),
+        400
+    );
+    assert_eq!(
+        app.patch_subscriber_attributes(&subscriber_id, serde_json::json!(["not", "an", "object"]))
+            .await
+            .status()
+            .as_u16(),
+        400
+    );
+}
+
+#[tokio::test]
+async fn dry_run_counts_the_confirmed_subscribers_matching_a_segment() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+    let beta_tester = confirmed_subscriber_id(&app).await;
+    let pro = confirmed_subscriber_id(&app).await;
+    confirmed_subscriber_id(&app).await;
+    app.put_subscriber_tag(&beta_tester, "beta-testers").await;
+    app.patch_subscriber_attributes(&pro, serde_json::json!({"plan": "pro", "seats": 12}))
+        .await;
+
+    assert_eq!(dry_run(&app, "joined within 30 days").await, 3);
+    assert_eq!(dry_run(&app, "tag = beta-testers").await, 1);
+    assert_eq!(dry_run(&app, "tag != beta-testers").await, 2);
+    assert_eq!(dry_run(&app, r#"attribute.plan = "pro""#).await, 1);
+    assert_eq!(dry_run(&app, "attribute.seats > 10").await, 1);
+    assert_eq!(dry_run(&app, "attribute.seats > 20").await, 0);
+    assert_eq!(dry_run(&app, "attribute.seats > \"10\"").await, 0);
+    assert_eq!(
+        dry_run(&app, "tag = beta-testers or attribute.plan = pro").await,
+        2
+    );
+    assert_eq!(
+        dry_run(&app, "not (tag = beta-testers or attribute.plan = pro)").await,
+        1
+    );
+}
+
+#[tokio::test]
+async fn invalid_segments_are_rejected_with_a_400() {
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    for body in [
+        serde_json::json!({"segment": "tag = "}),
+        serde_json::json!({"segment": "email = x"}),
+        serde_json::json!({"segment": "tag = a", "lists": ["no-such-list"]}),
+    ] {
+        let response = app.post_newsletters_dry_run(body).await;
+        assert_eq!(response.status().as_u16(), 400);
+    }
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {"text": "text", "html": "<p>html</p>"},
+            "segment": "joined within forever days",
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn dry_run_requires_authentication() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters/dry-run", &app.address))
+        .json(&serde_json::json!({}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn newsletters_are_only_delivered_to_the_segment() {
+    let app = spawn_app().await;
+    let beta_tester = confirmed_subscriber_id(&app).await;
+    confirmed_subscriber_id(&app).await;
+    app.put_subscriber_tag(&beta_tester, "beta-testers").await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchResponder::accepting_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {"text": "text", "html": "<p>html</p>"},
+            "segment": "tag = beta-testers",
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    let batch_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let batch: Vec<serde_json::Value> = serde_json::from_slice(&batch_request.body).unwrap();
+    assert_eq!(batch.len(), 1);
+
+    let segment = sqlx::query_scalar!("SELECT segment FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(segment.as_deref(), Some("tag = beta-testers"));
+}
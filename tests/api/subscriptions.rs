@@ -194,7 +194,7 @@ async fn subscribe_sends_a_confirmation_email_with_a_link(body: &str) {
     app.post_subscriptions(body.into()).await;
 
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
 
     assert_eq!(
         confirmation_links.html, confirmation_links.plain_text,
@@ -219,3 +219,76 @@ async fn subscribe_fails_if_there_is_a_fatal_database_error(body: &str) {
 
     assert_eq!(response.status().as_u16(), 500);
 }
+
+#[tokio::test]
+async fn subscribe_accepts_json_bodies() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT email, name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+    assert_eq!(saved.name, "le guin");
+}
+
+#[tokio::test]
+async fn invalid_json_subscriptions_point_at_every_invalid_field() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "",
+            "email": "definitely-not-an-email",
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/problem+json"
+    );
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "/problems/validation-error");
+    assert_eq!(problem["status"], 400);
+    assert_eq!(
+        problem["errors"],
+        serde_json::json!([
+            {
+                "pointer": "#/email",
+                "detail": "definitely-not-an-email is not a valid subscriber email.",
+            },
+            {
+                "pointer": "#/name",
+                "detail": " is not a valid subscriber name.",
+            },
+        ])
+    );
+}
+
+#[tokio::test]
+async fn invalid_form_subscriptions_also_get_a_problem_document() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=Ursula&email=ursula%40example.com&list=no-such-list".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["errors"][0]["pointer"], "#/list");
+}
@@ -1,7 +1,8 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
 use test_case::test_case;
 use wiremock::matchers::{method, path};
 use wiremock::{Mock, ResponseTemplate};
+use zero2prod::configuration::EmailBackend;
 
 #[tokio::test]
 async fn confirmations_without_token_are_rejected_with_a_400() {
@@ -74,3 +75,230 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber(
     assert_eq!(saved.name, name, "name is not equal");
     assert_eq!(saved.status, "confirmed");
 }
+
+async fn request_confirmation_link(app: &TestApp) -> reqwest::Url {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    app.get_confirmation_links(email_request).html
+}
+
+fn without_redirects() -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn confirming_renders_an_html_page() {
+    let app = spawn_app().await;
+    let link = request_confirmation_link(&app).await;
+
+    let response = reqwest::get(link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/html; charset=utf-8"
+    );
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("<h1>Subscription confirmed</h1>"));
+}
+
+#[tokio::test]
+async fn clicking_the_link_twice_reports_an_already_confirmed_subscription() {
+    let app = spawn_app().await;
+    let link = request_confirmation_link(&app).await;
+    reqwest::get(link.clone())
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let response = reqwest::get(link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("<h1>Already confirmed</h1>"));
+}
+
+#[tokio::test]
+async fn unknown_tokens_are_rejected_with_an_html_page() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!(
+        "{}/subscriptions/confirm?subscription_token=unknown",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("<h1>Invalid confirmation link</h1>"));
+}
+
+#[tokio::test]
+async fn expired_tokens_do_not_confirm_the_subscriber() {
+    let app = spawn_app().await;
+    let link = request_confirmation_link(&app).await;
+    sqlx::query!("UPDATE subscription_tokens SET created_at = now() - interval '73 hours'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = reqwest::get(link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 410);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("<h1>Confirmation link expired</h1>"));
+    let status = sqlx::query_scalar!("SELECT status FROM list_subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn pages_can_be_overridden_from_the_templates_directory() {
+    let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(directory.join("confirmed.html"), "<p>Welcome!</p>").unwrap();
+    let app = spawn_app_with(|c| {
+        c.confirmation.templates_directory = Some(directory.to_string_lossy().into_owned());
+    })
+    .await;
+    let link = request_confirmation_link(&app).await;
+
+    let response = reqwest::get(link).await.unwrap();
+
+    assert_eq!(response.text().await.unwrap(), "<p>Welcome!</p>");
+    let response = reqwest::get(format!(
+        "{}/subscriptions/confirm?subscription_token=unknown",
+        app.address
+    ))
+    .await
+    .unwrap();
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("<h1>Invalid confirmation link</h1>"));
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn outcomes_can_redirect_to_external_pages() {
+    let app = spawn_app_with(|c| {
+        c.confirmation.redirects.confirmed = Some("https://example.com/welcome?from=email".into());
+        c.confirmation.redirects.unknown_token = Some("https://example.com/oops".into());
+    })
+    .await;
+    let link = request_confirmation_link(&app).await;
+
+    let response = without_redirects().get(link).send().await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(
+        response.headers()["location"],
+        "https://example.com/welcome?from=email&outcome=confirmed"
+    );
+    let response = without_redirects()
+        .get(format!(
+            "{}/subscriptions/confirm?subscription_token=unknown",
+            app.address
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        response.headers()["location"],
+        "https://example.com/oops?outcome=unknown_token"
+    );
+}
+
+/// The text part of the single `.eml` file in `directory`.
+fn read_single_eml_text(directory: &std::path::Path) -> String {
+    use base64::Engine;
+
+    let files: Vec<_> = std::fs::read_dir(directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(files.len(), 1, "there should be one email in the mailbox");
+    let message = std::fs::read_to_string(&files[0]).unwrap();
+    let text_part = message
+        .split("Content-Type: text/plain; charset=utf-8\r\n")
+        .nth(1)
+        .unwrap();
+    let encoded: String = text_part
+        .split("\r\n\r\n")
+        .nth(1)
+        .unwrap()
+        .lines()
+        .take_while(|line| !line.starts_with("--"))
+        .collect();
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .unwrap();
+    String::from_utf8(decoded).unwrap()
+}
+
+#[tokio::test]
+async fn the_double_opt_in_works_offline_with_the_file_email_backend() {
+    let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    let app = spawn_app_with(|c| {
+        c.email_client.backend = EmailBackend::File;
+        c.email_client.mailbox_directory = Some(directory.clone());
+    })
+    .await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let text = read_single_eml_text(&directory);
+    let link = linkify::LinkFinder::new()
+        .links(&text)
+        .find(|link| *link.kind() == linkify::LinkKind::Url)
+        .unwrap();
+    let mut confirmation_link = reqwest::Url::parse(link.as_str()).unwrap();
+    confirmation_link.set_port(Some(app.port)).unwrap();
+    reqwest::get(confirmation_link)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+    assert!(app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .is_empty());
+    std::fs::remove_dir_all(directory).unwrap();
+}
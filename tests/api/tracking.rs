@@ -0,0 +1,213 @@
+use crate::helpers::{
+    create_confirmed_subscriber, spawn_app, spawn_app_with, BatchResponder, TestApp,
+};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::Mock;
+
+const ISSUE_HTML: &str = r#"<html><body><p>Read <a href="https://example.com/post?a=1&amp;b=2">this</a>.</p></body></html>"#;
+
+/// Publish an issue to a single confirmed subscriber and return the HTML they received.
+async fn publish_issue(app: &TestApp, tracking: serde_json::Value) -> (Uuid, String) {
+    create_confirmed_subscriber(app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(BatchResponder::accepting_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": ISSUE_HTML,
+            },
+            "tracking": tracking,
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let newsletter_issue_id = body["newsletter_issue_id"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let batch: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    (
+        newsletter_issue_id,
+        batch[0]["HtmlBody"].as_str().unwrap().to_owned(),
+    )
+}
+
+/// Extract the tracking link with the given prefix, pointing it at the test server.
+fn tracking_link(app: &TestApp, html: &str, prefix: &str) -> reqwest::Url {
+    let start = html.find(&format!("http://127.0.0.1{prefix}")).unwrap();
+    let end = start + html[start..].find('"').unwrap();
+    let mut link = reqwest::Url::parse(&html[start..end]).unwrap();
+    link.set_port(Some(app.port)).unwrap();
+    link
+}
+
+/// Remove the link to the preference center appended to every newsletter.
+fn without_preferences_footer(html: &str) -> String {
+    let start = html
+        .find(r#"<p><a href="http://127.0.0.1/preferences/"#)
+        .unwrap();
+    let end = start + html[start..].find("</p>").unwrap() + "</p>".len();
+    format!("{}{}", &html[..start], &html[end..])
+}
+
+/// An app with tracking enabled, as it has to be for issues to be tracked.
+async fn spawn_tracking_app() -> TestApp {
+    spawn_app_with(|c| {
+        c.tracking.opens_enabled = true;
+        c.tracking.clicks_enabled = true;
+    })
+    .await
+}
+
+fn tracked() -> serde_json::Value {
+    serde_json::json!({"opens": true, "clicks": true})
+}
+
+fn no_redirect_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn links_are_rewritten_and_an_open_pixel_is_added() {
+    let app = spawn_tracking_app().await;
+
+    let (_, html) = publish_issue(&app, tracked()).await;
+
+    assert!(!html.contains("https://example.com/post"));
+    assert!(html.contains(r#"<a href="http://127.0.0.1/r/"#));
+    assert!(html.contains(r#"<img src="http://127.0.0.1/o/"#));
+}
+
+#[tokio::test]
+async fn clicking_a_tracked_link_redirects_to_the_original_url() {
+    let app = spawn_tracking_app().await;
+    let (newsletter_issue_id, html) = publish_issue(&app, tracked()).await;
+
+    let response = no_redirect_client()
+        .get(tracking_link(&app, &html, "/r/"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 302);
+    assert_eq!(
+        response.headers()["Location"],
+        "https://example.com/post?a=1&b=2"
+    );
+    let stats: serde_json::Value = app
+        .get_issue_stats(&newsletter_issue_id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(stats["clicks"]["total"], 1);
+    assert_eq!(stats["clicks"]["unique"], 1);
+    assert_eq!(stats["links"][0]["url"], "https://example.com/post?a=1&b=2");
+}
+
+#[tokio::test]
+async fn loading_the_pixel_records_an_open() {
+    let app = spawn_tracking_app().await;
+    let (newsletter_issue_id, html) = publish_issue(&app, tracked()).await;
+    let pixel = tracking_link(&app, &html, "/o/");
+
+    for _ in 0..2 {
+        let response = reqwest::get(pixel.clone()).await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["Content-Type"], "image/gif");
+    }
+
+    let stats: serde_json::Value = app
+        .get_issue_stats(&newsletter_issue_id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(stats["delivered"], 1);
+    assert_eq!(stats["opens"]["total"], 2);
+    assert_eq!(stats["opens"]["unique"], 1);
+}
+
+#[tokio::test]
+async fn tampered_redirect_tokens_are_rejected() {
+    let app = spawn_tracking_app().await;
+    let (_, html) = publish_issue(&app, tracked()).await;
+    let mut link = tracking_link(&app, &html, "/r/");
+    let token = link.path().trim_start_matches("/r/").to_owned();
+    let (_, signature) = token.split_once('.').unwrap();
+    let forged_payload = base64_url(
+        r#"{"i":"00000000-0000-0000-0000-000000000000","s":"00000000-0000-0000-0000-000000000000","u":"https://evil.example.com"}"#,
+    );
+    link.set_path(&format!("/r/{forged_payload}.{signature}"));
+
+    let response = no_redirect_client().get(link).send().await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn issues_are_not_tracked_unless_asked_to() {
+    let app = spawn_tracking_app().await;
+
+    let (newsletter_issue_id, html) = publish_issue(&app, serde_json::json!({})).await;
+
+    assert_eq!(without_preferences_footer(&html), ISSUE_HTML);
+    let stats: serde_json::Value = app
+        .get_issue_stats(&newsletter_issue_id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(stats["track_opens"], false);
+    assert_eq!(stats["track_clicks"], false);
+}
+
+#[tokio::test]
+async fn tracking_is_off_unless_enabled_globally() {
+    let app = spawn_app().await;
+
+    let (_, html) = publish_issue(&app, tracked()).await;
+
+    assert_eq!(without_preferences_footer(&html), ISSUE_HTML);
+}
+
+#[tokio::test]
+async fn stats_require_authentication() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/admin/issues/{}/stats",
+            &app.address,
+            Uuid::new_v4()
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+fn base64_url(s: &str) -> String {
+    use base64::Engine;
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(s)
+}
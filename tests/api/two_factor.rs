@@ -0,0 +1,302 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use zero2prod::totp;
+
+async fn login(app: &TestApp, password: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/login", &app.address))
+        .json(&serde_json::json!({
+            "username": app.test_user.username,
+            "password": password,
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn post_second_factor(app: &TestApp, login_token: &str, code: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/login/second-factor", &app.address))
+        .json(&serde_json::json!({
+            "login_token": login_token,
+            "code": code,
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn get_lists_with_session(app: &TestApp, session_token: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("{}/admin/lists", &app.address))
+        .bearer_auth(session_token)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn get_lists_with_password(app: &TestApp) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("{}/admin/lists", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+fn code_for(secret: &[u8], steps_ahead: i64) -> String {
+    totp::code(
+        secret,
+        totp::time_step(Utc::now().timestamp()) + steps_ahead,
+    )
+}
+
+struct Enrolled {
+    secret: Vec<u8>,
+    recovery_codes: Vec<String>,
+}
+
+/// Enrol the test user, confirming with the current code.
+/// The code for a neighbouring step is tried as well, should the clock tick over a step
+/// boundary between computing a code and the server checking it.
+async fn enrol(app: &TestApp) -> Enrolled {
+    let client = reqwest::Client::new();
+    let enrolment: serde_json::Value = client
+        .post(format!("{}/admin/two-factor", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let secret = BASE32_NOPAD
+        .decode(enrolment["secret"].as_str().unwrap().as_bytes())
+        .unwrap();
+    let mut response = None;
+    for steps_ahead in [0, 1, -1] {
+        let attempt = client
+            .post(format!("{}/admin/two-factor/confirm", &app.address))
+            .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+            .json(&serde_json::json!({ "code": code_for(&secret, steps_ahead) }))
+            .send()
+            .await
+            .unwrap();
+        if attempt.status().as_u16() != 400 {
+            response = Some(attempt);
+            break;
+        }
+    }
+    let response: serde_json::Value = response
+        .expect("No code around the current step confirmed the enrolment.")
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let recovery_codes = response["recovery_codes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|code| code.as_str().unwrap().to_owned())
+        .collect();
+    Enrolled {
+        secret,
+        recovery_codes,
+    }
+}
+
+async fn login_token(app: &TestApp) -> String {
+    let body: serde_json::Value = login(app, &app.test_user.password.clone())
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body["status"], "second_factor_required");
+    body["login_token"].as_str().unwrap().to_owned()
+}
+
+#[tokio::test]
+async fn logging_in_without_two_factor_returns_a_session() {
+    let app = spawn_app().await;
+
+    let response = login(&app, &app.test_user.password.clone()).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "authenticated");
+    let session_token = body["session_token"].as_str().unwrap();
+    let response = get_lists_with_session(&app, session_token).await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn logging_in_with_a_wrong_password_is_rejected() {
+    let app = spawn_app().await;
+
+    let response = login(&app, "wrong-password").await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn unknown_sessions_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = get_lists_with_session(&app, "not-a-session").await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn enrolment_returns_a_provisioning_uri() {
+    let app = spawn_app().await;
+
+    let enrolment: serde_json::Value = reqwest::Client::new()
+        .post(format!("{}/admin/two-factor", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    let uri = enrolment["provisioning_uri"].as_str().unwrap();
+    assert!(uri.starts_with(&format!(
+        "otpauth://totp/zero2prod:{}?secret={}",
+        app.test_user.username,
+        enrolment["secret"].as_str().unwrap()
+    )));
+    // Two-factor authentication is not enabled until a code is confirmed.
+    let response = get_lists_with_password(&app).await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn enrolment_is_not_confirmed_with_a_wrong_code() {
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    client
+        .post(format!("{}/admin/two-factor", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await
+        .unwrap();
+
+    let response = client
+        .post(format!("{}/admin/two-factor/confirm", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .json(&serde_json::json!({ "code": "000000x" }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 400);
+    let response = get_lists_with_password(&app).await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn confirmed_enrolment_requires_a_second_factor_to_log_in() {
+    let app = spawn_app().await;
+    let enrolled = enrol(&app).await;
+    assert_eq!(enrolled.recovery_codes.len(), 10);
+
+    // Passwords alone are no longer enough.
+    let response = get_lists_with_password(&app).await;
+    assert_eq!(response.status().as_u16(), 401);
+
+    let login_token = login_token(&app).await;
+    // A pending login cannot be used as a session.
+    let response = get_lists_with_session(&app, &login_token).await;
+    assert_eq!(response.status().as_u16(), 401);
+
+    let response = post_second_factor(&app, &login_token, &code_for(&enrolled.secret, 1)).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let response = get_lists_with_session(&app, body["session_token"].as_str().unwrap()).await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn codes_cannot_be_used_twice() {
+    let app = spawn_app().await;
+    let enrolled = enrol(&app).await;
+    let code = code_for(&enrolled.secret, 1);
+
+    let response = post_second_factor(&app, &login_token(&app).await, &code).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = post_second_factor(&app, &login_token(&app).await, &code).await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn recovery_codes_can_be_used_once() {
+    let app = spawn_app().await;
+    let enrolled = enrol(&app).await;
+    let recovery_code = &enrolled.recovery_codes[0];
+
+    let response = post_second_factor(&app, &login_token(&app).await, recovery_code).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = post_second_factor(&app, &login_token(&app).await, recovery_code).await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn login_tokens_are_single_use() {
+    let app = spawn_app().await;
+    let enrolled = enrol(&app).await;
+    let login_token = login_token(&app).await;
+
+    let response = post_second_factor(&app, &login_token, &enrolled.recovery_codes[0]).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = post_second_factor(&app, &login_token, &enrolled.recovery_codes[1]).await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn a_second_enrolment_conflicts() {
+    let app = spawn_app().await;
+    let enrolled = enrol(&app).await;
+    let body: serde_json::Value =
+        post_second_factor(&app, &login_token(&app).await, &enrolled.recovery_codes[0])
+            .await
+            .json()
+            .await
+            .unwrap();
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/two-factor", &app.address))
+        .bearer_auth(body["session_token"].as_str().unwrap())
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn publishing_can_require_two_factor_authentication() {
+    let app = spawn_app_with(|c| c.two_factor.required_for_publishers = true).await;
+
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 403);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["type"], "/problems/forbidden");
+}